@@ -4,21 +4,484 @@ use proc_macro::TokenStream;
 use proc_macro2::Span;
 use quote::{quote, quote_spanned, ToTokens};
 use syn::{
-    parse, parse::Parse, punctuated::Punctuated, Attribute, Expr, ExprLit, Field, Ident,
-    ItemStruct, Lit, Meta, Result, Token, Type, Visibility,
+    ext::IdentExt, parse, parse::Parse, punctuated::Punctuated, spanned::Spanned, Attribute, Expr,
+    ExprLit, Field, Ident, ItemStruct, Lit, Meta, Result, Token, Type, Visibility,
 };
 
+/// Generate typed maps from struct definitions.
+///
+/// Multiple structs can be defined in one invocation. Inner attributes(`#![derive(Clone)]`)
+/// at the beginning apply to all of them, and a struct may refer to structs defined before it:
+/// - `#[extends(Base, ..)]`: include all the fields of the bases before its own fields.
+/// - `#[subset_of(Base)]`: every field must exist in the base with the same type; fields typed
+///   `_` take the type and attributes of the base field.
+///
+/// Prefilled maps can also have impl blocks(`impl Cx { .. }`) after their definition, whose
+/// methods are attached to the handlers of every state. `#[requires(a, ..)]` on a method makes
+/// it exist only when the fields `a, ..` are occupied, and `#[produces(b, ..)]` on a method
+/// taking `self` lets `_` in its return type stand for the handler with `b, ..` occupied.
+///
+/// Tuple structs(`struct Cx(PeerAddr, RouteInfo);`) are supported as well, their fields are
+/// named `_0`, `_1`, .. by position.
+///
+/// The prefilled store reserves space for all the fields, and `Store::SIZE` reports its size.
+/// Its layout is left to rustc unless the map has `#[reorder_by_align]`.
+/// `Store::OFFSET_{FIELD}` and `Store::ALIGN_{FIELD}` locate the slot of every field, which
+/// holds the value when the field is occupied, for readers of the raw bytes like eBPF exporters.
+/// `FIELD` is the field name in upper case without `r#`, and the offsets use `offset_of!`, which
+/// needs Rust 1.77.
+/// Its handler `StoreHandlerIn<S, ..>` is generic over the `certain_map::Storage` handle `S`:
+/// `StoreHandler<'a, ..>` borrows the store, `StoreOwnedHandler<..>` boxes it, and
+/// `Store::handler_in(storage)` accepts guards of shared stores like `RefMut` and `MutexGuard`.
+/// With the `scope` feature, `store.scope(async |cx| ..)` runs an async closure with the empty
+/// handler, and the returned future borrows the store, so it can be awaited without naming the
+/// handler lifetime.
+/// Handlers are `UnwindSafe` when the fields are, whatever the storage, so they can be moved into
+/// `catch_unwind`. Maps with `#[persistent]` fields are the exception: those fields survive a
+/// handler dropped by a panic, so their handlers are only `UnwindSafe` when the storage handle
+/// is, like an owned handler.
+///
+/// Map attributes:
+/// - `#[style = "unfilled"]`: generate the v0.2 style generic struct. Its parameters default to
+///   `Vacancy`, so the bare name is the empty map.
+/// - `#[empty(Name)]` / `#[full(Name)]`: aliases of the empty and full handler. Prefilled maps
+///   also get `NameState`, the alias of the state, for code keeping states with `Fork` / `Attach`.
+/// - `#[view(Name)]`: a trait implemented by every context having all the fields of the map, so
+///   any larger map satisfies it. With `#[derive(Clone)]`, `from_view(cx)` builds this map from
+///   such a context.
+/// - `#[ref_view(Name)]`: a struct of references to all the fields, returned by `ref_view()` of
+///   the full handler, so the complete context can be destructured.
+/// - `#[maybe_view(Name)]`: a struct of `Option` references to all the fields, returned by
+///   `maybe_view()` of any state, which is handy for logging and dynamic code.
+/// - `#[partial(Name)]`: a struct of `Option` fields implementing `ParamMaybeRef` /
+///   `ParamMaybeMut`, a context whose occupancy is only known at runtime.
+/// - `#[partition(ConnCx: peer_addr, tls; ReqCx: route)]`: also generate a map of each
+///   partition with its fields and the derives of this map. `partition()` of the handler moves
+///   the fields into the owned handlers of the partitions, keeping their states, and drops the
+///   fields in no partition.
+/// - `#[cache_key(a, b, ..)]`: `cache_key()` of the handlers with the fields `a, b, ..` occupied
+///   clones them into `StoreCacheKey`, a struct deriving `Clone`, `Hash` and `Eq`, so response
+///   caches are keyed on the same fields everywhere.
+/// - `#[drop_order(a, b, ..)]`: drop the fields `a, b, ..` first and in this order when the
+///   handler is dropped or reset, then the others in declaration order, for guards which must be
+///   released before other fields.
+/// - `#[state_enum(Name)]`: an enum over all the handler states with `From` / `TryFrom`
+///   conversions, for maps with at most 8 fields.
+/// - `#[derive(Clone, Debug, Valuable)]`: generate fork, Debug and `valuable::Valuable` support.
+///   `Valuable` requires the `valuable` feature and visits the occupied fields only.
+/// - `#[derive(Format)]`: implement `defmt::Format` for the handlers with the occupied fields,
+///   requires the `defmt` feature and a `defmt` dependency of the crate defining the map.
+/// - `#[derive(JsonSchema)]`: implement `schemars::JsonSchema` for the full handlers, requires
+///   the `schemars` feature. Fields shown in Debug output use the schema of their type, and the
+///   others are redacted.
+/// - `#[derive(Serialize, Deserialize)]`: implement serde `Serialize` for the handlers with the
+///   occupied fields, and `Deserialize` for the owned handler with every field which is not
+///   skipped, requires the `serde` feature.
+/// - `#[derive(Arbitrary)]`: implement `arbitrary::Arbitrary` for the full owned handler and the
+///   `#[partial(Name)]` struct, whose fields are occupied at random, requires the `arbitrary`
+///   feature.
+/// - `#[derive(Copy)]`: all the fields are Copy, so handlers skip the drop glue. The unfilled
+///   style map itself becomes Copy and can be passed by value freely.
+/// - `#[secure_clear]`: overwrite slots with zeros when values are taken or removed, when the
+///   handler is reset or dropped, and when the store is dropped.
+/// - `#[align_fields(cacheline)]`: align every field to a cache line to avoid false sharing
+///   between threads. `#[padded]` aligns a single field.
+/// - `#[reorder_by_align]`: make the store `repr(C)` with the slots sorted by descending
+///   alignment, whatever the declaration order, so no padding is needed between them. Slots of
+///   the same alignment keep the declaration order.
+/// - `#[codegen_stats]`: print the number of generated impls and tokens and the impls of every
+///   trait at expansion time. Setting the `CERTAIN_MAP_STATS=1` environment variable enables it
+///   for every map, after the crates defining maps are rebuilt.
+/// - `#[method_prefix(cx)]`: also generate inherent methods named with the prefix, like
+///   `cx_ref::<T>()`, `cx_set(v)` and `cx_take::<T>()`, which call the param traits without
+///   clashing with other traits in scope.
+/// - `#[global]`: a process-wide full handler, set once with `init_global(handler)` at startup
+///   and read with `global_handler()`, for configuration every request reads but never
+///   mutates. The fields must be `Send + Sync`.
+/// - `#[wasm]`: `to_js()` of the full handler and `Store::from_js(value)` convert between the
+///   full handler and a JS object keyed by the field names, requires the `wasm` feature. The
+///   fields must implement serde `Serialize` and `Deserialize`.
+/// - `#[hide_internals]`: mark the state types and the handler generic over the storage
+///   `#[doc(hidden)]`, so rustdoc only lists the store and the handler aliases, whose docs list
+///   the fields instead. Doc comments of the struct go to the store either way.
+/// - `#[assert_send]`: fail compilation if any field is not `Send`, the error names the field
+///   instead of the generated types a failed `Send` bound of a handler points at.
+/// - `#[deny_large(N)]`: fail compilation if any field is larger than N bytes, the error shows
+///   the field name and its size.
+/// - `#[vis(store = .., state = .., handler = .., owned = ..)]`: override the visibility of
+///   generated items, which default to the visibility of the struct. Traits connecting items of
+///   different visibility (like `Handler` for the store) are not implemented.
+///
+/// Field attributes:
+/// - `#[ensure(Clone, Debug, Valuable, Format)]`: generate `Param<T>`, Debug, Valuable and
+///   defmt output for the field. Fields without them are left out, which keeps secrets out of
+///   logs. Cloneable fields can also be read together with `Param<(A, B)>`, up to 4 elements.
+/// - `#[ensure(PartialEq)]`: generate `ParamEq<T>` for the field, which `assert_param_eq!` and
+///   `assert_param_ne!` compare in tests.
+/// - `#[flag]`: a unit struct marking a fact about the request, like `struct TlsTerminated;`.
+///   Handlers get `set_flag::<T>()` and `has_flag::<T>()`, and `Flag<T>` bounds the contexts
+///   where it is set.
+/// - `#[fallback(expr)]`: `ParamRef` (and `Param` of cloneable fields) of the field exists in
+///   every state, and returns the value of `expr` when the field is vacant. The value is
+///   created on the first read and kept in a static, so the field type must be `Send + Sync`.
+/// - `#[push]` / `#[push(Item)]`: generate `ParamPush<Item>` for a collection field, which
+///   creates the collection with `Default` on the first push and extends it afterwards.
+/// - `#[weak]`: a `std::sync::Weak<T>` field, which does not keep a shared resource alive.
+///   `ParamSet<Arc<T>>` downgrades the value, and `Param<Option<Arc<T>>>` upgrades it in every
+///   state, returning `None` when the field is vacant or the resource is gone. A
+///   `std::rc::Weak<T>` field works the same with `Rc<T>`. The path must name the module.
+/// - `#[persistent]`: a connection-scoped field which survives the handler. When a handler with
+///   all the persistent fields occupied is dropped, they are left in the store and the other
+///   fields are dropped, and `store.resume()` returns the handler with them occupied. Otherwise
+///   `resume()` returns `None`, and `handler()` drops the persistent fields left in the store.
+/// - `#[requires(a, ..)]`: `ParamSet` of the field only exists when the fields `a, ..` are
+///   occupied, which encodes ordering invariants into the types.
+/// - `#[conflicts_with(a, ..)]`: the fields are mutually exclusive, `ParamSet` of either only
+///   exists when the other is vacant. Fields which all conflict with each other share storage
+///   in the prefilled store, like a `#[one_of(group)]`.
+/// - `#[one_of(group)]`: at most one field of the group can be occupied and they share storage.
+///   `group()` of the handler returns an enum borrowing the occupied one, if any.
+/// - `#[baggage("key")]`: export and import the field with `to_baggage()` / `from_baggage()`,
+///   requires the `otel` feature. The field type must implement `Display` and `FromStr`.
+/// - `#[header("x-forwarded-peer")]`: export and import the field with `to_headers()` /
+///   `from_headers()` against `http::HeaderMap`, requires the `http` feature. The field type must
+///   implement `Display` and `FromStr`.
+/// - `#[graphql]`: export and import the field as async-graphql data with `to_graphql_data()` /
+///   `from_graphql_data()`, requires the `async-graphql` feature. The field type must be
+///   `Clone + Send + Sync + 'static`.
+/// - `#[serde(rename = "key", with = "module", skip)]`: passed to serde for the field, with
+///   `#[derive(Serialize, Deserialize)]`. Skipped fields are never serialized and stay vacant
+///   after deserialization.
+/// - `#[config(key = "server.region")]`: load the field with `fill_from(&source)` from a config
+///   source or with `fill_from_env()` from environment variables, requires the `config` feature.
+///   Values are deserialized, or parsed with `FromStr` with `#[config(key = "..", from_str)]`.
+/// - `#[wire]` / `#[wire(with = module)]`: exchange the field in the binary encoding of
+///   `to_wire()` / `from_wire()`, requires the `wire` feature. The field type must implement
+///   `certain_map::wire::Wire`, or the module must have `encode` and `decode` functions.
+/// - `#[clone_strategy(arc)]`: store the field as `Arc<T>`, so forking only bumps a reference
+///   count, which suits large read-mostly fields. The field type must be `Clone`: `ParamMut` and
+///   taking clone the value when it is still shared by a fork. `#[clone_strategy(rc)]` stores
+///   `Rc<T>` instead for thread-per-core runtimes, requires the `rc` feature.
+///
+/// With the `raw` feature, the prefilled store exposes the slot of every field as
+/// `raw_{name}()` / `raw_{name}_mut()`, and handlers expose their store with `raw_store()` /
+/// `raw_store_mut()`. A slot is initialized exactly when the field is occupied in the state
+/// reported by `OCCUPIED` of the handler, and `unsafe attach` / `attach_owned` rebuild a handler
+/// from a store or a boxed store and a state, so custom schedulers and serializers can move
+/// contexts without the macro's help. `raw_{name}_mut()` is `unsafe`: the store drops the
+/// `#[persistent]` fields left in it by the last handler on `handler()` and on its own drop,
+/// which are the only slots a store ever drops itself.
+///
+/// With the `provenance` feature, setting, taking, removing and pushing record the caller
+/// location of the prefilled handler, which `audit()` reports as the `location` of the field.
+///
+/// With the `vacancy-stats` feature, `ParamMaybeRef` / `ParamMaybeMut` calls finding a field
+/// vacant are counted per field, and `Store::vacancy_stats()` reports the counts of all
+/// instances of the map.
+///
+/// With the `test-gen` feature, every prefilled map also gets a `#[cfg(test)]` module which
+/// runs its generated code with drop-counting values in place of the field types, checking
+/// that each set, take, remove, reset and fork drops every value exactly once.
 #[proc_macro]
 pub fn certain_map(input: TokenStream) -> TokenStream {
-    let cmap: CMap = match parse(input) {
+    let cmaps: CMaps = match parse(input) {
         Ok(m) => m,
         Err(e) => return TokenStream::from(e.to_compile_error()),
     };
 
-    let output = cmap.to_token_stream();
+    let output = cmaps.to_token_stream();
     TokenStream::from(output)
 }
 
+/// Generate `fn from_cx(cx: &CX) -> Self` which builds the struct from the fields of a context.
+///
+/// Every field is cloned from the context through `ParamRef<T>`, so the context only needs the
+/// fields the struct uses. A field `Option<T>` marked `#[cx(maybe)]` is read through
+/// `ParamMaybeRef<T>` and is `None` when `T` is vacant; without the attribute it is the context
+/// field `Option<T>`.
+#[proc_macro_derive(FromContext, attributes(cx))]
+pub fn derive_from_context(input: TokenStream) -> TokenStream {
+    match parse::<CxView>(input) {
+        Ok(view) => TokenStream::from(view.to_from_context_tokens()),
+        Err(e) => TokenStream::from(e.to_compile_error()),
+    }
+}
+
+/// Generate `fn into_cx(self, cx: CX)` which sets every field of the struct into the context and
+/// returns the transformed context.
+#[proc_macro_derive(IntoContext)]
+pub fn derive_into_context(input: TokenStream) -> TokenStream {
+    match parse::<CxView>(input) {
+        Ok(view) => TokenStream::from(view.to_into_context_tokens()),
+        Err(e) => TokenStream::from(e.to_compile_error()),
+    }
+}
+
+/// Generate `fn try_from_cx(cx: &CX) -> Result<Self, MissingParam>` which builds the struct from
+/// the fields of a context whose occupancy is only known at runtime.
+///
+/// Every field is read through `ParamMaybeRef<T>`, and the error lists all the absent fields.
+/// Fields `Option<T>` marked `#[cx(maybe)]` are read as maybe `T` and are never missing.
+#[proc_macro_derive(TryFromContext, attributes(cx))]
+pub fn derive_try_from_context(input: TokenStream) -> TokenStream {
+    match parse::<CxView>(input) {
+        Ok(view) => TokenStream::from(view.to_try_from_context_tokens()),
+        Err(e) => TokenStream::from(e.to_compile_error()),
+    }
+}
+
+/// Generate a prefilled map with the fields of an existing struct, for code migrating from a
+/// plain context struct whose definition can not move into `certain_map!` yet.
+///
+/// The map takes the visibility of the struct and is named `{Struct}Map`, or by
+/// `#[certain_map(name = Cx)]`. Other items of `#[certain_map(..)]` are map attributes, like
+/// `#[certain_map(name = Cx, derive(Clone), full(CxFull))]`. `From` converts the struct into
+/// the full owned handler, and any full handler back into the struct.
+#[proc_macro_derive(CertainMapFields, attributes(certain_map))]
+pub fn derive_certain_map_fields(input: TokenStream) -> TokenStream {
+    match parse::<syn::DeriveInput>(input).and_then(certain_map_fields_tokens) {
+        Ok(tokens) => TokenStream::from(tokens),
+        Err(e) => TokenStream::from(e.to_compile_error()),
+    }
+}
+
+/// Add the context bounds to an `impl Service<(Req, CX)> for ..` block.
+///
+/// - `needs(A, ..)`: the context must have the fields, adds `CX: ParamRef<A>`.
+/// - `provides(B, ..)`: the service sets the fields in order, adds `CX: ParamSet<B>` and the
+///   bounds of the following transformed contexts.
+/// - `inner(T, ..)`: `T` must be the same service trait over `(Req, <CX with provided fields>)`.
+///
+/// ```rust,ignore
+/// #[cx_service(provides(RawBeforeAdd), inner(T))]
+/// impl<T, CX> Service<(u8, CX)> for Add1<T> {
+///     type Response = T::Response;
+///     type Error = T::Error;
+///
+///     fn call(&self, (num, cx): (u8, CX)) -> impl Future<Output = Result<T::Response, T::Error>> {
+///         self.0.call((num + 1, cx.param_set(RawBeforeAdd(num))))
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn cx_service(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args: CxServiceArgs = match parse(args) {
+        Ok(args) => args,
+        Err(e) => return TokenStream::from(e.to_compile_error()),
+    };
+    let item: syn::ItemImpl = match parse(input) {
+        Ok(item) => item,
+        Err(e) => return TokenStream::from(e.to_compile_error()),
+    };
+    match args.expand(item) {
+        Ok(item) => TokenStream::from(item.into_token_stream()),
+        Err(e) => TokenStream::from(e.to_compile_error()),
+    }
+}
+
+// One or more map definitions, with optional shared inner attributes in front of them.
+struct CMaps(Vec<CMap>);
+
+impl Parse for CMaps {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        let shared: Vec<Attribute> = input
+            .call(Attribute::parse_inner)?
+            .into_iter()
+            .map(|mut attr| {
+                attr.style = syn::AttrStyle::Outer;
+                attr
+            })
+            .collect();
+
+        let mut definitions: Vec<ItemStruct> = Vec::new();
+        let mut maps = Vec::new();
+        while !input.is_empty() {
+            let span = input.span();
+            // skip the attributes to tell impl blocks from structs
+            let ahead = input.fork();
+            ahead.call(Attribute::parse_outer)?;
+            if ahead.peek(Token![impl]) {
+                let item = syn::ItemImpl::parse(input)?;
+                let self_ident = match &*item.self_ty {
+                    Type::Path(path) if path.qself.is_none() => path.path.get_ident(),
+                    _ => None,
+                };
+                let map = self_ident
+                    .and_then(|ident| maps.iter_mut().rev().find(|m: &&mut CMap| &m.ident == ident))
+                    .ok_or_else(|| {
+                        syn::Error::new(
+                            item.self_ty.span(),
+                            "impl blocks must be for a map defined earlier in the same certain_map!",
+                        )
+                    })?;
+                map.add_methods(item)?;
+                continue;
+            }
+            let mut definition = ItemStruct::parse(input)?;
+            name_positional_fields(&mut definition);
+            resolve_relations(&mut definition, &definitions, span)?;
+            definition.attrs.splice(0..0, shared.iter().cloned());
+            definitions.push(definition.clone());
+            let mut map = CMap::from_struct(definition, span)?;
+            let sub_definitions = map.partition_definitions();
+            for (partition, sub_definition) in map.partitions.iter_mut().zip(sub_definitions) {
+                let sub_map = CMap::from_struct(sub_definition.clone(), span)?;
+                partition.paths = (0..sub_map.fields.len())
+                    .map(|idx| sub_map.slot_path(idx))
+                    .collect();
+                definitions.push(sub_definition);
+                maps.push(sub_map);
+            }
+            maps.push(map);
+        }
+        if maps.is_empty() {
+            return Err(syn::Error::new(
+                input.span(),
+                "expected a struct definition",
+            ));
+        }
+        Ok(CMaps(maps))
+    }
+}
+
+impl ToTokens for CMaps {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        for cmap in self.0.iter() {
+            cmap.to_tokens(tokens);
+        }
+    }
+}
+
+// Name the fields of a tuple struct definition `_0`, `_1`, .. by their position, so it can be
+// handled like a struct with named fields.
+fn name_positional_fields(definition: &mut ItemStruct) {
+    if let syn::Fields::Unnamed(unnamed) = &mut definition.fields {
+        let named = unnamed
+            .unnamed
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(idx, mut field)| {
+                field.ident = Some(quote::format_ident!("_{idx}", span = field.ty.span()));
+                field.colon_token = Some(Default::default());
+                field
+            })
+            .collect();
+        definition.fields = syn::Fields::Named(syn::FieldsNamed {
+            brace_token: Default::default(),
+            named,
+        });
+        definition.semi_token = None;
+    }
+}
+
+// Resolve `#[extends(Base, ..)]` and `#[subset_of(Base)]` against the maps defined before
+// in the same invocation, and remove the attributes.
+//
+// `extends` prepends all the fields of the bases. `subset_of` requires every field to exist
+// in the base with the same type; a field typed `_` takes its type and attributes from the base.
+fn resolve_relations(
+    definition: &mut ItemStruct,
+    defined: &[ItemStruct],
+    span: Span,
+) -> Result<()> {
+    let find = |ident: &Ident| {
+        defined.iter().find(|d| &d.ident == ident).ok_or_else(|| {
+            syn::Error::new(
+                ident.span(),
+                format!("`{ident}` must be defined earlier in the same certain_map!"),
+            )
+        })
+    };
+    fn named(fields: &mut syn::Fields, span: Span) -> Result<&mut Punctuated<Field, Token![,]>> {
+        match fields {
+            syn::Fields::Named(named) => Ok(&mut named.named),
+            _ => Err(syn::Error::new(
+                span,
+                "fields without names are not supported",
+            )),
+        }
+    }
+
+    if let Some(idx) = definition
+        .attrs
+        .iter()
+        .position(|attr| attr.path().is_ident("extends"))
+    {
+        let bases = definition
+            .attrs
+            .remove(idx)
+            .parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated)?;
+        let mut fields = Punctuated::<Field, Token![,]>::new();
+        for base in bases.iter() {
+            fields.extend(find(base)?.fields.iter().cloned());
+        }
+        let own = named(&mut definition.fields, span)?;
+        fields.extend(own.iter().cloned());
+        for (idx, field) in fields.iter().enumerate() {
+            if fields.iter().skip(idx + 1).any(|f| f.ident == field.ident) {
+                return Err(syn::Error::new(
+                    span,
+                    format!("duplicate field `{}`", field.ident.as_ref().unwrap()),
+                ));
+            }
+        }
+        *own = fields;
+    }
+
+    if let Some(idx) = definition
+        .attrs
+        .iter()
+        .position(|attr| attr.path().is_ident("subset_of"))
+    {
+        let base: Ident = definition.attrs.remove(idx).parse_args()?;
+        let base = find(&base)?;
+        for field in named(&mut definition.fields, span)?.iter_mut() {
+            let name = field.ident.as_ref().unwrap();
+            let base_field = base
+                .fields
+                .iter()
+                .find(|f| f.ident.as_ref() == Some(name))
+                .ok_or_else(|| {
+                    syn::Error::new(
+                        name.span(),
+                        format!("field `{name}` does not exist in `{}`", base.ident),
+                    )
+                })?;
+            if matches!(field.ty, Type::Infer(_)) {
+                field.ty = base_field.ty.clone();
+                if field.attrs.is_empty() {
+                    field.attrs = base_field.attrs.clone();
+                }
+            } else if field.ty.to_token_stream().to_string()
+                != base_field.ty.to_token_stream().to_string()
+            {
+                return Err(syn::Error::new(
+                    name.span(),
+                    format!("field `{name}` has a different type in `{}`", base.ident),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+const FIELD_ATTR_ERROR: &str =
+    "fields attr now only support #[ensure(Clone, Debug, Valuable, Format, PartialEq)], #[baggage(\"key\")], #[graphql], #[serde(..)], #[config(key = \"..\")], #[header(\"name\")], #[wire], #[clone_strategy(arc | rc)], #[flag], #[fallback(expr)], #[push], #[weak], #[persistent], #[requires(field)], #[conflicts_with(field)], #[one_of(group)] and #[padded]";
+
+// Whether to generate impls of `Handler` and `Attach`, which need generic associated types.
+const GAT: bool = cfg!(feature = "gat");
+const RAW: bool = cfg!(feature = "raw");
+const PROVENANCE: bool = cfg!(feature = "provenance");
+const TEST_GEN: bool = cfg!(feature = "test-gen");
+const RC: bool = cfg!(feature = "rc");
+const VACANCY_STATS: bool = cfg!(feature = "vacancy-stats");
+// Whether to generate `scope()`, whose async closure needs Rust 1.85.
+const SCOPE: bool = cfg!(feature = "scope");
+
+// The state enum has 2^n variants, so it is limited to small maps.
+const MAX_STATE_ENUM_FIELDS: usize = 8;
+
 #[derive(Copy, Clone, Default)]
 enum GenStyle {
     // PreFilled generates a struct with all fields, allows to pass `&mut Handler`
@@ -32,22 +495,284 @@ enum GenStyle {
     Unfilled,
 }
 
+#[derive(Clone)]
 struct CMap {
     attrs: Vec<Attribute>,
     vis: Visibility,
     ident: Ident,
     fields: Vec<Field>,
     fields_meta: Vec<Option<Punctuated<Meta, Token![,]>>>,
+    fields_baggage: Vec<Option<syn::LitStr>>,
+    // `#[header("name")]` of fields exchanged by `to_headers()` and `from_headers()`
+    fields_header: Vec<Option<syn::LitStr>>,
+    // whether the field is exchanged with async-graphql data
+    fields_graphql: Vec<bool>,
+    // `#[serde(..)]` of fields, used by `#[derive(Serialize, Deserialize)]`
+    fields_serde: Vec<SerdeField>,
+    // `#[config(key = "..")]` of fields loaded by `fill_from()` and `fill_from_env()`
+    fields_config: Vec<Option<ConfigField>>,
+    // `#[wire]` of fields exchanged by `to_wire()` and `from_wire()`
+    fields_wire: Vec<Option<WireField>>,
+    // whether the field is a unit struct set with `set_flag::<T>()`
+    fields_flag: Vec<bool>,
+    // `#[fallback(expr)]` of fields readable in every state
+    fields_fallback: Vec<Option<Expr>>,
+    // item types of fields with `#[push]`
+    fields_push: Vec<Option<Type>>,
+    // the pointer and the pointee type of fields with `#[weak]`
+    fields_weak: Vec<Option<(CloneStrategy, Type)>>,
+    // whether the field is `#[persistent]`
+    fields_persistent: Vec<bool>,
+    // indexes of the fields which must be occupied before setting the field
+    fields_requires: Vec<Vec<usize>>,
+    // indexes of the fields which must be vacant before setting the field
+    fields_conflicts: Vec<Vec<usize>>,
+    // the `#[one_of(group)]` sharing storage with the field
+    fields_one_of: Vec<Option<Ident>>,
+    // the union slot shared by the field, of its one_of group or of mutually conflicting fields
+    fields_slot: Vec<Option<Ident>>,
+    // whether the storage of the field is cache line aligned
+    fields_padded: Vec<bool>,
+    // `#[clone_strategy(..)]` of fields stored behind a shared pointer
+    fields_clone_strategy: Vec<Option<CloneStrategy>>,
 
     span: Span,
     style: GenStyle,
+    item_vis: ItemVis,
+    deny_large: Option<syn::LitInt>,
+    // check that every field is Send, reporting the fields which are not
+    assert_send: bool,
+    // a process-wide full handler set once with `init_global()`
+    global: bool,
+    // hide the state and the handler generic over the storage from rustdoc
+    hide_internals: bool,
+    // convert the full handler to and from JS objects
+    wasm: bool,
+    secure_clear: bool,
+    // `#[align_fields(cacheline)]`, which also aligns the one_of groups
+    align_fields: bool,
+    // `#[reorder_by_align]`, a `repr(C)` store with the slots sorted by descending alignment
+    reorder_by_align: bool,
+    // print a summary of the generated code at expansion time
+    codegen_stats: bool,
+    // `#[method_prefix(cx)]` of the inherent methods forwarding to the param traits
+    method_prefix: Option<Ident>,
+    // the maps of `#[partition(..)]`, which are generated next to this map
+    partitions: Vec<Partition>,
+    // methods of the impl blocks for the map, attached to its handler
+    methods: Vec<Method>,
+    // indexes of the fields of `#[cache_key(..)]`
+    cache_key: Vec<usize>,
+    // indexes of all the fields in the order they are dropped, `#[drop_order(..)]` first
+    drop_order: Vec<usize>,
+}
+
+// A method in an impl block for the map, with the fields it needs and sets.
+#[derive(Clone)]
+struct Method {
+    item: syn::ImplItemFn,
+    // indexes of the fields of `#[requires(..)]`, which are occupied when it is called
+    requires: Vec<usize>,
+    // indexes of the fields of `#[produces(..)]`, which are occupied in the handler it returns
+    produces: Vec<usize>,
+}
+
+// A map of `#[partition(Name: a, b; ..)]` with some fields of the parent map.
+#[derive(Clone)]
+struct Partition {
+    ident: Ident,
+    // indexes of the fields in the parent map
+    fields: Vec<usize>,
+    // paths of the slots in the store of the partition map, filled after it is built
+    paths: Vec<proc_macro2::TokenStream>,
+}
+
+struct PartitionDef {
+    ident: Ident,
+    fields: Punctuated<Ident, Token![,]>,
+}
+
+impl Parse for PartitionDef {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        let ident = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let fields = Punctuated::parse_separated_nonempty(input)?;
+        Ok(PartitionDef { ident, fields })
+    }
+}
+
+// The serde attributes of a field given by `#[serde(rename = "..", with = "..", skip)]`.
+#[derive(Clone, Default)]
+struct SerdeField {
+    rename: Option<syn::LitStr>,
+    with: Option<syn::Path>,
+    skip: bool,
+}
+
+impl SerdeField {
+    fn parse(&mut self, attr: &Attribute) -> Result<()> {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                self.rename = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("with") {
+                self.with = Some(meta.value()?.parse::<syn::LitStr>()?.parse()?);
+            } else if meta.path.is_ident("skip") {
+                self.skip = true;
+            } else {
+                return Err(meta.error("#[serde(..)] only supports rename, with and skip"));
+            }
+            Ok(())
+        })
+    }
+}
+
+// How a field with `#[clone_strategy(..)]` is shared between forks.
+#[derive(Clone, Copy)]
+enum CloneStrategy {
+    // stored as `Arc<T>`, so forks bump the reference count
+    Arc,
+    // stored as `Rc<T>`, the same without atomics
+    Rc,
+}
+
+impl CloneStrategy {
+    fn parse(attr: &Attribute) -> Result<Self> {
+        let strategy: Ident = attr.parse_args()?;
+        if strategy == "arc" {
+            Ok(CloneStrategy::Arc)
+        } else if strategy == "rc" {
+            if !RC {
+                return Err(syn::Error::new(
+                    strategy.span(),
+                    "#[clone_strategy(rc)] requires the `rc` feature",
+                ));
+            }
+            Ok(CloneStrategy::Rc)
+        } else {
+            Err(syn::Error::new(
+                strategy.span(),
+                "#[clone_strategy(..)] only supports arc and rc",
+            ))
+        }
+    }
+
+    // The path of the shared pointer.
+    fn pointer(self) -> proc_macro2::TokenStream {
+        match self {
+            CloneStrategy::Arc => quote!(::std::sync::Arc),
+            CloneStrategy::Rc => quote!(::std::rc::Rc),
+        }
+    }
+
+    // The path of the weak pointer.
+    fn weak(self) -> proc_macro2::TokenStream {
+        match self {
+            CloneStrategy::Arc => quote!(::std::sync::Weak),
+            CloneStrategy::Rc => quote!(::std::rc::Weak),
+        }
+    }
+}
+
+// The config key of a field given by `#[config(key = "..", from_str)]`.
+#[derive(Clone)]
+struct ConfigField {
+    key: syn::LitStr,
+    // parse the value with `FromStr` instead of deserializing it
+    from_str: bool,
+}
+
+impl ConfigField {
+    fn parse(attr: &Attribute) -> Result<Self> {
+        let mut key = None;
+        let mut from_str = false;
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("key") {
+                key = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("from_str") {
+                from_str = true;
+            } else {
+                return Err(meta.error("#[config(..)] only supports key and from_str"));
+            }
+            Ok(())
+        })?;
+        match key {
+            Some(key) => Ok(ConfigField { key, from_str }),
+            None => Err(syn::Error::new(
+                attr.span(),
+                "#[config(..)] needs a key, like #[config(key = \"region\")]",
+            )),
+        }
+    }
+}
+
+// The codec of a field given by `#[wire]` or `#[wire(with = module)]`.
+#[derive(Clone, Default)]
+struct WireField {
+    // a module with `encode` and `decode`, instead of the `Wire` impl of the field type
+    with: Option<syn::Path>,
+}
+
+impl WireField {
+    fn parse(attr: &Attribute) -> Result<Self> {
+        let mut wire = WireField::default();
+        if matches!(attr.meta, Meta::Path(_)) {
+            return Ok(wire);
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("with") {
+                wire.with = Some(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error("#[wire(..)] only supports with"));
+            }
+            Ok(())
+        })?;
+        Ok(wire)
+    }
+}
+
+// Visibility overrides of generated items given by `#[vis(handler = pub(crate), ..)]`.
+// Items without an override inherit the visibility of the struct.
+#[derive(Clone, Default)]
+struct ItemVis {
+    store: Option<Visibility>,
+    state: Option<Visibility>,
+    handler: Option<Visibility>,
+    owned: Option<Visibility>,
 }
 
-impl Parse for CMap {
+impl Parse for ItemVis {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
-        let span = input.span();
-        let mut definition = ItemStruct::parse(input)?;
+        let mut item_vis = ItemVis::default();
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            let vis: Visibility = input.parse()?;
+            let slot = match key.to_string().as_str() {
+                "store" => &mut item_vis.store,
+                "state" => &mut item_vis.state,
+                "handler" => &mut item_vis.handler,
+                "owned" => &mut item_vis.owned,
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "vis only supports store, state, handler and owned",
+                    ))
+                }
+            };
+            if slot.replace(vis).is_some() {
+                return Err(syn::Error::new(key.span(), "duplicate vis key"));
+            }
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+        Ok(item_vis)
+    }
+}
 
+impl CMap {
+    fn from_struct(mut definition: ItemStruct, span: Span) -> Result<Self> {
         if definition.generics.where_clause.is_some() {
             return Err(syn::Error::new(
                 span,
@@ -79,731 +804,5497 @@ impl Parse for CMap {
             definition.attrs.remove(idx);
         }
 
-        let fields: Vec<Field> = definition.fields.into_iter().collect();
-        if fields.iter().any(|f| f.ident.is_none()) {
-            return Err(syn::Error::new(
-                span,
-                "fields without names are not supported",
-            ));
+        // parse #[vis(..)] and remove it.
+        let mut item_vis = ItemVis::default();
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("vis"))
+        {
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    span,
+                    "#[vis(..)] is only supported by the prefilled style",
+                ));
+            }
+            item_vis = definition.attrs.remove(idx).parse_args()?;
         }
 
-        let mut fields_meta = Vec::with_capacity(fields.len());
-        for field in fields.iter() {
-            let maybe_meta = if let Some(attr) = field.attrs.first() {
-                if !attr.path().is_ident("ensure") {
-                    return Err(syn::Error::new(
-                        span,
-                        "fields attr now only support #[ensure(Clone)]",
-                    ));
-                }
-                let nested =
-                    attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
-                if nested
-                    .iter()
-                    .any(|meta| !matches!(meta, Meta::Path(path) if path.is_ident("Clone")))
-                {
-                    return Err(syn::Error::new(
-                        span,
-                        "fields attr now only support #[ensure(Clone)]",
-                    ));
-                }
-                Some(nested)
-            } else {
-                None
-            };
-            fields_meta.push(maybe_meta);
+        if let Some((_, enum_ident)) = Self::find_path_attr(&definition.attrs, "state_enum") {
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    enum_ident.span(),
+                    "#[state_enum(..)] is only supported by the prefilled style",
+                ));
+            }
+            if definition.fields.len() > MAX_STATE_ENUM_FIELDS {
+                return Err(syn::Error::new(
+                    enum_ident.span(),
+                    format!("#[state_enum(..)] supports at most {MAX_STATE_ENUM_FIELDS} fields"),
+                ));
+            }
         }
 
-        Ok(CMap {
-            attrs: definition.attrs,
-            vis: definition.vis,
-            ident: definition.ident,
-            fields,
-            fields_meta,
-            span,
-            style,
-        })
-    }
-}
+        if let Some((_, partial_ident)) = Self::find_path_attr(&definition.attrs, "partial") {
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    partial_ident.span(),
+                    "#[partial(..)] is only supported by the prefilled style",
+                ));
+            }
+        }
 
-impl CMap {
-    fn to_pre_filled_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        let mut derive_clone = false;
-        if let Some(derive) = Self::find_path_attr(&self.attrs, "derive") {
-            if derive.1 == "Clone" {
-                derive_clone = true;
+        // parse #[secure_clear] and remove it.
+        let mut secure_clear = false;
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("secure_clear"))
+        {
+            definition.attrs.remove(idx).meta.require_path_only()?;
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    span,
+                    "#[secure_clear] is only supported by the prefilled style",
+                ));
             }
+            secure_clear = true;
         }
 
-        let vis = &self.vis;
-        let ident = &self.ident;
-        let state_ident = quote::format_ident!("{ident}State");
-        let handler_ident = quote::format_ident!("{ident}Handler");
-        let generic_types: Vec<_> = (0..self.fields.len())
-            .map(generic_type)
-            .map(IdentOrTokens::from)
-            .collect();
-        let names: Vec<_> = self
-            .fields
+        // parse #[align_fields(cacheline)] and remove it.
+        let mut align_fields = false;
+        if let Some(idx) = definition
+            .attrs
             .iter()
-            .map(|f| f.ident.as_ref().unwrap())
-            .collect();
-        let types: Vec<_> = self.fields.iter().map(|f| &f.ty).collect();
+            .position(|attr| attr.path().is_ident("align_fields"))
+        {
+            let align: Ident = definition.attrs.remove(idx).parse_args()?;
+            if align != "cacheline" {
+                return Err(syn::Error::new(
+                    align.span(),
+                    "only #[align_fields(cacheline)] is supported",
+                ));
+            }
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    span,
+                    "#[align_fields(..)] is only supported by the prefilled style",
+                ));
+            }
+            align_fields = true;
+        }
 
-        // struct definition
-        tokens.extend(quote_spanned! {
-            self.span =>
-                #vis struct #ident {
-                    #(#names: ::std::mem::MaybeUninit<#types>,)*
-                }
-                #[allow(non_camel_case_types)]
-                #vis struct #state_ident<#(#generic_types),*>
-                where
-                    #(#generic_types: ::certain_map::MaybeAvailable,)*
-                {
-                    #(#names: ::std::marker::PhantomData<#generic_types>,)*
-                }
-                #[allow(non_camel_case_types)]
-                #[repr(transparent)]
-                #vis struct #handler_ident<'a, #(#generic_types),*>
-                where
-                    #(#generic_types: ::certain_map::MaybeAvailable,)*
-                {
-                    inner: &'a mut #ident,
-                    state: #state_ident<#(#generic_types),*>,
-                }
-        });
+        // parse #[reorder_by_align] and remove it.
+        let mut reorder_by_align = false;
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("reorder_by_align"))
+        {
+            definition.attrs.remove(idx).meta.require_path_only()?;
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    span,
+                    "#[reorder_by_align] is only supported by the prefilled style",
+                ));
+            }
+            reorder_by_align = true;
+        }
 
-        // type alias
-        if let Some((_, empty_ident)) = Self::find_path_attr(&self.attrs, "empty") {
-            let vacancy_types =
-                std::iter::repeat(quote!(::certain_map::Vacancy)).take(self.fields.len());
-            tokens.extend(quote_spanned! {
-                self.span =>
-                    #vis type #empty_ident<'a> = #handler_ident<'a, #(#vacancy_types),*>;
-            });
+        // parse #[deny_large(N)] and remove it.
+        let mut deny_large = None;
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("deny_large"))
+        {
+            let limit: syn::LitInt = definition.attrs.remove(idx).parse_args()?;
+            limit.base10_parse::<usize>()?;
+            deny_large = Some(limit);
         }
 
-        if let Some((_, full_ident)) = Self::find_path_attr(&self.attrs, "full") {
-            let occupied_types =
-                std::iter::repeat(quote!(::certain_map::OccupiedM)).take(self.fields.len());
-            tokens.extend(quote_spanned! {
-                self.span =>
-                    #vis type #full_ident<'a> = #handler_ident<'a, #(#occupied_types),*>;
-            });
+        // parse #[global] and remove it.
+        let mut global = false;
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("global"))
+        {
+            definition.attrs.remove(idx).meta.require_path_only()?;
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    span,
+                    "#[global] is only supported by the prefilled style",
+                ));
+            }
+            global = true;
         }
 
-        let clone_with = if derive_clone {
-            quote_spanned! {
-                self.span =>
-                    #[allow(non_camel_case_types)]
-                    unsafe fn clone_with<#(#generic_types),*>(&self, _state: &#state_ident<#(#generic_types),*>) -> Self
-                    where
-                        #(#generic_types: ::certain_map::MaybeAvailable,)*
-                    {
-                        Self {
-                            #(#names: #generic_types::do_clone(&self.#names),)*
-                        }
-                    }
+        // parse #[wasm] and remove it.
+        let mut wasm = false;
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("wasm"))
+        {
+            definition.attrs.remove(idx).meta.require_path_only()?;
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    span,
+                    "#[wasm] is only supported by the prefilled style",
+                ));
             }
-        } else {
-            quote!()
-        };
+            wasm = true;
+        }
 
-        // impl #ident
-        let vacancy_types =
-            std::iter::repeat(quote!(::certain_map::Vacancy)).take(self.fields.len());
-        let vacancy_types2 =
-            std::iter::repeat(quote!(::certain_map::Vacancy)).take(self.fields.len());
-        tokens.extend(quote_spanned! {
-            self.span =>
-                impl #ident {
-                    #[inline]
-                    pub const fn new() -> Self {
-                        Self {
-                            #(#names: ::std::mem::MaybeUninit::uninit(),)*
-                        }
-                    }
-                    #[inline]
-                    pub fn handler(&mut self) -> #handler_ident<'_, #(#vacancy_types),*> {
-                        #handler_ident {
-                            inner: self,
-                            state: #state_ident::new(),
-                        }
-                    }
+        // parse #[hide_internals] and remove it.
+        let mut hide_internals = false;
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("hide_internals"))
+        {
+            definition.attrs.remove(idx).meta.require_path_only()?;
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    span,
+                    "#[hide_internals] is only supported by the prefilled style",
+                ));
+            }
+            hide_internals = true;
+        }
+
+        // parse #[assert_send] and remove it.
+        let mut assert_send = false;
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("assert_send"))
+        {
+            definition.attrs.remove(idx).meta.require_path_only()?;
+            assert_send = true;
+        }
+
+        // parse #[codegen_stats] and remove it, `CERTAIN_MAP_STATS=1` enables it for all maps.
+        let mut codegen_stats =
+            matches!(std::env::var_os("CERTAIN_MAP_STATS"), Some(v) if !v.is_empty() && v != "0");
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("codegen_stats"))
+        {
+            definition.attrs.remove(idx).meta.require_path_only()?;
+            codegen_stats = true;
+        }
+
+        // parse #[method_prefix(cx)] and remove it.
+        let mut method_prefix = None;
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("method_prefix"))
+        {
+            method_prefix = Some(definition.attrs.remove(idx).parse_args::<Ident>()?);
+        }
+
+        // parse #[partition(ConnCx: peer_addr; ReqCx: route)] and remove it.
+        let mut partition_defs = Punctuated::<PartitionDef, Token![;]>::new();
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("partition"))
+        {
+            let attr = definition.attrs.remove(idx);
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    attr.span(),
+                    "#[partition(..)] is only supported by the prefilled style",
+                ));
+            }
+            partition_defs = attr.parse_args_with(Punctuated::parse_terminated)?;
+        }
+
+        // parse #[cache_key(a, b)] and remove it.
+        let mut cache_key_names = Punctuated::<Ident, Token![,]>::new();
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("cache_key"))
+        {
+            let attr = definition.attrs.remove(idx);
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    attr.span(),
+                    "#[cache_key(..)] is only supported by the prefilled style",
+                ));
+            }
+            cache_key_names = attr.parse_args_with(Punctuated::parse_terminated)?;
+            if cache_key_names.is_empty() {
+                return Err(syn::Error::new(
+                    attr.span(),
+                    "#[cache_key(..)] needs at least one field",
+                ));
+            }
+        }
+
+        // parse #[drop_order(a, b)] and remove it.
+        let mut drop_order_names = Punctuated::<Ident, Token![,]>::new();
+        if let Some(idx) = definition
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("drop_order"))
+        {
+            let attr = definition.attrs.remove(idx);
+            if matches!(style, GenStyle::Unfilled) {
+                return Err(syn::Error::new(
+                    attr.span(),
+                    "#[drop_order(..)] is only supported by the prefilled style",
+                ));
+            }
+            drop_order_names = attr.parse_args_with(Punctuated::parse_terminated)?;
+        }
+
+        let fields: Vec<Field> = definition.fields.into_iter().collect();
+        if fields.iter().any(|f| f.ident.is_none()) {
+            return Err(syn::Error::new(
+                span,
+                "fields without names are not supported",
+            ));
+        }
+
+        let mut fields_meta = Vec::with_capacity(fields.len());
+        let mut fields_baggage = Vec::with_capacity(fields.len());
+        let mut fields_header = Vec::with_capacity(fields.len());
+        let mut fields_graphql = Vec::with_capacity(fields.len());
+        let mut fields_serde = Vec::with_capacity(fields.len());
+        let mut fields_config = Vec::with_capacity(fields.len());
+        let mut fields_wire = Vec::with_capacity(fields.len());
+        let mut fields_flag = Vec::with_capacity(fields.len());
+        let mut fields_fallback = Vec::with_capacity(fields.len());
+        let mut fields_push = Vec::with_capacity(fields.len());
+        let mut fields_weak = Vec::with_capacity(fields.len());
+        let mut fields_persistent = Vec::with_capacity(fields.len());
+        let mut fields_requires = Vec::with_capacity(fields.len());
+        let mut fields_conflicts = vec![Vec::new(); fields.len()];
+        let mut fields_one_of = Vec::with_capacity(fields.len());
+        let mut fields_padded = Vec::with_capacity(fields.len());
+        let mut fields_clone_strategy = Vec::with_capacity(fields.len());
+        for (idx, field) in fields.iter().enumerate() {
+            let mut maybe_meta = None;
+            let mut baggage = None;
+            let mut header = None;
+            let mut graphql = false;
+            let mut serde = SerdeField::default();
+            let mut config = None;
+            let mut wire = None;
+            let mut flag = false;
+            let mut fallback = None;
+            let mut push = None;
+            let mut weak = None;
+            let mut persistent = false;
+            let mut requires = Vec::new();
+            let mut one_of = None;
+            let mut padded = align_fields;
+            let mut clone_strategy = None;
+            for attr in field.attrs.iter() {
+                if attr.path().is_ident("ensure") {
+                    let nested =
+                        attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+                    if nested.iter().any(|meta| {
+                        !matches!(meta, Meta::Path(path) if ["Clone", "Debug", "Valuable", "Format", "PartialEq"].iter().any(|name| path.is_ident(name)))
+                    }) {
+                        return Err(syn::Error::new(span, FIELD_ATTR_ERROR));
+                    }
+                    maybe_meta = Some(nested);
+                } else if attr.path().is_ident("baggage") {
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[baggage(..)] is only supported by the prefilled style",
+                        ));
+                    }
+                    baggage = Some(attr.parse_args::<syn::LitStr>()?);
+                } else if attr.path().is_ident("header") {
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[header(..)] is only supported by the prefilled style",
+                        ));
+                    }
+                    let name = attr.parse_args::<syn::LitStr>()?;
+                    // the name is used with `HeaderName::from_static`
+                    let valid = name.value().bytes().all(|b| {
+                        b.is_ascii_lowercase()
+                            || b.is_ascii_digit()
+                            || b"!#$%&'*+-.^_`|~".contains(&b)
+                    });
+                    if name.value().is_empty() || !valid {
+                        return Err(syn::Error::new(
+                            name.span(),
+                            "the header name must be a lowercase http token",
+                        ));
+                    }
+                    header = Some(name);
+                } else if attr.path().is_ident("graphql") {
+                    attr.meta.require_path_only()?;
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[graphql] is only supported by the prefilled style",
+                        ));
+                    }
+                    graphql = true;
+                } else if attr.path().is_ident("serde") {
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[serde(..)] is only supported by the prefilled style",
+                        ));
+                    }
+                    serde.parse(attr)?;
+                } else if attr.path().is_ident("config") {
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[config(..)] is only supported by the prefilled style",
+                        ));
+                    }
+                    config = Some(ConfigField::parse(attr)?);
+                } else if attr.path().is_ident("wire") {
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[wire] is only supported by the prefilled style",
+                        ));
+                    }
+                    wire = Some(WireField::parse(attr)?);
+                } else if attr.path().is_ident("clone_strategy") {
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[clone_strategy(..)] is only supported by the prefilled style",
+                        ));
+                    }
+                    clone_strategy = Some(CloneStrategy::parse(attr)?);
+                } else if attr.path().is_ident("flag") {
+                    attr.meta.require_path_only()?;
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[flag] is only supported by the prefilled style",
+                        ));
+                    }
+                    // the value is the path of the unit struct
+                    let unit = matches!(&field.ty, Type::Path(path) if path.qself.is_none()
+                        && path.path.segments.iter().all(|s| s.arguments.is_empty()));
+                    if !unit {
+                        return Err(syn::Error::new(
+                            field.ty.span(),
+                            "#[flag] fields must be unit structs, like `struct TlsTerminated;`",
+                        ));
+                    }
+                    flag = true;
+                } else if attr.path().is_ident("fallback") {
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[fallback(..)] is only supported by the prefilled style",
+                        ));
+                    }
+                    fallback = Some(attr.parse_args::<Expr>()?);
+                } else if attr.path().is_ident("push") {
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[push] is only supported by the prefilled style",
+                        ));
+                    }
+                    push = Some(match &attr.meta {
+                        Meta::List(_) => attr.parse_args::<Type>()?,
+                        _ => first_type_argument(&field.ty).cloned().ok_or_else(|| {
+                            syn::Error::new(
+                                field.ty.span(),
+                                "can not infer the item type, use #[push(Item)]",
+                            )
+                        })?,
+                    });
+                } else if attr.path().is_ident("weak") {
+                    attr.meta.require_path_only()?;
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[weak] is only supported by the prefilled style",
+                        ));
+                    }
+                    weak = Some(weak_pointee(&field.ty).ok_or_else(|| {
+                        syn::Error::new(
+                            field.ty.span(),
+                            "#[weak] fields must be `std::sync::Weak<T>` or `std::rc::Weak<T>`",
+                        )
+                    })?);
+                } else if attr.path().is_ident("persistent") {
+                    attr.meta.require_path_only()?;
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[persistent] is only supported by the prefilled style",
+                        ));
+                    }
+                    persistent = true;
+                } else if attr.path().is_ident("requires") {
+                    let deps =
+                        attr.parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated)?;
+                    for dep in deps {
+                        requires.push(Self::field_index(&fields, field, &dep)?);
+                    }
+                } else if attr.path().is_ident("padded") {
+                    attr.meta.require_path_only()?;
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[padded] is only supported by the prefilled style",
+                        ));
+                    }
+                    padded = true;
+                } else if attr.path().is_ident("one_of") {
+                    if matches!(style, GenStyle::Unfilled) {
+                        return Err(syn::Error::new(
+                            span,
+                            "#[one_of(..)] is only supported by the prefilled style",
+                        ));
+                    }
+                    let group = attr.parse_args::<Ident>()?;
+                    if fields.iter().any(|f| f.ident.as_ref() == Some(&group)) {
+                        return Err(syn::Error::new(
+                            group.span(),
+                            "the group name is already used by a field",
+                        ));
+                    }
+                    one_of = Some(group);
+                } else if attr.path().is_ident("conflicts_with") {
+                    let others =
+                        attr.parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated)?;
+                    // the exclusion is mutual
+                    for other in others {
+                        let other_idx = Self::field_index(&fields, field, &other)?;
+                        if !fields_conflicts[idx].contains(&other_idx) {
+                            fields_conflicts[idx].push(other_idx);
+                            fields_conflicts[other_idx].push(idx);
+                        }
+                    }
+                } else {
+                    return Err(syn::Error::new(span, FIELD_ATTR_ERROR));
+                }
+            }
+            fields_meta.push(maybe_meta);
+            fields_baggage.push(baggage);
+            fields_header.push(header);
+            fields_graphql.push(graphql);
+            fields_serde.push(serde);
+            fields_config.push(config);
+            fields_wire.push(wire);
+            fields_flag.push(flag);
+            fields_fallback.push(fallback);
+            if weak.is_some() && clone_strategy.is_some() {
+                return Err(syn::Error::new(
+                    field.span(),
+                    "#[weak] can not be used with #[clone_strategy(..)]",
+                ));
+            }
+            fields_push.push(push);
+            fields_weak.push(weak);
+            fields_persistent.push(persistent);
+            fields_requires.push(requires);
+            if padded && one_of.is_some() && !align_fields {
+                return Err(syn::Error::new(
+                    field.span(),
+                    "#[padded] can not be used with #[one_of(..)], use #[align_fields(cacheline)]",
+                ));
+            }
+            fields_one_of.push(one_of);
+            fields_padded.push(padded);
+            fields_clone_strategy.push(clone_strategy);
+        }
+        // fields of a group are mutually exclusive
+        for (idx, group) in fields_one_of.iter().enumerate() {
+            for (other_idx, other) in fields_one_of.iter().enumerate() {
+                if idx != other_idx
+                    && group.is_some()
+                    && group == other
+                    && !fields_conflicts[idx].contains(&other_idx)
+                {
+                    fields_conflicts[idx].push(other_idx);
+                }
+            }
+        }
+        let fields_slot = Self::overlap_slots(
+            &fields,
+            &fields_one_of,
+            &fields_conflicts,
+            &fields_padded,
+            align_fields,
+        );
+        for (idx, requires) in fields_requires.iter().enumerate() {
+            if requires
+                .iter()
+                .any(|dep| fields_conflicts[idx].contains(dep))
+            {
+                return Err(syn::Error::new(
+                    fields[idx].span(),
+                    "a field can not both require and conflict with another field",
+                ));
+            }
+            // from_baggage and from_graphql_data set the fields regardless of the state
+            if fields_baggage[idx].is_some()
+                && (!requires.is_empty() || !fields_conflicts[idx].is_empty())
+            {
+                return Err(syn::Error::new(
+                    fields[idx].span(),
+                    "#[baggage(..)] can not be used with #[requires(..)], #[conflicts_with(..)] or #[one_of(..)]",
+                ));
+            }
+            if fields_header[idx].is_some()
+                && (!requires.is_empty() || !fields_conflicts[idx].is_empty())
+            {
+                return Err(syn::Error::new(
+                    fields[idx].span(),
+                    "#[header(..)] can not be used with #[requires(..)], #[conflicts_with(..)] or #[one_of(..)]",
+                ));
+            }
+            if fields_graphql[idx] && (!requires.is_empty() || !fields_conflicts[idx].is_empty()) {
+                return Err(syn::Error::new(
+                    fields[idx].span(),
+                    "#[graphql] can not be used with #[requires(..)], #[conflicts_with(..)] or #[one_of(..)]",
+                ));
+            }
+            if fields_config[idx].is_some()
+                && (!requires.is_empty() || !fields_conflicts[idx].is_empty())
+            {
+                return Err(syn::Error::new(
+                    fields[idx].span(),
+                    "#[config(..)] can not be used with #[requires(..)], #[conflicts_with(..)] or #[one_of(..)]",
+                ));
+            }
+            if fields_wire[idx].is_some()
+                && (!requires.is_empty() || !fields_conflicts[idx].is_empty())
+            {
+                return Err(syn::Error::new(
+                    fields[idx].span(),
+                    "#[wire] can not be used with #[requires(..)], #[conflicts_with(..)] or #[one_of(..)]",
+                ));
+            }
+        }
+
+        let mut partitions: Vec<Partition> = Vec::new();
+        for def in partition_defs {
+            let mut indexes = Vec::new();
+            for name in def.fields.iter() {
+                let idx = fields
+                    .iter()
+                    .position(|f| f.ident.as_ref() == Some(name))
+                    .ok_or_else(|| {
+                        syn::Error::new(name.span(), format!("field `{name}` does not exist"))
+                    })?;
+                if indexes.contains(&idx) || partitions.iter().any(|p| p.fields.contains(&idx)) {
+                    return Err(syn::Error::new(
+                        name.span(),
+                        format!("field `{name}` is already in a partition"),
+                    ));
+                }
+                // the partition maps do not keep the relations between fields
+                if !fields_requires[idx].is_empty() || !fields_conflicts[idx].is_empty() {
+                    return Err(syn::Error::new(
+                        name.span(),
+                        "fields with #[requires(..)], #[conflicts_with(..)] or #[one_of(..)] can not be partitioned",
+                    ));
+                }
+                indexes.push(idx);
+            }
+            partitions.push(Partition {
+                ident: def.ident,
+                fields: indexes,
+                paths: Vec::new(),
+            });
+        }
+
+        let mut cache_key = Vec::new();
+        for name in cache_key_names.iter() {
+            let idx = fields
+                .iter()
+                .position(|f| f.ident.as_ref() == Some(name))
+                .ok_or_else(|| {
+                    syn::Error::new(name.span(), format!("field `{name}` does not exist"))
+                })?;
+            if cache_key.contains(&idx) {
+                return Err(syn::Error::new(
+                    name.span(),
+                    format!("field `{name}` is already in the cache key"),
+                ));
+            }
+            cache_key.push(idx);
+        }
+
+        let mut drop_order = Vec::new();
+        for name in drop_order_names.iter() {
+            let idx = fields
+                .iter()
+                .position(|f| f.ident.as_ref() == Some(name))
+                .ok_or_else(|| {
+                    syn::Error::new(name.span(), format!("field `{name}` does not exist"))
+                })?;
+            if drop_order.contains(&idx) {
+                return Err(syn::Error::new(
+                    name.span(),
+                    format!("field `{name}` is already in the drop order"),
+                ));
+            }
+            drop_order.push(idx);
+        }
+        for idx in 0..fields.len() {
+            if !drop_order.contains(&idx) {
+                drop_order.push(idx);
+            }
+        }
+
+        // the global handler is full
+        if global && fields_conflicts.iter().any(|c| !c.is_empty()) {
+            return Err(syn::Error::new(
+                span,
+                "#[global] can not be used with conflicting fields, since the map can never be full",
+            ));
+        }
+        // the slot of a persistent field is kept by the store, so it can not be shared
+        for (idx, field) in fields.iter().enumerate() {
+            if fields_persistent[idx] && fields_slot[idx].is_some() {
+                return Err(syn::Error::new(
+                    field.span(),
+                    "#[persistent] can not be used with conflicting fields",
+                ));
+            }
+            if fields_persistent[idx] && secure_clear {
+                return Err(syn::Error::new(
+                    field.span(),
+                    "#[persistent] can not be used with #[secure_clear]",
+                ));
+            }
+        }
+        if wasm && fields_conflicts.iter().any(|c| !c.is_empty()) {
+            return Err(syn::Error::new(
+                span,
+                "#[wasm] can not be used with conflicting fields, since the map can never be full",
+            ));
+        }
+
+        let cmap = CMap {
+            attrs: definition.attrs,
+            vis: definition.vis,
+            ident: definition.ident,
+            fields,
+            fields_meta,
+            fields_baggage,
+            fields_header,
+            fields_graphql,
+            fields_serde,
+            fields_config,
+            fields_wire,
+            fields_flag,
+            fields_fallback,
+            fields_push,
+            fields_weak,
+            fields_persistent,
+            fields_requires,
+            fields_conflicts,
+            fields_one_of,
+            fields_slot,
+            fields_padded,
+            fields_clone_strategy,
+            span,
+            style,
+            item_vis,
+            deny_large,
+            assert_send,
+            global,
+            hide_internals,
+            wasm,
+            secure_clear,
+            align_fields,
+            reorder_by_align,
+            codegen_stats,
+            method_prefix,
+            partitions,
+            methods: Vec::new(),
+            cache_key,
+            drop_order,
+        };
+
+        // Copy maps have no Drop to leave the persistent fields in the store
+        if cmap.fields_persistent.contains(&true) && cmap.has_derive("Copy") {
+            return Err(syn::Error::new(
+                span,
+                "#[persistent] fields can not be used with #[derive(Copy)]",
+            ));
+        }
+
+        // Deserialize occupies every field which is not skipped
+        if matches!(cmap.style, GenStyle::PreFilled) && cmap.has_derive("Deserialize") {
+            for (idx, field) in cmap.fields.iter().enumerate() {
+                if cmap.fields_serde[idx].skip {
+                    continue;
+                }
+                if cmap.fields_conflicts[idx]
+                    .iter()
+                    .any(|&other| !cmap.fields_serde[other].skip)
+                {
+                    return Err(syn::Error::new(
+                        field.span(),
+                        "#[derive(Deserialize)] needs #[serde(skip)] on all but one of the conflicting fields",
+                    ));
+                }
+            }
+        }
+        Ok(cmap)
+    }
+}
+
+impl CMap {
+    fn to_pre_filled_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let derive_clone = self.has_derive("Clone");
+        let hidden = if self.hide_internals {
+            quote!(#[doc(hidden)])
+        } else {
+            quote!()
+        };
+
+        let vis = &self.vis;
+        let ident = &self.ident;
+        let state_ident = quote::format_ident!("{ident}State");
+        let handler_ident = quote::format_ident!("{ident}Handler");
+        let owned_ident = quote::format_ident!("{ident}OwnedHandler");
+        let generic_types: Vec<_> = (0..self.fields.len())
+            .map(generic_type)
+            .map(IdentOrTokens::from)
+            .collect();
+        let names: Vec<_> = self
+            .fields
+            .iter()
+            .map(|f| f.ident.as_ref().unwrap())
+            .collect();
+        let store_vis = self.item_vis.store.as_ref().unwrap_or(vis);
+        let state_vis = self.item_vis.state.as_ref().unwrap_or(vis);
+        let handler_vis = self.item_vis.handler.as_ref().unwrap_or(vis);
+        let owned_vis = self.item_vis.owned.as_ref().unwrap_or(vis);
+        let generic_ident = quote::format_ident!("{ident}HandlerIn");
+        let generic_vis = Self::handler_in_vis(handler_vis, owned_vis, vis);
+
+        // Fields of a `#[one_of(group)]` share a union slot named by the group, and so do
+        // mutually conflicting fields.
+        let groups = self.one_of_groups();
+        let slot_groups = self.slot_groups();
+        let plain: Vec<_> = (0..self.fields.len())
+            .filter(|&idx| self.fields_slot[idx].is_none())
+            .collect();
+        let plain_names: Vec<_> = plain.iter().map(|&idx| names[idx]).collect();
+        let plain_slots: Vec<_> = plain
+            .iter()
+            .map(|&idx| {
+                let ty = self.slot_ty(idx);
+                Self::padded_tokens(
+                    self.fields_padded[idx],
+                    quote!(::std::mem::MaybeUninit<#ty>),
+                )
+            })
+            .collect();
+        let plain_inits: Vec<_> = plain
+            .iter()
+            .map(|&idx| {
+                Self::padded_value_tokens(
+                    self.fields_padded[idx],
+                    quote!(::std::mem::MaybeUninit::uninit()),
+                )
+            })
+            .collect();
+        let plain_clones: Vec<_> = plain
+            .iter()
+            .map(|&idx| {
+                let generic_type = &generic_types[idx];
+                let slot = self.slot_place(quote!(self), idx, false);
+                Self::padded_value_tokens(
+                    self.fields_padded[idx],
+                    quote!(#generic_type::do_clone(&#slot)),
+                )
+            })
+            .collect();
+        let group_names: Vec<_> = slot_groups.iter().map(|(group, _)| *group).collect();
+        let group_unions: Vec<_> = group_names
+            .iter()
+            .map(|group| quote::format_ident!("{ident}Slot_{group}"))
+            .collect();
+        let group_slots: Vec<_> = group_unions
+            .iter()
+            .map(|union_ident| Self::padded_tokens(self.align_fields, quote!(#union_ident)))
+            .collect();
+        for ((group, members), union_ident) in slot_groups.iter().zip(group_unions.iter()) {
+            let member_names = members.iter().map(|&idx| names[idx]);
+            let member_types = members.iter().map(|&idx| self.slot_ty(idx));
+            let doc = format!("Storage of the `{group}` fields of [`{ident}`].");
+            // the slots of conflicting fields are an implementation detail
+            let hidden = if self.fields_one_of[members[0]].is_none() {
+                quote!(#[doc(hidden)])
+            } else {
+                hidden.clone()
+            };
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[doc = #doc]
+                    #hidden
+                    #[allow(non_camel_case_types)]
+                    #[repr(C)]
+                    #store_vis union #union_ident {
+                        #(#member_names: ::std::mem::ManuallyDrop<::std::mem::MaybeUninit<#member_types>>,)*
+                    }
+            });
+        }
+        let group_inits: Vec<_> = slot_groups
+            .iter()
+            .zip(group_unions.iter())
+            .map(|((_, members), union_ident)| {
+                let first = names[members[0]];
+                Self::padded_value_tokens(
+                    self.align_fields,
+                    quote! {
+                        #union_ident {
+                            #first: ::std::mem::ManuallyDrop::new(::std::mem::MaybeUninit::uninit()),
+                        }
+                    },
+                )
+            })
+            .collect();
+
+        // A `#[reorder_by_align]` store declares its roots, the plain slots followed by the union
+        // slots, at the positions given by `__ORDER`, which sorts them by descending alignment
+        // once the types are known. `repr(C)` keeps the positions, and the roots are reached
+        // through accessors reading the offsets of their positions.
+        let root_slots: Vec<_> = plain_slots.iter().chain(group_slots.iter()).collect();
+        let root_count = root_slots.len();
+        let positions: Vec<_> = (0..root_count)
+            .map(|pos| quote::format_ident!("__slot_{pos}"))
+            .collect();
+        let (repr, slots_def, slots_init) = if self.reorder_by_align {
+            let sorted_slots = (0..root_count).map(|pos| {
+                quote!(<::certain_map::SortedSlot<{ #ident::__ORDER[#pos] }> as ::certain_map::SlotOf<#ident>>::Slot)
+            });
+            let sorted_inits = (0..root_count).map(|pos| {
+                quote!(<::certain_map::SortedSlot<{ #ident::__ORDER[#pos] }> as ::certain_map::SlotOf<#ident>>::UNINIT)
+            });
+            for (root, (slot, init)) in root_slots
+                .iter()
+                .zip(plain_inits.iter().chain(group_inits.iter()))
+                .enumerate()
+            {
+                tokens.extend(quote_spanned! {
+                    self.span =>
+                        impl ::certain_map::SlotOf<#ident> for ::certain_map::SortedSlot<#root> {
+                            type Slot = #slot;
+                            const UNINIT: Self::Slot = #init;
+                        }
+                });
+            }
+            (
+                quote!(#[repr(C)]),
+                quote!(#(#positions: #sorted_slots,)*),
+                quote!(#(#positions: #sorted_inits,)*),
+            )
+        } else {
+            (
+                quote!(),
+                quote!(#(#plain_names: #plain_slots,)* #(#group_names: #group_slots,)*),
+                quote!(#(#plain_names: #plain_inits,)* #(#group_names: #group_inits,)*),
+            )
+        };
+
+        // The caller locations of the last set/take of every field.
+        let field_count = self.fields.len();
+        let (provenance_slot, provenance_init, provenance_clone) = if PROVENANCE {
+            (
+                quote!(__provenance: [::std::option::Option<&'static ::std::panic::Location<'static>>; #field_count],),
+                quote!(__provenance: [::std::option::Option::None; #field_count],),
+                quote!(__provenance: self.__provenance,),
+            )
+        } else {
+            (quote!(), quote!(), quote!())
+        };
+
+        // Whether the `#[persistent]` fields were left in the store by the last handler.
+        let persistent: Vec<_> = self
+            .drop_order
+            .iter()
+            .copied()
+            .filter(|&idx| self.fields_persistent[idx])
+            .collect();
+        let (persisted_slot, persisted_init) = if persistent.is_empty() {
+            (quote!(), quote!())
+        } else {
+            (quote!(__persisted: bool,), quote!(__persisted: false,))
+        };
+
+        // struct definition
+        let docs = self.attrs.iter().filter(|attr| attr.path().is_ident("doc"));
+        let mut handler_doc = format!(
+            "A handler borrowing [`{ident}`], whose generics are the occupancy of every field."
+        );
+        let mut owned_doc = format!(
+            "A handler owning a boxed [`{ident}`], whose generics are the occupancy of every field."
+        );
+        // rustdoc does not list the impls of a hidden type on its aliases
+        if self.hide_internals {
+            let mut fields_doc = String::from(
+                "\n\nThe fields are accessed with the param traits of `certain_map`, like \
+                 `ParamRef` and `ParamSet`:",
+            );
+            for field in self.fields.iter() {
+                let name = field.ident.as_ref().unwrap();
+                let ty = field.ty.to_token_stream().to_string();
+                fields_doc.push_str(&format!("\n- `{name}`: `{ty}`"));
+            }
+            handler_doc.push_str(&fields_doc);
+            owned_doc.push_str(&fields_doc);
+        }
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #(#docs)*
+                #repr
+                #store_vis struct #ident {
+                    #slots_def
+                    #provenance_slot
+                    #persisted_slot
+                }
+                #hidden
+                #[allow(non_camel_case_types)]
+                #state_vis struct #state_ident<#(#generic_types),*>
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    #(#names: ::std::marker::PhantomData<#generic_types>,)*
+                }
+                #hidden
+                #[allow(non_camel_case_types)]
+                #[repr(transparent)]
+                #[must_use = "the state is moved into the returned handler, dropping it drops the fields of the context"]
+                #generic_vis struct #generic_ident<_CMS, #(#generic_types),*>
+                where
+                    _CMS: ::certain_map::Storage<#ident>,
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    inner: _CMS,
+                    state: #state_ident<#(#generic_types),*>,
+                }
+                #[doc = #handler_doc]
+                #[allow(non_camel_case_types, dead_code)]
+                #handler_vis type #handler_ident<'a, #(#generic_types),*> = #generic_ident<&'a mut #ident, #(#generic_types),*>;
+                #[doc = #owned_doc]
+                #[allow(non_camel_case_types, dead_code)]
+                #owned_vis type #owned_ident<#(#generic_types),*> = #generic_ident<::std::boxed::Box<#ident>, #(#generic_types),*>;
+        });
+
+        // type alias, with an alias of the state named `{alias}State`
+        if let Some((_, empty_ident)) = Self::find_path_attr(&self.attrs, "empty") {
+            let vacancy_types: Vec<_> =
+                repeat_tokens(quote!(::certain_map::Vacancy), self.fields.len()).collect();
+            let empty_state_ident = quote::format_ident!("{empty_ident}State");
+            let doc = format!("The handler of [`{ident}`] with all the fields vacant.");
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[doc = #doc]
+                    #handler_vis type #empty_ident<'a> = #handler_ident<'a, #(#vacancy_types),*>;
+                    #hidden
+                    #state_vis type #empty_state_ident = #state_ident<#(#vacancy_types),*>;
+            });
+        }
+
+        if let Some((_, full_ident)) = Self::find_path_attr(&self.attrs, "full") {
+            let occupied_types: Vec<_> =
+                repeat_tokens(quote!(::certain_map::OccupiedM), self.fields.len()).collect();
+            let full_state_ident = quote::format_ident!("{full_ident}State");
+            let doc = format!("The handler of [`{ident}`] with all the fields occupied.");
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[doc = #doc]
+                    #handler_vis type #full_ident<'a> = #handler_ident<'a, #(#occupied_types),*>;
+                    #hidden
+                    #state_vis type #full_state_ident = #state_ident<#(#occupied_types),*>;
+            });
+        }
+
+        if let Some((_, enum_ident)) = Self::find_path_attr(&self.attrs, "state_enum") {
+            self.state_enum_tokens(tokens, &enum_ident, &handler_ident, handler_vis);
+        }
+
+        for (group, members) in groups.iter() {
+            self.one_of_enum_tokens(
+                tokens,
+                group,
+                members,
+                &[(
+                    quote!(_CMS: ::certain_map::Storage<#ident>,),
+                    quote!(#generic_ident<_CMS, #(#generic_types),*>),
+                )],
+            );
+        }
+
+        if let Some((_, maybe_view_ident)) = Self::find_path_attr(&self.attrs, "maybe_view") {
+            self.maybe_view_tokens(
+                tokens,
+                &maybe_view_ident,
+                &[(
+                    quote!(_CMS, #(#generic_types,)*),
+                    quote!(#generic_ident<_CMS, #(#generic_types),*>),
+                )],
+                quote! {
+                    _CMS: ::certain_map::Storage<#ident>,
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                },
+            );
+        }
+
+        if let Some((_, ref_view_ident)) = Self::find_path_attr(&self.attrs, "ref_view") {
+            let occupied_types: Vec<_> =
+                repeat_tokens(occupied_m_type(), self.fields.len()).collect();
+            let refs: Vec<_> = (0..self.fields.len())
+                .map(|idx| {
+                    let slot = self.slot_place(quote!(self.inner), idx, false);
+                    self.slot_ref(idx, quote!(unsafe { <::certain_map::OccupiedM as ::certain_map::Available>::do_ref(&#slot) }))
+                })
+                .collect();
+            self.ref_view_tokens(
+                tokens,
+                &ref_view_ident,
+                &[(
+                    quote!(_CMS: ::certain_map::Storage<#ident>),
+                    quote!(#generic_ident<_CMS, #(#occupied_types),*>),
+                )],
+                &refs,
+            );
+        }
+
+        if self.has_derive("JsonSchema") {
+            let occupied_types: Vec<_> =
+                repeat_tokens(occupied_m_type(), self.fields.len()).collect();
+            self.json_schema_tokens(
+                tokens,
+                &[(
+                    quote!(_CMS: ::certain_map::Storage<#ident>),
+                    quote!(#generic_ident<_CMS, #(#occupied_types),*>),
+                )],
+            );
+        }
+
+        if self.global {
+            let occupied_types: Vec<_> =
+                repeat_tokens(occupied_m_type(), self.fields.len()).collect();
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(dead_code)]
+                    impl #ident {
+                        // The full handler shared by the whole process.
+                        fn global_cell() -> &'static ::std::sync::OnceLock<#owned_ident<#(#occupied_types),*>> {
+                            static GLOBAL: ::std::sync::OnceLock<#owned_ident<#(#occupied_types),*>> =
+                                ::std::sync::OnceLock::new();
+                            &GLOBAL
+                        }
+
+                        /// Set the process-wide context once at startup. Returns the handler back
+                        /// if it is already set.
+                        #owned_vis fn init_global(
+                            handler: #owned_ident<#(#occupied_types),*>,
+                        ) -> ::std::result::Result<(), #owned_ident<#(#occupied_types),*>> {
+                            Self::global_cell().set(handler)
+                        }
+
+                        /// The process-wide context, which can be read but never mutated.
+                        ///
+                        /// # Panics
+                        /// Panics if `init_global()` has not been called.
+                        #[inline]
+                        #owned_vis fn global_handler() -> &'static #owned_ident<#(#occupied_types),*> {
+                            match Self::global_cell().get() {
+                                ::std::option::Option::Some(handler) => handler,
+                                ::std::option::Option::None => ::std::panic!(
+                                    "{}::init_global() must be called before global_handler()",
+                                    ::std::stringify!(#ident),
+                                ),
+                            }
+                        }
+
+                        /// The process-wide context, or `None` before `init_global()`.
+                        #[inline]
+                        #owned_vis fn try_global_handler() -> ::std::option::Option<&'static #owned_ident<#(#occupied_types),*>> {
+                            Self::global_cell().get()
+                        }
+                    }
+            });
+        }
+
+        if self.wasm {
+            self.wasm_tokens(tokens, &generic_ident, &owned_ident);
+        }
+        if self.has_derive("Serialize") {
+            self.serialize_tokens(tokens, &generic_ident);
+        }
+        // A map with conflicting fields can never be full.
+        if self.has_derive("Arbitrary") && self.fields_conflicts.iter().all(Vec::is_empty) {
+            self.arbitrary_tokens(tokens, &owned_ident);
+        }
+        if let Some((_, partial_ident)) = Self::find_path_attr(&self.attrs, "partial") {
+            self.partial_tokens(tokens, &partial_ident);
+        }
+        if self.has_derive("Deserialize") {
+            self.deserialize_tokens(tokens, &owned_ident);
+        }
+
+        if let Some((_, view_ident)) = Self::find_path_attr(&self.attrs, "view") {
+            self.view_trait_tokens(tokens, &view_ident);
+            // A map with conflicting fields can never be full.
+            if derive_clone && self.fields_conflicts.iter().all(Vec::is_empty) {
+                let slots =
+                    (0..self.fields.len()).map(|idx| self.slot_place(quote!(inner), idx, true));
+                let values = self.fields.iter().enumerate().map(|(idx, f)| {
+                    let ty = &f.ty;
+                    self.to_slot_value(
+                        idx,
+                        quote!(::std::clone::Clone::clone(::certain_map::ParamRef::<#ty>::param_ref(cx))),
+                    )
+                });
+                let occupied_types = repeat_tokens(occupied_m_type(), self.fields.len());
+                tokens.extend(quote_spanned! {
+                    self.span =>
+                        #[allow(dead_code)]
+                        impl #ident {
+                            /// Build a full owned handler by cloning the fields from any context
+                            /// which has all of them.
+                            #owned_vis fn from_view<CX: #view_ident + ?Sized>(cx: &CX) -> #owned_ident<#(#occupied_types),*> {
+                                let mut inner = ::std::boxed::Box::new(Self::new());
+                                #(#slots = ::std::mem::MaybeUninit::new(#values);)*
+                                #owned_ident {
+                                    inner,
+                                    state: #state_ident::new(),
+                                }
+                            }
+                        }
+                });
+            }
+        }
+
+        let group_clones: Vec<_> = slot_groups
+            .iter()
+            .zip(group_unions.iter())
+            .map(|((_, members), union_ident)| {
+                let first = names[members[0]];
+                let member_names = members.iter().map(|&idx| names[idx]);
+                let member_slots = members
+                    .iter()
+                    .map(|&idx| self.slot_place(quote!(self), idx, false));
+                let member_generics = members.iter().map(|&idx| &generic_types[idx]);
+                let member_generics2 = members.iter().map(|&idx| &generic_types[idx]);
+                quote! {{
+                    let mut slot = #union_ident {
+                        #first: ::std::mem::ManuallyDrop::new(::std::mem::MaybeUninit::uninit()),
+                    };
+                    #(if <#member_generics as ::certain_map::MaybeAvailable>::OCCUPIED {
+                        slot.#member_names = ::std::mem::ManuallyDrop::new(
+                            #member_generics2::do_clone(&#member_slots),
+                        );
+                    })*
+                    slot
+                }}
+            })
+            .map(|slot| Self::padded_value_tokens(self.align_fields, slot))
+            .collect();
+        let clone_body = if self.reorder_by_align {
+            let root_mut = |root| quote::format_ident!("__root_{root}_mut");
+            let plain_muts = (0..plain.len()).map(root_mut);
+            let group_muts = (plain.len()..root_count).map(root_mut);
+            let provenance_copy = if PROVENANCE {
+                quote!(store.__provenance = self.__provenance;)
+            } else {
+                quote!()
+            };
+            // the roots have no drop glue, so assigning them drops nothing
+            quote! {
+                let mut store = Self::new();
+                #(*store.#plain_muts() = #plain_clones;)*
+                #(*store.#group_muts() = #group_clones;)*
+                #provenance_copy
+                store
+            }
+        } else {
+            quote! {
+                Self {
+                    #(#plain_names: #plain_clones,)*
+                    #(#group_names: #group_clones,)*
+                    #provenance_clone
+                    #persisted_init
+                }
+            }
+        };
+        let clone_with = if derive_clone {
+            quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    unsafe fn clone_with<#(#generic_types),*>(&self, _state: &#state_ident<#(#generic_types),*>) -> Self
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        #clone_body
+                    }
+            }
+        } else {
+            quote!()
+        };
+
+        self.copy_assert_tokens(tokens);
+
+        if self.secure_clear {
+            let all: Vec<_> = (0..self.fields.len()).collect();
+            let clear_all = self.clear_slots_tokens(quote!(self), &all);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl ::std::ops::Drop for #ident {
+                        fn drop(&mut self) {
+                            #clear_all
+                        }
+                    }
+            });
+        }
+
+        // The store drops the persistent fields left by the last handler.
+        let (release_persisted, release_persisted_in, persisted_methods) = if persistent.is_empty()
+        {
+            (quote!(), quote!(), quote!())
+        } else {
+            let persistent_slots = persistent
+                .iter()
+                .map(|&idx| self.slot_place(quote!(self), idx, true));
+            let resume_types = (0..self.fields.len()).map(|idx| {
+                if self.fields_persistent[idx] {
+                    occupied_m_type()
+                } else {
+                    vacancy_type()
+                }
+            });
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl ::std::ops::Drop for #ident {
+                        #[inline]
+                        fn drop(&mut self) {
+                            self.release_persisted();
+                        }
+                    }
+            });
+            (
+                quote!(self.release_persisted();),
+                quote! {
+                    let mut storage = storage;
+                    ::std::ops::DerefMut::deref_mut(&mut storage).release_persisted();
+                },
+                quote! {
+                    #[inline]
+                    fn release_persisted(&mut self) {
+                        if ::std::mem::replace(&mut self.__persisted, false) {
+                            unsafe {
+                                #(<::certain_map::OccupiedM as ::certain_map::MaybeAvailable>::do_drop(&mut #persistent_slots);)*
+                            }
+                        }
+                    }
+                    /// The handler with the `#[persistent]` fields left in the store by the last
+                    /// handler, or `None` if it did not have all of them.
+                    #[inline]
+                    #handler_vis fn resume(&mut self) -> ::std::option::Option<#handler_ident<'_, #(#resume_types),*>> {
+                        if !::std::mem::replace(&mut self.__persisted, false) {
+                            return ::std::option::Option::None;
+                        }
+                        ::std::option::Option::Some(#handler_ident {
+                            inner: self,
+                            state: #state_ident::new(),
+                        })
+                    }
+                },
+            )
+        };
+
+        // impl #ident
+        let fields_const = self.fields_const_tokens();
+        let vacancy_stats = self.vacancy_stats_tokens();
+        let vacancy_types = repeat_tokens(quote!(::certain_map::Vacancy), self.fields.len());
+        let vacancy_types2 = repeat_tokens(quote!(::certain_map::Vacancy), self.fields.len());
+        let vacancy_types3 = repeat_tokens(quote!(::certain_map::Vacancy), self.fields.len());
+        let vacancy_types4 = repeat_tokens(quote!(::certain_map::Vacancy), self.fields.len());
+        let raw_slots = if RAW {
+            self.raw_slots_tokens()
+        } else {
+            quote!()
+        };
+        let scope = if SCOPE {
+            let vacancy_types = repeat_tokens(quote!(::certain_map::Vacancy), self.fields.len());
+            quote! {
+                /// Run `f` with an empty handler of the store. The returned future borrows the
+                /// store, so the store outlives the handler used across awaits.
+                #[inline]
+                #handler_vis async fn scope<_CMR>(
+                    &mut self,
+                    f: impl ::std::ops::AsyncFnOnce(#handler_ident<'_, #(#vacancy_types),*>) -> _CMR,
+                ) -> _CMR {
+                    f(self.handler()).await
+                }
+            }
+        } else {
+            quote!()
+        };
+        // Padded slots and unions are `repr(C)`, so a slot starts where the outermost storage
+        // holding it starts, whose offset only needs the stable single-level `offset_of!`.
+        let layout_consts = {
+            let offset_names = names.iter().map(|name| {
+                quote::format_ident!("OFFSET_{}", name.unraw().to_string().to_uppercase())
+            });
+            let align_names = names.iter().map(|name| {
+                quote::format_ident!("ALIGN_{}", name.unraw().to_string().to_uppercase())
+            });
+            let offsets: Vec<_> = (0..self.fields.len())
+                .map(|idx| {
+                    if self.reorder_by_align {
+                        let root = self.slot_root(idx);
+                        quote!(Self::__OFFSETS[#root])
+                    } else {
+                        let root = self.fields_slot[idx].as_ref().unwrap_or(names[idx]);
+                        quote!(::std::mem::offset_of!(Self, #root))
+                    }
+                })
+                .collect();
+            let slot_tys: Vec<_> = (0..self.fields.len())
+                .map(|idx| self.slot_ty(idx))
+                .collect();
+            quote! {
+                /// The alignment of the store.
+                pub const ALIGN: usize = ::std::mem::align_of::<Self>();
+                #(
+                    #[doc = ::std::concat!("The offset of the `", ::std::stringify!(#names), "` slot in the store, holding a `", ::std::stringify!(#slot_tys), "` when occupied.")]
+                    pub const #offset_names: usize = #offsets;
+                    #[doc = ::std::concat!("The alignment of the `", ::std::stringify!(#names), "` slot in the store.")]
+                    pub const #align_names: usize = ::std::mem::align_of::<#slot_tys>();
+                )*
+            }
+        };
+        let sorted_roots = if self.reorder_by_align {
+            let roots: Vec<_> = (0..root_count).collect();
+            let root_refs = roots
+                .iter()
+                .map(|root| quote::format_ident!("__root_{root}"));
+            let root_muts = roots
+                .iter()
+                .map(|root| quote::format_ident!("__root_{root}_mut"));
+            quote! {
+                // The roots in descending alignment, ties kept in declaration order.
+                const __ORDER: [usize; #root_count] = {
+                    let aligns: [usize; #root_count] = [#(::std::mem::align_of::<#root_slots>()),*];
+                    let mut order: [usize; #root_count] = [#(#roots),*];
+                    let mut i = 1;
+                    while i < #root_count {
+                        let mut j = i;
+                        while j > 0 && aligns[order[j - 1]] < aligns[order[j]] {
+                            let root = order[j];
+                            order[j] = order[j - 1];
+                            order[j - 1] = root;
+                            j -= 1;
+                        }
+                        i += 1;
+                    }
+                    order
+                };
+                // The offset of every root, found at the position of its rank.
+                const __OFFSETS: [usize; #root_count] = {
+                    let positions: [usize; #root_count] = [#(::std::mem::offset_of!(Self, #positions)),*];
+                    let mut offsets = [0; #root_count];
+                    let mut pos = 0;
+                    while pos < #root_count {
+                        offsets[Self::__ORDER[pos]] = positions[pos];
+                        pos += 1;
+                    }
+                    offsets
+                };
+                #(
+                    #[inline]
+                    fn #root_refs(&self) -> &#root_slots {
+                        unsafe { &*(self as *const Self).cast::<u8>().add(Self::__OFFSETS[#roots]).cast() }
+                    }
+                    #[inline]
+                    fn #root_muts(&mut self) -> &mut #root_slots {
+                        unsafe { &mut *(self as *mut Self).cast::<u8>().add(Self::__OFFSETS[#roots]).cast() }
+                    }
+                )*
+            }
+        } else {
+            quote!()
+        };
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(dead_code)]
+                impl #ident {
+                    #fields_const
+                    #vacancy_stats
+                    #sorted_roots
+                    /// The size of the store, including all the fields and padding.
+                    pub const SIZE: usize = ::std::mem::size_of::<Self>();
+                    #layout_consts
+
+                    #[inline]
+                    pub const fn new() -> Self {
+                        Self {
+                            #slots_init
+                            #provenance_init
+                            #persisted_init
+                        }
+                    }
+                    #[inline]
+                    #handler_vis fn handler(&mut self) -> #handler_ident<'_, #(#vacancy_types),*> {
+                        #release_persisted
+                        #handler_ident {
+                            inner: self,
+                            state: #state_ident::new(),
+                        }
+                    }
+                    /// Create a boxed store with an empty handler which owns it.
+                    #[inline]
+                    #owned_vis fn owned_handler() -> #owned_ident<#(#vacancy_types3),*> {
+                        #owned_ident {
+                            inner: ::std::boxed::Box::new(Self::new()),
+                            state: #state_ident::new(),
+                        }
+                    }
+                    /// Create an empty handler accessing the store through a storage handle, like
+                    /// the `RefMut` of a store shared with `Rc<RefCell<_>>`.
+                    #[inline]
+                    #generic_vis fn handler_in<_CMS: ::certain_map::Storage<Self>>(storage: _CMS) -> #generic_ident<_CMS, #(#vacancy_types4),*> {
+                        #release_persisted_in
+                        #generic_ident {
+                            inner: storage,
+                            state: #state_ident::new(),
+                        }
+                    }
+                    #scope
                     #clone_with
+                    #raw_slots
+                    #persisted_methods
+                }
+                impl ::std::default::Default for #ident {
+                    #[inline]
+                    fn default() -> Self {
+                        Self::new()
+                    }
+                }
+        });
+
+        // the inherent method of maps with persistent fields takes precedence in the body
+        tokens.extend(quote_spanned! {
+            self.span =>
+                impl ::certain_map::ReleasePersisted for #ident {
+                    #[inline]
+                    fn release_persisted(&mut self) {
+                        #release_persisted
+                    }
+                }
+        });
+
+        // Trait impls can not expose an associated type which is less visible than the
+        // implementing type, so they are only generated when the visibilities match.
+        if GAT && same_vis(store_vis, handler_vis) {
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl ::certain_map::Handler for #ident {
+                        type Hdr<'a> = #handler_ident<'a, #(#vacancy_types2),*>
+                        where
+                            Self: 'a;
+                        #[inline]
+                        fn handler(&mut self) -> Self::Hdr<'_> {
+                            self.handler()
+                        }
+                    }
+            });
+        }
+        if GAT && same_vis(state_vis, handler_vis) {
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#(#generic_types),*> ::certain_map::Attach<#ident> for #state_ident<#(#generic_types),*>
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        type Hdr<'a> = #handler_ident<'a, #(#generic_types),*>;
+                        #[inline]
+                        unsafe fn attach(self, store: &mut #ident) -> Self::Hdr<'_> {
+                            self.attach(store)
+                        }
+                    }
+            });
+        }
+
+        // impl #state_ident
+        let field_count = self.fields.len();
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(non_camel_case_types, dead_code)]
+                impl<#(#generic_types),*> #state_ident<#(#generic_types),*>
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    /// The occupancy of every field in this state.
+                    pub const OCCUPIED: [bool; #field_count] = [
+                        #(<#generic_types as ::certain_map::MaybeAvailable>::OCCUPIED,)*
+                    ];
+
+                    #[inline]
+                    #state_vis const fn new() -> Self {
+                        Self {
+                            #(#names: ::std::marker::PhantomData,)*
+                        }
+                    }
+                    /// # Safety
+                    /// The caller must make sure the attached map has the data of current state.
+                    #[inline]
+                    #handler_vis unsafe fn attach(self, inner: &mut #ident) -> #handler_ident<'_, #(#generic_types),*> {
+                        #handler_ident {
+                            inner,
+                            state: Self::new(),
+                        }
+                    }
+                    /// Attach to the store behind a storage handle.
+                    ///
+                    /// # Safety
+                    /// The caller must make sure the attached map has the data of current state.
+                    #[inline]
+                    #generic_vis unsafe fn attach_in<_CMS: ::certain_map::Storage<#ident>>(self, storage: _CMS) -> #generic_ident<_CMS, #(#generic_types),*> {
+                        #generic_ident {
+                            inner: storage,
+                            state: Self::new(),
+                        }
+                    }
+                    /// Attach to a boxed store, returning the handler which owns it.
+                    ///
+                    /// # Safety
+                    /// The caller must make sure the attached map has the data of current state.
+                    #[inline]
+                    #owned_vis unsafe fn attach_owned(self, inner: ::std::boxed::Box<#ident>) -> #owned_ident<#(#generic_types),*> {
+                        #owned_ident {
+                            inner,
+                            state: Self::new(),
+                        }
+                    }
+                }
+                #[allow(non_camel_case_types)]
+                impl<#(#generic_types),*> ::std::default::Default for #state_ident<#(#generic_types),*>
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    #[inline]
+                    fn default() -> Self {
+                        Self::new()
+                    }
+                }
+                #[allow(non_camel_case_types)]
+                impl<#(#generic_types),*> ::std::clone::Clone for #state_ident<#(#generic_types),*>
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    #[inline]
+                    fn clone(&self) -> Self {
+                        *self
+                    }
+                }
+                #[allow(non_camel_case_types)]
+                impl<#(#generic_types),*> ::std::marker::Copy for #state_ident<#(#generic_types),*>
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                }
+        });
+
+        if same_vis(state_vis, owned_vis) {
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#(#generic_types),*> ::certain_map::AttachOwned<#ident> for #state_ident<#(#generic_types),*>
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        type Owned = #owned_ident<#(#generic_types),*>;
+                        #[inline]
+                        unsafe fn attach_owned(self, store: ::std::boxed::Box<#ident>) -> Self::Owned {
+                            self.attach_owned(store)
+                        }
+                    }
+            });
+        }
+
+        // conversions between #owned_ident and StoredCtx, which needs the Attach impl above
+        if GAT && same_vis(state_vis, handler_vis) {
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#(#generic_types),*> ::std::convert::From<#owned_ident<#(#generic_types),*>>
+                        for ::certain_map::StoredCtx<#ident, #state_ident<#(#generic_types),*>>
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        #[inline]
+                        fn from(owned: #owned_ident<#(#generic_types),*>) -> Self {
+                            let owned = ::std::mem::ManuallyDrop::new(owned);
+                            // Safety: the store is moved out exactly once and the owned handler
+                            // is never dropped, so the fields are owned by the StoredCtx now.
+                            unsafe {
+                                let inner = ::std::ptr::read(&owned.inner);
+                                ::certain_map::StoredCtx::from_parts(inner, #state_ident::new())
+                            }
+                        }
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#(#generic_types),*> ::std::convert::From<::certain_map::StoredCtx<#ident, #state_ident<#(#generic_types),*>>>
+                        for #owned_ident<#(#generic_types),*>
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        #[inline]
+                        fn from(stored: ::certain_map::StoredCtx<#ident, #state_ident<#(#generic_types),*>>) -> Self {
+                            let (inner, state) = stored.into_parts();
+                            Self { inner, state }
+                        }
+                    }
+            });
+        }
+
+        let hdr = HandlerTy::new(&generic_ident, ident);
+        self.to_pre_filled_handler_tokens(tokens, &hdr, derive_clone);
+        self.methods_tokens(tokens, &hdr);
+        if !self.cache_key.is_empty() {
+            self.cache_key_tokens(tokens, &hdr);
+        }
+    }
+
+    // The `#[cache_key(..)]` struct and `cache_key()` of the handlers with its fields occupied.
+    fn cache_key_tokens(&self, tokens: &mut proc_macro2::TokenStream, hdr: &HandlerTy) {
+        let ident = &self.ident;
+        let vis = &self.vis;
+        let key_ident = quote::format_ident!("{ident}CacheKey");
+        let impl_lt = hdr.impl_generics();
+        let generic_types: Vec<_> = (0..self.fields.len())
+            .map(generic_type)
+            .map(IdentOrTokens::from)
+            .collect();
+        let hdr_ty = hdr.ty(&generic_types);
+        let bounds = generic_types.iter().enumerate().map(|(idx, generic)| {
+            if self.cache_key.contains(&idx) {
+                quote!(#generic: ::certain_map::Available)
+            } else {
+                quote!(#generic: ::certain_map::MaybeAvailable)
+            }
+        });
+        let names: Vec<_> = self
+            .cache_key
+            .iter()
+            .map(|&idx| self.fields[idx].ident.as_ref().unwrap())
+            .collect();
+        let types: Vec<_> = self
+            .cache_key
+            .iter()
+            .map(|&idx| &self.fields[idx].ty)
+            .collect();
+        let doc = format!(
+            "The cache key of [`{ident}`], cloned from the fields of `#[cache_key(..)]` by `cache_key()`."
+        );
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[doc = #doc]
+                #[derive(Clone, PartialEq, Eq, Hash)]
+                #vis struct #key_ident {
+                    #(pub #names: #types,)*
+                }
+
+                #[allow(non_camel_case_types, dead_code)]
+                impl<#impl_lt #(#generic_types),*> #hdr_ty
+                where
+                    #(#bounds,)*
+                {
+                    /// Clone the fields of the cache key.
+                    #[inline]
+                    pub fn cache_key(&self) -> #key_ident {
+                        #key_ident {
+                            #(#names: ::std::clone::Clone::clone(::certain_map::ParamRef::<#types>::param_ref(self)),)*
+                        }
+                    }
+                }
+        });
+    }
+
+    // Take the methods of an impl block for the map, with their `#[requires(..)]` and
+    // `#[produces(..)]`.
+    fn add_methods(&mut self, item: syn::ItemImpl) -> Result<()> {
+        if matches!(self.style, GenStyle::Unfilled) {
+            return Err(syn::Error::new(
+                item.impl_token.span,
+                "impl blocks are only supported by the prefilled style",
+            ));
+        }
+        if let Some((_, path, _)) = &item.trait_ {
+            return Err(syn::Error::new(
+                path.span(),
+                "only inherent impl blocks are supported, implement traits outside certain_map!",
+            ));
+        }
+        if !item.generics.params.is_empty() || item.generics.where_clause.is_some() {
+            return Err(syn::Error::new(
+                item.generics.span(),
+                "impl blocks can not have generics, the state generics are filled in",
+            ));
+        }
+        if let Some(attr) = item.attrs.first() {
+            return Err(syn::Error::new(
+                attr.span(),
+                "impl blocks do not support attributes",
+            ));
+        }
+        for impl_item in item.items {
+            let mut item = match impl_item {
+                syn::ImplItem::Fn(item) => item,
+                other => {
+                    return Err(syn::Error::new(
+                        other.span(),
+                        "impl blocks can only have methods",
+                    ))
+                }
+            };
+            let mut requires = Vec::new();
+            let mut produces = Vec::new();
+            let mut attrs = Vec::new();
+            for attr in item.attrs {
+                let target = if attr.path().is_ident("requires") {
+                    &mut requires
+                } else if attr.path().is_ident("produces") {
+                    &mut produces
+                } else {
+                    attrs.push(attr);
+                    continue;
+                };
+                let names =
+                    attr.parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated)?;
+                for name in names {
+                    let idx = self
+                        .fields
+                        .iter()
+                        .position(|f| f.ident.as_ref() == Some(&name))
+                        .ok_or_else(|| {
+                            syn::Error::new(name.span(), format!("field `{name}` does not exist"))
+                        })?;
+                    if !target.contains(&idx) {
+                        target.push(idx);
+                    }
+                }
+            }
+            item.attrs = attrs;
+            if !produces.is_empty()
+                && !matches!(item.sig.receiver(), Some(r) if r.reference.is_none())
+            {
+                return Err(syn::Error::new(
+                    item.sig.ident.span(),
+                    "methods with #[produces(..)] must take self by value",
+                ));
+            }
+            self.methods.push(Method {
+                item,
+                requires,
+                produces,
+            });
+        }
+        Ok(())
+    }
+
+    // An inherent impl for every method of the impl blocks, where the required fields are
+    // occupied. `_` in the return type is the handler with the produced fields occupied.
+    fn methods_tokens(&self, tokens: &mut proc_macro2::TokenStream, hdr: &HandlerTy) {
+        let impl_lt = hdr.impl_generics();
+        let generic_types: Vec<_> = (0..self.fields.len())
+            .map(generic_type)
+            .map(IdentOrTokens::from)
+            .collect();
+        let hdr_ty = hdr.ty(&generic_types);
+        for method in self.methods.iter() {
+            let bounds = generic_types.iter().enumerate().map(|(idx, generic)| {
+                if method.requires.contains(&idx) {
+                    quote!(#generic: ::certain_map::Available)
+                } else {
+                    quote!(#generic: ::certain_map::MaybeAvailable)
+                }
+            });
+            let produced_ty: Type = syn::parse2(hdr.ty((0..self.fields.len()).map(|idx| {
+                if method.produces.contains(&idx) {
+                    IdentOrTokens::from(occupied_m_type())
+                } else {
+                    IdentOrTokens::from(generic_type(idx))
+                }
+            })))
+            .unwrap();
+            let mut item = method.item.clone();
+            if let syn::ReturnType::Type(_, ty) = &mut item.sig.output {
+                replace_infer(ty, &produced_ty);
+            }
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> #hdr_ty
+                    where
+                        #(#bounds,)*
+                    {
+                        #item
+                    }
+            });
+        }
+    }
+
+    // The visibility of the handler generic over the storage, which backs the aliases of both
+    // the borrowed and the owned handler.
+    fn handler_in_vis<'v>(
+        handler_vis: &'v Visibility,
+        owned_vis: &'v Visibility,
+        vis: &'v Visibility,
+    ) -> &'v Visibility {
+        if same_vis(handler_vis, owned_vis) {
+            handler_vis
+        } else {
+            vis
+        }
+    }
+
+    // Generate fork, Drop and Param* impls for the prefilled handler. Borrowed, owned and
+    // shared handlers only differ in the storage handle, so the impls are generic over it.
+    fn to_pre_filled_handler_tokens(
+        &self,
+        tokens: &mut proc_macro2::TokenStream,
+        hdr: &HandlerTy,
+        derive_clone: bool,
+    ) {
+        let ident = &self.ident;
+        let state_ident = quote::format_ident!("{ident}State");
+        let owned_ident = quote::format_ident!("{ident}OwnedHandler");
+        let generic_types: Vec<_> = (0..self.fields.len())
+            .map(generic_type)
+            .map(IdentOrTokens::from)
+            .collect();
+        let names: Vec<_> = self
+            .fields
+            .iter()
+            .map(|f| f.ident.as_ref().unwrap())
+            .collect();
+        let impl_lt = hdr.impl_generics();
+        let hdr_ident = hdr.ident;
+        let handler_ident = quote::format_ident!("{ident}Handler");
+        let hdr_ty = hdr.ty(&generic_types);
+        let store_vis = self.item_vis.store.as_ref().unwrap_or(&self.vis);
+        let state_vis = self.item_vis.state.as_ref().unwrap_or(&self.vis);
+        let owned_vis = self.item_vis.owned.as_ref().unwrap_or(&self.vis);
+        let hdr_vis = Self::handler_in_vis(
+            self.item_vis.handler.as_ref().unwrap_or(&self.vis),
+            owned_vis,
+            &self.vis,
+        );
+        let slots: Vec<_> = (0..self.fields.len())
+            .map(|idx| self.slot_place(quote!(self.inner), idx, false))
+            .collect();
+        let all: Vec<_> = (0..self.fields.len()).collect();
+        let clear_all = self.clear_slots_tokens(quote!(self.inner), &all);
+        let derive_copy = self.has_derive("Copy");
+        let drop_generics: Vec<_> = self
+            .drop_order
+            .iter()
+            .map(|&idx| generic_type(idx))
+            .collect();
+        let drop_slots: Vec<_> = self
+            .drop_order
+            .iter()
+            .map(|&idx| self.slot_place(quote!(self.inner), idx, true))
+            .collect();
+        let drop_slots_next: Vec<_> = self
+            .drop_order
+            .iter()
+            .map(|&idx| self.slot_place(quote!(next.inner), idx, true))
+            .collect();
+        let drop_all = if derive_copy {
+            quote!()
+        } else if self.fields_persistent.contains(&true) {
+            // the persistent fields are left in the store if all of them are occupied
+            let persistent_generics = (0..self.fields.len())
+                .filter(|&idx| self.fields_persistent[idx])
+                .map(generic_type);
+            let drops = self.drop_order.iter().map(|&idx| {
+                let generic_type = generic_type(idx);
+                let slot = self.slot_place(quote!(self.inner), idx, true);
+                let drop = quote!(#generic_type::do_drop(&mut #slot););
+                if self.fields_persistent[idx] {
+                    quote!(if !persist { #drop })
+                } else {
+                    drop
+                }
+            });
+            quote! {
+                let persist = #(<#persistent_generics as ::certain_map::MaybeAvailable>::OCCUPIED)&&*;
+                self.inner.__persisted = persist;
+                unsafe {
+                    #(#drops)*
+                }
+            }
+        } else {
+            quote! {
+                unsafe {
+                    #(#drop_generics::do_drop(&mut #drop_slots);)*
+                }
+            }
+        };
+        // Consuming methods transition before dropping, so a panicking destructor unwinds with
+        // the slot already vacant in the handler instead of dropping the value twice.
+        let clear_all_next = self.clear_slots_tokens(quote!(next.inner), &all);
+        let drop_all_next = if derive_copy {
+            quote!()
+        } else {
+            quote!(#(#drop_generics::do_drop(&mut #drop_slots_next);)*)
+        };
+
+        // impl transition for #hdr_ty, which moves the storage handle into the handler of another
+        // state
+        let target_generics: Vec<_> = (0..self.fields.len())
+            .map(|idx| quote::format_ident!("_CMU_{idx}"))
+            .collect();
+        let target_hdr_ty = hdr.ty(&target_generics);
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(non_camel_case_types)]
+                impl<#impl_lt #(#generic_types),*> #hdr_ty
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    /// # Safety
+                    /// The store must have the data of the target state.
+                    #[inline(always)]
+                    unsafe fn transition<#(#target_generics),*>(self) -> #target_hdr_ty
+                    where
+                        #(#target_generics: ::certain_map::MaybeAvailable,)*
+                    {
+                        let this = ::std::mem::ManuallyDrop::new(self);
+                        // Safety: the handle is moved out exactly once and self is never dropped.
+                        let inner = unsafe { ::std::ptr::read(&this.inner) };
+                        #hdr_ident {
+                            inner,
+                            state: #state_ident::new(),
+                        }
+                    }
+                }
+        });
+
+        if derive_clone {
+            // impl fork for #hdr_ty
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types, dead_code)]
+                    impl<#impl_lt #(#generic_types),*> #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        #[inline]
+                        #state_vis fn fork(&self) -> (#ident, #state_ident<#(#generic_types),*>) {
+                            // Safety: we are sure about the state of the map.
+                            let inner = unsafe { self.inner.clone_with(&self.state) };
+                            (inner, #state_ident::new())
+                        }
+                        #[inline]
+                        #owned_vis fn fork_owned(&self) -> #owned_ident<#(#generic_types),*> {
+                            // Safety: we are sure about the state of the map.
+                            let inner = unsafe { self.inner.clone_with(&self.state) };
+                            #owned_ident {
+                                inner: ::std::boxed::Box::new(inner),
+                                state: #state_ident::new(),
+                            }
+                        }
+                    }
+            });
+            if same_vis(hdr_vis, store_vis) && same_vis(hdr_vis, state_vis) {
+                tokens.extend(quote_spanned! {
+                    self.span =>
+                        #[allow(non_camel_case_types)]
+                        impl<#impl_lt #(#generic_types),*> ::certain_map::Fork for #hdr_ty
+                        where
+                            #(#generic_types: ::certain_map::MaybeAvailable,)*
+                        {
+                            type Store = #ident;
+                            type State = #state_ident<#(#generic_types),*>;
+                            #[inline]
+                            fn fork(&self) -> (Self::Store, Self::State) {
+                                self.fork()
+                            }
+                        }
+                });
+            }
+            if same_vis(hdr_vis, owned_vis) {
+                tokens.extend(quote_spanned! {
+                    self.span =>
+                        #[allow(non_camel_case_types)]
+                        impl<#impl_lt #(#generic_types),*> ::certain_map::ForkOwned for #hdr_ty
+                        where
+                            #(#generic_types: ::certain_map::MaybeAvailable,)*
+                        {
+                            type Owned = #owned_ident<#(#generic_types),*>;
+                            #[inline]
+                            fn fork_owned(&self) -> Self::Owned {
+                                self.fork_owned()
+                            }
+                        }
+                });
+            }
+        }
+
+        // impl clone_owned for #hdr_ty, which only needs the occupied fields to be Clone
+        let clone_slots = (0..self.fields.len()).map(|idx| {
+            let generic_type = generic_type(idx);
+            let slot_ty = self.slot_ty(idx);
+            let slot = self.slot_place(quote!(self.inner), idx, false);
+            let value =
+                quote!(<#generic_type as ::certain_map::SlotClone<#slot_ty>>::clone_slot(&#slot));
+            // vacant slots are left alone, they may share storage with an occupied one
+            let clone = self.slot_place(quote!(inner), idx, true);
+            let assign = match self.fields_slot[idx] {
+                Some(_) => quote!(#clone = ::std::mem::ManuallyDrop::new(#value);),
+                None => quote!(#clone = #value;),
+            };
+            quote! {
+                if <#generic_type as ::certain_map::MaybeAvailable>::OCCUPIED {
+                    #assign
+                }
+            }
+        });
+        let clone_provenance = if PROVENANCE {
+            quote!(inner.__provenance = self.inner.__provenance;)
+        } else {
+            quote!()
+        };
+        let slot_types = (0..self.fields.len()).map(|idx| self.slot_ty(idx));
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(non_camel_case_types, dead_code)]
+                impl<#impl_lt #(#generic_types),*> #hdr_ty
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    /// Clone the occupied fields into a new boxed store, returning a `'static`
+                    /// handler of the same state which owns it, like for a background task.
+                    #[inline]
+                    #owned_vis fn clone_owned(&self) -> #owned_ident<#(#generic_types),*>
+                    where
+                        #(#generic_types: ::certain_map::SlotClone<#slot_types>,)*
+                    {
+                        let mut inner = ::std::boxed::Box::new(#ident::new());
+                        // Safety: the slots are cloned according to the state, a panic leaks the
+                        // cloned values since the store never drops them.
+                        unsafe {
+                            #(#clone_slots)*
+                        }
+                        #clone_provenance
+                        #owned_ident {
+                            inner,
+                            state: #state_ident::new(),
+                        }
+                    }
+                }
+        });
+
+        // impl reset for #hdr_ty
+        let vacancy_types = repeat_tokens(vacancy_type(), self.fields.len());
+        let empty_ty = hdr.ty(vacancy_types);
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(non_camel_case_types, dead_code)]
+                impl<#impl_lt #(#generic_types),*> #hdr_ty
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    /// Drop all occupied fields and return the empty handler of the same store.
+                    #[inline]
+                    #[allow(unused_mut)]
+                    pub fn reset(self) -> #empty_ty {
+                        unsafe {
+                            let mut next: #empty_ty = self.transition();
+                            #drop_all_next
+                            #clear_all_next
+                            next
+                        }
+                    }
+                }
+                #[allow(non_camel_case_types, dead_code)]
+                impl<#impl_lt #(#generic_types),*> #hdr_ty
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    /// Mark all fields vacant without running their destructors, for values owned
+                    /// elsewhere like in an arena.
+                    #[inline]
+                    #[allow(unused_mut)]
+                    pub fn forget_all(mut self) -> #empty_ty {
+                        #clear_all
+                        unsafe {
+                            self.transition()
+                        }
+                    }
+                }
+                #[allow(non_camel_case_types)]
+                impl<#impl_lt #(#generic_types),*> ::certain_map::Reset for #hdr_ty
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    type Empty = #empty_ty;
+                    #[inline]
+                    fn reset(self) -> Self::Empty {
+                        self.reset()
+                    }
+                }
+        });
+
+        // impl absorb for #hdr_ty, moving values may break the exclusion of conflicting fields
+        if self.fields_conflicts.iter().all(Vec::is_empty) {
+            let other_types: Vec<_> = (0..self.fields.len())
+                .map(|idx| quote::format_ident!("_CMS_{idx}"))
+                .collect();
+            let union_ty = hdr.ty(generic_types
+                .iter()
+                .zip(other_types.iter())
+                .map(|(g, o)| quote!(<#g as ::certain_map::SlotUnion<#o>>::Output)));
+            let vacancy_types = repeat_tokens(vacancy_type(), self.fields.len());
+            let slots_mut =
+                (0..self.fields.len()).map(|idx| self.slot_place(quote!(self.inner), idx, true));
+            let other_slots =
+                (0..self.fields.len()).map(|idx| self.slot_place(quote!(other.inner), idx, false));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types, dead_code)]
+                    impl<#impl_lt #(#generic_types),*> #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        /// Move the occupied fields of another handler into this one, the values
+                        /// of `other` replace the ones already set.
+                        #[allow(unused_mut)]
+                        #owned_vis fn absorb<#(#other_types),*>(
+                            mut self,
+                            mut other: #owned_ident<#(#other_types),*>,
+                        ) -> #union_ty
+                        where
+                            #(#other_types: ::certain_map::MaybeAvailable,)*
+                            #(#generic_types: ::certain_map::SlotUnion<#other_types>,)*
+                        {
+                            unsafe {
+                                // The values are moved out, so other is empty now.
+                                let other: #owned_ident<#(#vacancy_types),*> = other.transition();
+                                #(if <#other_types as ::certain_map::MaybeAvailable>::OCCUPIED {
+                                    let mut old = ::std::ptr::read(&#slots);
+                                    ::std::ptr::copy_nonoverlapping(&#other_slots, &mut #slots_mut, 1);
+                                    #generic_types::do_drop(&mut old);
+                                })*
+                                self.transition()
+                            }
+                        }
+                    }
+            });
+        }
+
+        // impl migrate for #hdr_ty
+        let clear_source = self.clear_slots_tokens(quote!(empty.inner), &all);
+        // the persistent fields left in dest are overwritten, and the moved values are owned by
+        // the returned state instead of dest
+        let (release_dest, unmark_dest) = if self.fields_persistent.contains(&true) {
+            (
+                quote!(dest.release_persisted();),
+                quote!(dest.__persisted = false;),
+            )
+        } else {
+            (quote!(), quote!())
+        };
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(non_camel_case_types, dead_code)]
+                impl<#impl_lt #(#generic_types),*> #hdr_ty
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    /// Move all the occupied fields into another store, returning the state to attach
+                    /// to it and the empty handler of the current store.
+                    #[inline]
+                    #[allow(clippy::type_complexity)]
+                    #state_vis fn migrate(self, dest: &mut #ident) -> (#state_ident<#(#generic_types),*>, #empty_ty) {
+                        // Safety: the values are moved to dest bitwise and the current store is
+                        // marked empty, so they are owned by dest only.
+                        #release_dest
+                        unsafe {
+                            ::std::ptr::copy_nonoverlapping::<#ident>(&*self.inner, dest, 1);
+                            #unmark_dest
+                            #[allow(unused_mut)]
+                            let mut empty: #empty_ty = self.transition();
+                            #clear_source
+                            (#state_ident::new(), empty)
+                        }
+                    }
+                }
+        });
+
+        // impl try_promote for #hdr_ty
+        let target_types: Vec<_> = (0..self.fields.len())
+            .map(|idx| quote::format_ident!("_CMS_{idx}"))
+            .collect();
+        let target_ty = hdr.ty(&target_types);
+        let promote_drops = self.drop_order.iter().map(|&idx| {
+            let generic_type = generic_type(idx);
+            let target_type = &target_types[idx];
+            let slot = self.slot_place(quote!(next.inner), idx, true);
+            let clear = self.clear_slots_tokens(quote!(next.inner), &[idx]);
+            quote! {
+                if !<#target_type as ::certain_map::MaybeAvailable>::OCCUPIED {
+                    #generic_type::do_drop(&mut #slot);
+                    #clear
+                }
+            }
+        });
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(non_camel_case_types, dead_code)]
+                impl<#impl_lt #(#generic_types),*> #hdr_ty
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    /// Convert to the handler of another state after checking at runtime that every
+                    /// field of the target is occupied. Occupied fields the target does not have
+                    /// are dropped.
+                    #[allow(unused_mut, clippy::type_complexity)]
+                    pub fn try_promote<#(#target_types),*>(
+                        mut self,
+                    ) -> ::std::result::Result<#target_ty, ::certain_map::PromoteError<Self>>
+                    where
+                        #(#target_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        let mut missing = ::std::vec::Vec::new();
+                        #(if <#target_types as ::certain_map::MaybeAvailable>::OCCUPIED
+                            && !<#generic_types as ::certain_map::MaybeAvailable>::OCCUPIED
+                        {
+                            missing.push(::std::stringify!(#names));
+                        })*
+                        if !missing.is_empty() {
+                            return ::std::result::Result::Err(::certain_map::PromoteError {
+                                handler: self,
+                                missing: ::certain_map::MissingParam { missing },
+                            });
+                        }
+                        unsafe {
+                            let mut next: #target_ty = self.transition();
+                            #(#promote_drops)*
+                            ::std::result::Result::Ok(next)
+                        }
+                    }
+                }
+        });
+
+        // impl Join<#hdr_ty of another state> for #hdr_ty
+        let other_types: Vec<_> = (0..self.fields.len())
+            .map(|idx| quote::format_ident!("_CMS_{idx}"))
+            .collect();
+        let other_ty = hdr.ty(&other_types);
+        let joined_types: Vec<_> = generic_types
+            .iter()
+            .zip(other_types.iter())
+            .map(|(g, o)| quote!(<#g as ::certain_map::SlotIntersect<#o>>::Output))
+            .collect();
+        let joined_ty = hdr.ty(&joined_types);
+        let join_drops = self.drop_order.iter().map(|&idx| {
+            let generic_type = generic_type(idx);
+            let joined_type = &joined_types[idx];
+            let slot = self.slot_place(quote!(next.inner), idx, true);
+            let clear = self.clear_slots_tokens(quote!(next.inner), &[idx]);
+            quote! {
+                if !<#joined_type as ::certain_map::MaybeAvailable>::OCCUPIED {
+                    #generic_type::do_drop(&mut #slot);
+                    #clear
+                }
+            }
+        });
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(non_camel_case_types)]
+                impl<#impl_lt #(#generic_types,)* #(#other_types),*> ::certain_map::Join<#other_ty> for #hdr_ty
+                where
+                    #(#other_types: ::certain_map::MaybeAvailable,)*
+                    #(#generic_types: ::certain_map::SlotIntersect<#other_types>,)*
+                {
+                    type Output = #joined_ty;
+
+                    #[inline]
+                    #[allow(unused_mut)]
+                    fn join(self) -> Self::Output {
+                        unsafe {
+                            let mut next: Self::Output = self.transition();
+                            #(#join_drops)*
+                            next
+                        }
+                    }
+                }
+        });
+
+        // impl DowngradeTo<#state_ident of a weaker state> for #hdr_ty
+        let target_state = quote!(#state_ident<#(#other_types),*>);
+        let downgrade_drops = self.drop_order.iter().map(|&idx| {
+            let generic_type = generic_type(idx);
+            let other_type = &other_types[idx];
+            let slot = self.slot_place(quote!(next.inner), idx, true);
+            let clear = self.clear_slots_tokens(quote!(next.inner), &[idx]);
+            quote! {
+                if !<#other_type as ::certain_map::MaybeAvailable>::OCCUPIED {
+                    #generic_type::do_drop(&mut #slot);
+                    #clear
+                }
+            }
+        });
+        let forget_clears = (0..self.fields.len()).map(|idx| {
+            let other_type = &other_types[idx];
+            let clear = self.clear_slots_tokens(quote!(self.inner), &[idx]);
+            quote! {
+                if !<#other_type as ::certain_map::MaybeAvailable>::OCCUPIED {
+                    #clear
+                }
+            }
+        });
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(non_camel_case_types)]
+                impl<#impl_lt #(#generic_types,)* #(#other_types),*> ::certain_map::DowngradeTo<#target_state> for #hdr_ty
+                where
+                    #(#other_types: ::certain_map::MaybeAvailable,)*
+                    #(#generic_types: ::certain_map::SlotDowngrade<#other_types>,)*
+                {
+                    type Output = #other_ty;
+
+                    #[inline]
+                    #[allow(unused_mut)]
+                    fn downgrade(self) -> Self::Output {
+                        unsafe {
+                            let mut next: Self::Output = self.transition();
+                            #(#downgrade_drops)*
+                            next
+                        }
+                    }
+
+                    #[inline]
+                    #[allow(unused_mut)]
+                    fn forget_extra(mut self) -> Self::Output {
+                        #(#forget_clears)*
+                        unsafe {
+                            self.transition()
+                        }
+                    }
+                }
+                #[allow(non_camel_case_types, dead_code)]
+                impl<#impl_lt #(#generic_types),*> #hdr_ty
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    /// Convert to the handler of the state type `T` of the map, whose occupied fields
+                    /// are a subset of the current ones. The other fields are dropped.
+                    #[inline]
+                    pub fn downgrade_to<T>(self) -> <Self as ::certain_map::DowngradeTo<T>>::Output
+                    where
+                        Self: ::certain_map::DowngradeTo<T>,
+                    {
+                        ::certain_map::DowngradeTo::<T>::downgrade(self)
+                    }
+
+                    /// Like `downgrade_to`, but marks the other fields vacant without running their
+                    /// destructors, for values owned elsewhere like in an arena.
+                    #[inline]
+                    pub fn forget_to<T>(self) -> <Self as ::certain_map::DowngradeTo<T>>::Output
+                    where
+                        Self: ::certain_map::DowngradeTo<T>,
+                    {
+                        ::certain_map::DowngradeTo::<T>::forget_extra(self)
+                    }
+                }
+        });
+
+        // impl partition for #hdr_ty
+        if !self.partitions.is_empty() {
+            let partitioned: Vec<_> = self
+                .partitions
+                .iter()
+                .flat_map(|p| p.fields.iter().copied())
+                .collect();
+            let mut owned_types = Vec::new();
+            let mut moves = Vec::new();
+            for partition in self.partitions.iter() {
+                let sub_ident = &partition.ident;
+                let sub_owned = quote::format_ident!("{sub_ident}OwnedHandler");
+                let sub_state = quote::format_ident!("{sub_ident}State");
+                let sub_generics = partition.fields.iter().map(|&idx| generic_type(idx));
+                owned_types.push(quote!(#sub_owned<#(#sub_generics),*>));
+                let sub_paths = &partition.paths;
+                let slots = partition
+                    .fields
+                    .iter()
+                    .map(|&idx| self.slot_place(quote!(self.inner), idx, false));
+                moves.push(quote! {{
+                    let mut inner = ::std::boxed::Box::new(#sub_ident::new());
+                    #(inner.#sub_paths = ::std::ptr::read(&#slots);)*
+                    #sub_owned {
+                        inner,
+                        state: #sub_state::new(),
+                    }
+                }});
+            }
+            let clear = self.clear_slots_tokens(quote!(self.inner), &partitioned);
+            let rest_ty = hdr.ty((0..self.fields.len()).map(|idx| {
+                if partitioned.contains(&idx) {
+                    IdentOrTokens::from(vacancy_type())
+                } else {
+                    IdentOrTokens::from(generic_type(idx))
+                }
+            }));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types, dead_code)]
+                    impl<#impl_lt #(#generic_types),*> #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        /// Move the fields into the maps of `#[partition(..)]`, which keep their
+                        /// states. Fields in no partition are dropped.
+                        #[allow(unused_mut)]
+                        pub fn partition(mut self) -> (#(#owned_types,)*) {
+                            // Safety: every slot is read once and becomes vacant in the
+                            // transitioned handler, so it is never dropped twice.
+                            unsafe {
+                                let partitions = (#(#moves,)*);
+                                #clear
+                                let rest: #rest_ty = self.transition();
+                                drop(rest);
+                                partitions
+                            }
+                        }
+                    }
+            });
+        }
+
+        // impl audit and Debug for #hdr_ty
+        let field_count = self.fields.len();
+        let derive_debug = self.has_derive("Debug");
+        let types: Vec<_> = self.fields.iter().map(|f| &f.ty).collect();
+        let locations = (0..self.fields.len()).map(|idx| {
+            if PROVENANCE {
+                quote!(location: self.inner.__provenance[#idx],)
+            } else {
+                quote!(location: ::std::option::Option::None,)
+            }
+        });
+        let raw_store = if RAW {
+            quote! {
+                /// The store behind the handler, whose slots are initialized exactly where
+                /// `OCCUPIED` is true.
+                #[inline]
+                pub fn raw_store(&self) -> &#ident {
+                    &self.inner
+                }
+
+                /// Mutable access to the store behind the handler.
+                ///
+                /// # Safety
+                /// The caller must keep the slots initialized exactly where `OCCUPIED` is true:
+                /// a value moved out of an occupied slot must be written back, and a value
+                /// written into a vacant slot is leaked.
+                #[inline]
+                pub unsafe fn raw_store_mut(&mut self) -> &mut #ident {
+                    &mut self.inner
+                }
+            }
+        } else {
+            quote!()
+        };
+        let prefixed_methods = self.prefixed_methods_tokens();
+        let debug_values = (0..self.fields.len()).map(|idx| {
+            if derive_debug || self.field_ensures(idx, "Debug") {
+                let value = self.field_maybe_ref(idx);
+                quote!(::std::option::Option::map(#value, |v| ::std::format!("{v:?}")))
+            } else {
+                quote!(::std::option::Option::None)
+            }
+        });
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(non_camel_case_types, dead_code)]
+                impl<#impl_lt #(#generic_types),*> #hdr_ty
+                where
+                    #(#generic_types: ::certain_map::MaybeAvailable,)*
+                {
+                    /// The occupancy of every field in this state.
+                    pub const OCCUPIED: [bool; #field_count] = [
+                        #(<#generic_types as ::certain_map::MaybeAvailable>::OCCUPIED,)*
+                    ];
+
+                    /// List the name, type and occupancy of every field, with the Debug output
+                    /// of occupied fields which ensure Debug.
+                    pub fn audit(&self) -> ::std::vec::Vec<::certain_map::FieldAudit> {
+                        ::std::vec![
+                            #(::certain_map::FieldAudit {
+                                name: ::std::stringify!(#names),
+                                type_name: ::std::any::type_name::<#types>(),
+                                occupied: <#generic_types as ::certain_map::MaybeAvailable>::OCCUPIED,
+                                debug: #debug_values,
+                                #locations
+                            },)*
+                        ]
+                    }
+
+                    /// The names of the occupied fields in declaration order.
+                    pub fn occupied_names(&self) -> impl ::std::iter::Iterator<Item = &'static str> {
+                        [#(::std::stringify!(#names)),*]
+                            .into_iter()
+                            .zip(Self::OCCUPIED)
+                            .filter_map(|(name, occupied)| if occupied { Some(name) } else { None })
+                    }
+
+                    /// Borrow the handler as a context for a sub-call, which can read and mutate the
+                    /// fields but not change the state.
+                    #[inline]
+                    pub fn reborrow(&mut self) -> ::certain_map::Reborrow<'_, Self> {
+                        ::certain_map::Reborrow::new(self)
+                    }
+
+                    #raw_store
+                    #prefixed_methods
+                }
+        });
+        if derive_debug {
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::std::fmt::Debug for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                            f.debug_struct(::std::stringify!(#handler_ident))
+                                #(.field(::std::stringify!(#names), &unsafe {
+                                    ::certain_map::DebugSlot::<#generic_types, _>::new(&#slots)
+                                }))*
+                                .finish()
+                        }
+                    }
+            });
+        }
+
+        // impl Valuable for #hdr_ty with the occupied fields which are Valuable
+        let derive_valuable = self.has_derive("Valuable");
+        let valuable_fields: Vec<_> = (0..self.fields.len())
+            .filter(|idx| derive_valuable || self.field_ensures(*idx, "Valuable"))
+            .collect();
+        if !valuable_fields.is_empty() {
+            let valuable_names = valuable_fields
+                .iter()
+                .map(|idx| self.fields[*idx].ident.as_ref().unwrap());
+            let valuable_values = valuable_fields.iter().map(|idx| self.field_maybe_ref(*idx));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::valuable::Valuable for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        fn as_value(&self) -> ::certain_map::valuable::Value<'_> {
+                            ::certain_map::valuable::Value::Structable(self)
+                        }
+                        fn visit(&self, visit: &mut dyn ::certain_map::valuable::Visit) {
+                            #(if let Some(value) = #valuable_values {
+                                visit.visit_named_fields(&::certain_map::valuable::NamedValues::new(
+                                    &[::certain_map::valuable::NamedField::new(::std::stringify!(#valuable_names))],
+                                    &[::certain_map::valuable::Valuable::as_value(value)],
+                                ));
+                            })*
+                        }
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::valuable::Structable for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        fn definition(&self) -> ::certain_map::valuable::StructDef<'_> {
+                            ::certain_map::valuable::StructDef::new_dynamic(
+                                ::std::stringify!(#handler_ident),
+                                ::certain_map::valuable::Fields::Named(&[]),
+                            )
+                        }
+                    }
+            });
+        }
+
+        // impl defmt::Format for #hdr_ty with the occupied fields which are Format
+        let derive_format = self.has_derive("Format");
+        let format_fields: Vec<_> = (0..self.fields.len())
+            .filter(|idx| derive_format || self.field_ensures(*idx, "Format"))
+            .collect();
+        if !format_fields.is_empty() {
+            // literal format strings, which defmt interns with the field names in them
+            let format_start =
+                syn::LitStr::new(&format!("{} {{{{ ", handler_ident.unraw()), self.span);
+            let format_names = format_fields.iter().map(|idx| {
+                let name = self.fields[*idx].ident.as_ref().unwrap().unraw();
+                syn::LitStr::new(&format!("{name}: {{}}, "), self.span)
+            });
+            let format_values = format_fields.iter().map(|idx| self.field_maybe_ref(*idx));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::defmt::Format for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        fn format(&self, fmt: ::certain_map::defmt::Formatter<'_>) {
+                            ::defmt::write!(fmt, #format_start);
+                            #(if let Some(value) = #format_values {
+                                ::defmt::write!(fmt, #format_names, value);
+                            })*
+                            ::defmt::write!(fmt, "}}");
+                        }
+                    }
+            });
+        }
+
+        // impl to_baggage and from_baggage for #hdr_ty
+        if self.fields_baggage.iter().any(Option::is_some) {
+            let baggage_fields: Vec<_> = self
+                .fields
+                .iter()
+                .zip(self.fields_baggage.iter())
+                .enumerate()
+                .filter_map(|(idx, (field, key))| key.as_ref().map(|key| (idx, field, key)))
+                .collect();
+            let baggage_values = baggage_fields
+                .iter()
+                .map(|(idx, ..)| self.field_maybe_ref(*idx));
+            let baggage_names: Vec<_> = baggage_fields
+                .iter()
+                .map(|(_, field, _)| field.ident.as_ref().unwrap())
+                .collect();
+            let baggage_types: Vec<_> = baggage_fields
+                .iter()
+                .map(|(_, field, _)| &field.ty)
+                .collect();
+            let baggage_keys: Vec<_> = baggage_fields.iter().map(|(_, _, key)| key).collect();
+            let filled_ty = hdr.ty((0..self.fields.len()).map(|idx| {
+                if self.fields_baggage[idx].is_some() {
+                    IdentOrTokens::from(occupied_m_type())
+                } else {
+                    IdentOrTokens::from(generic_type(idx))
+                }
+            }));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types, dead_code)]
+                    impl<#impl_lt #(#generic_types),*> #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        /// Export the occupied fields marked `#[baggage("key")]` as W3C baggage.
+                        pub fn to_baggage(&self) -> ::certain_map::otel::Baggage {
+                            let mut baggage = ::certain_map::otel::Baggage::new();
+                            #(if let Some(value) = #baggage_values {
+                                let _ = baggage.insert(#baggage_keys, ::std::string::ToString::to_string(value));
+                            })*
+                            baggage
+                        }
+
+                        /// Set all the fields marked `#[baggage("key")]` from the baggage. Fails if
+                        /// any key is missing or its value can not be parsed, giving the handler
+                        /// back without setting any field.
+                        pub fn from_baggage(
+                            self,
+                            baggage: &::certain_map::otel::Baggage,
+                        ) -> ::std::result::Result<#filled_ty, ::certain_map::otel::FromBaggageError<Self>> {
+                            // parse every field before setting any, so a failure keeps the handler
+                            #(let #baggage_names: #baggage_types = match ::certain_map::otel::parse_baggage(baggage, #baggage_keys) {
+                                ::std::result::Result::Ok(value) => value,
+                                ::std::result::Result::Err(error) => {
+                                    return ::std::result::Result::Err(::certain_map::otel::FromBaggageError {
+                                        handler: self,
+                                        error,
+                                    });
+                                }
+                            };)*
+                            let this = self;
+                            #(let this = ::certain_map::ParamSet::<#baggage_types>::param_set(this, #baggage_names);)*
+                            Ok(this)
+                        }
+                    }
+            });
+        }
+
+        // impl to_headers and from_headers for #hdr_ty
+        let header_fields: Vec<_> = (0..self.fields.len())
+            .filter(|idx| self.fields_header[*idx].is_some())
+            .collect();
+        if !header_fields.is_empty() {
+            let header_values = header_fields.iter().map(|idx| self.field_maybe_ref(*idx));
+            let header_names: Vec<_> = header_fields
+                .iter()
+                .map(|idx| self.fields[*idx].ident.as_ref().unwrap())
+                .collect();
+            let header_types: Vec<_> = header_fields
+                .iter()
+                .map(|idx| &self.fields[*idx].ty)
+                .collect();
+            let header_keys: Vec<_> = header_fields
+                .iter()
+                .map(|idx| self.fields_header[*idx].as_ref().unwrap())
+                .collect();
+            let filled_ty = hdr.ty((0..self.fields.len()).map(|idx| {
+                if self.fields_header[idx].is_some() {
+                    IdentOrTokens::from(occupied_m_type())
+                } else {
+                    IdentOrTokens::from(generic_type(idx))
+                }
+            }));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types, dead_code)]
+                    impl<#impl_lt #(#generic_types),*> #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        /// Export the occupied fields marked `#[header("name")]` as http headers.
+                        /// Fails if any value is not a valid header value.
+                        pub fn to_headers(
+                            &self,
+                        ) -> ::std::result::Result<::certain_map::http::HeaderMap, ::certain_map::http::HeaderError> {
+                            let mut headers = ::certain_map::http::HeaderMap::new();
+                            #(if let Some(value) = #header_values {
+                                ::certain_map::http::insert_header(&mut headers, #header_keys, value)?;
+                            })*
+                            Ok(headers)
+                        }
+
+                        /// Set all the fields marked `#[header("name")]` from the headers. Fails if
+                        /// any header is missing or its value can not be parsed.
+                        pub fn from_headers(
+                            self,
+                            headers: &::certain_map::http::HeaderMap,
+                        ) -> ::std::result::Result<#filled_ty, ::certain_map::http::HeaderError> {
+                            #(let #header_names: #header_types = ::certain_map::http::parse_header(headers, #header_keys)?;)*
+                            let this = self;
+                            #(let this = ::certain_map::ParamSet::<#header_types>::param_set(this, #header_names);)*
+                            Ok(this)
+                        }
+                    }
+            });
+        }
+
+        // impl to_wire and from_wire for #hdr_ty
+        let wire_fields: Vec<_> = (0..self.fields.len())
+            .filter(|idx| self.fields_wire[*idx].is_some())
+            .collect();
+        if !wire_fields.is_empty() {
+            let wire_names: Vec<_> = wire_fields
+                .iter()
+                .map(|idx| self.fields[*idx].ident.as_ref().unwrap())
+                .collect();
+            let wire_name_strs: Vec<_> = wire_names.iter().map(|name| name.to_string()).collect();
+            let wire_types: Vec<_> = wire_fields
+                .iter()
+                .map(|idx| &self.fields[*idx].ty)
+                .collect();
+            let wire_indexes: Vec<_> = wire_fields
+                .iter()
+                .map(|idx| proc_macro2::Literal::u64_unsuffixed(*idx as u64))
+                .collect();
+            let wire_values = wire_fields.iter().map(|idx| self.field_maybe_ref(*idx));
+            let (wire_encoders, wire_decoders): (Vec<_>, Vec<_>) = wire_fields
+                .iter()
+                .map(|idx| {
+                    let ty = &self.fields[*idx].ty;
+                    match &self.fields_wire[*idx].as_ref().unwrap().with {
+                        Some(with) => (quote!(#with::encode), quote!(#with::decode)),
+                        None => (
+                            quote!(<#ty as ::certain_map::wire::Wire>::encode),
+                            quote!(<#ty as ::certain_map::wire::Wire>::decode),
+                        ),
+                    }
+                })
+                .unzip();
+            let filled_ty = hdr.ty((0..self.fields.len()).map(|idx| {
+                if self.fields_wire[idx].is_some() {
+                    IdentOrTokens::from(occupied_m_type())
+                } else {
+                    IdentOrTokens::from(generic_type(idx))
+                }
+            }));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types, dead_code)]
+                    impl<#impl_lt #(#generic_types),*> #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        /// Encode the occupied fields marked `#[wire]`.
+                        pub fn to_wire(&self) -> ::std::vec::Vec<u8> {
+                            let mut buf = ::std::vec::Vec::new();
+                            self.encode_wire(&mut buf);
+                            buf
+                        }
+
+                        /// Append the encoding of the occupied fields marked `#[wire]` to `buf`.
+                        pub fn encode_wire(&self, buf: &mut ::std::vec::Vec<u8>) {
+                            #(if let Some(value) = #wire_values {
+                                ::certain_map::wire::write_record(buf, #wire_indexes, value, #wire_encoders);
+                            })*
+                        }
+
+                        /// Set all the fields marked `#[wire]` from the encoding. Fails if any
+                        /// field is missing or can not be decoded.
+                        pub fn from_wire(
+                            self,
+                            mut bytes: &[u8],
+                        ) -> ::std::result::Result<#filled_ty, ::certain_map::wire::WireError> {
+                            #(let mut #wire_names: ::std::option::Option<#wire_types> = ::std::option::Option::None;)*
+                            while !bytes.is_empty() {
+                                match ::certain_map::wire::read_record(&mut bytes)? {
+                                    #((#wire_indexes, value) => {
+                                        #wire_names = ::std::option::Option::Some(
+                                            #wire_decoders(value)
+                                                .ok_or(::certain_map::wire::WireError::Invalid(#wire_name_strs))?,
+                                        );
+                                    })*
+                                    _ => {}
+                                }
+                            }
+                            #(let #wire_names = #wire_names.ok_or(::certain_map::wire::WireError::Missing(#wire_name_strs))?;)*
+                            let this = self;
+                            #(let this = ::certain_map::ParamSet::<#wire_types>::param_set(this, #wire_names);)*
+                            Ok(this)
+                        }
+                    }
+            });
+        }
+
+        // impl fill_from and fill_from_env for #hdr_ty
+        let config_fields: Vec<_> = (0..self.fields.len())
+            .filter(|idx| self.fields_config[*idx].is_some())
+            .collect();
+        if !config_fields.is_empty() {
+            let config_names: Vec<_> = config_fields
+                .iter()
+                .map(|idx| self.fields[*idx].ident.as_ref().unwrap())
+                .collect();
+            let config_types: Vec<_> = config_fields
+                .iter()
+                .map(|idx| &self.fields[*idx].ty)
+                .collect();
+            let config_values = config_fields.iter().map(|idx| {
+                let config = self.fields_config[*idx].as_ref().unwrap();
+                let key = &config.key;
+                if config.from_str {
+                    quote!(::certain_map::config::parse_value(&values, #key)?)
+                } else {
+                    quote!(::certain_map::config::deserialize_value(&values, #key)?)
+                }
+            });
+            let filled_ty = hdr.ty((0..self.fields.len()).map(|idx| {
+                if self.fields_config[idx].is_some() {
+                    IdentOrTokens::from(occupied_m_type())
+                } else {
+                    IdentOrTokens::from(generic_type(idx))
+                }
+            }));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types, dead_code)]
+                    impl<#impl_lt #(#generic_types),*> #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        /// Set all the fields marked `#[config(key = "..")]` from the config
+                        /// source. Fails if any key is missing or its value is invalid.
+                        pub fn fill_from(
+                            self,
+                            source: &impl ::certain_map::config::Source,
+                        ) -> ::std::result::Result<#filled_ty, ::certain_map::config::ConfigError> {
+                            let values = ::certain_map::config::collect(source)?;
+                            #(let #config_names: #config_types = #config_values;)*
+                            let this = self;
+                            #(let this = ::certain_map::ParamSet::<#config_types>::param_set(this, #config_names);)*
+                            Ok(this)
+                        }
+
+                        /// Set all the fields marked `#[config(key = "..")]` from environment
+                        /// variables, the key `server.region` reads `SERVER__REGION`.
+                        pub fn fill_from_env(
+                            self,
+                        ) -> ::std::result::Result<#filled_ty, ::certain_map::config::ConfigError> {
+                            self.fill_from(&::certain_map::config::environment())
+                        }
+                    }
+            });
+        }
+
+        // impl to_graphql_data and from_graphql_data for #hdr_ty
+        let graphql_fields: Vec<_> = (0..self.fields.len())
+            .filter(|idx| self.fields_graphql[*idx])
+            .collect();
+        if !graphql_fields.is_empty() {
+            let graphql_values = graphql_fields.iter().map(|idx| self.field_maybe_ref(*idx));
+            let graphql_names: Vec<_> = graphql_fields
+                .iter()
+                .map(|idx| self.fields[*idx].ident.as_ref().unwrap())
+                .collect();
+            let graphql_types: Vec<_> = graphql_fields
+                .iter()
+                .map(|idx| &self.fields[*idx].ty)
+                .collect();
+            let filled_ty = hdr.ty((0..self.fields.len()).map(|idx| {
+                if self.fields_graphql[idx] {
+                    IdentOrTokens::from(occupied_m_type())
+                } else {
+                    IdentOrTokens::from(generic_type(idx))
+                }
+            }));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types, dead_code)]
+                    impl<#impl_lt #(#generic_types),*> #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        /// Insert clones of the occupied fields marked `#[graphql]` into the
+                        /// async-graphql data, where resolvers read them with `ctx.data()`.
+                        pub fn to_graphql_data(&self, data: &mut ::certain_map::graphql::Data) {
+                            #(if let Some(value) = #graphql_values {
+                                data.insert(::std::clone::Clone::clone(value));
+                            })*
+                        }
+
+                        /// Set all the fields marked `#[graphql]` from the async-graphql data,
+                        /// like the data provided by resolvers through a `DataSink`. Fails with
+                        /// the missing fields if any.
+                        pub fn from_graphql_data(
+                            self,
+                            data: &::certain_map::graphql::Data,
+                        ) -> ::std::result::Result<#filled_ty, ::certain_map::MissingParam> {
+                            let mut missing = ::std::vec::Vec::new();
+                            #(let #graphql_names = ::certain_map::graphql::data_value::<#graphql_types>(data);
+                            if #graphql_names.is_none() {
+                                missing.push(::std::stringify!(#graphql_names));
+                            })*
+                            match (#(#graphql_names,)*) {
+                                (#(Some(#graphql_names),)*) => {
+                                    let this = self;
+                                    #(let this = ::certain_map::ParamSet::<#graphql_types>::param_set(this, #graphql_names);)*
+                                    Ok(this)
+                                }
+                                _ => Err(::certain_map::MissingParam { missing }),
+                            }
+                        }
+                    }
+            });
+        }
+
+        // impl UnwindSafe for #hdr_ty: the handler owns the fields like a box, and a new handler
+        // of the store after unwinding starts empty, so only the fields have to be unwind safe
+        // whatever the storage handle is. Persistent fields outlive a handler dropped by a panic
+        // and resume() observes them, so those handlers keep the auto trait of the handle.
+        if !self.fields_persistent.contains(&true) {
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::std::panic::UnwindSafe for #hdr_ty
+                    where
+                        <_CMS as ::std::ops::Deref>::Target: ::std::panic::UnwindSafe,
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                    }
+            });
+        }
+
+        // impl Drop for #hdr_ty, Copy fields have nothing to drop
+        if !derive_copy || self.secure_clear {
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> Drop for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        fn drop(&mut self) {
+                            #drop_all
+                            #clear_all
+                        }
+                    }
+            });
+        }
+
+        // impl ParamRef<T>/ParamMut<T>/ParamTake<T> for #hdr_ty
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let generic_type = generic_type(idx);
+            let (track_caller, record) = self.provenance_tokens(idx);
+            let generic_types_rest1 = IgnoreIter::new(generic_types.iter(), idx);
+            let generic_types_rest2 = IgnoreIter::new(generic_types.iter(), idx);
+            let generic_types_rest3 = IgnoreIter::new(generic_types.iter(), idx);
+            let vacancy = IdentOrTokens::from(vacancy_type());
+            let transformed_vacancy = hdr.ty(ReplaceIter::new(generic_types.iter(), idx, &vacancy));
+            let clear = self.clear_slots_tokens(quote!(self.inner), &[idx]);
+            let field_ref = self.field_ref(idx, &generic_type);
+            let field_mut = self.field_mut(idx, &generic_type);
+            let field_take = self.field_take(idx, &generic_type);
+            // fields with a fallback are readable in every state
+            let (ref_bound, field_ref) = match &self.fields_fallback[idx] {
+                Some(fallback) => {
+                    let field_maybe_ref = self.field_maybe_ref(idx);
+                    (
+                        quote!(::certain_map::MaybeAvailable),
+                        quote! {
+                            match #field_maybe_ref {
+                                ::std::option::Option::Some(value) => value,
+                                ::std::option::Option::None => {
+                                    static FALLBACK: ::std::sync::OnceLock<#ty> = ::std::sync::OnceLock::new();
+                                    FALLBACK.get_or_init(|| #fallback)
+                                }
+                            }
+                        },
+                    )
+                }
+                None => (quote!(::certain_map::Available), field_ref),
+            };
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::ParamRef<#ty> for #hdr_ty
+                    where
+                        #generic_type: #ref_bound,
+                        #(#generic_types_rest1: ::certain_map::MaybeAvailable,)*
+                    {
+                        #[inline]
+                        fn param_ref(&self) -> &#ty {
+                            #field_ref
+                        }
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::ParamMut<#ty> for #hdr_ty
+                    where
+                        #generic_type: ::certain_map::Available,
+                        #(#generic_types_rest2: ::certain_map::MaybeAvailable,)*
+                    {
+                        #[inline]
+                        fn param_mut(&mut self) -> &mut #ty {
+                            #field_mut
+                        }
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::ParamTake<#ty> for #hdr_ty
+                    where
+                        #generic_type: ::certain_map::Available,
+                        #(#generic_types_rest3: ::certain_map::MaybeAvailable,)*
+                    {
+                        type Transformed = #transformed_vacancy;
+                        #[inline]
+                        #track_caller
+                        #[allow(unused_mut)]
+                        fn param_take(mut self) -> (Self::Transformed, #ty) {
+                            let item = #field_take;
+                            #record
+                            #clear
+                            (unsafe { self.transition() }, item)
+                        }
+                    }
+            });
+        }
+
+        // impl ParamMaybeRef<T>/ParamMaybeMut<T>/ParamSet<T>/ParamRemove<T> for #hdr_ty
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let slot = self.slot_place(quote!(self.inner), idx, true);
+            let slot_next = self.slot_place(quote!(next.inner), idx, true);
+            let generic_type = generic_type(idx);
+            let (track_caller, record) = self.provenance_tokens(idx);
+
+            let occupied = IdentOrTokens::from(occupied_m_type());
+            let vacancy = IdentOrTokens::from(vacancy_type());
+            let transformed_vacancy = hdr.ty(ReplaceIter::new(generic_types.iter(), idx, &vacancy));
+            let clear = self.clear_slots_tokens(quote!(self.inner), &[idx]);
+            let clear_next = self.clear_slots_tokens(quote!(next.inner), &[idx]);
+            let (set_free, set_slots) = self.set_slots(idx, &generic_types, |_| occupied_m_type());
+            let set_ty = hdr.ty(&set_slots);
+            let set_transformed = hdr.ty(ReplaceIter::new(set_slots.iter(), idx, &occupied));
+            let field_maybe_ref = self.count_vacancy_tokens(idx, self.field_maybe_ref(idx));
+            let field_maybe_mut = self.count_vacancy_tokens(idx, self.field_maybe_mut(idx));
+            let slot_item = self.to_slot_value(idx, quote!(item));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::ParamMaybeRef<#ty> for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        #[inline]
+                        fn param_maybe_ref(&self) -> Option<&#ty> {
+                            #field_maybe_ref
+                        }
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::ParamMaybeMut<#ty> for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        #[inline]
+                        fn param_maybe_mut(&mut self) -> Option<&mut #ty> {
+                            #field_maybe_mut
+                        }
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#set_free),*> ::certain_map::ParamSet<#ty> for #set_ty
+                    where
+                        #(#set_free: ::certain_map::MaybeAvailable,)*
+                    {
+                        type Transformed = #set_transformed;
+                        #[inline]
+                        #track_caller
+                        #[allow(unused_mut)]
+                        fn param_set(mut self, item: #ty) -> Self::Transformed {
+                            #record
+                            unsafe {
+                                #generic_type::do_set(&mut #slot, #slot_item);
+                                self.transition()
+                            }
+                        }
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::ParamRemove<#ty> for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        type Transformed = #transformed_vacancy;
+                        #[inline]
+                        #track_caller
+                        #[allow(unused_mut)]
+                        fn param_remove(mut self) -> Self::Transformed {
+                            #record
+                            unsafe {
+                                let mut next: Self::Transformed = self.transition();
+                                #generic_type::do_drop(&mut #slot_next);
+                                #clear_next
+                                next
+                            }
+                        }
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::ParamDiscard<#ty> for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        type Transformed = #transformed_vacancy;
+                        #[inline]
+                        #track_caller
+                        fn param_discard(self) -> (Self::Transformed, bool) {
+                            let occupied = <#generic_type as ::certain_map::MaybeAvailable>::OCCUPIED;
+                            (::certain_map::ParamRemove::<#ty>::param_remove(self), occupied)
+                        }
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::ParamForget<#ty> for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        type Transformed = #transformed_vacancy;
+                        #[inline]
+                        #[allow(unused_mut)]
+                        fn param_forget(mut self) -> Self::Transformed {
+                            #clear
+                            unsafe {
+                                self.transition()
+                            }
+                        }
+                    }
+            });
+        }
+
+        // impl ParamVacant<T> for #hdr_ty
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let generic_types_rest1 = IgnoreIter::new(generic_types.iter(), idx);
+            let generic_types_rest2 = IgnoreIter::new(generic_types.iter(), idx);
+            let vacancy = IdentOrTokens::from(vacancy_type());
+            let hdr_vacancy = hdr.ty(ReplaceIter::new(generic_types.iter(), idx, &vacancy));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types_rest1),*> ::certain_map::ParamVacant<#ty> for #hdr_vacancy
+                    where
+                        #(#generic_types_rest2: ::certain_map::MaybeAvailable,)*
+                    {
+                    }
+            });
+        }
+
+        // impl ParamState<T> for #hdr_ty
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let generic_type = generic_type(idx);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::ParamState<#ty> for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        type State = #generic_type;
+                    }
+            });
+        }
+
+        // impl Flag<T> and FlagOf<Store> for fields with #[flag]
+        for (idx, field) in self.fields.iter().enumerate() {
+            if !self.fields_flag[idx] {
+                continue;
+            }
+            let ty = &field.ty;
+            let store = hdr.store;
+            let generic_type = generic_type(idx);
+            let generic_types_rest = IgnoreIter::new(generic_types.iter(), idx);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl ::certain_map::FlagOf<#store> for #ty {
+                        const FLAG: Self = #ty;
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::Flag<#ty> for #hdr_ty
+                    where
+                        #generic_type: ::certain_map::Available,
+                        #(#generic_types_rest: ::certain_map::MaybeAvailable,)*
+                    {
+                    }
+            });
+        }
+        // impl ParamEq<T> for fields with #[ensure(PartialEq)]
+        for (idx, field) in self.fields.iter().enumerate() {
+            if !self.field_ensures(idx, "PartialEq") {
+                continue;
+            }
+            let ty = &field.ty;
+            let generic_type = generic_type(idx);
+            let generic_types_rest = IgnoreIter::new(generic_types.iter(), idx);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::ParamEq<#ty> for #hdr_ty
+                    where
+                        #generic_type: ::certain_map::Available,
+                        #(#generic_types_rest: ::certain_map::MaybeAvailable,)*
+                    {
+                    }
+            });
+        }
+        if self.fields_flag.iter().any(|flag| *flag) {
+            let store = hdr.store;
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types, dead_code)]
+                    impl<#impl_lt #(#generic_types),*> #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        /// Set the `#[flag]` field of type `T`.
+                        #[inline]
+                        pub fn set_flag<T: ::certain_map::FlagOf<#store>>(
+                            self,
+                        ) -> <Self as ::certain_map::ParamSet<T>>::Transformed
+                        where
+                            Self: ::certain_map::ParamSet<T>,
+                        {
+                            ::certain_map::ParamSet::<T>::param_set(self, T::FLAG)
+                        }
+
+                        /// Whether the `#[flag]` field of type `T` is set.
+                        #[inline]
+                        pub fn has_flag<T: ::certain_map::FlagOf<#store>>(&self) -> bool
+                        where
+                            Self: ::certain_map::ParamMaybeRef<T>,
+                        {
+                            ::certain_map::ParamMaybeRef::<T>::param_maybe_ref(self).is_some()
+                        }
+                    }
+            });
+        }
+
+        // impl ParamPush<Item> for fields with #[push]
+        for (idx, field) in self.fields.iter().enumerate() {
+            let item = match &self.fields_push[idx] {
+                Some(item) => item,
+                None => continue,
+            };
+            let ty = &field.ty;
+            let generic_types_rest1 = IgnoreIter::new(generic_types.iter(), idx);
+            let generic_types_rest2 = IgnoreIter::new(generic_types.iter(), idx);
+            let generic_types_rest3 = IgnoreIter::new(generic_types.iter(), idx);
+            let generic_types_rest4 = IgnoreIter::new(generic_types.iter(), idx);
+            let occupied = IdentOrTokens::from(occupied_m_type());
+            let vacancy = IdentOrTokens::from(vacancy_type());
+            let hdr_occupied = hdr.ty(ReplaceIter::new(generic_types.iter(), idx, &occupied));
+            let hdr_vacancy = hdr.ty(ReplaceIter::new(generic_types.iter(), idx, &vacancy));
+            let (track_caller, record) = self.provenance_tokens(idx);
+            let collection = self.field_mut(
+                idx,
+                quote!(<::certain_map::OccupiedM as ::certain_map::Available>),
+            );
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types_rest1),*> ::certain_map::ParamPush<#item> for #hdr_vacancy
+                    where
+                        #(#generic_types_rest2: ::certain_map::MaybeAvailable,)*
+                        Self: ::certain_map::ParamSet<#ty, Transformed = #hdr_occupied>,
+                    {
+                        type Transformed = #hdr_occupied;
+                        #[inline]
+                        #track_caller
+                        fn param_push(self, item: #item) -> Self::Transformed {
+                            let mut collection = <#ty as ::std::default::Default>::default();
+                            ::std::iter::Extend::extend(&mut collection, ::std::iter::once(item));
+                            ::certain_map::ParamSet::<#ty>::param_set(self, collection)
+                        }
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types_rest3),*> ::certain_map::ParamPush<#item> for #hdr_occupied
+                    where
+                        #(#generic_types_rest4: ::certain_map::MaybeAvailable,)*
+                    {
+                        type Transformed = Self;
+                        #[inline]
+                        #track_caller
+                        #[allow(unused_mut)]
+                        fn param_push(mut self, item: #item) -> Self::Transformed {
+                            #record
+                            let collection = #collection;
+                            ::std::iter::Extend::extend(collection, ::std::iter::once(item));
+                            self
+                        }
+                    }
+            });
+        }
+
+        // impl ParamSet<Arc<T>> and Param<Option<Arc<T>>> for fields with #[weak], or the Rc
+        // versions for `rc::Weak` fields
+        for (idx, field) in self.fields.iter().enumerate() {
+            let (strategy, pointee) = match &self.fields_weak[idx] {
+                Some((strategy, pointee)) => (*strategy, pointee),
+                None => continue,
+            };
+            let (pointer, weak) = (strategy.pointer(), strategy.weak());
+            let ty = &field.ty;
+            let (track_caller, _) = self.provenance_tokens(idx);
+            let field_maybe_ref = self.field_maybe_ref(idx);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::ParamSet<#pointer<#pointee>> for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                        Self: ::certain_map::ParamSet<#ty>,
+                    {
+                        type Transformed = <Self as ::certain_map::ParamSet<#ty>>::Transformed;
+                        #[inline]
+                        #track_caller
+                        fn param_set(self, item: #pointer<#pointee>) -> Self::Transformed {
+                            ::certain_map::ParamSet::<#ty>::param_set(self, #pointer::downgrade(&item))
+                        }
+                    }
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_lt #(#generic_types),*> ::certain_map::Param<Option<#pointer<#pointee>>> for #hdr_ty
+                    where
+                        #(#generic_types: ::certain_map::MaybeAvailable,)*
+                    {
+                        #[inline]
+                        fn param(&self) -> Option<#pointer<#pointee>> {
+                            ::std::option::Option::and_then(#field_maybe_ref, #weak::upgrade)
+                        }
+                    }
+            });
+        }
+
+        // impl Param<T> and Param<Option<T>> if #[ensure(Clone)] or derive_clone
+        for (idx, field) in self.fields.iter().enumerate() {
+            if derive_clone || self.field_ensures(idx, "Clone") {
+                let ty = &field.ty;
+                let generic_type = generic_type(idx);
+                let generic_types_rest = IgnoreIter::new(generic_types.iter(), idx);
+                let (read_bound, field_read) = if self.fields_fallback[idx].is_some() {
+                    (
+                        quote!(::certain_map::MaybeAvailable),
+                        quote!(::std::clone::Clone::clone(::certain_map::ParamRef::<#ty>::param_ref(self))),
+                    )
+                } else {
+                    (
+                        quote!(::certain_map::Available),
+                        self.field_read(idx, &generic_type),
+                    )
+                };
+                let field_maybe_ref = self.field_maybe_ref(idx);
+                tokens.extend(quote_spanned! {
+                    self.span =>
+                        #[allow(non_camel_case_types)]
+                        impl<#impl_lt #(#generic_types),*> ::certain_map::Param<#ty> for #hdr_ty
+                        where
+                            #generic_type: #read_bound,
+                            #(#generic_types_rest: ::certain_map::MaybeAvailable,)*
+                        {
+                            #[inline]
+                            fn param(&self) -> #ty {
+                                #field_read
+                            }
+                        }
+                        #[allow(non_camel_case_types)]
+                        impl<#impl_lt #(#generic_types),*> ::certain_map::Param<Option<#ty>> for #hdr_ty
+                        where
+                            #(#generic_types: ::certain_map::MaybeAvailable,)*
+                        {
+                            #[inline]
+                            fn param(&self) -> Option<#ty> {
+                                #[allow(clippy::clone_on_copy)]
+                                ::std::option::Option::<&#ty>::cloned(#field_maybe_ref)
+                            }
+                        }
+                });
+            }
+        }
+
+        // impl Param<(A, B, ..)> for the cloneable fields
+        let cloneable = (0..self.fields.len())
+            .filter(|&idx| derive_clone || self.field_ensures(idx, "Clone"))
+            .count();
+        tokens.extend(self.tuple_param_tokens(
+            quote!(#impl_lt #(#generic_types,)*),
+            hdr_ty.clone(),
+            quote!(#(#generic_types: ::certain_map::MaybeAvailable,)*),
+            cloneable,
+        ));
+    }
+
+    fn to_unfilled_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let mut attrs = self.attrs.clone();
+        let vis = &self.vis;
+        let ident = &self.ident;
+        let generic_types: Vec<_> = (0..self.fields.len())
+            .map(generic_type)
+            .map(IdentOrTokens::from)
+            .collect();
+        let names: Vec<_> = self
+            .fields
+            .iter()
+            .map(|f| f.ident.as_ref().unwrap())
+            .collect();
+
+        // struct definition
+        if let Some((empty_idx, empty_ident)) = Self::find_path_attr(&attrs, "empty") {
+            attrs.remove(empty_idx);
+            let vacancy_types = repeat_tokens(quote!(::certain_map::Vacancy), self.fields.len());
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #vis type #empty_ident = #ident<#(#vacancy_types),*>;
+            });
+        }
+
+        if let Some((full_idx, full_ident)) = Self::find_path_attr(&attrs, "full") {
+            attrs.remove(full_idx);
+            let occupied_types = self.fields.iter().map(|f| occupied_type(&f.ty));
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #vis type #full_ident = #ident<#(#occupied_types),*>;
+            });
+        }
+
+        // derive(Copy) is kept on the struct, check the fields for a clear error
+        self.copy_assert_tokens(tokens);
+
+        // impl occupied_names
+        let types: Vec<_> = self.fields.iter().map(|f| &f.ty).collect();
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(non_camel_case_types, dead_code)]
+                impl<#(#generic_types),*> #ident<#(#generic_types),*>
+                where
+                    #(Self: ::certain_map::ParamMaybeRef<#types>,)*
+                {
+                    /// The names of the occupied fields in declaration order.
+                    pub fn occupied_names(&self) -> impl ::std::iter::Iterator<Item = &'static str> {
+                        [#((
+                            ::std::stringify!(#names),
+                            ::certain_map::ParamMaybeRef::<#types>::param_maybe_ref(self).is_some(),
+                        )),*]
+                            .into_iter()
+                            .filter_map(|(name, occupied)| if occupied { Some(name) } else { None })
+                    }
+                }
+
+                #[allow(non_camel_case_types, dead_code)]
+                impl<#(#generic_types),*> #ident<#(#generic_types),*> {
+                    /// Borrow the map as a context for a sub-call, which can read and mutate the
+                    /// fields but not change the state.
+                    #[inline]
+                    pub fn reborrow(&mut self) -> ::certain_map::Reborrow<'_, Self> {
+                        ::certain_map::Reborrow::new(self)
+                    }
+                }
+        });
+
+        if self.method_prefix.is_some() {
+            let prefixed_methods = self.prefixed_methods_tokens();
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    #[allow(non_camel_case_types, dead_code)]
+                    impl<#(#generic_types),*> #ident<#(#generic_types),*> {
+                        #prefixed_methods
+                    }
+            });
+        }
+
+        if let Some((maybe_view_idx, maybe_view_ident)) = Self::find_path_attr(&attrs, "maybe_view")
+        {
+            attrs.remove(maybe_view_idx);
+            self.maybe_view_tokens(
+                tokens,
+                &maybe_view_ident,
+                &[(
+                    quote!(#(#generic_types,)*),
+                    quote!(#ident<#(#generic_types),*>),
+                )],
+                quote!(),
+            );
+        }
+
+        if let Some((ref_view_idx, ref_view_ident)) = Self::find_path_attr(&attrs, "ref_view") {
+            attrs.remove(ref_view_idx);
+            let occupied_types = self.fields.iter().map(|f| occupied_type(&f.ty));
+            let refs: Vec<_> = self
+                .fields
+                .iter()
+                .map(|f| {
+                    let name = f.ident.as_ref().unwrap();
+                    quote!(&self.#name.0)
+                })
+                .collect();
+            self.ref_view_tokens(
+                tokens,
+                &ref_view_ident,
+                &[(quote!(), quote!(#ident<#(#occupied_types),*>))],
+                &refs,
+            );
+        }
+
+        if let Some((view_idx, view_ident)) = Self::find_path_attr(&attrs, "view") {
+            attrs.remove(view_idx);
+            self.view_trait_tokens(tokens, &view_ident);
+            if self.has_derive("Clone") {
+                let types = self.fields.iter().map(|f| &f.ty);
+                let occupied_types = self.fields.iter().map(|f| occupied_type(&f.ty));
+                tokens.extend(quote_spanned! {
+                    self.span =>
+                        impl #ident<#(#occupied_types),*> {
+                            /// Build a full map by cloning the fields from any context which has
+                            /// all of them.
+                            pub fn from_view<CX: #view_ident + ?Sized>(cx: &CX) -> Self {
+                                Self {
+                                    #(#names: ::certain_map::Occupied(::std::clone::Clone::clone(
+                                        ::certain_map::ParamRef::<#types>::param_ref(cx),
+                                    )),)*
+                                }
+                            }
+                        }
+                });
+            }
+        }
+
+        // the transforms return the map with the fields moved into it
+        let must_use = if attrs.iter().any(|attr| attr.path().is_ident("must_use")) {
+            quote!()
+        } else {
+            quote!(#[must_use = "the fields are moved into the returned map, dropping it drops them"])
+        };
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #(#attrs)*
+                #must_use
+                #vis struct #ident<#(#generic_types = ::certain_map::Vacancy),*> {
+                    #(#names: #generic_types, )*
+                }
+        });
+
+        // impl new and Default
+        let fields_const = self.fields_const_tokens();
+        let vacancy_stats = self.vacancy_stats_tokens();
+        let vacancy_types1 = repeat_tokens(quote!(::certain_map::Vacancy), self.fields.len());
+        let vacancy_types2 = repeat_tokens(quote!(::certain_map::Vacancy), self.fields.len());
+        let vacancy_values = repeat_tokens(quote!(::certain_map::Vacancy), self.fields.len());
+        tokens.extend(quote_spanned! {
+            self.span =>
+                impl ::std::default::Default for #ident<#(#vacancy_types1),*> {
+                    #[inline]
+                    fn default() -> Self {
+                        Self::new()
+                    }
+                }
+                impl #ident<#(#vacancy_types2),*> {
+                    #fields_const
+                    #vacancy_stats
+
+                    pub const fn new() -> Self {
+                        Self {
+                            #(#names: #vacancy_values),*
+                        }
+                    }
+                }
+        });
+
+        // impl Reset
+        let vacancy_types = repeat_tokens(vacancy_type(), self.fields.len());
+        tokens.extend(quote_spanned! {
+            self.span =>
+                impl<#(#generic_types),*> ::certain_map::Reset for #ident<#(#generic_types),*> {
+                    type Empty = #ident<#(#vacancy_types),*>;
+                    #[inline]
+                    fn reset(self) -> Self::Empty {
+                        #ident::new()
+                    }
+                }
+        });
+
+        // impl ParamRef<T>
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let name = field.ident.as_ref().unwrap();
+            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
+            let occupied = IdentOrTokens::from(occupied_type(ty));
+            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl<#(#generic_types_ignored),*> ::certain_map::ParamRef<#ty> for #ident<#(#generic_types_replaced),*> {
+                        #[inline]
+                        fn param_ref(&self) -> &#ty {
+                            &self.#name.0
+                        }
+                    }
+            });
+        }
+
+        // impl ParamMaybeRef<T> for occupied
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let name = field.ident.as_ref().unwrap();
+            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
+            let occupied = IdentOrTokens::from(occupied_type(ty));
+            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl<#(#generic_types_ignored),*> ::certain_map::ParamMaybeRef<#ty> for #ident<#(#generic_types_replaced),*> {
+                        #[inline]
+                        fn param_maybe_ref(&self) -> Option<&#ty> {
+                            Some(&self.#name.0)
+                        }
+                    }
+            });
+        }
+
+        // impl ParamMaybeRef<T> for vacancy
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let none = self.count_vacancy_tokens(idx, quote!(None));
+            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
+            let vacancy = IdentOrTokens::from(vacancy_type());
+            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl<#(#generic_types_ignored),*> ::certain_map::ParamMaybeRef<#ty> for #ident<#(#generic_types_replaced),*> {
+                        #[inline]
+                        fn param_maybe_ref(&self) -> Option<&#ty> {
+                            #none
+                        }
+                    }
+            });
+        }
+
+        // impl ParamMut
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let name = field.ident.as_ref().unwrap();
+            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
+            let occupied = IdentOrTokens::from(occupied_type(ty));
+            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl<#(#generic_types_ignored),*> ::certain_map::ParamMut<#ty> for #ident<#(#generic_types_replaced),*> {
+                        #[inline]
+                        fn param_mut(&mut self) -> &mut #ty {
+                            &mut self.#name.0
+                        }
+                    }
+            });
+        }
+
+        // impl ParamMaybeMut<T> for occupied
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let name = field.ident.as_ref().unwrap();
+            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
+            let occupied = IdentOrTokens::from(occupied_type(ty));
+            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl<#(#generic_types_ignored),*> ::certain_map::ParamMaybeMut<#ty> for #ident<#(#generic_types_replaced),*> {
+                        #[inline]
+                        fn param_maybe_mut(&mut self) -> Option<&mut #ty> {
+                            Some(&mut self.#name.0)
+                        }
+                    }
+            });
+        }
+
+        // impl ParamMaybeMut<T> for vacancy
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let none = self.count_vacancy_tokens(idx, quote!(None));
+            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
+            let vacancy = IdentOrTokens::from(vacancy_type());
+            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl<#(#generic_types_ignored),*> ::certain_map::ParamMaybeMut<#ty> for #ident<#(#generic_types_replaced),*> {
+                        #[inline]
+                        fn param_maybe_mut(&mut self) -> Option<&mut #ty> {
+                            #none
+                        }
+                    }
+            });
+        }
+
+        // impl Param<T> and Param<Option<T>> if #[ensure(Clone)]
+        for (idx, field) in self.fields.iter().enumerate() {
+            if self.field_ensures(idx, "Clone") {
+                let ty = &field.ty;
+                let name = field.ident.as_ref().unwrap();
+                let occupied = IdentOrTokens::from(occupied_type(ty));
+                let vacancy = IdentOrTokens::from(vacancy_type());
+
+                let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
+                let generic_types_occupied = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+
+                let generic_types_ignored2 = IgnoreIter::new(generic_types.iter(), idx);
+                let generic_types_occupied2 =
+                    ReplaceIter::new(generic_types.iter(), idx, &occupied);
+
+                let generic_types_ignored3 = IgnoreIter::new(generic_types.iter(), idx);
+                let generic_types_vacancy = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
+                tokens.extend(quote_spanned! {
+                self.span =>
+                    impl<#(#generic_types_ignored),*> ::certain_map::Param<#ty> for #ident<#(#generic_types_occupied),*> {
+                        #[inline]
+                        fn param(&self) -> #ty {
+                            #[allow(clippy::clone_on_copy)]
+                            self.#name.0.clone()
+                        }
+                    }
+                    impl<#(#generic_types_ignored2),*> ::certain_map::Param<Option<#ty>> for #ident<#(#generic_types_occupied2),*> {
+                        #[inline]
+                        fn param(&self) -> Option<#ty> {
+                            #[allow(clippy::clone_on_copy)]
+                            Some(self.#name.0.clone())
+                        }
+                    }
+                    impl<#(#generic_types_ignored3),*> ::certain_map::Param<Option<#ty>> for #ident<#(#generic_types_vacancy),*> {
+                        #[inline]
+                        fn param(&self) -> Option<#ty> {
+                            None
+                        }
+                    }
+                });
+            }
+        }
+
+        // impl ParamEq<T> if #[ensure(PartialEq)]
+        for (idx, field) in self.fields.iter().enumerate() {
+            if self.field_ensures(idx, "PartialEq") {
+                let ty = &field.ty;
+                let occupied = IdentOrTokens::from(occupied_type(ty));
+                let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
+                let generic_types_occupied = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+                tokens.extend(quote_spanned! {
+                    self.span =>
+                    impl<#(#generic_types_ignored),*> ::certain_map::ParamEq<#ty> for #ident<#(#generic_types_occupied),*> {}
+                });
+            }
+        }
+
+        // impl ParamVacant
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let vacancy = IdentOrTokens::from(vacancy_type());
+            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
+            let generic_types_vacancy = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                impl<#(#generic_types_ignored),*> ::certain_map::ParamVacant<#ty> for #ident<#(#generic_types_vacancy),*> {}
+            });
+        }
+
+        // impl ParamSet
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let name = field.ident.as_ref().unwrap();
+            let occupied = IdentOrTokens::from(occupied_type(ty));
+            let (set_free, set_slots) =
+                self.set_slots(idx, &generic_types, |i| occupied_type(&self.fields[i].ty));
+            let generic_types_replaced = ReplaceIter::new(set_slots.iter(), idx, &occupied);
+            let direct_assign = quote!(#name: ::certain_map::Occupied(item));
+            let assignations = ReplaceIter::new(
+                names.iter().map(|&name| quote!(#name: self.#name)),
+                idx,
+                direct_assign,
+            );
+            tokens.extend(quote_spanned! {
+                self.span =>
+                impl<#(#set_free),*> ::certain_map::ParamSet<#ty> for #ident<#(#set_slots),*> {
+                    type Transformed = #ident<#(#generic_types_replaced),*>;
+
+                    #[inline]
+                    fn param_set(self, item: #ty) -> Self::Transformed {
+                        #ident {
+                            #(#assignations),*
+                        }
+                    }
                 }
-                impl ::certain_map::Handler for #ident {
-                    type Hdr<'a> = #handler_ident<'a, #(#vacancy_types2),*>
-                    where
-                        Self: 'a;
+            });
+        }
+
+        // impl ParamRemove
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let name = field.ident.as_ref().unwrap();
+            let vacancy = IdentOrTokens::from(vacancy_type());
+            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
+            let generic_types_replaced2 = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
+            let direct_assign = quote!(#name: ::certain_map::Vacancy);
+            let assignations = ReplaceIter::new(
+                names.iter().map(|&name| quote!(#name: self.#name)),
+                idx,
+                direct_assign.clone(),
+            );
+            let assignations2 = ReplaceIter::new(
+                names.iter().map(|&name| quote!(#name: self.#name)),
+                idx,
+                direct_assign,
+            );
+            tokens.extend(quote_spanned! {
+                self.span =>
+                impl<#(#generic_types),*> ::certain_map::ParamRemove<#ty> for #ident<#(#generic_types),*> {
+                    type Transformed = #ident<#(#generic_types_replaced),*>;
+
                     #[inline]
-                    fn handler(&mut self) -> Self::Hdr<'_> {
-                        self.handler()
+                    fn param_remove(self) -> Self::Transformed {
+                        #ident {
+                            #(#assignations),*
+                        }
                     }
                 }
-                impl ::std::default::Default for #ident {
+                impl<#(#generic_types),*> ::certain_map::ParamForget<#ty> for #ident<#(#generic_types),*> {
+                    type Transformed = #ident<#(#generic_types_replaced2),*>;
+
                     #[inline]
-                    fn default() -> Self {
-                        Self::new()
+                    fn param_forget(self) -> Self::Transformed {
+                        let transformed = #ident {
+                            #(#assignations2),*
+                        };
+                        ::std::mem::forget(self.#name);
+                        transformed
+                    }
+                }
+            });
+        }
+
+        // impl ParamDiscard
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let occupied = IdentOrTokens::from(occupied_type(ty));
+            let vacancy = IdentOrTokens::from(vacancy_type());
+            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
+            let generic_types_occupied = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+            let generic_types_ignored2 = IgnoreIter::new(generic_types.iter(), idx);
+            let generic_types_vacancy = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
+            let generic_types_vacancy2 = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                impl<#(#generic_types_ignored),*> ::certain_map::ParamDiscard<#ty> for #ident<#(#generic_types_occupied),*> {
+                    type Transformed = <Self as ::certain_map::ParamRemove<#ty>>::Transformed;
+
+                    #[inline]
+                    fn param_discard(self) -> (Self::Transformed, bool) {
+                        (::certain_map::ParamRemove::<#ty>::param_remove(self), true)
+                    }
+                }
+                impl<#(#generic_types_ignored2),*> ::certain_map::ParamDiscard<#ty> for #ident<#(#generic_types_vacancy),*> {
+                    type Transformed = #ident<#(#generic_types_vacancy2),*>;
+
+                    #[inline]
+                    fn param_discard(self) -> (Self::Transformed, bool) {
+                        (self, false)
+                    }
+                }
+            });
+        }
+
+        // impl ParamTake
+        for (idx, field) in self.fields.iter().enumerate() {
+            let ty = &field.ty;
+            let name = field.ident.as_ref().unwrap();
+            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
+            let occupied = IdentOrTokens::from(occupied_type(ty));
+            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+
+            let vacancy = IdentOrTokens::from(vacancy_type());
+            let generic_types_replaced_transformed =
+                ReplaceIter::new(generic_types.iter(), idx, &vacancy);
+            let direct_assign = quote!(#name: ::certain_map::Vacancy);
+            let assignations = ReplaceIter::new(
+                names.iter().map(|&name| quote!(#name: self.#name)),
+                idx,
+                direct_assign,
+            );
+            let removed_name = names[idx];
+            let removed = quote!(self.#removed_name);
+            tokens.extend(quote_spanned! {
+                self.span =>
+                impl<#(#generic_types_ignored),*> ::certain_map::ParamTake<#ty> for #ident<#(#generic_types_replaced),*> {
+                    type Transformed = #ident<#(#generic_types_replaced_transformed),*>;
+
+                    #[inline]
+                    fn param_take(self) -> (Self::Transformed, #ty) {
+                        let after_remove = #ident {
+                            #(#assignations),*
+                        };
+                        (after_remove, #removed.0)
                     }
                 }
+            });
+        }
+
+        // impl Param<(A, B, ..)> for the fields with #[ensure(Clone)]
+        let cloneable = (0..self.fields.len())
+            .filter(|&idx| self.field_ensures(idx, "Clone"))
+            .count();
+        tokens.extend(self.tuple_param_tokens(
+            quote!(#(#generic_types,)*),
+            quote!(#ident<#(#generic_types),*>),
+            quote!(),
+            cloneable,
+        ));
+    }
+
+    // A trait implemented by every context which has all the fields of the map, so any larger map
+    // structurally satisfying this one can be accepted.
+    fn view_trait_tokens(&self, tokens: &mut proc_macro2::TokenStream, view_ident: &Ident) {
+        let vis = &self.vis;
+        let types: Vec<_> = self.fields.iter().map(|f| &f.ty).collect();
+        let doc = format!("Contexts which have all the fields of [`{}`].", self.ident);
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[doc = #doc]
+                #vis trait #view_ident: #(::certain_map::ParamRef<#types> +)* {}
+                impl<CX: #(::certain_map::ParamRef<#types> +)* ?Sized> #view_ident for CX {}
         });
+    }
 
-        // impl #state_ident
+    // A struct of shared references to all the fields, returned by `ref_view()` of the full
+    // types in `full_tys`. `refs` are the expressions borrowing each field from `self`.
+    fn ref_view_tokens(
+        &self,
+        tokens: &mut proc_macro2::TokenStream,
+        ref_view_ident: &Ident,
+        full_tys: &[(proc_macro2::TokenStream, proc_macro2::TokenStream)],
+        refs: &[proc_macro2::TokenStream],
+    ) {
+        let vis = &self.vis;
+        let names: Vec<_> = self
+            .fields
+            .iter()
+            .map(|f| f.ident.as_ref().unwrap())
+            .collect();
+        let types = self.fields.iter().map(|f| &f.ty);
+        let doc = format!("References to all the fields of a full [`{}`].", self.ident);
         tokens.extend(quote_spanned! {
             self.span =>
-                #[allow(non_camel_case_types)]
-                impl<#(#generic_types),*> #state_ident<#(#generic_types),*>
-                where
-                    #(#generic_types: ::certain_map::MaybeAvailable,)*
-                {
-                    const fn new() -> Self {
-                        Self {
-                            #(#names: ::std::marker::PhantomData,)*
+                #[doc = #doc]
+                #vis struct #ref_view_ident<'a> {
+                    #(pub #names: &'a #types,)*
+                }
+        });
+        for (impl_generics, full_ty) in full_tys {
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl<#impl_generics> #full_ty {
+                        /// Borrow all the fields with plain field syntax.
+                        #[inline]
+                        #vis fn ref_view(&self) -> #ref_view_ident<'_> {
+                            #ref_view_ident {
+                                #(#names: #refs,)*
+                            }
                         }
                     }
-                    /// # Safety
-                    /// The caller must make sure the attached map has the data of current state.
-                    #[inline]
-                    pub unsafe fn attach(self, inner: &mut #ident) -> #handler_ident<'_, #(#generic_types),*> {
-                        #handler_ident {
-                            inner,
-                            state: Self::new(),
+            });
+        }
+    }
+
+    // `JsonSchema` for the full types, with the schema of fields shown in Debug output.
+    fn json_schema_tokens(
+        &self,
+        tokens: &mut proc_macro2::TokenStream,
+        full_tys: &[(proc_macro2::TokenStream, proc_macro2::TokenStream)],
+    ) {
+        let ident = &self.ident;
+        let derive_debug = self.has_derive("Debug");
+        let fields: Vec<_> = self
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(idx, field)| {
+                let name = field.ident.as_ref().unwrap();
+                let ty = &field.ty;
+                let schema = if derive_debug || self.field_ensures(idx, "Debug") {
+                    quote!(::std::option::Option::Some(generator.subschema_for::<#ty>()))
+                } else {
+                    quote!(::std::option::Option::None)
+                };
+                quote!((::std::stringify!(#name), #schema))
+            })
+            .collect();
+        for (impl_generics, full_ty) in full_tys {
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl<#impl_generics> ::certain_map::schema::JsonSchema for #full_ty {
+                        fn schema_name() -> ::std::borrow::Cow<'static, str> {
+                            ::std::borrow::Cow::Borrowed(::std::stringify!(#ident))
+                        }
+                        #[allow(unused_variables)]
+                        fn json_schema(
+                            generator: &mut ::certain_map::schema::SchemaGenerator,
+                        ) -> ::certain_map::schema::Schema {
+                            ::certain_map::schema::map_schema(
+                                ::std::stringify!(#ident),
+                                ::std::vec![#(#fields),*],
+                            )
+                        }
+                    }
+            });
+        }
+    }
+
+    // `to_js()` of the full handler and `from_js()` of the store, converting between the full
+    // handler and a JS object keyed by the field names.
+    fn wasm_tokens(
+        &self,
+        tokens: &mut proc_macro2::TokenStream,
+        generic_ident: &Ident,
+        owned_ident: &Ident,
+    ) {
+        let ident = &self.ident;
+        let owned_vis = self.item_vis.owned.as_ref().unwrap_or(&self.vis);
+        let names: Vec<_> = self
+            .fields
+            .iter()
+            .map(|f| f.ident.as_ref().unwrap())
+            .collect();
+        let types: Vec<_> = self.fields.iter().map(|f| &f.ty).collect();
+        let occupied_types: Vec<_> = repeat_tokens(occupied_m_type(), self.fields.len()).collect();
+        tokens.extend(quote_spanned! {
+            self.span =>
+                impl<_CMS: ::certain_map::Storage<#ident>> #generic_ident<_CMS, #(#occupied_types),*> {
+                    /// Convert the fields into a JS object keyed by the field names.
+                    pub fn to_js(&self) -> ::std::result::Result<::certain_map::wasm::JsValue, ::certain_map::wasm::Error> {
+                        #[derive(::certain_map::wasm::serde::Serialize)]
+                        #[serde(crate = "::certain_map::wasm::serde")]
+                        struct Fields<'a> {
+                            #(#names: &'a #types,)*
+                        }
+                        ::certain_map::wasm::to_value(&Fields {
+                            #(#names: ::certain_map::ParamRef::<#types>::param_ref(self),)*
+                        })
+                    }
+                }
+
+                #[allow(dead_code)]
+                impl #ident {
+                    /// Build a full owned handler from a JS object keyed by the field names.
+                    #owned_vis fn from_js(
+                        value: ::certain_map::wasm::JsValue,
+                    ) -> ::std::result::Result<#owned_ident<#(#occupied_types),*>, ::certain_map::wasm::Error> {
+                        #[derive(::certain_map::wasm::serde::Deserialize)]
+                        #[serde(crate = "::certain_map::wasm::serde")]
+                        struct Fields {
+                            #(#names: #types,)*
+                        }
+                        let fields: Fields = ::certain_map::wasm::from_value(value)?;
+                        let cx = Self::owned_handler();
+                        #(let cx = ::certain_map::ParamSet::<#types>::param_set(cx, fields.#names);)*
+                        ::std::result::Result::Ok(cx)
+                    }
+                }
+        });
+    }
+
+    // `Serialize` for the handlers of every state, with the occupied fields which are not skipped.
+    fn serialize_tokens(&self, tokens: &mut proc_macro2::TokenStream, generic_ident: &Ident) {
+        let ident = &self.ident;
+        let generic_types: Vec<_> = (0..self.fields.len()).map(generic_type).collect();
+        let fields: Vec<_> = (0..self.fields.len())
+            .filter(|&idx| !self.fields_serde[idx].skip)
+            .collect();
+        let counted = fields.iter().map(|&idx| &generic_types[idx]);
+        let serialize_fields = fields.iter().map(|&idx| {
+            let field = &self.fields[idx];
+            let ty = &field.ty;
+            let key = self.serde_key(idx);
+            let field_maybe_ref = self.field_maybe_ref(idx);
+            let value = match &self.fields_serde[idx].with {
+                Some(with) => quote! {{
+                    struct SerializeWith<'a>(&'a #ty);
+                    impl ::certain_map::serialize::Serialize for SerializeWith<'_> {
+                        fn serialize<__S: ::certain_map::serialize::Serializer>(
+                            &self,
+                            serializer: __S,
+                        ) -> ::std::result::Result<__S::Ok, __S::Error> {
+                            #with::serialize(self.0, serializer)
                         }
                     }
+                    &SerializeWith(value)
+                }},
+                None => quote!(value),
+            };
+            quote! {
+                match #field_maybe_ref {
+                    ::std::option::Option::Some(value) => state.serialize_field(#key, #value)?,
+                    ::std::option::Option::None => state.skip_field(#key)?,
                 }
+            }
+        });
+        tokens.extend(quote_spanned! {
+            self.span =>
                 #[allow(non_camel_case_types)]
-                impl<#(#generic_types),*> ::certain_map::Attach<#ident> for #state_ident<#(#generic_types),*>
+                impl<_CMS: ::certain_map::Storage<#ident>, #(#generic_types),*> ::certain_map::serialize::Serialize
+                    for #generic_ident<_CMS, #(#generic_types),*>
                 where
                     #(#generic_types: ::certain_map::MaybeAvailable,)*
                 {
-                    type Hdr<'a> = #handler_ident<'a, #(#generic_types),*>;
-                    #[inline]
-                    unsafe fn attach(self, store: &mut #ident) -> Self::Hdr<'_> {
-                        self.attach(store)
+                    fn serialize<__S: ::certain_map::serialize::Serializer>(
+                        &self,
+                        serializer: __S,
+                    ) -> ::std::result::Result<__S::Ok, __S::Error> {
+                        use ::certain_map::serialize::serde::ser::SerializeStruct;
+                        let len = 0 #(+ <#counted as ::certain_map::MaybeAvailable>::OCCUPIED as usize)*;
+                        let mut state = serializer.serialize_struct(::std::stringify!(#ident), len)?;
+                        #(#serialize_fields)*
+                        state.end()
                     }
                 }
         });
+    }
 
-        if derive_clone {
-            // impl #handler_ident
-            tokens.extend(quote_spanned! {
-                self.span =>
-                    #[allow(non_camel_case_types)]
-                    impl<#(#generic_types),*> #handler_ident<'_, #(#generic_types),*>
-                    where
-                        #(#generic_types: ::certain_map::MaybeAvailable,)*
-                    {
-                        #[inline]
-                        pub fn fork(&self) -> (#ident, #state_ident<#(#generic_types),*>) {
-                            // Safety: we are sure about the state of the map.
-                            let inner = unsafe { self.inner.clone_with(&self.state) };
-                            (inner, #state_ident::new())
+    // `Deserialize` for the owned handler with every field occupied except the skipped ones.
+    fn deserialize_tokens(&self, tokens: &mut proc_macro2::TokenStream, owned_ident: &Ident) {
+        let ident = &self.ident;
+        let name = syn::LitStr::new(&ident.to_string(), ident.span());
+        let state_ident = quote::format_ident!("{ident}State");
+        let states = (0..self.fields.len()).map(|idx| {
+            if self.fields_serde[idx].skip {
+                vacancy_type()
+            } else {
+                occupied_m_type()
+            }
+        });
+        let fields: Vec<_> = (0..self.fields.len())
+            .filter(|&idx| !self.fields_serde[idx].skip)
+            .collect();
+        let names: Vec<_> = fields
+            .iter()
+            .map(|&idx| self.fields[idx].ident.as_ref().unwrap())
+            .collect();
+        let types = fields.iter().map(|&idx| &self.fields[idx].ty);
+        let slots = fields
+            .iter()
+            .map(|&idx| self.slot_place(quote!(inner), idx, true));
+        let serde_attrs = fields.iter().map(|&idx| {
+            let key = self.serde_key(idx);
+            let with = self.fields_serde[idx].with.as_ref().map(|with| {
+                let with = with.to_token_stream().to_string().replace(' ', "");
+                quote!(, with = #with)
+            });
+            quote!(#[serde(rename = #key #with)])
+        });
+        let writes = fields.iter().zip(slots).map(|(&idx, slot)| {
+            let name = names[fields.iter().position(|&i| i == idx).unwrap()];
+            let value = self.to_slot_value(idx, quote!(fields.#name));
+            if self.fields_slot[idx].is_some() {
+                quote! {
+                    #slot = ::std::mem::ManuallyDrop::new(::std::mem::MaybeUninit::new(#value));
+                }
+            } else {
+                quote!(#slot = ::std::mem::MaybeUninit::new(#value);)
+            }
+        });
+        tokens.extend(quote_spanned! {
+            self.span =>
+                impl<'de> ::certain_map::serialize::Deserialize<'de> for #owned_ident<#(#states),*> {
+                    fn deserialize<__D: ::certain_map::serialize::Deserializer<'de>>(
+                        deserializer: __D,
+                    ) -> ::std::result::Result<Self, __D::Error> {
+                        #[derive(::certain_map::serialize::Deserialize)]
+                        #[serde(crate = "::certain_map::serialize::serde", rename = #name)]
+                        struct Fields {
+                            #(#serde_attrs #names: #types,)*
                         }
-                    }
-                    #[allow(non_camel_case_types)]
-                    impl<#(#generic_types),*> ::certain_map::Fork for #handler_ident<'_, #(#generic_types),*>
-                    where
-                        #(#generic_types: ::certain_map::MaybeAvailable,)*
-                    {
-                        type Store = #ident;
-                        type State = #state_ident<#(#generic_types),*>;
-                        #[inline]
-                        fn fork(&self) -> (Self::Store, Self::State) {
-                            self.fork()
+                        let fields = <Fields as ::certain_map::serialize::Deserialize<'de>>::deserialize(deserializer)?;
+                        let mut inner = ::std::boxed::Box::new(#ident::new());
+                        #[allow(unused_unsafe)]
+                        unsafe {
+                            #(#writes)*
                         }
+                        ::std::result::Result::Ok(#owned_ident {
+                            inner,
+                            state: #state_ident::new(),
+                        })
                     }
-            });
-        }
+                }
+        });
+    }
 
-        // impl Drop for #handler_ident
+    // `Arbitrary` for the full owned handler, with arbitrary values of every field.
+    fn arbitrary_tokens(&self, tokens: &mut proc_macro2::TokenStream, owned_ident: &Ident) {
+        let ident = &self.ident;
+        let state_ident = quote::format_ident!("{ident}State");
+        let names: Vec<_> = self
+            .fields
+            .iter()
+            .map(|f| f.ident.as_ref().unwrap())
+            .collect();
+        let types = self.fields.iter().map(|f| &f.ty);
+        let slots = (0..self.fields.len()).map(|idx| self.slot_place(quote!(inner), idx, true));
+        let values = names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| self.to_slot_value(idx, quote!(#name)));
+        let occupied_types = repeat_tokens(occupied_m_type(), self.fields.len());
         tokens.extend(quote_spanned! {
             self.span =>
-                #[allow(non_camel_case_types)]
-                impl<#(#generic_types),*> Drop for #handler_ident<'_, #(#generic_types),*>
-                where
-                    #(#generic_types: ::certain_map::MaybeAvailable,)*
-                {
-                    fn drop(&mut self) {
-                        unsafe {
-                            #(#generic_types::do_drop(&mut self.inner.#names);)*
-                        }
+                impl<'__a> ::certain_map::fuzz::Arbitrary<'__a> for #owned_ident<#(#occupied_types),*> {
+                    fn arbitrary(
+                        u: &mut ::certain_map::fuzz::Unstructured<'__a>,
+                    ) -> ::certain_map::fuzz::Result<Self> {
+                        #(let #names: #types = ::certain_map::fuzz::Arbitrary::arbitrary(u)?;)*
+                        let mut inner = ::std::boxed::Box::new(#ident::new());
+                        #(#slots = ::std::mem::MaybeUninit::new(#values);)*
+                        ::std::result::Result::Ok(#owned_ident {
+                            inner,
+                            state: #state_ident::new(),
+                        })
                     }
                 }
         });
+    }
 
-        // impl ParamRef<T>/ParamMut<T>/ParamTake<T> for #handler_ident
-        for (idx, field) in self.fields.iter().enumerate() {
-            let ty = &field.ty;
-            let name = field.ident.as_ref().unwrap();
-            let generic_type = generic_type(idx);
-            let generic_types_rest1 = IgnoreIter::new(generic_types.iter(), idx);
-            let generic_types_rest2 = IgnoreIter::new(generic_types.iter(), idx);
-            let generic_types_rest3 = IgnoreIter::new(generic_types.iter(), idx);
-            let vacancy = IdentOrTokens::from(vacancy_type());
-            let generic_types_replaced_vacancy =
-                ReplaceIter::new(generic_types.iter(), idx, &vacancy);
-            tokens.extend(quote_spanned! {
-                self.span =>
-                    #[allow(non_camel_case_types)]
-                    impl<#(#generic_types),*> ::certain_map::ParamRef<#ty> for #handler_ident<'_, #(#generic_types),*>
-                    where
-                        #generic_type: ::certain_map::Available,
-                        #(#generic_types_rest1: ::certain_map::MaybeAvailable,)*
-                    {
+    // The struct of `#[partial(Name)]`, which owns the fields with their occupancy known only at
+    // runtime, so fuzz targets can generate contexts of any state for code bounded on
+    // `ParamMaybeRef` / `ParamMaybeMut`.
+    fn partial_tokens(&self, tokens: &mut proc_macro2::TokenStream, partial_ident: &Ident) {
+        let vis = &self.vis;
+        let names: Vec<_> = self
+            .fields
+            .iter()
+            .map(|f| f.ident.as_ref().unwrap())
+            .collect();
+        let types: Vec<_> = self.fields.iter().map(|f| &f.ty).collect();
+        let doc = format!(
+            "The fields of [`{}`] which are occupied at runtime, `None` for vacant ones.",
+            self.ident
+        );
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[doc = #doc]
+                #[derive(Default)]
+                #vis struct #partial_ident {
+                    #(pub #names: ::std::option::Option<#types>,)*
+                }
+                #(
+                    impl ::certain_map::ParamMaybeRef<#types> for #partial_ident {
                         #[inline]
-                        fn param_ref(&self) -> &#ty {
-                            unsafe { #generic_type::do_ref(&self.inner.#name) }
+                        fn param_maybe_ref(&self) -> ::std::option::Option<&#types> {
+                            self.#names.as_ref()
                         }
                     }
-                    #[allow(non_camel_case_types)]
-                    impl<#(#generic_types),*> ::certain_map::ParamMut<#ty> for #handler_ident<'_, #(#generic_types),*>
-                    where
-                        #generic_type: ::certain_map::Available,
-                        #(#generic_types_rest2: ::certain_map::MaybeAvailable,)*
-                    {
+                    impl ::certain_map::ParamMaybeMut<#types> for #partial_ident {
                         #[inline]
-                        fn param_mut(&mut self) -> &mut #ty {
-                            unsafe { #generic_type::do_mut(&mut self.inner.#name) }
+                        fn param_maybe_mut(&mut self) -> ::std::option::Option<&mut #types> {
+                            self.#names.as_mut()
                         }
                     }
-                    #[allow(non_camel_case_types)]
-                    impl<'a, #(#generic_types),*> ::certain_map::ParamTake<#ty> for #handler_ident<'a, #(#generic_types),*>
-                    where
-                        #generic_type: ::certain_map::Available,
-                        #(#generic_types_rest3: ::certain_map::MaybeAvailable,)*
-                    {
-                        type Transformed = #handler_ident<'a, #(#generic_types_replaced_vacancy),*>;
-                        #[inline]
-                        fn param_take(self) -> (Self::Transformed, #ty) {
-                            let item = unsafe { #generic_type::do_take(&self.inner.#name) };
-                            #[allow(clippy::missing_transmute_annotations)]
-                            (unsafe { ::std::mem::transmute(self) }, item)
+                )*
+        });
+        if self.has_derive("Arbitrary") {
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl<'__a> ::certain_map::fuzz::Arbitrary<'__a> for #partial_ident {
+                        fn arbitrary(
+                            u: &mut ::certain_map::fuzz::Unstructured<'__a>,
+                        ) -> ::certain_map::fuzz::Result<Self> {
+                            ::std::result::Result::Ok(#partial_ident {
+                                #(#names: ::certain_map::fuzz::Arbitrary::arbitrary(u)?,)*
+                            })
                         }
                     }
             });
         }
+    }
 
-        // impl ParamMaybeRef<T>/ParamMaybeMut<T>/ParamSet<T>/ParamRemove<T> for #handler_ident
-        for (idx, field) in self.fields.iter().enumerate() {
-            let ty = &field.ty;
-            let name = field.ident.as_ref().unwrap();
-            let generic_type = generic_type(idx);
+    // The serialized name of a field, which is the field name unless renamed.
+    fn serde_key(&self, idx: usize) -> syn::LitStr {
+        match &self.fields_serde[idx].rename {
+            Some(rename) => rename.clone(),
+            None => {
+                let name = self.fields[idx].ident.as_ref().unwrap();
+                syn::LitStr::new(&name.to_string(), name.span())
+            }
+        }
+    }
 
-            let occupied = IdentOrTokens::from(occupied_m_type());
-            let generic_types_replaced_occupied =
-                ReplaceIter::new(generic_types.iter(), idx, &occupied);
-            let vacancy = IdentOrTokens::from(vacancy_type());
-            let generic_types_replaced_vacancy =
-                ReplaceIter::new(generic_types.iter(), idx, &vacancy);
+    // An enum borrowing the occupied field of a `#[one_of(group)]`, returned by `group()` of the
+    // handler types in `hdr_tys`, given with the impl generics in front of the state generics.
+    fn one_of_enum_tokens(
+        &self,
+        tokens: &mut proc_macro2::TokenStream,
+        group: &Ident,
+        members: &[usize],
+        hdr_tys: &[(proc_macro2::TokenStream, proc_macro2::TokenStream)],
+    ) {
+        let vis = &self.vis;
+        let ident = &self.ident;
+        let camel: String = group
+            .unraw()
+            .to_string()
+            .split('_')
+            .map(|word| {
+                let mut chars = word.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => String::new(),
+                }
+            })
+            .collect();
+        let enum_ident = quote::format_ident!("{ident}{camel}");
+        let generic_types: Vec<_> = (0..self.fields.len()).map(generic_type).collect();
+        let names: Vec<_> = members
+            .iter()
+            .map(|&idx| self.fields[idx].ident.as_ref().unwrap())
+            .collect();
+        let types: Vec<_> = members.iter().map(|&idx| &self.fields[idx].ty).collect();
+        let doc = format!("The occupied `{group}` field of [`{ident}`].");
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[doc = #doc]
+                #[allow(non_camel_case_types)]
+                #vis enum #enum_ident<'a> {
+                    #(#names(&'a #types),)*
+                }
+        });
+        for (impl_generics, hdr_ty) in hdr_tys {
             tokens.extend(quote_spanned! {
                 self.span =>
                     #[allow(non_camel_case_types)]
-                    impl<#(#generic_types),*> ::certain_map::ParamMaybeRef<#ty> for #handler_ident<'_, #(#generic_types),*>
-                    where
-                        #(#generic_types: ::certain_map::MaybeAvailable,)*
-                    {
-                        #[inline]
-                        fn param_maybe_ref(&self) -> Option<&#ty> {
-                            unsafe { #generic_type::do_maybe_ref(&self.inner.#name) }
-                        }
-                    }
-                    #[allow(non_camel_case_types)]
-                    impl<#(#generic_types),*> ::certain_map::ParamMaybeMut<#ty> for #handler_ident<'_, #(#generic_types),*>
-                    where
-                        #(#generic_types: ::certain_map::MaybeAvailable,)*
-                    {
-                        #[inline]
-                        fn param_maybe_mut(&mut self) -> Option<&mut #ty> {
-                            unsafe { #generic_type::do_maybe_mut(&mut self.inner.#name) }
-                        }
-                    }
-                    #[allow(non_camel_case_types)]
-                    impl<'a, #(#generic_types),*> ::certain_map::ParamSet<#ty> for #handler_ident<'a, #(#generic_types),*>
-                    where
-                        #(#generic_types: ::certain_map::MaybeAvailable,)*
-                    {
-                        type Transformed = #handler_ident<'a, #(#generic_types_replaced_occupied),*>;
-                        #[inline]
-                        fn param_set(self, item: #ty) -> Self::Transformed {
-                            unsafe {
-                                #generic_type::do_set(&mut self.inner.#name, item);
-                                #[allow(clippy::missing_transmute_annotations)]
-                                ::std::mem::transmute(self)
-                            }
-                        }
-                    }
-                    #[allow(non_camel_case_types)]
-                    impl<'a, #(#generic_types),*> ::certain_map::ParamRemove<#ty> for #handler_ident<'a, #(#generic_types),*>
+                    impl<#impl_generics #(#generic_types),*> #hdr_ty
                     where
                         #(#generic_types: ::certain_map::MaybeAvailable,)*
                     {
-                        type Transformed = #handler_ident<'a, #(#generic_types_replaced_vacancy),*>;
+                        /// Borrow the occupied field of the group, if any.
                         #[inline]
-                        fn param_remove(self) -> Self::Transformed {
-                            unsafe {
-                                #generic_type::do_drop(&mut self.inner.#name);
-                                #[allow(clippy::missing_transmute_annotations)]
-                                ::std::mem::transmute(self)
-                            }
+                        #vis fn #group(&self) -> ::std::option::Option<#enum_ident<'_>> {
+                            #(if let ::std::option::Option::Some(value) =
+                                ::certain_map::ParamMaybeRef::<#types>::param_maybe_ref(self)
+                            {
+                                return ::std::option::Option::Some(#enum_ident::#names(value));
+                            })*
+                            ::std::option::Option::None
                         }
                     }
             });
         }
-
-        // impl Param<T> and Param<Option<T>> if #[ensure(Clone)] or derive_clone
-        for (idx, (field, maybe_meta)) in
-            self.fields.iter().zip(self.fields_meta.iter()).enumerate()
-        {
-            if derive_clone
-                || maybe_meta
-                    .iter()
-                    .flat_map(|x| x.iter())
-                    .any(|meta| matches!(meta, Meta::Path(path) if path.is_ident("Clone")))
-            {
-                let ty = &field.ty;
-                let name = field.ident.as_ref().unwrap();
-                let generic_type = generic_type(idx);
-                let generic_types_rest = IgnoreIter::new(generic_types.iter(), idx);
-                tokens.extend(quote_spanned! {
-                    self.span =>
-                        #[allow(non_camel_case_types)]
-                        impl<#(#generic_types),*> ::certain_map::Param<#ty> for #handler_ident<'_, #(#generic_types),*>
-                        where
-                            #generic_type: ::certain_map::Available,
-                            #(#generic_types_rest: ::certain_map::MaybeAvailable,)*
-                        {
-                            #[inline]
-                            fn param(&self) -> #ty {
-                                unsafe { #generic_type::do_read(&self.inner.#name) }
-                            }
-                        }
-                        #[allow(non_camel_case_types)]
-                        impl<#(#generic_types),*> ::certain_map::Param<Option<#ty>> for #handler_ident<'_, #(#generic_types),*>
-                        where
-                            #(#generic_types: ::certain_map::MaybeAvailable,)*
-                        {
-                            #[inline]
-                            fn param(&self) -> Option<#ty> {
-                                #[allow(clippy::clone_on_copy)]
-                                unsafe { #generic_type::do_maybe_ref(&self.inner.#name).cloned() }
-                            }
-                        }
-                });
-            }
-        }
     }
 
-    fn to_unfilled_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        let mut attrs = self.attrs.clone();
+    // A struct of optional references to all the fields, returned by `maybe_view()` of every
+    // state. `tys` are the impl generics and self types to implement it for.
+    fn maybe_view_tokens(
+        &self,
+        tokens: &mut proc_macro2::TokenStream,
+        maybe_view_ident: &Ident,
+        tys: &[(proc_macro2::TokenStream, proc_macro2::TokenStream)],
+        bounds: proc_macro2::TokenStream,
+    ) {
         let vis = &self.vis;
-        let ident = &self.ident;
-        let generic_types: Vec<_> = (0..self.fields.len())
-            .map(generic_type)
-            .map(IdentOrTokens::from)
-            .collect();
         let names: Vec<_> = self
             .fields
             .iter()
             .map(|f| f.ident.as_ref().unwrap())
             .collect();
-
-        // struct definition
-        if let Some((empty_idx, empty_ident)) = Self::find_path_attr(&attrs, "empty") {
-            attrs.remove(empty_idx);
-            let vacancy_types =
-                std::iter::repeat(quote!(::certain_map::Vacancy)).take(self.fields.len());
-            tokens.extend(quote_spanned! {
-                self.span =>
-                    #vis type #empty_ident = #ident<#(#vacancy_types),*>;
-            });
-        }
-
-        if let Some((full_idx, full_ident)) = Self::find_path_attr(&attrs, "full") {
-            attrs.remove(full_idx);
-            let occupied_types = self.fields.iter().map(|f| occupied_type(&f.ty));
-            tokens.extend(quote_spanned! {
-                self.span =>
-                    #vis type #full_ident = #ident<#(#occupied_types),*>;
-            });
-        }
-
-        tokens.extend(quote_spanned! {
-            self.span =>
-                #(#attrs)*
-                #vis struct #ident<#(#generic_types),*> {
-                    #(#names: #generic_types, )*
-                }
-        });
-
-        // impl new and Default
-        let vacancy_types1 =
-            std::iter::repeat(quote!(::certain_map::Vacancy)).take(self.fields.len());
-        let vacancy_types2 =
-            std::iter::repeat(quote!(::certain_map::Vacancy)).take(self.fields.len());
-        let vacancy_values =
-            std::iter::repeat(quote!(::certain_map::Vacancy)).take(self.fields.len());
+        let types: Vec<_> = self.fields.iter().map(|f| &f.ty).collect();
+        let doc = format!("Optional references to the fields of [`{}`].", self.ident);
         tokens.extend(quote_spanned! {
             self.span =>
-                impl ::std::default::Default for #ident<#(#vacancy_types1),*> {
-                    #[inline]
-                    fn default() -> Self {
-                        Self::new()
-                    }
-                }
-                impl #ident<#(#vacancy_types2),*> {
-                    pub const fn new() -> Self {
-                        Self {
-                            #(#names: #vacancy_values),*
-                        }
-                    }
+                #[doc = #doc]
+                #vis struct #maybe_view_ident<'a> {
+                    #(pub #names: ::std::option::Option<&'a #types>,)*
                 }
         });
-
-        // impl ParamRef<T>
-        for (idx, field) in self.fields.iter().enumerate() {
-            let ty = &field.ty;
-            let name = field.ident.as_ref().unwrap();
-            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
-            let occupied = IdentOrTokens::from(occupied_type(ty));
-            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+        for (impl_generics, self_ty) in tys {
             tokens.extend(quote_spanned! {
                 self.span =>
-                    impl<#(#generic_types_ignored),*> ::certain_map::ParamRef<#ty> for #ident<#(#generic_types_replaced),*> {
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_generics> #self_ty
+                    where
+                        #bounds
+                        #(Self: ::certain_map::ParamMaybeRef<#types>,)*
+                    {
+                        /// Borrow the occupied fields, vacant ones are `None`.
                         #[inline]
-                        fn param_ref(&self) -> &#ty {
-                            &self.#name.0
+                        #vis fn maybe_view(&self) -> #maybe_view_ident<'_> {
+                            #maybe_view_ident {
+                                #(#names: ::certain_map::ParamMaybeRef::<#types>::param_maybe_ref(self),)*
+                            }
                         }
                     }
             });
         }
+    }
 
-        // impl ParamMaybeRef<T> for occupied
-        for (idx, field) in self.fields.iter().enumerate() {
-            let ty = &field.ty;
+    // A `#[cfg(test)]` module testing a copy of the map whose field types are replaced with
+    // drop-counting dummies, which checks every set/take/remove/fork/reset/drop path of the
+    // generated code for the attributes of this map.
+    fn test_gen_tokens(&self) -> proc_macro2::TokenStream {
+        let ident = &self.ident;
+        let mod_ident = quote::format_ident!("__certain_map_tests_{ident}");
+        let derive_clone = self.has_derive("Clone");
+        let field_count = self.fields.len();
+
+        let mut shadow = self.clone();
+        shadow.vis = Visibility::Inherited;
+        shadow.item_vis = ItemVis::default();
+        shadow.attrs = if derive_clone {
+            vec![syn::parse_quote!(#[derive(Clone)])]
+        } else {
+            Vec::new()
+        };
+        for (idx, field) in shadow.fields.iter_mut().enumerate() {
+            field.attrs.clear();
+            field.ty = syn::parse_quote!(__Dummy<#idx>);
+        }
+        shadow.fields_meta = vec![None; field_count];
+        shadow.fields_baggage = vec![None; field_count];
+        shadow.fields_header = vec![None; field_count];
+        shadow.fields_graphql = vec![false; field_count];
+        shadow.fields_serde = vec![SerdeField::default(); field_count];
+        shadow.fields_config = vec![None; field_count];
+        shadow.fields_wire = vec![None; field_count];
+        shadow.fields_flag = vec![false; field_count];
+        shadow.fields_fallback = vec![None; field_count];
+        shadow.fields_clone_strategy = vec![None; field_count];
+        shadow.fields_push = vec![None; field_count];
+        shadow.fields_weak = vec![None; field_count];
+        shadow.fields_persistent = vec![false; field_count];
+        shadow.deny_large = None;
+        shadow.assert_send = false;
+        shadow.global = false;
+        shadow.wasm = false;
+        shadow.codegen_stats = false;
+        shadow.partitions = Vec::new();
+        shadow.methods = Vec::new();
+        shadow.cache_key = Vec::new();
+        let mut shadow_tokens = proc_macro2::TokenStream::new();
+        shadow.to_pre_filled_tokens(&mut shadow_tokens);
+
+        // the fields required by idx, in an order they can be set
+        fn prerequisites(requires: &[Vec<usize>], idx: usize, order: &mut Vec<usize>) {
+            for &dep in &requires[idx] {
+                if !order.contains(&dep) {
+                    // mark it first so a cycle can not recurse forever
+                    order.push(dep);
+                    prerequisites(requires, dep, order);
+                    order.retain(|&i| i != dep);
+                    order.push(dep);
+                }
+            }
+        }
+
+        let tests = self.fields.iter().enumerate().map(|(idx, field)| {
             let name = field.ident.as_ref().unwrap();
-            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
-            let occupied = IdentOrTokens::from(occupied_type(ty));
-            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &occupied);
-            tokens.extend(quote_spanned! {
-                self.span =>
-                    impl<#(#generic_types_ignored),*> ::certain_map::ParamMaybeRef<#ty> for #ident<#(#generic_types_replaced),*> {
-                        #[inline]
-                        fn param_maybe_ref(&self) -> Option<&#ty> {
-                            Some(&self.#name.0)
+            let test_ident = quote::format_ident!("field_{}", name.unraw());
+            let mut deps = Vec::new();
+            prerequisites(&self.fields_requires, idx, &mut deps);
+            deps.retain(|&dep| dep != idx);
+            let set_deps = quote! {
+                #(let h = ::certain_map::ParamSet::<__Dummy<#deps>>::param_set(h, __Dummy::<#deps>(counters[#deps].clone()));)*
+            };
+            let set = quote! {
+                ::certain_map::ParamSet::<__Dummy<#idx>>::param_set(h, __Dummy::<#idx>(counters[#idx].clone()))
+            };
+            let fork = if derive_clone {
+                quote! {
+                    // fork_owned clones the values, and dropping either side drops its own
+                    let counters = __counters();
+                    let mut store = #ident::new();
+                    let h = store.handler();
+                    #set_deps
+                    let h = #set;
+                    let forked = h.fork_owned();
+                    let (mut forked_store, forked_state) = h.fork();
+                    drop(h);
+                    assert_eq!(counters[#idx].get(), 1);
+                    drop(forked);
+                    assert_eq!(counters[#idx].get(), 2);
+                    drop(unsafe { forked_state.attach(&mut forked_store) });
+                    assert_eq!(counters[#idx].get(), 3);
+                    #(assert_eq!(counters[#deps].get(), 3);)*
+                }
+            } else {
+                quote!()
+            };
+            quote! {
+                #[test]
+                fn #test_ident() {
+                    // setting twice drops the first value, removing drops the second one
+                    let counters = __counters();
+                    let mut store = #ident::new();
+                    let h = store.handler();
+                    #set_deps
+                    let h = #set;
+                    let h = #set;
+                    assert_eq!(counters[#idx].get(), 1);
+                    let h = ::certain_map::ParamRemove::<__Dummy<#idx>>::param_remove(h);
+                    assert_eq!(counters[#idx].get(), 2);
+                    drop(h);
+                    assert_eq!(counters[#idx].get(), 2);
+                    #(assert_eq!(counters[#deps].get(), 1);)*
+
+                    // taking moves the value out without dropping it
+                    let counters = __counters();
+                    let mut store = #ident::new();
+                    let h = store.handler();
+                    #set_deps
+                    let h = #set;
+                    let (h, value) = ::certain_map::ParamTake::<__Dummy<#idx>>::param_take(h);
+                    drop(h);
+                    assert_eq!(counters[#idx].get(), 0);
+                    drop(value);
+                    assert_eq!(counters[#idx].get(), 1);
+
+                    // resetting and dropping the handler drop the value once
+                    let counters = __counters();
+                    let mut store = #ident::new();
+                    let h = store.handler();
+                    #set_deps
+                    let h = #set;
+                    let h = h.reset();
+                    assert_eq!(counters[#idx].get(), 1);
+                    #(assert_eq!(counters[#deps].get(), 1);)*
+                    #set_deps
+                    let h = #set;
+                    drop(h);
+                    assert_eq!(counters[#idx].get(), 2);
+                    #(assert_eq!(counters[#deps].get(), 2);)*
+
+                    // the owned handler drops the value with the boxed store
+                    let counters = __counters();
+                    let h = #ident::owned_handler();
+                    #set_deps
+                    let h = #set;
+                    drop(h);
+                    assert_eq!(counters[#idx].get(), 1);
+
+                    #fork
+                }
+            }
+        });
+
+        quote_spanned! {
+            self.span =>
+                #[cfg(test)]
+                #[allow(non_snake_case, dead_code, clippy::redundant_clone)]
+                mod #mod_ident {
+                    use ::std::{cell::Cell, rc::Rc};
+
+                    struct __Dummy<const I: usize>(Rc<Cell<usize>>);
+
+                    impl<const I: usize> Clone for __Dummy<I> {
+                        fn clone(&self) -> Self {
+                            Self(self.0.clone())
                         }
                     }
-            });
-        }
 
-        // impl ParamMaybeRef<T> for vacancy
-        for (idx, field) in self.fields.iter().enumerate() {
-            let ty = &field.ty;
-            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
-            let vacancy = IdentOrTokens::from(vacancy_type());
-            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
-            tokens.extend(quote_spanned! {
-                self.span =>
-                    impl<#(#generic_types_ignored),*> ::certain_map::ParamMaybeRef<#ty> for #ident<#(#generic_types_replaced),*> {
-                        #[inline]
-                        fn param_maybe_ref(&self) -> Option<&#ty> {
-                            None
+                    impl<const I: usize> Drop for __Dummy<I> {
+                        fn drop(&mut self) {
+                            self.0.set(self.0.get() + 1);
                         }
                     }
-            });
+
+                    fn __counters() -> ::std::vec::Vec<Rc<Cell<usize>>> {
+                        (0..#field_count).map(|_| Rc::new(Cell::new(0))).collect()
+                    }
+
+                    #shadow_tokens
+
+                    #(#tests)*
+                }
         }
+    }
 
-        // impl ParamMut
+    // Summary of the generated items for `#[codegen_stats]`: the number of impls and tokens, and
+    // the impls of every trait.
+    fn codegen_stats_summary(&self, output: &proc_macro2::TokenStream) -> String {
+        fn count_tokens(stream: proc_macro2::TokenStream) -> usize {
+            stream
+                .into_iter()
+                .map(|tt| match tt {
+                    proc_macro2::TokenTree::Group(group) => 2 + count_tokens(group.stream()),
+                    _ => 1,
+                })
+                .sum()
+        }
+
+        let mut per_trait = std::collections::BTreeMap::<String, usize>::new();
+        let mut impls = 0;
+        let mut others = 0;
+        if let Ok(file) = syn::parse2::<syn::File>(output.clone()) {
+            for item in file.items {
+                match item {
+                    syn::Item::Impl(item) => {
+                        impls += 1;
+                        let name = match &item.trait_ {
+                            Some((_, path, _)) => path
+                                .segments
+                                .last()
+                                .map(|seg| seg.ident.to_string())
+                                .unwrap_or_default(),
+                            None => "(inherent)".to_string(),
+                        };
+                        *per_trait.entry(name).or_default() += 1;
+                    }
+                    _ => others += 1,
+                }
+            }
+        }
+        let mut per_trait: Vec<_> = per_trait.into_iter().collect();
+        per_trait.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let mut summary = format!(
+            "certain_map `{}`: {} fields, {impls} impls, {others} other items, {} tokens",
+            self.ident,
+            self.fields.len(),
+            count_tokens(output.clone()),
+        );
+        for (name, count) in per_trait {
+            summary.push_str(&format!("\n    {name}: {count}"));
+        }
+        summary
+    }
+
+    // Fail compilation if a field is larger than `#[deny_large(N)]`. The field name and the limit
+    // are in the name of a marker type and the size shows up as its const argument in the
+    // type mismatch error.
+    fn deny_large_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let limit = match &self.deny_large {
+            Some(limit) => limit,
+            None => return,
+        };
+        // validated when parsing
+        let limit_value: usize = limit.base10_parse().unwrap();
         for (idx, field) in self.fields.iter().enumerate() {
             let ty = &field.ty;
-            let name = field.ident.as_ref().unwrap();
-            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
-            let occupied = IdentOrTokens::from(occupied_type(ty));
-            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+            let slot_ty = self.slot_ty(idx);
+            let marker = quote::format_ident!(
+                "field_{}_exceeds_deny_large_{}",
+                field.ident.as_ref().unwrap().unraw(),
+                limit_value,
+                span = ty.span()
+            );
             tokens.extend(quote_spanned! {
-                self.span =>
-                    impl<#(#generic_types_ignored),*> ::certain_map::ParamMut<#ty> for #ident<#(#generic_types_replaced),*> {
-                        #[inline]
-                        fn param_mut(&mut self) -> &mut #ty {
-                            &mut self.#name.0
-                        }
-                    }
+                ty.span() =>
+                    const _: () = {
+                        #[allow(non_camel_case_types)]
+                        struct #marker<const SIZE: usize>;
+                        const SIZE: usize = ::std::mem::size_of::<#slot_ty>();
+                        let _: #marker<0> = #marker::<{ if SIZE > #limit_value { SIZE } else { 0 } }>;
+                    };
             });
         }
+    }
 
-        // impl ParamMaybeMut<T> for occupied
-        for (idx, field) in self.fields.iter().enumerate() {
+    // Fail compilation if a field is not Send with `#[assert_send]`. The error names the field and
+    // points at its type, instead of the generated types a failed `Send` bound points at.
+    fn assert_send_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        if !self.assert_send {
+            return;
+        }
+        for field in self.fields.iter() {
             let ty = &field.ty;
             let name = field.ident.as_ref().unwrap();
-            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
-            let occupied = IdentOrTokens::from(occupied_type(ty));
-            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+            let marker = Ident::new(&name.to_string(), ty.span());
             tokens.extend(quote_spanned! {
-                self.span =>
-                    impl<#(#generic_types_ignored),*> ::certain_map::ParamMaybeMut<#ty> for #ident<#(#generic_types_replaced),*> {
-                        #[inline]
-                        fn param_maybe_mut(&mut self) -> Option<&mut #ty> {
-                            Some(&mut self.#name.0)
+                ty.span() =>
+                    const _: () = {
+                        #[allow(non_camel_case_types)]
+                        struct #marker;
+                        #[allow(dead_code)]
+                        fn assert_send() {
+                            ::certain_map::assert_send_field::<#marker, #ty>();
                         }
-                    }
+                    };
             });
         }
+    }
 
-        // impl ParamMaybeMut<T> for vacancy
-        for (idx, field) in self.fields.iter().enumerate() {
-            let ty = &field.ty;
-            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
-            let vacancy = IdentOrTokens::from(vacancy_type());
-            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
-            tokens.extend(quote_spanned! {
-                self.span =>
-                    impl<#(#generic_types_ignored),*> ::certain_map::ParamMaybeMut<#ty> for #ident<#(#generic_types_replaced),*> {
-                        #[inline]
-                        fn param_maybe_mut(&mut self) -> Option<&mut #ty> {
-                            None
-                        }
+    // Zero the given slots of the store if the map has `#[secure_clear]`.
+    fn clear_slots_tokens(
+        &self,
+        store: proc_macro2::TokenStream,
+        indexes: &[usize],
+    ) -> proc_macro2::TokenStream {
+        if !self.secure_clear {
+            return quote!();
+        }
+        let clears = indexes.iter().map(|&idx| {
+            let slot = self.slot_place(&store, idx, true);
+            match self.fields_slot[idx] {
+                // union fields can only be accessed in unsafe code
+                Some(_) => quote!(unsafe { ::certain_map::__secure_clear(&mut #slot) };),
+                None => quote!(::certain_map::__secure_clear(&mut #slot);),
+            }
+        });
+        quote_spanned! {
+            self.span =>
+                #(#clears)*
+        }
+    }
+
+    // The type stored in the slot of the field, the shared pointer of `#[clone_strategy(..)]`.
+    fn slot_ty(&self, idx: usize) -> proc_macro2::TokenStream {
+        let ty = &self.fields[idx].ty;
+        match self.fields_clone_strategy[idx] {
+            Some(strategy) => {
+                let pointer = strategy.pointer();
+                quote!(#pointer<#ty>)
+            }
+            None => quote!(#ty),
+        }
+    }
+
+    // Convert a value of the field into the value stored in its slot.
+    fn to_slot_value(
+        &self,
+        idx: usize,
+        value: proc_macro2::TokenStream,
+    ) -> proc_macro2::TokenStream {
+        match self.fields_clone_strategy[idx] {
+            Some(strategy) => {
+                let pointer = strategy.pointer();
+                quote!(#pointer::new(#value))
+            }
+            None => value,
+        }
+    }
+
+    // Borrow the field from a reference to its slot value.
+    fn slot_ref(&self, idx: usize, slot: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+        match self.fields_clone_strategy[idx] {
+            Some(_) => quote!(&**#slot),
+            None => slot,
+        }
+    }
+
+    // `Option<&T>` of the field in any state.
+    fn field_maybe_ref(&self, idx: usize) -> proc_macro2::TokenStream {
+        let generic_type = generic_type(idx);
+        let slot = self.slot_place(quote!(self.inner), idx, false);
+        let slot = quote!(unsafe { #generic_type::do_maybe_ref(&#slot) });
+        match self.fields_clone_strategy[idx] {
+            Some(_) => quote!(::std::option::Option::map(#slot, |v| &**v)),
+            None => slot,
+        }
+    }
+
+    // `Option<&mut T>` of the field in any state. Shared values are cloned on write.
+    fn field_maybe_mut(&self, idx: usize) -> proc_macro2::TokenStream {
+        let generic_type = generic_type(idx);
+        let slot = self.slot_place(quote!(self.inner), idx, true);
+        let slot = quote!(unsafe { #generic_type::do_maybe_mut(&mut #slot) });
+        match self.fields_clone_strategy[idx] {
+            Some(strategy) => {
+                let pointer = strategy.pointer();
+                quote!(::std::option::Option::map(#slot, #pointer::make_mut))
+            }
+            None => slot,
+        }
+    }
+
+    // `&T` of the occupied field, where the state generic of the field is `Available`.
+    fn field_ref(&self, idx: usize, generic_type: impl ToTokens) -> proc_macro2::TokenStream {
+        let slot = self.slot_place(quote!(self.inner), idx, false);
+        self.slot_ref(idx, quote!(unsafe { #generic_type::do_ref(&#slot) }))
+    }
+
+    // `&mut T` of the occupied field. Shared values are cloned on write.
+    fn field_mut(&self, idx: usize, generic_type: impl ToTokens) -> proc_macro2::TokenStream {
+        let slot = self.slot_place(quote!(self.inner), idx, true);
+        let slot = quote!(unsafe { #generic_type::do_mut(&mut #slot) });
+        match self.fields_clone_strategy[idx] {
+            Some(strategy) => {
+                let pointer = strategy.pointer();
+                quote!(#pointer::make_mut(#slot))
+            }
+            None => slot,
+        }
+    }
+
+    // Move the occupied field out, cloning it if the value is still shared by a fork.
+    fn field_take(&self, idx: usize, generic_type: impl ToTokens) -> proc_macro2::TokenStream {
+        let slot = self.slot_place(quote!(self.inner), idx, false);
+        let slot = quote!(unsafe { #generic_type::do_take(&#slot) });
+        match self.fields_clone_strategy[idx] {
+            Some(strategy) => {
+                let pointer = strategy.pointer();
+                quote! {
+                    match #pointer::try_unwrap(#slot) {
+                        ::std::result::Result::Ok(value) => value,
+                        ::std::result::Result::Err(shared) => ::std::clone::Clone::clone(&*shared),
                     }
-            });
+                }
+            }
+            None => slot,
         }
+    }
 
-        // impl Param<T> and Param<Option<T>> if #[ensure(Clone)]
-        for (idx, (field, maybe_meta)) in
-            self.fields.iter().zip(self.fields_meta.iter()).enumerate()
-        {
-            if maybe_meta
+    // A clone of the occupied field.
+    fn field_read(&self, idx: usize, generic_type: impl ToTokens) -> proc_macro2::TokenStream {
+        match self.fields_clone_strategy[idx] {
+            Some(_) => {
+                let value = self.field_ref(idx, generic_type);
+                quote!(::std::clone::Clone::clone(#value))
+            }
+            None => {
+                let slot = self.slot_place(quote!(self.inner), idx, false);
+                quote!(unsafe { #generic_type::do_read(&#slot) })
+            }
+        }
+    }
+
+    // Path of the storage of the field at idx in the store.
+    fn slot_path(&self, idx: usize) -> proc_macro2::TokenStream {
+        let name = self.fields[idx].ident.as_ref().unwrap();
+        match (&self.fields_slot[idx], self.fields_padded[idx]) {
+            (Some(group), true) => quote!(#group.0.#name),
+            (Some(group), false) => quote!(#group.#name),
+            (None, true) => quote!(#name.0),
+            (None, false) => quote!(#name),
+        }
+    }
+
+    // The storage of the field at idx in the store `base`, borrowed mutably if `mutable`. The
+    // slots of a `#[reorder_by_align]` store are reached through the accessors of their root.
+    fn slot_place(
+        &self,
+        base: impl ToTokens,
+        idx: usize,
+        mutable: bool,
+    ) -> proc_macro2::TokenStream {
+        if !self.reorder_by_align {
+            let path = self.slot_path(idx);
+            return quote!(#base.#path);
+        }
+        let name = self.fields[idx].ident.as_ref().unwrap();
+        let root = self.slot_root(idx);
+        let accessor = match mutable {
+            true => quote::format_ident!("__root_{root}_mut"),
+            false => quote::format_ident!("__root_{root}"),
+        };
+        match (&self.fields_slot[idx], self.fields_padded[idx]) {
+            (Some(_), true) => quote!((*#base.#accessor()).0.#name),
+            (Some(_), false) => quote!((*#base.#accessor()).#name),
+            (None, true) => quote!((*#base.#accessor()).0),
+            (None, false) => quote!((*#base.#accessor())),
+        }
+    }
+
+    // Index of the outermost storage of the field at idx, the plain slots in declaration order
+    // followed by the union slots.
+    fn slot_root(&self, idx: usize) -> usize {
+        match &self.fields_slot[idx] {
+            Some(group) => {
+                let plain = self.fields_slot.iter().filter(|s| s.is_none()).count();
+                let groups = self.slot_groups();
+                plain + groups.iter().position(|(g, _)| *g == group).unwrap()
+            }
+            None => self.fields_slot[..idx]
                 .iter()
-                .flat_map(|x| x.iter())
-                .any(|meta| matches!(meta, Meta::Path(path) if path.is_ident("Clone")))
+                .filter(|s| s.is_none())
+                .count(),
+        }
+    }
+
+    // `#[track_caller]` and the statement recording the caller as the last writer of the field,
+    // with the `provenance` feature.
+    fn provenance_tokens(
+        &self,
+        idx: usize,
+    ) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+        if PROVENANCE {
+            (
+                quote!(#[track_caller]),
+                quote!(self.inner.__provenance[#idx] = ::std::option::Option::Some(::std::panic::Location::caller());),
+            )
+        } else {
+            (quote!(), quote!())
+        }
+    }
+
+    // Inherent methods of `#[method_prefix(..)]`, which are generic over the field type and
+    // forward to the param traits implemented for the handler.
+    fn prefixed_methods_tokens(&self) -> proc_macro2::TokenStream {
+        let prefix = match &self.method_prefix {
+            Some(prefix) => prefix,
+            None => return quote!(),
+        };
+        let method = |name: &str| quote::format_ident!("{prefix}_{name}", span = prefix.span());
+        let (get, get_ref, get_mut) = (method("get"), method("ref"), method("mut"));
+        let (maybe_ref, maybe_mut) = (method("maybe_ref"), method("maybe_mut"));
+        let (set, take, remove) = (method("set"), method("take"), method("remove"));
+        quote! {
+            /// Get a clone of the field of type `T`.
+            #[inline]
+            pub fn #get<T>(&self) -> T
+            where
+                Self: ::certain_map::Param<T>,
             {
-                let ty = &field.ty;
-                let name = field.ident.as_ref().unwrap();
-                let occupied = IdentOrTokens::from(occupied_type(ty));
-                let vacancy = IdentOrTokens::from(vacancy_type());
+                ::certain_map::Param::<T>::param(self)
+            }
 
-                let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
-                let generic_types_occupied = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+            /// Borrow the field of type `T`.
+            #[inline]
+            pub fn #get_ref<T>(&self) -> &T
+            where
+                Self: ::certain_map::ParamRef<T>,
+            {
+                ::certain_map::ParamRef::<T>::param_ref(self)
+            }
 
-                let generic_types_ignored2 = IgnoreIter::new(generic_types.iter(), idx);
-                let generic_types_occupied2 =
-                    ReplaceIter::new(generic_types.iter(), idx, &occupied);
+            /// Mutably borrow the field of type `T`.
+            #[inline]
+            pub fn #get_mut<T>(&mut self) -> &mut T
+            where
+                Self: ::certain_map::ParamMut<T>,
+            {
+                ::certain_map::ParamMut::<T>::param_mut(self)
+            }
 
-                let generic_types_ignored3 = IgnoreIter::new(generic_types.iter(), idx);
-                let generic_types_vacancy = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
-                tokens.extend(quote_spanned! {
-                self.span =>
-                    impl<#(#generic_types_ignored),*> ::certain_map::Param<#ty> for #ident<#(#generic_types_occupied),*> {
-                        #[inline]
-                        fn param(&self) -> #ty {
-                            #[allow(clippy::clone_on_copy)]
-                            self.#name.0.clone()
-                        }
-                    }
-                    impl<#(#generic_types_ignored2),*> ::certain_map::Param<Option<#ty>> for #ident<#(#generic_types_occupied2),*> {
-                        #[inline]
-                        fn param(&self) -> Option<#ty> {
-                            #[allow(clippy::clone_on_copy)]
-                            Some(self.#name.0.clone())
-                        }
-                    }
-                    impl<#(#generic_types_ignored3),*> ::certain_map::Param<Option<#ty>> for #ident<#(#generic_types_vacancy),*> {
-                        #[inline]
-                        fn param(&self) -> Option<#ty> {
-                            None
-                        }
-                    }
-                });
+            /// Borrow the field of type `T` if it is occupied.
+            #[inline]
+            pub fn #maybe_ref<T>(&self) -> ::std::option::Option<&T>
+            where
+                Self: ::certain_map::ParamMaybeRef<T>,
+            {
+                ::certain_map::ParamMaybeRef::<T>::param_maybe_ref(self)
+            }
+
+            /// Mutably borrow the field of type `T` if it is occupied.
+            #[inline]
+            pub fn #maybe_mut<T>(&mut self) -> ::std::option::Option<&mut T>
+            where
+                Self: ::certain_map::ParamMaybeMut<T>,
+            {
+                ::certain_map::ParamMaybeMut::<T>::param_maybe_mut(self)
+            }
+
+            /// Set the field of type `T`.
+            #[inline]
+            pub fn #set<T>(self, item: T) -> <Self as ::certain_map::ParamSet<T>>::Transformed
+            where
+                Self: ::certain_map::ParamSet<T>,
+            {
+                ::certain_map::ParamSet::<T>::param_set(self, item)
+            }
+
+            /// Take the field of type `T` out.
+            #[inline]
+            pub fn #take<T>(self) -> (<Self as ::certain_map::ParamTake<T>>::Transformed, T)
+            where
+                Self: ::certain_map::ParamTake<T>,
+            {
+                ::certain_map::ParamTake::<T>::param_take(self)
+            }
+
+            /// Remove the field of type `T` whether it is occupied or not.
+            #[inline]
+            pub fn #remove<T>(self) -> <Self as ::certain_map::ParamRemove<T>>::Transformed
+            where
+                Self: ::certain_map::ParamRemove<T>,
+            {
+                ::certain_map::ParamRemove::<T>::param_remove(self)
             }
         }
+    }
 
-        // impl ParamSet
-        for (idx, field) in self.fields.iter().enumerate() {
-            let ty = &field.ty;
+    // `raw_{name}` / `raw_{name}_mut` of the store, exposing the slot of every field.
+    fn raw_slots_tokens(&self) -> proc_macro2::TokenStream {
+        let methods = self.fields.iter().enumerate().map(|(idx, field)| {
             let name = field.ident.as_ref().unwrap();
-            let occupied = IdentOrTokens::from(occupied_type(ty));
-            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &occupied);
-            let direct_assign = quote!(#name: ::certain_map::Occupied(item));
-            let assignations = ReplaceIter::new(
-                names.iter().map(|&name| quote!(#name: self.#name)),
-                idx,
-                direct_assign,
-            );
-            tokens.extend(quote_spanned! {
-                self.span =>
-                impl<#(#generic_types),*> ::certain_map::ParamSet<#ty> for #ident<#(#generic_types),*> {
-                    type Transformed = #ident<#(#generic_types_replaced),*>;
+            let ty = self.slot_ty(idx);
+            let slot = self.slot_place(quote!(self), idx, false);
+            let slot_mut = self.slot_place(quote!(self), idx, true);
+            let getter = quote::format_ident!("raw_{}", name.unraw());
+            let getter_mut = quote::format_ident!("raw_{}_mut", name.unraw());
+            let doc = format!("The slot of `{name}`, initialized only when the field is occupied.");
+            // Union fields need unsafe to be borrowed, which is fine since every member is
+            // `MaybeUninit`.
+            let (slot, slot_mut) = match self.fields_slot[idx] {
+                Some(_) => (
+                    quote!(unsafe { &*#slot }),
+                    quote!(unsafe { &mut *#slot_mut }),
+                ),
+                None => (quote!(&#slot), quote!(&mut #slot_mut)),
+            };
+            // `raw__0` of tuple structs is not snake case.
+            quote! {
+                #[doc = #doc]
+                #[inline]
+                #[allow(non_snake_case)]
+                pub fn #getter(&self) -> &::std::mem::MaybeUninit<#ty> {
+                    #slot
+                }
+                #[doc = #doc]
+                ///
+                /// # Safety
+                /// While the store keeps the `#[persistent]` fields left by the last handler, it
+                /// drops them on `handler()` and on its own drop, so the slots of those fields
+                /// must stay initialized with values owned by this store until then.
+                #[inline]
+                #[allow(non_snake_case)]
+                pub unsafe fn #getter_mut(&mut self) -> &mut ::std::mem::MaybeUninit<#ty> {
+                    #slot_mut
+                }
+            }
+        });
+        quote!(#(#methods)*)
+    }
+
+    // Wrap a slot type or value into `CachePadded` if it is padded.
+    fn padded_tokens(padded: bool, tokens: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+        match padded {
+            true => quote!(::certain_map::CachePadded<#tokens>),
+            false => tokens,
+        }
+    }
+
+    fn padded_value_tokens(
+        padded: bool,
+        tokens: proc_macro2::TokenStream,
+    ) -> proc_macro2::TokenStream {
+        match padded {
+            true => quote!(::certain_map::CachePadded(#tokens)),
+            false => tokens,
+        }
+    }
+
+    // The union slots with the indexes of the fields sharing them, in declaration order.
+    fn slot_groups(&self) -> Vec<(&Ident, Vec<usize>)> {
+        let mut groups: Vec<(&Ident, Vec<usize>)> = Vec::new();
+        for (idx, slot) in self.fields_slot.iter().enumerate() {
+            let slot = match slot {
+                Some(slot) => slot,
+                None => continue,
+            };
+            match groups.iter_mut().find(|(s, _)| *s == slot) {
+                Some((_, members)) => members.push(idx),
+                None => groups.push((slot, vec![idx])),
+            }
+        }
+        groups
+    }
+
+    // Assign the union slots. Fields of a one_of group share the slot of the group. Other
+    // fields are put into the first group whose members all conflict with them, since at most
+    // one field of such a group is occupied at any time, and a group of one keeps its own slot.
+    // Padded fields only overlap when all the fields are aligned.
+    fn overlap_slots(
+        fields: &[Field],
+        fields_one_of: &[Option<Ident>],
+        fields_conflicts: &[Vec<usize>],
+        fields_padded: &[bool],
+        align_fields: bool,
+    ) -> Vec<Option<Ident>> {
+        let mut overlaps: Vec<Vec<usize>> = Vec::new();
+        for idx in 0..fields.len() {
+            if fields_one_of[idx].is_some()
+                || fields_conflicts[idx].is_empty()
+                || (fields_padded[idx] && !align_fields)
+            {
+                continue;
+            }
+            match overlaps
+                .iter_mut()
+                .find(|members| members.iter().all(|m| fields_conflicts[idx].contains(m)))
+            {
+                Some(members) => members.push(idx),
+                None => overlaps.push(vec![idx]),
+            }
+        }
+        let mut slots = fields_one_of.to_vec();
+        for members in overlaps.iter().filter(|members| members.len() > 1) {
+            let first = fields[members[0]].ident.as_ref().unwrap();
+            let slot = quote::format_ident!("__overlap_{}", first.unraw());
+            for &idx in members {
+                slots[idx] = Some(slot.clone());
+            }
+        }
+        slots
+    }
+
+    // The `#[one_of(group)]` groups with the indexes of their fields, in declaration order.
+    fn one_of_groups(&self) -> Vec<(&Ident, Vec<usize>)> {
+        let mut groups: Vec<(&Ident, Vec<usize>)> = Vec::new();
+        for (idx, group) in self.fields_one_of.iter().enumerate() {
+            let group = match group {
+                Some(group) => group,
+                None => continue,
+            };
+            match groups.iter_mut().find(|(g, _)| *g == group) {
+                Some((_, members)) => members.push(idx),
+                None => groups.push((group, vec![idx])),
+            }
+        }
+        groups
+    }
+
+    // `const FIELDS: &[FieldInfo]` describing the fields in declaration order.
+    fn fields_const_tokens(&self) -> proc_macro2::TokenStream {
+        let names = self.fields.iter().map(|f| f.ident.as_ref().unwrap());
+        let types = self.fields.iter().map(|f| &f.ty);
+        let types2 = (0..self.fields.len()).map(|idx| self.slot_ty(idx));
+        let indexes = 0..self.fields.len();
+        quote_spanned! {
+            self.span =>
+                /// Static information about the fields in declaration order.
+                pub const FIELDS: &'static [::certain_map::FieldInfo] = &[
+                    #(::certain_map::FieldInfo {
+                        name: ::std::stringify!(#names),
+                        type_name: ::std::stringify!(#types),
+                        size: ::std::mem::size_of::<#types2>(),
+                        index: #indexes,
+                    },)*
+                ];
+        }
+    }
+
+    // The counters of `vacancy_stats()` in the store, with the `vacancy-stats` feature.
+    fn vacancy_stats_tokens(&self) -> proc_macro2::TokenStream {
+        if !VACANCY_STATS {
+            return quote!();
+        }
+        let field_count = self.fields.len();
+        let names = self.fields.iter().map(|f| f.ident.as_ref().unwrap());
+        let indexes = 0..field_count;
+        let counters = repeat_tokens(
+            quote!(::std::sync::atomic::AtomicUsize::new(0)),
+            field_count,
+        );
+        quote_spanned! {
+            self.span =>
+                #[doc(hidden)]
+                #[inline]
+                pub fn __vacancy_counters() -> &'static [::std::sync::atomic::AtomicUsize; #field_count] {
+                    static COUNTERS: [::std::sync::atomic::AtomicUsize; #field_count] = [#(#counters),*];
+                    &COUNTERS
+                }
+                /// The number of `ParamMaybeRef` / `ParamMaybeMut` calls on every field which
+                /// found it vacant, in declaration order, counted for all instances of the map.
+                pub fn vacancy_stats() -> [::certain_map::VacancyStat; #field_count] {
+                    let counters = Self::__vacancy_counters();
+                    [#(::certain_map::VacancyStat {
+                        name: ::std::stringify!(#names),
+                        misses: counters[#indexes].load(::std::sync::atomic::Ordering::Relaxed),
+                    }),*]
+                }
+                /// Reset the counters of `vacancy_stats()`.
+                pub fn reset_vacancy_stats() {
+                    for counter in Self::__vacancy_counters() {
+                        counter.store(0, ::std::sync::atomic::Ordering::Relaxed);
+                    }
+                }
+        }
+    }
+
+    // Count `value` in `vacancy_stats()` if it is `None`, with the `vacancy-stats` feature.
+    fn count_vacancy_tokens(
+        &self,
+        idx: usize,
+        value: proc_macro2::TokenStream,
+    ) -> proc_macro2::TokenStream {
+        if !VACANCY_STATS {
+            return value;
+        }
+        let ident = &self.ident;
+        let store = match self.style {
+            GenStyle::PreFilled => quote!(#ident),
+            GenStyle::Unfilled => {
+                let vacancy_types =
+                    repeat_tokens(quote!(::certain_map::Vacancy), self.fields.len());
+                quote!(#ident::<#(#vacancy_types),*>)
+            }
+        };
+        quote!(::certain_map::__count_vacancy(#value, &#store::__vacancy_counters()[#idx]))
+    }
 
-                    #[inline]
-                    fn param_set(self, item: #ty) -> Self::Transformed {
-                        #ident {
-                            #(#assignations),*
-                        }
-                    }
+    // An enum over all the states of the handler with conversions from and to the typed
+    // handlers. The variant is named by the occupied fields, or `Empty`.
+    fn state_enum_tokens(
+        &self,
+        tokens: &mut proc_macro2::TokenStream,
+        enum_ident: &Ident,
+        handler_ident: &Ident,
+        handler_vis: &Visibility,
+    ) {
+        let mut variants = Vec::new();
+        let mut variant_names = Vec::new();
+        let mut handler_types = Vec::new();
+        for mask in 0..1usize << self.fields.len() {
+            let occupied: Vec<_> = (0..self.fields.len())
+                .filter(|idx| mask & (1 << idx) != 0)
+                .map(|idx| self.fields[idx].ident.as_ref().unwrap().to_string())
+                .collect();
+            let name = if occupied.is_empty() {
+                "Empty".to_string()
+            } else {
+                occupied.join("__")
+            };
+            let states = (0..self.fields.len()).map(|idx| {
+                if mask & (1 << idx) != 0 {
+                    occupied_m_type()
+                } else {
+                    vacancy_type()
                 }
             });
+            handler_types.push(quote!(#handler_ident<'a, #(#states),*>));
+            variants.push(Ident::new(&name, self.span));
+            variant_names.push(name);
         }
+        let debug = if self.has_derive("Debug") {
+            quote!(#[derive(Debug)])
+        } else {
+            quote!()
+        };
 
-        // impl ParamRemove
-        for (idx, field) in self.fields.iter().enumerate() {
-            let ty = &field.ty;
-            let name = field.ident.as_ref().unwrap();
-            let vacancy = IdentOrTokens::from(vacancy_type());
-            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &vacancy);
-            let direct_assign = quote!(#name: ::certain_map::Vacancy);
-            let assignations = ReplaceIter::new(
-                names.iter().map(|&name| quote!(#name: self.#name)),
-                idx,
-                direct_assign,
-            );
-            tokens.extend(quote_spanned! {
-                self.span =>
-                impl<#(#generic_types),*> ::certain_map::ParamRemove<#ty> for #ident<#(#generic_types),*> {
-                    type Transformed = #ident<#(#generic_types_replaced),*>;
-
+        tokens.extend(quote_spanned! {
+            self.span =>
+                #[allow(non_camel_case_types)]
+                #debug
+                #handler_vis enum #enum_ident<'a> {
+                    #(#variants(#handler_types),)*
+                }
+                impl #enum_ident<'_> {
+                    /// The name of the current state.
                     #[inline]
-                    fn param_remove(self) -> Self::Transformed {
-                        #ident {
-                            #(#assignations),*
+                    pub const fn state_name(&self) -> &'static str {
+                        match self {
+                            #(Self::#variants(_) => #variant_names,)*
                         }
                     }
                 }
+        });
+        for (variant, handler_ty) in variants.iter().zip(handler_types.iter()) {
+            tokens.extend(quote_spanned! {
+                self.span =>
+                    impl<'a> ::std::convert::From<#handler_ty> for #enum_ident<'a> {
+                        #[inline]
+                        fn from(handler: #handler_ty) -> Self {
+                            Self::#variant(handler)
+                        }
+                    }
+                    impl<'a> ::std::convert::TryFrom<#enum_ident<'a>> for #handler_ty {
+                        type Error = #enum_ident<'a>;
+                        #[inline]
+                        fn try_from(value: #enum_ident<'a>) -> Result<Self, Self::Error> {
+                            match value {
+                                #enum_ident::#variant(handler) => Ok(handler),
+                                #[allow(unreachable_patterns)]
+                                other => Err(other),
+                            }
+                        }
+                    }
             });
         }
+    }
 
-        // impl ParamTake
-        for (idx, field) in self.fields.iter().enumerate() {
-            let ty = &field.ty;
-            let name = field.ident.as_ref().unwrap();
-            let generic_types_ignored = IgnoreIter::new(generic_types.iter(), idx);
-            let occupied = IdentOrTokens::from(occupied_type(ty));
-            let generic_types_replaced = ReplaceIter::new(generic_types.iter(), idx, &occupied);
+    // The definitions of the `#[partition(..)]` maps, with the fields and derives of this map.
+    fn partition_definitions(&self) -> Vec<ItemStruct> {
+        let derives: Vec<_> = self
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("derive"))
+            .collect();
+        self.partitions
+            .iter()
+            .map(|partition| {
+                let ident = &partition.ident;
+                let vis = &self.vis;
+                let fields = partition.fields.iter().map(|&idx| &self.fields[idx]);
+                syn::parse_quote! {
+                    #(#derives)*
+                    #vis struct #ident {
+                        #(#fields,)*
+                    }
+                }
+            })
+            .collect()
+    }
 
-            let vacancy = IdentOrTokens::from(vacancy_type());
-            let generic_types_replaced_transformed =
-                ReplaceIter::new(generic_types.iter(), idx, &vacancy);
-            let direct_assign = quote!(#name: ::certain_map::Vacancy);
-            let assignations = ReplaceIter::new(
-                names.iter().map(|&name| quote!(#name: self.#name)),
-                idx,
-                direct_assign,
-            );
-            let removed_name = names[idx];
-            let removed = quote!(self.#removed_name);
+    // Whether the map has `#[derive(..)]` containing the given trait.
+    fn has_derive(&self, name: &str) -> bool {
+        self.attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("derive"))
+            .filter_map(|attr| {
+                attr.parse_args_with(Punctuated::<syn::Path, Token![,]>::parse_terminated)
+                    .ok()
+            })
+            .any(|paths| paths.iter().any(|path| path.is_ident(name)))
+    }
+
+    // impl Param<(A, B, ..)> for `self_ty` when it implements Param for every element.
+    // Arities that clash with a tuple typed field are skipped to keep the impls coherent.
+    fn tuple_param_tokens(
+        &self,
+        impl_generics: proc_macro2::TokenStream,
+        self_ty: proc_macro2::TokenStream,
+        bounds: proc_macro2::TokenStream,
+        cloneable: usize,
+    ) -> proc_macro2::TokenStream {
+        let mut tokens = proc_macro2::TokenStream::new();
+        for arity in 2..=cloneable.min(TUPLE_PARAM_MAX) {
+            let clash = self
+                .fields
+                .iter()
+                .any(|field| matches!(&field.ty, Type::Tuple(t) if t.elems.len() == arity));
+            if clash {
+                continue;
+            }
+            let elems: Vec<_> = (0..arity)
+                .map(|idx| quote::format_ident!("_CMP_{idx}"))
+                .collect();
+            let reads = repeat_tokens(quote!(::certain_map::Param::param(self)), arity);
             tokens.extend(quote_spanned! {
                 self.span =>
-                impl<#(#generic_types_ignored),*> ::certain_map::ParamTake<#ty> for #ident<#(#generic_types_replaced),*> {
-                    type Transformed = #ident<#(#generic_types_replaced_transformed),*>;
-
-                    #[inline]
-                    fn param_take(self) -> (Self::Transformed, #ty) {
-                        let after_remove = #ident {
-                            #(#assignations),*
-                        };
-                        (after_remove, #removed.0)
+                    #[allow(non_camel_case_types)]
+                    impl<#impl_generics #(#elems),*> ::certain_map::Param<(#(#elems,)*)> for #self_ty
+                    where
+                        #bounds
+                        #(Self: ::certain_map::Param<#elems>,)*
+                    {
+                        #[inline]
+                        fn param(&self) -> (#(#elems,)*) {
+                            (#(#reads,)*)
+                        }
                     }
-                }
             });
         }
+        tokens
+    }
+
+    // With `#[derive(Copy)]`, fail compilation if any field is not Copy.
+    fn copy_assert_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        if !self.has_derive("Copy") {
+            return;
+        }
+        let types = self.fields.iter().map(|f| &f.ty);
+        tokens.extend(quote_spanned! {
+            self.span =>
+                const _: () = {
+                    fn assert_copy<T: ::std::marker::Copy>() {}
+                    #[allow(dead_code)]
+                    fn assert_fields() {
+                        #(assert_copy::<#types>();)*
+                    }
+                };
+        });
+    }
+
+    // Index of the field named `name` referred by an attribute of `field`.
+    fn field_index(fields: &[Field], field: &Field, name: &Ident) -> syn::Result<usize> {
+        match fields.iter().position(|f| f.ident.as_ref() == Some(name)) {
+            Some(idx) if fields[idx].ident != field.ident => Ok(idx),
+            Some(_) => Err(syn::Error::new(
+                name.span(),
+                "a field can not refer to itself",
+            )),
+            None => Err(syn::Error::new(
+                name.span(),
+                format!("no field named `{name}`"),
+            )),
+        }
+    }
+
+    // The slots of `ParamSet` for the field at idx, with the fields it requires fixed to
+    // `occupied` and the fields it conflicts with fixed to `Vacancy`. Returns the remaining
+    // generic types and the slots.
+    fn set_slots<'a>(
+        &self,
+        idx: usize,
+        generic_types: &'a [IdentOrTokens],
+        occupied: impl Fn(usize) -> proc_macro2::TokenStream,
+    ) -> (Vec<&'a IdentOrTokens>, Vec<IdentOrTokens>) {
+        let requires = &self.fields_requires[idx];
+        let conflicts = &self.fields_conflicts[idx];
+        let free = generic_types
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !requires.contains(i) && !conflicts.contains(i))
+            .map(|(_, ty)| ty)
+            .collect();
+        let slots = generic_types
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| {
+                if requires.contains(&i) {
+                    IdentOrTokens::from(occupied(i))
+                } else if conflicts.contains(&i) {
+                    IdentOrTokens::from(vacancy_type())
+                } else {
+                    IdentOrTokens::from(ty.to_token_stream())
+                }
+            })
+            .collect();
+        (free, slots)
+    }
+
+    // Whether the field at idx has `#[ensure(..)]` containing the given trait.
+    fn field_ensures(&self, idx: usize, name: &str) -> bool {
+        self.fields_meta[idx]
+            .iter()
+            .flat_map(|x| x.iter())
+            .any(|meta| matches!(meta, Meta::Path(path) if path.is_ident(name)))
     }
 
     fn find_path_attr(attrs: &[Attribute], ident: &str) -> Option<(usize, Ident)> {
@@ -825,13 +6316,525 @@ impl CMap {
 
 impl ToTokens for CMap {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let mut output = proc_macro2::TokenStream::new();
         match self.style {
-            GenStyle::PreFilled => self.to_pre_filled_tokens(tokens),
-            GenStyle::Unfilled => self.to_unfilled_tokens(tokens),
+            GenStyle::PreFilled => self.to_pre_filled_tokens(&mut output),
+            GenStyle::Unfilled => self.to_unfilled_tokens(&mut output),
+        }
+        self.deny_large_tokens(&mut output);
+        self.assert_send_tokens(&mut output);
+        if self.codegen_stats {
+            eprintln!("{}", self.codegen_stats_summary(&output));
+        }
+        if TEST_GEN && matches!(self.style, GenStyle::PreFilled) {
+            output.extend(self.test_gen_tokens());
+        }
+        tokens.extend(output);
+    }
+}
+
+// Arguments of `#[cx_service(needs(..), provides(..), inner(..))]`.
+#[derive(Default)]
+struct CxServiceArgs {
+    needs: Vec<Type>,
+    provides: Vec<Type>,
+    inner: Vec<Type>,
+}
+
+impl Parse for CxServiceArgs {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        let mut args = CxServiceArgs::default();
+        for meta in Punctuated::<Meta, Token![,]>::parse_terminated(input)? {
+            let list = meta.require_list()?;
+            let slot = if list.path.is_ident("needs") {
+                &mut args.needs
+            } else if list.path.is_ident("provides") {
+                &mut args.provides
+            } else if list.path.is_ident("inner") {
+                &mut args.inner
+            } else {
+                return Err(syn::Error::new(
+                    list.path.span(),
+                    "cx_service only supports needs(..), provides(..) and inner(..)",
+                ));
+            };
+            slot.extend(list.parse_args_with(Punctuated::<Type, Token![,]>::parse_terminated)?);
+        }
+        Ok(args)
+    }
+}
+
+impl CxServiceArgs {
+    fn expand(self, mut item: syn::ItemImpl) -> Result<syn::ItemImpl> {
+        const EXPECTED: &str = "expected `impl Service<(Req, CX)> for ..`";
+        let trait_path = match &item.trait_ {
+            Some((None, path, _)) => path.clone(),
+            _ => return Err(syn::Error::new(item.self_ty.span(), EXPECTED)),
+        };
+        let (req, cx) = match trait_path.segments.last().map(|s| &s.arguments) {
+            Some(syn::PathArguments::AngleBracketed(args)) => match args.args.first() {
+                Some(syn::GenericArgument::Type(Type::Tuple(tuple))) if tuple.elems.len() == 2 => {
+                    (tuple.elems[0].clone(), tuple.elems[1].clone())
+                }
+                _ => return Err(syn::Error::new(trait_path.span(), EXPECTED)),
+            },
+            _ => return Err(syn::Error::new(trait_path.span(), EXPECTED)),
+        };
+        let cx_ident = match &cx {
+            Type::Path(path) if path.qself.is_none() => path.path.get_ident().cloned(),
+            _ => None,
+        }
+        .ok_or_else(|| syn::Error::new(cx.span(), "the context must be a generic type"))?;
+        if !item
+            .generics
+            .type_params()
+            .any(|param| param.ident == cx_ident)
+        {
+            item.generics
+                .params
+                .push(syn::GenericParam::Type(cx_ident.into()));
+        }
+
+        let mut predicates: Vec<syn::WherePredicate> = Vec::new();
+        for ty in self.needs.iter() {
+            predicates
+                .push(syn::parse_quote_spanned!(ty.span() => #cx: ::certain_map::ParamRef<#ty>));
+        }
+        let mut provided = quote!(#cx);
+        for ty in self.provides.iter() {
+            predicates.push(
+                syn::parse_quote_spanned!(ty.span() => #provided: ::certain_map::ParamSet<#ty>),
+            );
+            provided = quote!(<#provided as ::certain_map::ParamSet<#ty>>::Transformed);
+        }
+        for ty in self.inner.iter() {
+            let mut inner_path = trait_path.clone();
+            if let Some(segment) = inner_path.segments.last_mut() {
+                segment.arguments =
+                    syn::PathArguments::AngleBracketed(syn::parse_quote!(<(#req, #provided)>));
+            }
+            predicates.push(syn::parse_quote_spanned!(ty.span() => #ty: #inner_path));
+        }
+        item.generics
+            .make_where_clause()
+            .predicates
+            .extend(predicates);
+        Ok(item)
+    }
+}
+
+// A plain struct whose fields are read from or written into a context by the derives.
+struct CxView {
+    ident: Ident,
+    generics: syn::Generics,
+    members: Vec<syn::Member>,
+    types: Vec<Type>,
+    // The `T` of the `Option<T>` fields marked `#[cx(maybe)]`.
+    maybe: Vec<Option<Type>>,
+}
+
+impl Parse for CxView {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        let input: syn::DeriveInput = input.parse()?;
+        let fields = match input.data {
+            syn::Data::Struct(data) => data.fields,
+            _ => {
+                return Err(syn::Error::new(
+                    input.ident.span(),
+                    "only structs are supported",
+                ))
+            }
+        };
+        let members = fields
+            .iter()
+            .enumerate()
+            .map(|(idx, field)| match &field.ident {
+                Some(ident) => syn::Member::Named(ident.clone()),
+                None => syn::Member::Unnamed(syn::Index {
+                    index: idx as u32,
+                    span: field.ty.span(),
+                }),
+            })
+            .collect();
+        let mut maybe = Vec::with_capacity(fields.len());
+        for field in fields.iter() {
+            let mut inner = None;
+            for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("cx")) {
+                attr.parse_nested_meta(|meta| {
+                    if !meta.path.is_ident("maybe") {
+                        return Err(meta.error("expected `maybe`"));
+                    }
+                    inner = Some(option_inner(&field.ty).cloned().ok_or_else(|| {
+                        syn::Error::new(
+                            field.ty.span(),
+                            "`#[cx(maybe)]` needs an `Option<T>` field",
+                        )
+                    })?);
+                    Ok(())
+                })?;
+            }
+            maybe.push(inner);
+        }
+        Ok(CxView {
+            ident: input.ident,
+            generics: input.generics,
+            members,
+            types: fields.into_iter().map(|field| field.ty).collect(),
+            maybe,
+        })
+    }
+}
+
+impl CxView {
+    fn to_from_context_tokens(&self) -> proc_macro2::TokenStream {
+        let ident = &self.ident;
+        let (impl_generics, ty_generics, where_clause) = self.generics.split_for_impl();
+        let members = &self.members;
+        let mut bounds = Vec::with_capacity(self.types.len());
+        let mut values = Vec::with_capacity(self.types.len());
+        for (ty, maybe) in self.types.iter().zip(self.maybe.iter()) {
+            match maybe {
+                Some(inner) => {
+                    bounds.push(quote_spanned!(ty.span() => ::certain_map::ParamMaybeRef<#inner>));
+                    values.push(quote_spanned!(ty.span() =>
+                        ::certain_map::ParamMaybeRef::<#inner>::param_maybe_ref(cx).cloned()));
+                }
+                None => {
+                    bounds.push(quote_spanned!(ty.span() => ::certain_map::ParamRef<#ty>));
+                    values.push(quote_spanned!(ty.span() =>
+                        ::std::clone::Clone::clone(::certain_map::ParamRef::<#ty>::param_ref(cx))));
+                }
+            }
+        }
+        quote! {
+            impl #impl_generics #ident #ty_generics #where_clause {
+                /// Build from the fields of the context.
+                #[inline]
+                #[allow(clippy::clone_on_copy)]
+                pub fn from_cx<CX>(cx: &CX) -> Self
+                where
+                    CX: #(#bounds +)*,
+                {
+                    Self {
+                        #(#members: #values,)*
+                    }
+                }
+            }
+        }
+    }
+
+    fn to_into_context_tokens(&self) -> proc_macro2::TokenStream {
+        let ident = &self.ident;
+        let (impl_generics, ty_generics, where_clause) = self.generics.split_for_impl();
+        let members = &self.members;
+        let types = &self.types;
+        let bindings: Vec<_> = (0..self.types.len())
+            .map(|idx| quote::format_ident!("__field{idx}"))
+            .collect();
+        // The context type after setting each field.
+        let mut transformed = vec![quote!(CX)];
+        for ty in self.types.iter() {
+            let last = transformed.last().unwrap();
+            transformed.push(quote!(<#last as ::certain_map::ParamSet<#ty>>::Transformed));
+        }
+        let output = transformed.last().unwrap();
+        let inputs = &transformed[..self.types.len()];
+        quote! {
+            impl #impl_generics #ident #ty_generics #where_clause {
+                /// Set every field into the context.
+                #[inline]
+                pub fn into_cx<CX>(self, cx: CX) -> #output
+                where
+                    #(#inputs: ::certain_map::ParamSet<#types>,)*
+                {
+                    let Self { #(#members: #bindings,)* } = self;
+                    #(let cx = ::certain_map::ParamSet::<#types>::param_set(cx, #bindings);)*
+                    cx
+                }
+            }
+        }
+    }
+
+    fn to_try_from_context_tokens(&self) -> proc_macro2::TokenStream {
+        let ident = &self.ident;
+        let (impl_generics, ty_generics, where_clause) = self.generics.split_for_impl();
+        let members = &self.members;
+        let bindings: Vec<_> = (0..self.types.len())
+            .map(|idx| quote::format_ident!("__field{idx}"))
+            .collect();
+        let mut bounds = Vec::with_capacity(self.types.len());
+        let mut reads = Vec::with_capacity(self.types.len());
+        let mut values = Vec::with_capacity(self.types.len());
+        for (((ty, maybe), member), binding) in self
+            .types
+            .iter()
+            .zip(self.maybe.iter())
+            .zip(members)
+            .zip(bindings.iter())
+        {
+            let inner = maybe.as_ref().unwrap_or(ty);
+            bounds.push(quote_spanned!(ty.span() => ::certain_map::ParamMaybeRef<#inner>));
+            let read = quote_spanned!(ty.span() =>
+                ::certain_map::ParamMaybeRef::<#inner>::param_maybe_ref(cx).cloned());
+            if maybe.is_some() {
+                reads.push(quote!(let #binding = #read;));
+                values.push(quote!(#binding));
+            } else {
+                let name = match member {
+                    syn::Member::Named(ident) => ident.to_string(),
+                    syn::Member::Unnamed(_) => ty.to_token_stream().to_string(),
+                };
+                reads.push(quote! {
+                    let #binding = #read;
+                    if #binding.is_none() {
+                        missing.push(#name);
+                    }
+                });
+                values.push(quote!(#binding.unwrap()));
+            }
+        }
+        quote! {
+            impl #impl_generics #ident #ty_generics #where_clause {
+                /// Build from the fields of the context, or list the fields which are absent.
+                #[allow(clippy::clone_on_copy)]
+                pub fn try_from_cx<CX>(cx: &CX) -> ::std::result::Result<Self, ::certain_map::MissingParam>
+                where
+                    CX: #(#bounds +)*,
+                {
+                    let mut missing = ::std::vec::Vec::new();
+                    #(#reads)*
+                    if !missing.is_empty() {
+                        return ::std::result::Result::Err(::certain_map::MissingParam { missing });
+                    }
+                    ::std::result::Result::Ok(Self {
+                        #(#members: #values,)*
+                    })
+                }
+            }
+        }
+    }
+}
+
+// The pointer and the `T` of a `std::sync::Weak<T>` or `std::rc::Weak<T>` field. The module has
+// to be written out, a bare `Weak<T>` can be either.
+fn weak_pointee(ty: &Type) -> Option<(CloneStrategy, Type)> {
+    let path = match ty {
+        Type::Path(path) if path.qself.is_none() => &path.path,
+        _ => return None,
+    };
+    let names: Vec<_> = path.segments.iter().map(|s| s.ident.to_string()).collect();
+    let strategy = match names.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["std" | "alloc", "sync", "Weak"] | ["sync", "Weak"] => CloneStrategy::Arc,
+        ["std" | "alloc", "rc", "Weak"] | ["rc", "Weak"] => CloneStrategy::Rc,
+        _ => return None,
+    };
+    Some((strategy, first_type_argument(ty)?.clone()))
+}
+
+// The first generic type argument of a type path, like `T` of `Vec<T>`.
+fn first_type_argument(ty: &Type) -> Option<&Type> {
+    let path = match ty {
+        Type::Path(path) if path.qself.is_none() => path,
+        _ => return None,
+    };
+    match &path.path.segments.last()?.arguments {
+        syn::PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+            syn::GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+// The map of `#[derive(CertainMapFields)]` and the conversions between it and the struct.
+fn certain_map_fields_tokens(input: syn::DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let span = input.ident.span();
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            input.generics.span(),
+            "CertainMapFields does not support generic structs",
+        ));
+    }
+    let fields = match input.data {
+        syn::Data::Struct(data) => data.fields,
+        _ => return Err(syn::Error::new(span, "only structs are supported")),
+    };
+
+    let mut name = quote::format_ident!("{}Map", input.ident);
+    let mut attrs = Vec::new();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("certain_map"))
+    {
+        for meta in attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)? {
+            match meta {
+                Meta::NameValue(name_val) if name_val.path.is_ident("name") => {
+                    name = syn::parse2(name_val.value.into_token_stream())?;
+                }
+                meta => attrs.push(syn::parse_quote!(#[#meta])),
+            }
+        }
+    }
+
+    let members: Vec<_> = fields
+        .iter()
+        .enumerate()
+        .map(|(idx, field)| match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(syn::Index {
+                index: idx as u32,
+                span: field.ty.span(),
+            }),
+        })
+        .collect();
+    let types: Vec<_> = fields.iter().map(|field| field.ty.clone()).collect();
+    let bindings: Vec<_> = (0..types.len())
+        .map(|idx| quote::format_ident!("__field{idx}"))
+        .collect();
+
+    // the attributes of the fields belong to the struct, like its serde attributes
+    let mut map_fields = fields;
+    for field in map_fields.iter_mut() {
+        field.attrs.clear();
+        field.vis = Visibility::Inherited;
+    }
+    let mut definition = ItemStruct {
+        attrs,
+        vis: input.vis,
+        struct_token: Default::default(),
+        ident: name.clone(),
+        generics: Default::default(),
+        fields: map_fields,
+        semi_token: None,
+    };
+    name_positional_fields(&mut definition);
+    let cmap = CMap::from_struct(definition, span)?;
+    if matches!(cmap.style, GenStyle::Unfilled) {
+        return Err(syn::Error::new(
+            span,
+            "CertainMapFields only generates prefilled maps",
+        ));
+    }
+
+    let ident = &input.ident;
+    let generic_ident = quote::format_ident!("{name}HandlerIn");
+    let owned_ident = quote::format_ident!("{name}OwnedHandler");
+    let occupied_types: Vec<_> = repeat_tokens(occupied_m_type(), types.len()).collect();
+    let mut tokens = cmap.into_token_stream();
+    tokens.extend(quote_spanned! {
+        span =>
+            impl ::std::convert::From<#ident> for #owned_ident<#(#occupied_types),*> {
+                #[inline]
+                fn from(value: #ident) -> Self {
+                    let #ident { #(#members: #bindings,)* } = value;
+                    let cx = #name::owned_handler();
+                    #(let cx = ::certain_map::ParamSet::<#types>::param_set(cx, #bindings);)*
+                    cx
+                }
+            }
+
+            impl<_CMS: ::certain_map::Storage<#name>> ::std::convert::From<#generic_ident<_CMS, #(#occupied_types),*>> for #ident {
+                #[inline]
+                fn from(cx: #generic_ident<_CMS, #(#occupied_types),*>) -> Self {
+                    #(let (cx, #bindings) = ::certain_map::ParamTake::<#types>::param_take(cx);)*
+                    drop(cx);
+                    #ident {
+                        #(#members: #bindings,)*
+                    }
+                }
+            }
+    });
+    Ok(tokens)
+}
+
+// The `T` of a field typed `Option<T>`.
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let path = match ty {
+        Type::Path(path) => path,
+        _ => return None,
+    };
+    let segment = path.path.segments.last()?;
+    if path.qself.is_some() || segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) if args.args.len() == 1 => {
+            match args.args.first()? {
+                syn::GenericArgument::Type(inner) => Some(inner),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// The prefilled handler type, generic over the storage handle `_CMS` of the store.
+struct HandlerTy<'a> {
+    ident: &'a Ident,
+    store: &'a Ident,
+}
+
+impl<'a> HandlerTy<'a> {
+    fn new(ident: &'a Ident, store: &'a Ident) -> Self {
+        Self { ident, store }
+    }
+
+    // The storage parameter to put in front of generic types in `impl<...>`.
+    fn impl_generics(&self) -> proc_macro2::TokenStream {
+        let store = self.store;
+        quote!(_CMS: ::certain_map::Storage<#store>,)
+    }
+
+    fn ty<T: ToTokens>(&self, generics: impl IntoIterator<Item = T>) -> proc_macro2::TokenStream {
+        let ident = self.ident;
+        let generics = generics.into_iter();
+        quote!(#ident<_CMS, #(#generics),*>)
+    }
+}
+
+// Replace `_` in a type, including the generic arguments of paths like `Result<_, E>`.
+fn replace_infer(ty: &mut Type, with: &Type) {
+    match ty {
+        Type::Infer(_) => *ty = with.clone(),
+        Type::Paren(paren) => replace_infer(&mut paren.elem, with),
+        Type::Group(group) => replace_infer(&mut group.elem, with),
+        Type::Tuple(tuple) => {
+            for elem in tuple.elems.iter_mut() {
+                replace_infer(elem, with);
+            }
+        }
+        Type::Path(path) => {
+            for segment in path.path.segments.iter_mut() {
+                if let syn::PathArguments::AngleBracketed(args) = &mut segment.arguments {
+                    for arg in args.args.iter_mut() {
+                        if let syn::GenericArgument::Type(ty) = arg {
+                            replace_infer(ty, with);
+                        }
+                    }
+                }
+            }
         }
+        _ => {}
     }
 }
 
+// Whether two visibilities are written the same way.
+fn same_vis(a: &Visibility, b: &Visibility) -> bool {
+    a.to_token_stream().to_string() == b.to_token_stream().to_string()
+}
+
+// `iter::repeat_n` is not used to keep the toolchain requirement low.
+fn repeat_tokens(
+    tokens: proc_macro2::TokenStream,
+    n: usize,
+) -> impl Iterator<Item = proc_macro2::TokenStream> {
+    (0..n).map(move |_| tokens.clone())
+}
+
+// The largest tuple supported by `Param<(A, B, ..)>`.
+const TUPLE_PARAM_MAX: usize = 4;
+
 fn generic_type(num: usize) -> Ident {
     quote::format_ident!("_CMT_{num}")
 }
@@ -1,25 +1,92 @@
 // Copyright 2024 ihciah. All Rights Reserved.
 
-use certain_map::{certain_map, Param, ParamRef, ParamRemove, ParamSet, ParamTake};
+use certain_map::{
+    certain_map, CertainMapFields, FromContext, IntoContext, Param, ParamRef, ParamRemove,
+    ParamSet, ParamTake, StoredCtx,
+};
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct UserName(String);
 
 #[derive(Copy, Clone)]
 pub struct UserAge(u8);
 
+// A small typed view of the context, see `AuthView::from_cx` below.
+#[derive(FromContext)]
+pub struct AuthView {
+    name: UserName,
+    #[cx(maybe)]
+    age: Option<UserAge>,
+}
+
+// A bundle of fields produced at once, see `Profile::into_cx` below.
+#[derive(IntoContext)]
+pub struct Profile(UserName, UserAge);
+
+// A conventional context struct, mirrored by the generated `LegacyMap`, see `Legacy::from`
+// below.
+#[derive(CertainMapFields)]
+#[certain_map(name = LegacyMap, full(LegacyMapFull))]
+pub struct Legacy {
+    name: UserName,
+    age: UserAge,
+}
+
+certain_map! {
+    /// Configuration read by every request, see `Settings::global_handler` below.
+    #[global]
+    // Only the store and the handler aliases appear in rustdoc.
+    #[hide_internals]
+    pub struct Settings {
+        region: String,
+    }
+}
+
 certain_map! {
     #[empty(MyCertainMapEmpty)]
     #[full(MyCertainMapFull)]
+    #[state_enum(MyCertainMapAny)]
+    #[ref_view(MyCertainMapView)]
+    #[maybe_view(MyCertainMapMaybeView)]
+    #[method_prefix(cx)]
+    #[cache_key(name)]
     #[derive(Clone)]
     pub struct MyCertainMap {
+        #[ensure(Debug)]
         name: UserName,
         #[ensure(Clone)]
         age: UserAge,
     }
+
+    // Methods of impl blocks are attached to the handlers of the map.
+    impl MyCertainMap {
+        #[requires(name)]
+        pub fn greeting(&self) -> String {
+            format!("hello, {}", ParamRef::<UserName>::param_ref(self).0)
+        }
+
+        #[produces(age)]
+        pub fn with_default_age(self) -> _ {
+            self.param_set(UserAge(18))
+        }
+    }
 }
 
 fn main() {
+    // FIELDS describes the fields without any instance.
+    for field in MyCertainMap::FIELDS {
+        println!(
+            "field {}: {} ({} bytes)",
+            field.name, field.type_name, field.size
+        );
+    }
+    println!(
+        "store: {} bytes, name at {}, age at {}",
+        MyCertainMap::SIZE,
+        MyCertainMap::OFFSET_NAME,
+        MyCertainMap::OFFSET_AGE
+    );
+
     let mut store = MyCertainMap::new();
     let meta = store.handler();
 
@@ -32,10 +99,29 @@ fn main() {
     let meta = meta.param_set(UserName("ihciah".to_string()));
     // Now we can get it with certainty.
     log_username(&meta);
+    // Extract a view with only the fields it needs, optional fields may be absent.
+    let view = AuthView::from_cx(&meta);
+    println!("view: {} {:?}", view.name.0, view.age.map(|age| age.0));
+    // audit() shows which fields are set, with values of fields with #[ensure(Debug)].
+    println!("{:?}", meta.audit());
+    // maybe_view() borrows the fields of any state, vacant ones are None.
+    let MyCertainMapMaybeView { name, age } = meta.maybe_view();
+    println!(
+        "maybe view: {:?} {:?}",
+        name.map(|name| &name.0),
+        age.map(|age| age.0)
+    );
 
     // Fork the store and handler(like Clone).
     let (mut store_forked, state_forked) = meta.fork();
     let meta_forked = unsafe { state_forked.attach(&mut store_forked) };
+    // Or fork into a handler owning a boxed store, which needs no unsafe and is 'static.
+    let meta_owned = meta.fork_owned();
+    // clone_owned() only needs the occupied fields to be Clone, for maps without the derive.
+    let meta_task = meta.clone_owned();
+    std::thread::spawn(move || log_username(&meta_task))
+        .join()
+        .unwrap();
 
     let (meta, removed) = ParamTake::<UserName>::param_take(meta);
     assert_eq!(removed.0, "ihciah");
@@ -43,6 +129,7 @@ fn main() {
     // log_username(&meta);
     // It does not affect forked meta.
     log_username(&meta_forked);
+    log_username(&meta_owned);
 
     // We can also remove a type no matter if it exist.
     let meta = ParamRemove::<UserName>::param_remove(meta);
@@ -50,6 +137,77 @@ fn main() {
     let meta = meta.param_set(UserAge(24));
     // we can get ownership of fields with #[ensure(Clone)]
     log_age(&meta);
+
+    // Reset drops all fields and returns the empty handler on the same store, which is handy
+    // when reusing one store in a loop.
+    let mut meta = meta.reset();
+    for age in 0..2 {
+        let full = meta
+            .param_set(UserAge(age))
+            .param_set(UserName("loop".to_string()));
+        log_age(&full);
+        meta = full.reset();
+    }
+
+    // With #[state_enum(MyCertainMapAny)] the state can be matched at runtime.
+    let any = MyCertainMapAny::from(meta.param_set(UserAge(30)));
+    println!("state: {}", any.state_name());
+    if let MyCertainMapAny::age(meta) = any {
+        log_age(&meta);
+    }
+
+    // Set a bundle of fields in one call.
+    let mut store = MyCertainMap::new();
+    let full: MyCertainMapFull =
+        Profile(UserName("bundle".to_string()), UserAge(1)).into_cx(store.handler());
+    log_username(&full);
+    // Cloneable fields can also be read together as a tuple.
+    let (name, age): (UserName, UserAge) = full.param();
+    println!("bundle: {} {}", name.0, age.0);
+    // A full handler can be destructured with #[ref_view(MyCertainMapView)].
+    let MyCertainMapView { name, age } = full.ref_view();
+    println!("view: {} {}", name.0, age.0);
+    // #[full(MyCertainMapFull)] also names the state, so it can be kept apart from the store.
+    let (mut full_store, full_state): (_, MyCertainMapFullState) = full.fork();
+    log_username(&unsafe { full_state.attach(&mut full_store) });
+
+    // To keep a context in a long-lived struct(like a connection), store an owned handler as
+    // StoredCtx and re-derive handlers from it when needed.
+    let mut stored: StoredCtx<_, _> = MyCertainMap::owned_handler()
+        .param_set(UserName("conn".to_string()))
+        .into();
+    log_username(&stored.handler());
+
+    // With #[method_prefix(cx)] the fields can also be accessed with inherent methods, which
+    // never clash with other traits in scope.
+    let meta = MyCertainMap::owned_handler().cx_set(UserAge(7));
+    println!("prefixed: {}", meta.cx_ref::<UserAge>().0);
+
+    // A plain struct with #[derive(CertainMapFields)] converts to and from its full handler.
+    let legacy = Legacy {
+        name: UserName("legacy".to_string()),
+        age: UserAge(2),
+    };
+    let full = LegacyMapOwnedHandler::from(legacy);
+    log_username(&full);
+    let legacy = Legacy::from(full);
+    println!("legacy: {} {}", legacy.name.0, legacy.age.0);
+
+    // Helpers defined in the macro work on any state with the fields they require.
+    let meta = MyCertainMap::owned_handler()
+        .param_set(UserName("helper".to_string()))
+        .with_default_age();
+    println!("{}", meta.greeting());
+    log_age(&meta);
+    // With #[cache_key(name)] the key of response caches is cloned from the context.
+    let key: MyCertainMapCacheKey = meta.cache_key();
+    println!("cache key: {}", key.name.0);
+
+    // With #[global] a full handler is set once at startup and read from anywhere.
+    let settings = Settings::owned_handler().param_set("eu-west".to_string());
+    assert!(Settings::init_global(settings).is_ok());
+    let region: &String = Settings::global_handler().param_ref();
+    println!("region: {region}");
 }
 
 fn log_username<T: ParamRef<UserName>>(meta: &T) {
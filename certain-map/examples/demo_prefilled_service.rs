@@ -4,10 +4,11 @@
 //! service-async Service).
 //! To pass information across service layers, and make it able to decouple the Context concrete
 //! type, we can use certain_map with param crate.
+//! `demo_service_async` shows the ready-made services of the `service` feature.
 
 use std::{convert::Infallible, future::Future, marker::PhantomData, ops::Add};
 
-use certain_map::{Attach, Fork, Handler};
+use certain_map::{cx_service, Attach, Fork, Handler};
 use certain_map_macros::certain_map;
 use param::{ParamRef, ParamSet};
 
@@ -28,6 +29,8 @@ pub struct RawBeforeMul(u8);
 certain_map! {
     #[empty(MyCertainMapEmpty)]
     #[full(MyCertainMapFull)]
+    // Contexts of multi-threaded runtimes must be Send, and the error names the offending field.
+    #[assert_send]
     #[derive(Clone)]
     pub struct MyCertainMap {
         raw_before_add: RawBeforeAdd,
@@ -36,13 +39,11 @@ certain_map! {
 }
 
 // Define a service that adds 1 to the input number.
+// `#[cx_service]` generates the bounds `Mul2` below writes by hand.
 struct Add1<T>(T);
 
-impl<T, CX> Service<(u8, CX)> for Add1<T>
-where
-    T: Service<(u8, CX::Transformed)>,
-    CX: ParamSet<RawBeforeAdd>,
-{
+#[cx_service(provides(RawBeforeAdd), inner(T))]
+impl<T, CX> Service<(u8, CX)> for Add1<T> {
     type Response = T::Response;
     type Error = T::Error;
 
@@ -76,10 +77,8 @@ where
 // Define a service that prints the context and return the input.
 struct Identical;
 
-impl<CX> Service<(u8, CX)> for Identical
-where
-    CX: ParamRef<RawBeforeAdd> + ParamRef<RawBeforeMul>,
-{
+#[cx_service(needs(RawBeforeAdd, RawBeforeMul))]
+impl<CX> Service<(u8, CX)> for Identical {
     type Response = u8;
     type Error = Infallible;
 
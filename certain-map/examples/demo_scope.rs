@@ -0,0 +1,29 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! This demo shows `scope()` of the store, which creates the handler for an async closure and
+//! keeps the store borrowed until the returned future is done. Run it with `--features scope`.
+
+use certain_map::{certain_map, ParamRef, ParamSet};
+
+#[derive(Clone)]
+pub struct UserName(String);
+
+certain_map! {
+    pub struct MyCertainMap {
+        name: UserName,
+    }
+}
+
+async fn greet<CX: ParamRef<UserName>>(cx: CX) -> String {
+    format!("hello, {}", cx.param_ref().0)
+}
+
+#[tokio::main]
+async fn main() {
+    let mut store = MyCertainMap::new();
+    let greeting = store
+        .scope(async |cx| greet(cx.param_set(UserName("ihciah".to_string()))).await)
+        .await;
+    assert_eq!(greeting, "hello, ihciah");
+    println!("{greeting}");
+}
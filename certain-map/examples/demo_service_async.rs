@@ -0,0 +1,191 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! This demo is used to show the services of the `service` feature, which build the contexts of
+//! service-async stacks. `demo_prefilled_service` writes the same services by hand without
+//! depending on service-async.
+
+use std::{convert::Infallible, future::Future, ops::Add};
+
+use certain_map::{
+    assert_stack_ok, cx_service,
+    service::{ContextService, ForkJoin, Inspect, Inspector, Recycle, Service, SetParam},
+    Attach, Fork,
+};
+use certain_map_macros::certain_map;
+use param::{ParamRef, ParamSet};
+
+#[derive(Clone)]
+pub struct RawBeforeAdd(u8);
+
+#[derive(Clone)]
+pub struct RawBeforeMul(u8);
+
+certain_map! {
+    #[empty(MyCertainMapEmpty)]
+    #[full(MyCertainMapFull)]
+    // Contexts of multi-threaded runtimes must be Send, and the error names the offending field.
+    #[assert_send]
+    #[derive(Clone)]
+    pub struct MyCertainMap {
+        raw_before_add: RawBeforeAdd,
+        raw_before_mul: RawBeforeMul,
+    }
+}
+
+// Define a service that adds 1 to the input number.
+// `#[cx_service]` generates the bounds `Mul2` below writes by hand.
+struct Add1<T>(T);
+
+#[cx_service(provides(RawBeforeAdd), inner(T))]
+impl<T, CX> Service<(u8, CX)> for Add1<T> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    fn call(
+        &self,
+        (num, cx): (u8, CX),
+    ) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        self.0.call((num + 1, cx.param_set(RawBeforeAdd(num))))
+    }
+}
+
+// Define a service that multiplies the input number by 2.
+struct Mul2<T>(T);
+
+impl<T, CX> Service<(u8, CX)> for Mul2<T>
+where
+    T: Service<(u8, CX::Transformed)>,
+    CX: ParamSet<RawBeforeMul>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    fn call(
+        &self,
+        (num, cx): (u8, CX),
+    ) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        self.0.call((num * 2, cx.param_set(RawBeforeMul(num))))
+    }
+}
+
+// Define a service that prints the context and return the input.
+struct Identical;
+
+#[cx_service(needs(RawBeforeAdd, RawBeforeMul))]
+impl<CX> Service<(u8, CX)> for Identical {
+    type Response = u8;
+    type Error = Infallible;
+
+    async fn call(&self, (num, cx): (u8, CX)) -> Result<Self::Response, Self::Error> {
+        println!(
+            "num before add: {}",
+            ParamRef::<RawBeforeAdd>::param_ref(&cx).0
+        );
+        println!(
+            "num before mul: {}",
+            ParamRef::<RawBeforeMul>::param_ref(&cx).0
+        );
+        println!("num: {num}");
+        Ok(num)
+    }
+}
+
+// A service that call inner twice and return the sum.
+// This is to show how to fork context.
+struct DupSvc<T>(T);
+
+impl<T, R, CXIn, CXStore, CXState, Resp, Err> Service<(R, CXIn)> for DupSvc<T>
+where
+    R: Copy,
+    Resp: Add<Output = Resp>,
+    CXIn: Fork<Store = CXStore, State = CXState>,
+    CXStore: 'static,
+    for<'a> CXState: Attach<CXStore>,
+    for<'a> T: Service<(R, <CXState as Attach<CXStore>>::Hdr<'a>), Response = Resp, Error = Err>,
+{
+    type Response = Resp;
+    type Error = Err;
+
+    async fn call(&self, (req, ctx): (R, CXIn)) -> Result<Self::Response, Self::Error> {
+        // fork ctx
+        let (mut store, state) = ctx.fork();
+        let forked_ctx = unsafe { state.attach(&mut store) };
+        let r1 = self.0.call((req, forked_ctx)).await?;
+
+        // fork ctx
+        let (mut store, state) = ctx.fork();
+        let forked_ctx = unsafe { state.attach(&mut store) };
+        let r2 = self.0.call((req, forked_ctx)).await?;
+
+        Ok(r1 + r2)
+    }
+}
+
+// Log the context without a full service, any context with RawBeforeAdd fits.
+struct LogBeforeAdd;
+
+impl<CX: ParamRef<RawBeforeAdd>> Inspector<u8, CX> for LogBeforeAdd {
+    fn inspect(&self, num: &u8, cx: &CX) {
+        println!("inspect: {num} was {}", cx.param_ref().0);
+    }
+}
+
+// Make sure the stack works with an empty context where it is defined.
+assert_stack_ok!(MyCertainMap, Add1<Mul2<Identical>>, u8);
+assert_stack_ok!(MyCertainMap, ForkJoin<Add1<Mul2<Identical>>>, u8);
+assert_stack_ok!(MyCertainMap, SetParam<RawBeforeAdd, Mul2<Identical>>, u8);
+assert_stack_ok!(
+    MyCertainMap,
+    Add1<Inspect<LogBeforeAdd, Mul2<Identical>>>,
+    u8
+);
+// The following line fails to compile since `Identical` requires RawBeforeMul but nobody sets it.
+// assert_stack_ok!(MyCertainMap, Add1<Identical>, u8);
+
+#[tokio::main]
+async fn main() {
+    // Create service and initialize store, then call with the handler.
+    // (2 + 1) * 2 = 6 is expected.
+    let svc = Add1(Mul2(Identical));
+    let mut store = MyCertainMap::new();
+    assert_eq!(svc.call((2, store.handler())).await.unwrap(), 6);
+
+    // You can even create a service to initialize store and pass the handler.
+    let svc = ContextService::<MyCertainMap, _>::new(svc);
+    assert_eq!(svc.call(2).await.unwrap(), 6);
+
+    // To show how to fork ctx.
+    let svc = ContextService::<MyCertainMap, _>::new(DupSvc(Add1(Mul2(Identical))));
+    // It is expected to print 2 times.
+    assert_eq!(svc.call(2).await.unwrap(), 12);
+
+    // `ForkJoin` does the same as `DupSvc` for any number of forks, and runs the calls
+    // concurrently.
+    let svc = ContextService::<MyCertainMap, _>::new(ForkJoin::new(2, Add1(Mul2(Identical))));
+    // It is expected to print 2 times.
+    assert_eq!(svc.call(2).await.unwrap().into_iter().sum::<u8>(), 12);
+
+    // Fixed values can be set with `SetParam` instead of a hand-written service.
+    let svc =
+        ContextService::<MyCertainMap, _>::new(SetParam::new(RawBeforeAdd(0), Mul2(Identical)));
+    assert_eq!(svc.call(2).await.unwrap(), 4);
+
+    // `Inspect` inside `Add1` sees the field it sets.
+    let svc =
+        ContextService::<MyCertainMap, _>::new(Add1(Inspect::new(LogBeforeAdd, Mul2(Identical))));
+    assert_eq!(svc.call(2).await.unwrap(), 6);
+
+    // Stores can be reused across requests, and defaults can be set by the context service.
+    let svc = ContextService::<MyCertainMap, _>::new(Mul2(Identical))
+        .with_pool(16)
+        .with_default(RawBeforeAdd(0));
+    assert_eq!(svc.call(2).await.unwrap(), 4);
+    assert_eq!(svc.call(3).await.unwrap(), 6);
+    assert_eq!(svc.pool().unwrap().idle(), 1);
+
+    // `Recycle` shares its pool with the services made from it, and also takes the store back
+    // when a call is cancelled.
+    let svc = Recycle::<MyCertainMap, _>::new(16, Add1(Mul2(Identical)));
+    assert_eq!(svc.call(2).await.unwrap(), 6);
+    assert_eq!(svc.pool().idle(), 1);
+}
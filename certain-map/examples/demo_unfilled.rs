@@ -28,6 +28,8 @@ fn main() {
 
     // With #[default(MyCertainMapEmpty)] we can get an empty type.
     assert_type::<MyCertainMapEmpty>(&meta);
+    // The parameters default to Vacancy, so the bare name is the empty map as well.
+    assert_type::<MyCertainMap>(&meta);
 
     // The following line compiles fail since there's no UserName in the map.
     // log_username(&meta);
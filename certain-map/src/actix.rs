@@ -0,0 +1,226 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Carry a certain_map context through [actix-web](https://docs.rs/actix-web) requests.
+//!
+//! The [`CertainMap`] middleware creates a context for every request and keeps it in the
+//! request extensions with its type erased, since a middleware can not change the request
+//! type. Handlers get it back with the [`Cx`] extractor, which names the expected state and
+//! so re-establishes the `Param` bounds. Middlewares in between can transform the context with
+//! [`RequestCx`].
+//!
+//! ```rust
+//! use actix_web::{web, App, HttpRequest};
+//! use certain_map::{
+//!     actix::{CertainMap, Cx, RequestCx},
+//!     certain_map, OccupiedM, ParamRef, ParamSet, Vacancy,
+//! };
+//!
+//! #[derive(Clone)]
+//! pub struct Path(String);
+//! #[derive(Clone)]
+//! pub struct UserId(u64);
+//!
+//! certain_map! {
+//!     pub struct ReqCx {
+//!         path: Path,
+//!         user: UserId,
+//!     }
+//! }
+//!
+//! // Provided by an authentication middleware in a real application.
+//! fn authenticate(req: &HttpRequest) -> Result<(), actix_web::Error> {
+//!     req.map_cx(|cx: ReqCxOwnedHandler<OccupiedM, Vacancy>| cx.param_set(UserId(1)))?;
+//!     Ok(())
+//! }
+//!
+//! async fn index(cx: Cx<ReqCxOwnedHandler<OccupiedM, OccupiedM>>) -> String {
+//!     ParamRef::<Path>::param_ref(&*cx).0.clone()
+//! }
+//!
+//! let app = App::new()
+//!     .wrap(CertainMap::new(|req| {
+//!         ReqCx::owned_handler().param_set(Path(req.path().to_string()))
+//!     }))
+//!     .route("/", web::get().to(index));
+//! # let _ = (app, authenticate);
+//! ```
+
+use std::{
+    any::{type_name, Any},
+    fmt,
+    future::{ready, Ready},
+    ops::{Deref, DerefMut},
+    rc::Rc,
+};
+
+use actix_web::{
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    FromRequest, HttpMessage, HttpRequest, ResponseError,
+};
+
+/// Middleware creating a context for every request.
+///
+/// The closure builds the initial owned handler, usually `Store::owned_handler()` with the
+/// fields known from the request already set.
+pub struct CertainMap<F> {
+    init: Rc<F>,
+}
+
+impl<F> CertainMap<F> {
+    #[inline]
+    pub fn new<H>(init: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> H,
+    {
+        Self {
+            init: Rc::new(init),
+        }
+    }
+}
+
+impl<S, B, F, H> Transform<S, ServiceRequest> for CertainMap<F>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    F: Fn(&ServiceRequest) -> H + 'static,
+    H: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = CertainMapMiddleware<S, F>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CertainMapMiddleware {
+            service,
+            init: self.init.clone(),
+        }))
+    }
+}
+
+/// The service created by [`CertainMap`].
+pub struct CertainMapMiddleware<S, F> {
+    service: S,
+    init: Rc<F>,
+}
+
+impl<S, B, F, H> Service<ServiceRequest> for CertainMapMiddleware<S, F>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    F: Fn(&ServiceRequest) -> H,
+    H: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = S::Future;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let cx = (self.init)(&req);
+        req.put_cx(cx);
+        self.service.call(req)
+    }
+}
+
+// The erased context in the request extensions.
+struct ErasedCx(Box<dyn Any>);
+
+/// Error of getting the context of a request, which responds with 500.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CxError {
+    /// No context in the request, the middleware is missing or the context is taken.
+    Missing,
+    /// The context is in another state than the expected one.
+    WrongState(&'static str),
+}
+
+impl fmt::Display for CxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CxError::Missing => f.write_str("no certain_map context in the request"),
+            CxError::WrongState(expected) => {
+                write!(f, "the certain_map context is not in state {expected}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CxError {}
+
+impl ResponseError for CxError {}
+
+/// Access the erased context of a request.
+pub trait RequestCx {
+    /// Store the context, replacing the previous one.
+    fn put_cx<H: 'static>(&self, cx: H);
+
+    /// Take the context out if it is in state `H`. A context in another state is kept.
+    fn take_cx<H: 'static>(&self) -> Result<H, CxError>;
+
+    /// Transform the context in state `H` into another state.
+    #[inline]
+    fn map_cx<H: 'static, O: 'static>(&self, f: impl FnOnce(H) -> O) -> Result<(), CxError> {
+        let cx = self.take_cx()?;
+        self.put_cx(f(cx));
+        Ok(())
+    }
+}
+
+impl<M: HttpMessage> RequestCx for M {
+    #[inline]
+    fn put_cx<H: 'static>(&self, cx: H) {
+        self.extensions_mut().insert(ErasedCx(Box::new(cx)));
+    }
+
+    fn take_cx<H: 'static>(&self) -> Result<H, CxError> {
+        let mut extensions = self.extensions_mut();
+        let ErasedCx(cx) = extensions.remove::<ErasedCx>().ok_or(CxError::Missing)?;
+        match cx.downcast::<H>() {
+            Ok(cx) => Ok(*cx),
+            Err(cx) => {
+                extensions.insert(ErasedCx(cx));
+                Err(CxError::WrongState(type_name::<H>()))
+            }
+        }
+    }
+}
+
+/// Extractor taking the context of the request in state `H`.
+///
+/// `H` is the generated owned handler type of the expected state, so the extracted value
+/// implements the `Param` traits of the occupied fields.
+pub struct Cx<H>(pub H);
+
+impl<H> Cx<H> {
+    #[inline]
+    pub fn into_inner(self) -> H {
+        self.0
+    }
+}
+
+impl<H> Deref for Cx<H> {
+    type Target = H;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<H> DerefMut for Cx<H> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<H: 'static> FromRequest for Cx<H> {
+    type Error = CxError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    #[inline]
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.take_cx().map(Cx))
+    }
+}
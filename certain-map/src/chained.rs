@@ -0,0 +1,262 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+use std::ops::{Deref, DerefMut};
+
+use crate::{
+    OccupiedM, Param, ParamDiscard, ParamForget, ParamMaybeMut, ParamMaybeRef, ParamMut, ParamPush,
+    ParamRef, ParamRemove, ParamSet, ParamState, ParamTake, ParamVacant, Vacancy,
+};
+
+/// A request context chained to the context of its connection, which reads a field from the
+/// request and falls back to the connection when it is vacant there.
+///
+/// Services are written against one context type while the data lives at two scopes: the
+/// connection context is set once per connection and borrowed by every request. `ParamRef<T>`
+/// and `ParamMut<T>` are picked at compile time by the state of the field in the request, so the
+/// request map must declare the fields of the connection map, usually with `#[extends(..)]`,
+/// and `ParamMaybeRef<T>` / `ParamMaybeMut<T>` need the field in both maps. `conn` is any
+/// pointer to the connection handler like `&H` or `Rc<H>`, and the traits which transform the
+/// context only touch the request, so values set in the request shadow the connection.
+///
+/// ```rust
+/// # use certain_map::{certain_map, Chained, ParamRef, ParamSet};
+/// pub struct Peer(&'static str);
+/// pub struct Route(&'static str);
+///
+/// certain_map! {
+///     pub struct ConnCx {
+///         peer: Peer,
+///     }
+///
+///     #[extends(ConnCx)]
+///     pub struct ReqCx {
+///         route: Route,
+///     }
+/// }
+///
+/// fn access_log<CX: ParamRef<Peer> + ParamRef<Route>>(cx: &CX) -> String {
+///     let peer: &Peer = cx.param_ref();
+///     let route: &Route = cx.param_ref();
+///     format!("{} {}", peer.0, route.0)
+/// }
+///
+/// let conn = ConnCx::owned_handler().param_set(Peer("10.0.0.1"));
+/// let mut store = ReqCx::new();
+/// let cx = Chained::new(store.handler(), &conn).param_set(Route("/api"));
+/// assert_eq!(access_log(&cx), "10.0.0.1 /api");
+///
+/// // The peer set by a proxy protocol header in the request shadows the connection.
+/// let cx = cx.param_set(Peer("192.168.0.7"));
+/// assert_eq!(access_log(&cx), "192.168.0.7 /api");
+/// ```
+#[must_use = "dropping the returned handler drops the context"]
+pub struct Chained<R, C> {
+    pub req: R,
+    pub conn: C,
+}
+
+impl<R, C> Chained<R, C> {
+    #[inline]
+    pub const fn new(req: R, conn: C) -> Self {
+        Self { req, conn }
+    }
+
+    /// Split into the request and the connection context.
+    #[inline]
+    pub fn into_parts(self) -> (R, C) {
+        (self.req, self.conn)
+    }
+}
+
+/// Borrow the field of type T from the request context `R` or the connection context `C`,
+/// implemented for the state of the field in the request, see [`Chained`].
+pub trait ChainRef<R: ?Sized, C: ?Sized, T> {
+    fn chain_ref<'a>(req: &'a R, conn: &'a C) -> &'a T;
+}
+
+impl<R: ParamRef<T> + ?Sized, C: ?Sized, T> ChainRef<R, C, T> for OccupiedM {
+    #[inline]
+    fn chain_ref<'a>(req: &'a R, _conn: &'a C) -> &'a T {
+        req.param_ref()
+    }
+}
+
+impl<R: ?Sized, C: ParamRef<T> + ?Sized, T> ChainRef<R, C, T> for Vacancy {
+    #[inline]
+    fn chain_ref<'a>(_req: &'a R, conn: &'a C) -> &'a T {
+        conn.param_ref()
+    }
+}
+
+/// Mutably borrow the field of type T like [`ChainRef`].
+pub trait ChainMut<R: ?Sized, C: ?Sized, T> {
+    fn chain_mut<'a>(req: &'a mut R, conn: &'a mut C) -> &'a mut T;
+}
+
+impl<R: ParamMut<T> + ?Sized, C: ?Sized, T> ChainMut<R, C, T> for OccupiedM {
+    #[inline]
+    fn chain_mut<'a>(req: &'a mut R, _conn: &'a mut C) -> &'a mut T {
+        req.param_mut()
+    }
+}
+
+impl<R: ?Sized, C: ParamMut<T> + ?Sized, T> ChainMut<R, C, T> for Vacancy {
+    #[inline]
+    fn chain_mut<'a>(_req: &'a mut R, conn: &'a mut C) -> &'a mut T {
+        conn.param_mut()
+    }
+}
+
+impl<R, C, T> ParamRef<T> for Chained<R, C>
+where
+    R: ParamState<T>,
+    C: Deref,
+    R::State: ChainRef<R, C::Target, T>,
+{
+    #[inline]
+    fn param_ref(&self) -> &T {
+        <R::State as ChainRef<R, C::Target, T>>::chain_ref(&self.req, &*self.conn)
+    }
+}
+
+impl<R, C, T> ParamMut<T> for Chained<R, C>
+where
+    R: ParamState<T>,
+    C: DerefMut,
+    R::State: ChainMut<R, C::Target, T>,
+{
+    #[inline]
+    fn param_mut(&mut self) -> &mut T {
+        <R::State as ChainMut<R, C::Target, T>>::chain_mut(&mut self.req, &mut *self.conn)
+    }
+}
+
+impl<R, C, T> ParamMaybeRef<T> for Chained<R, C>
+where
+    R: ParamMaybeRef<T>,
+    C: Deref,
+    C::Target: ParamMaybeRef<T>,
+{
+    #[inline]
+    fn param_maybe_ref(&self) -> Option<&T> {
+        match self.req.param_maybe_ref() {
+            Some(item) => Some(item),
+            None => self.conn.param_maybe_ref(),
+        }
+    }
+}
+
+impl<R, C, T> ParamMaybeMut<T> for Chained<R, C>
+where
+    R: ParamMaybeMut<T>,
+    C: DerefMut,
+    C::Target: ParamMaybeMut<T>,
+{
+    #[inline]
+    fn param_maybe_mut(&mut self) -> Option<&mut T> {
+        match self.req.param_maybe_mut() {
+            Some(item) => Some(item),
+            None => self.conn.param_maybe_mut(),
+        }
+    }
+}
+
+// Chained is not Clone for the reason noted on `WithCx`.
+impl<R, C, T: Clone> Param<T> for Chained<R, C>
+where
+    Self: ParamRef<T>,
+{
+    #[inline]
+    fn param(&self) -> T {
+        self.param_ref().clone()
+    }
+}
+
+impl<R: ParamSet<T>, C, T> ParamSet<T> for Chained<R, C> {
+    type Transformed = Chained<R::Transformed, C>;
+
+    #[inline]
+    fn param_set(self, item: T) -> Self::Transformed {
+        Chained {
+            req: self.req.param_set(item),
+            conn: self.conn,
+        }
+    }
+}
+
+impl<R: ParamRemove<T>, C, T> ParamRemove<T> for Chained<R, C> {
+    type Transformed = Chained<R::Transformed, C>;
+
+    #[inline]
+    fn param_remove(self) -> Self::Transformed {
+        Chained {
+            req: self.req.param_remove(),
+            conn: self.conn,
+        }
+    }
+}
+
+impl<R: ParamTake<T>, C, T> ParamTake<T> for Chained<R, C> {
+    type Transformed = Chained<R::Transformed, C>;
+
+    #[inline]
+    fn param_take(self) -> (Self::Transformed, T) {
+        let (req, item) = self.req.param_take();
+        (
+            Chained {
+                req,
+                conn: self.conn,
+            },
+            item,
+        )
+    }
+}
+
+impl<R: ParamPush<T>, C, T> ParamPush<T> for Chained<R, C> {
+    type Transformed = Chained<R::Transformed, C>;
+
+    #[inline]
+    fn param_push(self, item: T) -> Self::Transformed {
+        Chained {
+            req: self.req.param_push(item),
+            conn: self.conn,
+        }
+    }
+}
+
+impl<R: ParamDiscard<T>, C, T> ParamDiscard<T> for Chained<R, C> {
+    type Transformed = Chained<R::Transformed, C>;
+
+    #[inline]
+    fn param_discard(self) -> (Self::Transformed, bool) {
+        let (req, dropped) = self.req.param_discard();
+        (
+            Chained {
+                req,
+                conn: self.conn,
+            },
+            dropped,
+        )
+    }
+}
+
+impl<R: ParamForget<T>, C, T> ParamForget<T> for Chained<R, C> {
+    type Transformed = Chained<R::Transformed, C>;
+
+    #[inline]
+    fn param_forget(self) -> Self::Transformed {
+        Chained {
+            req: self.req.param_forget(),
+            conn: self.conn,
+        }
+    }
+}
+
+// Vacant only if neither context has the field.
+impl<R, C, T> ParamVacant<T> for Chained<R, C>
+where
+    R: ParamVacant<T>,
+    C: Deref,
+    C::Target: ParamVacant<T>,
+{
+}
@@ -0,0 +1,136 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Load deployment-level fields like the region or feature flags with
+//! [config](https://docs.rs/config).
+//!
+//! Fields marked `#[config(key = "..")]` are set by the generated `fill_from(&source)`, which
+//! reads every key from a config source (like a built `Config`), and `fill_from_env()`, which
+//! reads them from environment variables: the key `region` reads `REGION` and `server.region`
+//! reads `SERVER__REGION`. Values are deserialized into the field type, or parsed with `FromStr`
+//! for fields marked `#[config(key = "..", from_str)]`. Dotted keys read nested tables.
+//!
+//! ```rust
+//! use certain_map::{certain_map, ParamRef};
+//!
+//! #[derive(Debug, PartialEq)]
+//! pub struct Region(String);
+//!
+//! impl std::str::FromStr for Region {
+//!     type Err = std::convert::Infallible;
+//!
+//!     fn from_str(s: &str) -> Result<Self, Self::Err> {
+//!         Ok(Region(s.to_string()))
+//!     }
+//! }
+//!
+//! certain_map! {
+//!     pub struct Cx {
+//!         #[config(key = "server.region", from_str)]
+//!         region: Region,
+//!         #[config(key = "server.shards")]
+//!         shards: u16,
+//!         request_id: u64,
+//!     }
+//! }
+//!
+//! let source = config::Config::builder()
+//!     .set_default("server.region", "eu-west")
+//!     .unwrap()
+//!     .set_default("server.shards", 4)
+//!     .unwrap()
+//!     .build()
+//!     .unwrap();
+//! let cx = Cx::owned_handler().fill_from(&source).unwrap();
+//! assert_eq!(ParamRef::<Region>::param_ref(&cx).0, "eu-west");
+//! assert_eq!(*ParamRef::<u16>::param_ref(&cx), 4);
+//! ```
+
+use std::{fmt, str::FromStr};
+
+use config::ValueKind;
+pub use config::{Environment, Map, Source, Value};
+use serde::de::DeserializeOwned;
+
+/// Error of the generated `fill_from()` and `fill_from_env()`.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The source can not be read.
+    Source(config::ConfigError),
+    /// The key is not in the source.
+    Missing(&'static str),
+    /// The value of the key can not be converted into the field type.
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Source(e) => write!(f, "config source can not be read: {e}"),
+            ConfigError::Missing(key) => write!(f, "config key `{key}` is missing"),
+            ConfigError::Invalid(key, e) => write!(f, "config key `{key}` is invalid: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Source(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The source of `fill_from_env()`.
+#[doc(hidden)]
+#[inline]
+pub fn environment() -> Environment {
+    Environment::default().separator("__")
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn collect(source: &impl Source) -> Result<Map<String, Value>, ConfigError> {
+    source.collect().map_err(ConfigError::Source)
+}
+
+/// Find the value of a dotted key, descending into nested tables.
+fn lookup(values: &Map<String, Value>, key: &'static str) -> Result<Value, ConfigError> {
+    if let Some(value) = values.get(key) {
+        return Ok(value.clone());
+    }
+    let mut parts = key.split('.');
+    let mut value = parts.next().and_then(|part| values.get(part));
+    for part in parts {
+        value = match value.map(|value| &value.kind) {
+            Some(ValueKind::Table(table)) => table.get(part),
+            _ => None,
+        };
+    }
+    value.cloned().ok_or(ConfigError::Missing(key))
+}
+
+#[doc(hidden)]
+pub fn deserialize_value<T: DeserializeOwned>(
+    values: &Map<String, Value>,
+    key: &'static str,
+) -> Result<T, ConfigError> {
+    lookup(values, key)?
+        .try_deserialize()
+        .map_err(|e| ConfigError::Invalid(key, e.to_string()))
+}
+
+#[doc(hidden)]
+pub fn parse_value<T: FromStr>(
+    values: &Map<String, Value>,
+    key: &'static str,
+) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    lookup(values, key)?
+        .into_string()
+        .map_err(|e| ConfigError::Invalid(key, e.to_string()))?
+        .parse()
+        .map_err(|e: T::Err| ConfigError::Invalid(key, e.to_string()))
+}
@@ -0,0 +1,33 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Log handlers with [defmt](https://docs.rs/defmt), for embedded targets.
+//!
+//! With `#[derive(Format)]` on a prefilled map, or `#[ensure(Format)]` on some fields, every
+//! handler implements `defmt::Format` and writes the occupied fields among them, without
+//! going through `core::fmt`. Each field is written with its own format string, so the field
+//! names are interned like the rest of the defmt strings and never sent over the wire.
+//!
+//! The generated code calls `defmt::write!`, so the crate defining the map must depend on
+//! `defmt` as well, like with defmt's own derive.
+//!
+//! This crate and the code generated by `certain_map!` need `std`, so only targets with `std`
+//! can use it; `no_std` targets are not supported.
+//!
+//! ```rust
+//! use certain_map::certain_map;
+//!
+//! certain_map! {
+//!     #[derive(Format)]
+//!     pub struct Cx {
+//!         request_id: u32,
+//!         retries: u8,
+//!     }
+//! }
+//!
+//! fn assert_format<T: defmt::Format>(_: &T) {}
+//!
+//! let mut store = Cx::new();
+//! assert_format(&store.handler());
+//! ```
+
+pub use ::defmt::{Format, Formatter};
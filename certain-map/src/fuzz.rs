@@ -0,0 +1,32 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Generate contexts for fuzz targets and property tests with
+//! [arbitrary](https://docs.rs/arbitrary).
+//!
+//! With `#[derive(Arbitrary)]` on a prefilled map, the full owned handler implements
+//! `Arbitrary` with arbitrary values of every field, so typed service pipelines can be driven
+//! with realistic contexts. The `#[partial(Name)]` struct also implements it with every field
+//! occupied at random, for code which reads fields with `ParamMaybeRef`.
+//!
+//! ```rust
+//! use certain_map::{certain_map, fuzz::{Arbitrary, Unstructured}, ParamMaybeRef, ParamRef};
+//!
+//! certain_map! {
+//!     #[partial(CxPartial)]
+//!     #[derive(Arbitrary)]
+//!     pub struct Cx {
+//!         user: u64,
+//!         retries: u8,
+//!     }
+//! }
+//!
+//! let mut u = Unstructured::new(&[7, 0, 0, 0, 0, 0, 0, 0, 3, 1, 5]);
+//! let cx = CxOwnedHandler::arbitrary(&mut u).unwrap();
+//! assert_eq!(*ParamRef::<u64>::param_ref(&cx), 7);
+//! assert_eq!(*ParamRef::<u8>::param_ref(&cx), 3);
+//!
+//! let partial = CxPartial::arbitrary(&mut u).unwrap();
+//! assert!(ParamMaybeRef::<u64>::param_maybe_ref(&partial).is_some());
+//! ```
+
+pub use arbitrary::{Arbitrary, Error, Result, Unstructured};
@@ -0,0 +1,98 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Exchange context fields with [async-graphql](https://docs.rs/async-graphql) resolvers.
+//!
+//! Fields marked `#[graphql]` are inserted into the request data by the generated
+//! `to_graphql_data()`, so resolvers read them with `ctx.data::<T>()`. Resolvers can hand values
+//! back through a [`DataSink`] in the request data, and `from_graphql_data()` sets all the
+//! marked fields from the collected data or fails.
+//!
+//! ```rust
+//! use std::sync::Arc;
+//!
+//! use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Request, Schema};
+//! use certain_map::{certain_map, graphql::DataSink, ParamRef, ParamSet};
+//!
+//! #[derive(Clone)]
+//! pub struct Tenant(String);
+//! #[derive(Clone)]
+//! pub struct Quota(u32);
+//!
+//! certain_map! {
+//!     pub struct Cx {
+//!         #[graphql]
+//!         tenant: Tenant,
+//!     }
+//! }
+//!
+//! certain_map! {
+//!     pub struct ResolvedCx {
+//!         #[graphql]
+//!         quota: Quota,
+//!     }
+//! }
+//!
+//! struct Query;
+//!
+//! #[Object]
+//! impl Query {
+//!     async fn tenant(&self, ctx: &Context<'_>) -> String {
+//!         ctx.data_unchecked::<Arc<DataSink>>().provide(Quota(10));
+//!         ctx.data_unchecked::<Tenant>().0.clone()
+//!     }
+//! }
+//!
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let schema = Schema::new(Query, EmptyMutation, EmptySubscription);
+//! let mut store = Cx::new();
+//! let cx = store.handler().param_set(Tenant("acme".to_string()));
+//!
+//! let sink = Arc::new(DataSink::default());
+//! let mut request = Request::new("{ tenant }").data(sink.clone());
+//! cx.to_graphql_data(&mut request.data);
+//! let response = schema.execute(request).await;
+//! assert!(response.is_ok());
+//!
+//! let mut store = ResolvedCx::new();
+//! let resolved = store.handler().from_graphql_data(&sink.take()).unwrap();
+//! assert_eq!(ParamRef::<Quota>::param_ref(&resolved).0, 10);
+//! # });
+//! ```
+
+use std::{any::Any, sync::Mutex};
+
+pub use async_graphql::Data;
+
+/// Data provided by resolvers, collected to be set into a context after the execution.
+///
+/// Put an `Arc<DataSink>` into the request data and keep a clone of it; resolvers get it with
+/// `ctx.data::<Arc<DataSink>>()` and [`provide`](Self::provide) values.
+#[derive(Default)]
+pub struct DataSink {
+    data: Mutex<Data>,
+}
+
+impl DataSink {
+    /// Provide a value, replacing the previous one of the same type.
+    #[inline]
+    pub fn provide<D: Any + Send + Sync>(&self, value: D) {
+        self.data
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(value);
+    }
+
+    /// Take all the provided values.
+    #[inline]
+    pub fn take(&self) -> Data {
+        std::mem::take(&mut *self.data.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn data_value<T: Any + Clone>(data: &Data) -> Option<T> {
+    data.get(&std::any::TypeId::of::<T>())?
+        .downcast_ref::<T>()
+        .cloned()
+}
@@ -0,0 +1,219 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Mirror values between the extensions of [http](https://docs.rs/http) requests and the
+//! context, for stacks mixing tower-http layers with service-async services.
+//!
+//! tower-http layers keep what they produce in the request extensions, like the `RequestId` of
+//! `SetRequestIdLayer`. [`FromExtension`] copies such a value into the context so services
+//! read it with `ParamRef`, and [`IntoExtension`] copies a context field into the extensions
+//! for the tower services the stack calls with the request, like a client wrapped in
+//! tower-http layers, so there is a single source of truth on each side of the bridge. Any
+//! extension works the same way, like the client address put by the server as `ConnectInfo`.
+//! Trace spans are not kept in the extensions, so they have to be set into the context by the
+//! service creating them.
+//!
+//! The bridges are service-async services over `(Request, CX)`, so they only work inside
+//! service-async stacks. They are not tower `Layer`s or `Service`s: the tower-http layers run
+//! in a tower stack in front of or behind the service-async one, which meets them through the
+//! request.
+//!
+//! ```rust
+//! use certain_map::{
+//!     certain_map,
+//!     http::{FromExtension, IntoExtension},
+//!     service::Service,
+//!     ParamRef, ParamSet,
+//! };
+//! use http::Request;
+//!
+//! // Usually `tower_http::request_id::RequestId`.
+//! #[derive(Clone, PartialEq, Debug)]
+//! pub struct RequestId(u64);
+//! #[derive(Clone, PartialEq, Debug)]
+//! pub struct Tenant(&'static str);
+//!
+//! certain_map! {
+//!     pub struct Cx {
+//!         request_id: Option<RequestId>,
+//!         tenant: Tenant,
+//!     }
+//! }
+//!
+//! struct Inner;
+//!
+//! impl<B, CX: ParamRef<Option<RequestId>>> Service<(Request<B>, CX)> for Inner {
+//!     type Response = (Option<RequestId>, Option<Tenant>);
+//!     type Error = ();
+//!
+//!     async fn call(&self, (req, cx): (Request<B>, CX)) -> Result<Self::Response, ()> {
+//!         Ok((cx.param_ref().clone(), req.extensions().get::<Tenant>().cloned()))
+//!     }
+//! }
+//!
+//! # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+//! let svc = FromExtension::<RequestId, _>::new(IntoExtension::<Tenant, _>::new(Inner));
+//! let mut req = Request::new(());
+//! req.extensions_mut().insert(RequestId(7));
+//! let mut store = Cx::new();
+//! let cx = store.handler().param_set(Tenant("acme"));
+//! let resp = svc.call((req, cx)).await.unwrap();
+//! assert_eq!(resp, (Some(RequestId(7)), Some(Tenant("acme"))));
+//! # });
+//! ```
+//!
+//! Context values crossing a hop boundary are carried as headers instead. Fields marked
+//! `#[header("name")]` are exported by the generated `to_headers()` and imported by
+//! `from_headers()`, which sets all of them or fails. Values are written with `Display` and read
+//! with `FromStr`.
+//!
+//! ```rust
+//! use std::net::SocketAddr;
+//!
+//! use certain_map::{certain_map, ParamRef, ParamSet};
+//!
+//! certain_map! {
+//!     pub struct Cx {
+//!         #[header("x-forwarded-peer")]
+//!         peer: SocketAddr,
+//!         local: u8,
+//!     }
+//! }
+//!
+//! let cx = Cx::owned_handler().param_set("10.0.0.1:5000".parse::<SocketAddr>().unwrap());
+//! let headers = cx.to_headers().unwrap();
+//! assert_eq!(headers["x-forwarded-peer"], "10.0.0.1:5000");
+//!
+//! // On the next hop.
+//! let cx = Cx::owned_handler().from_headers(&headers).unwrap();
+//! assert_eq!(ParamRef::<SocketAddr>::param_ref(&cx).port(), 5000);
+//! ```
+
+use std::{
+    fmt::{self, Display},
+    future::Future,
+    marker::PhantomData,
+    str::FromStr,
+};
+
+pub use http::HeaderMap;
+use http::{HeaderName, HeaderValue, Request};
+
+use crate::{service::Service, ParamMaybeRef, ParamSet};
+
+/// A service-async service which sets a clone of the `T` extension of the request into the
+/// context as `Option<T>`, which is `None` if the extension is absent.
+pub struct FromExtension<T, S> {
+    pub inner: S,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T, S> FromExtension<T, S> {
+    #[inline]
+    pub const fn new(inner: S) -> Self {
+        Self {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, S, B, CX> Service<(Request<B>, CX)> for FromExtension<T, S>
+where
+    T: Clone + Send + Sync + 'static,
+    CX: ParamSet<Option<T>>,
+    S: Service<(Request<B>, CX::Transformed)>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    #[inline]
+    fn call(
+        &self,
+        (req, cx): (Request<B>, CX),
+    ) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        let value = req.extensions().get::<T>().cloned();
+        self.inner.call((req, cx.param_set(value)))
+    }
+}
+
+/// A service-async service which inserts a clone of the `T` field of the context into the
+/// request extensions, if it is occupied.
+pub struct IntoExtension<T, S> {
+    pub inner: S,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T, S> IntoExtension<T, S> {
+    #[inline]
+    pub const fn new(inner: S) -> Self {
+        Self {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, S, B, CX> Service<(Request<B>, CX)> for IntoExtension<T, S>
+where
+    T: Clone + Send + Sync + 'static,
+    CX: ParamMaybeRef<T>,
+    S: Service<(Request<B>, CX)>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    #[inline]
+    fn call(
+        &self,
+        (mut req, cx): (Request<B>, CX),
+    ) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        if let Some(value) = cx.param_maybe_ref() {
+            req.extensions_mut().insert(value.clone());
+        }
+        self.inner.call((req, cx))
+    }
+}
+
+/// Error of the generated `to_headers()` and `from_headers()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The header is not in the map.
+    Missing(&'static str),
+    /// The value of the header can not be converted from or into the field type.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Missing(name) => write!(f, "header `{name}` is missing"),
+            HeaderError::Invalid(name) => write!(f, "header `{name}` has an invalid value"),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+#[doc(hidden)]
+#[inline]
+pub fn insert_header<T: Display>(
+    headers: &mut HeaderMap,
+    name: &'static str,
+    value: &T,
+) -> Result<(), HeaderError> {
+    let value = HeaderValue::try_from(value.to_string()).map_err(|_| HeaderError::Invalid(name))?;
+    headers.insert(HeaderName::from_static(name), value);
+    Ok(())
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn parse_header<T: FromStr>(headers: &HeaderMap, name: &'static str) -> Result<T, HeaderError> {
+    headers
+        .get(name)
+        .ok_or(HeaderError::Missing(name))?
+        .to_str()
+        .map_err(|_| HeaderError::Invalid(name))?
+        .parse()
+        .map_err(|_| HeaderError::Invalid(name))
+}
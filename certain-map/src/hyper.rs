@@ -0,0 +1,118 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Carry a certain_map context alongside [hyper](https://docs.rs/hyper) requests.
+//!
+//! [`CxService`] implements `hyper::service::Service<R>` and calls the inner service with
+//! `(request, context)`. The context is created for every request by a closure, or forked from
+//! a context shared by the connection with [`CxService::fork`]. Contexts are owned handlers, so
+//! the inner future can keep them across awaits; a request context is torn down with the inner
+//! future and the connection context with the service, dropping their occupied fields.
+//!
+//! ```rust
+//! use std::{convert::Infallible, future::Ready, net::SocketAddr};
+//!
+//! use certain_map::{certain_map, hyper::CxService, ParamRef, ParamSet};
+//! use hyper::{service::Service, Request};
+//!
+//! #[derive(Clone)]
+//! pub struct PeerAddr(SocketAddr);
+//!
+//! certain_map! {
+//!     #[derive(Clone)]
+//!     pub struct Cx {
+//!         peer: PeerAddr,
+//!     }
+//! }
+//!
+//! struct Echo;
+//!
+//! impl<B, CX: ParamRef<PeerAddr>> Service<(Request<B>, CX)> for Echo {
+//!     type Response = String;
+//!     type Error = Infallible;
+//!     type Future = Ready<Result<String, Infallible>>;
+//!
+//!     fn call(&self, (_, cx): (Request<B>, CX)) -> Self::Future {
+//!         std::future::ready(Ok(cx.param_ref().0.to_string()))
+//!     }
+//! }
+//!
+//! // Built for every accepted connection.
+//! let peer = PeerAddr("127.0.0.1:8080".parse().unwrap());
+//! let svc = CxService::fork(Cx::owned_handler().param_set(peer), Echo);
+//! let _ = svc.call(Request::new(()));
+//! ```
+
+use crate::ForkOwned;
+
+/// Create the context of a request.
+///
+/// Implemented for closures `Fn(&R) -> CX` and [`ForkConn`].
+pub trait MakeCx<R> {
+    type Cx;
+    fn make_cx(&self, req: &R) -> Self::Cx;
+}
+
+impl<R, F, CX> MakeCx<R> for F
+where
+    F: Fn(&R) -> CX,
+{
+    type Cx = CX;
+
+    #[inline]
+    fn make_cx(&self, req: &R) -> Self::Cx {
+        self(req)
+    }
+}
+
+/// Fork the context of the connection for every request.
+pub struct ForkConn<H>(pub H);
+
+impl<R, H: ForkOwned> MakeCx<R> for ForkConn<H> {
+    type Cx = H::Owned;
+
+    #[inline]
+    fn make_cx(&self, _: &R) -> Self::Cx {
+        self.0.fork_owned()
+    }
+}
+
+/// A hyper service which creates a context for every request and calls the inner service with
+/// `(request, context)`.
+pub struct CxService<M, S> {
+    pub make_cx: M,
+    pub inner: S,
+}
+
+impl<M, S> CxService<M, S> {
+    /// Create the context of every request with `make_cx`, usually a closure returning
+    /// `Store::owned_handler()` with the fields known from the request set.
+    #[inline]
+    pub const fn new(make_cx: M, inner: S) -> Self {
+        Self { make_cx, inner }
+    }
+}
+
+impl<H, S> CxService<ForkConn<H>, S> {
+    /// Fork `conn` for every request, which is usually created for the connection with the
+    /// fields shared by its requests.
+    #[inline]
+    pub const fn fork(conn: H, inner: S) -> Self {
+        Self::new(ForkConn(conn), inner)
+    }
+}
+
+impl<R, M, S> hyper::service::Service<R> for CxService<M, S>
+where
+    M: MakeCx<R>,
+    S: hyper::service::Service<(R, M::Cx)>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    #[inline]
+    fn call(&self, req: R) -> Self::Future {
+        let cx = self.make_cx.make_cx(&req);
+        self.inner.call((req, cx))
+    }
+}
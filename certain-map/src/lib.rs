@@ -2,10 +2,161 @@
 
 #![doc = include_str!("../README.md")]
 
-use std::mem::MaybeUninit;
+use std::{mem::MaybeUninit, ops::DerefMut};
+
+#[cfg(feature = "actix")]
+pub mod actix;
+mod chained;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "defmt")]
+pub mod defmt;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+#[cfg(feature = "async-graphql")]
+pub mod graphql;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "hyper")]
+pub mod hyper;
+#[cfg(feature = "local")]
+pub mod local;
+#[cfg(feature = "otel")]
+pub mod otel;
+mod pool;
+pub mod provide;
+mod reborrow;
+#[cfg(feature = "schemars")]
+pub mod schema;
+#[cfg(feature = "secrecy")]
+pub mod secret;
+#[cfg(feature = "serde")]
+pub mod serialize;
+#[cfg(feature = "service")]
+pub mod service;
+#[cfg(feature = "gat")]
+mod stored;
+#[cfg(feature = "tokio")]
+pub mod task;
+#[cfg(feature = "tokio")]
+pub mod task_local;
+#[cfg(any(kani, test))]
+mod verify;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "wire")]
+pub mod wire;
+mod with_cx;
+
+// The generated code refers to `::certain_map`, which the proofs expand inside this crate.
+#[cfg(any(kani, test))]
+extern crate self as certain_map;
+
+pub use chained::{ChainMut, ChainRef, Chained};
+pub use pool::{PoolCell, StorePool};
+pub use reborrow::Reborrow;
+#[cfg(feature = "gat")]
+pub use stored::{StoredCtx, StoredHandler};
+pub use with_cx::WithCx;
+
+#[cfg(feature = "valuable")]
+pub use valuable;
 
 /// Re-export macro.
+///
+/// A field with `#[requires(..)]` can only be set after the fields it requires:
+///
+/// ```rust
+/// # use certain_map::{certain_map, ParamSet};
+/// pub struct UserId(u64);
+/// pub struct Role(&'static str);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         user_id: UserId,
+///         #[requires(user_id)]
+///         role: Role,
+///     }
+/// }
+///
+/// let mut store = Cx::new();
+/// let _cx = store.handler().param_set(UserId(1)).param_set(Role("admin"));
+/// ```
+///
+/// ```rust,compile_fail,E0277
+/// # use certain_map::{certain_map, ParamSet};
+/// # pub struct UserId(u64);
+/// # pub struct Role(&'static str);
+/// # certain_map! {
+/// #     pub struct Cx {
+/// #         user_id: UserId,
+/// #         #[requires(user_id)]
+/// #         role: Role,
+/// #     }
+/// # }
+/// let mut store = Cx::new();
+/// // The user id is vacant, so the role can not be set.
+/// let _cx = ParamSet::<Role>::param_set(store.handler(), Role("admin"));
+/// ```
+///
+/// Fields with `#[conflicts_with(..)]` can not be occupied together:
+///
+/// ```rust,compile_fail,E0277
+/// # use certain_map::{certain_map, ParamSet};
+/// pub struct Password(String);
+/// pub struct Token(String);
+///
+/// certain_map! {
+///     pub struct Credential {
+///         #[conflicts_with(token)]
+///         password: Password,
+///         token: Token,
+///     }
+/// }
+///
+/// let mut store = Credential::new();
+/// let cx = store.handler().param_set(Password("secret".to_string()));
+/// // The password is occupied, so the token can not be set.
+/// let _cx = ParamSet::<Token>::param_set(cx, Token("abc".to_string()));
+/// ```
+///
+/// `#[deny_large(N)]` rejects fields larger than N bytes, the error names the field and its
+/// size, here ``expected struct `field_buffer_exceeds_deny_large_64<0>`, found struct
+/// `field_buffer_exceeds_deny_large_64<4096>` ``:
+///
+/// ```rust,compile_fail,E0308
+/// # use certain_map::certain_map;
+/// pub struct Flags(u8);
+/// pub struct Buffer([u8; 4096]);
+///
+/// certain_map! {
+///     #[deny_large(64)]
+///     pub struct Cx {
+///         flags: Flags,
+///         buffer: Buffer,
+///     }
+/// }
+/// ```
+///
+/// `#[assert_send]` rejects fields which are not `Send`, with the error ``the field `counter` of
+/// type `Rc<u32>` is not `Send` ``:
+///
+/// ```rust,compile_fail,E0277
+/// # use certain_map::certain_map;
+/// use std::rc::Rc;
+///
+/// certain_map! {
+///     #[assert_send]
+///     pub struct Cx {
+///         counter: Rc<u32>,
+///     }
+/// }
+/// ```
 pub use certain_map_macros::certain_map;
+/// Re-export macro.
+pub use certain_map_macros::{
+    cx_service, CertainMapFields, FromContext, IntoContext, TryFromContext,
+};
 /// Item of type T has been set in a certain_map slot.
 ///
 /// When used as a trait bound, `Param<T>` ensures that the constrained type has previously
@@ -20,6 +171,7 @@ pub use certain_map_macros::certain_map;
 /// # Example
 ///
 /// ```rust
+/// # use certain_map::Param;
 /// fn process_param<P: Param<T>, T>(param_provider: P) {
 ///     let value: T = param_provider.param();
 ///     // Use the value of type T
@@ -40,6 +192,7 @@ pub use param::Param;
 /// # Example
 ///
 /// ```rust
+/// # use certain_map::ParamMaybeMut;
 /// fn process_param_maybe_mut<P: ParamMaybeMut<T>, T>(param_provider: &mut P) {
 ///     if let Some(value_mut) = param_provider.param_maybe_mut() {
 ///         // Modify the value of type T
@@ -61,6 +214,7 @@ pub use param::ParamMaybeMut;
 /// # Example
 ///
 /// ```rust
+/// # use certain_map::ParamMaybeRef;
 /// fn process_param_maybe_ref<P: ParamMaybeRef<T>, T>(param_provider: &P) {
 ///     if let Some(value_ref) = param_provider.param_maybe_ref() {
 ///         // Use the reference to the value of type T
@@ -79,6 +233,7 @@ pub use param::ParamMaybeRef;
 /// # Example
 ///
 /// ```rust
+/// # use certain_map::ParamMut;
 /// fn process_param_mut<P: ParamMut<T>, T>(param_provider: &mut P) {
 ///     let value_mut: &mut T = param_provider.param_mut();
 ///     // Modify the value of type T
@@ -95,6 +250,7 @@ pub use param::ParamMut;
 /// # Example
 ///
 /// ```rust
+/// # use certain_map::ParamRef;
 /// fn process_param_ref<P: ParamRef<T>, T>(param_provider: &P) {
 ///     let value_ref: &T = param_provider.param_ref();
 ///     // Use the reference to the value of type T
@@ -103,6 +259,8 @@ pub use param::ParamMut;
 pub use param::ParamRef;
 /// Item of type T can be removed certain_map slot irrespective of it
 /// having been set before.
+///
+/// The returned handler is not `#[must_use]`, see [`ParamSet`].
 pub use param::ParamRemove;
 /// Item of type T is vacant in certain_map slot.
 ///
@@ -116,11 +274,310 @@ pub use param::ParamRemove;
 /// field that has already been set. If you attempt to set a value in a slot that is already
 /// [`Occupied`](struct.Occupied.html), the Rust compiler will raise an error, preventing
 /// accidental overwrites and ensuring the integrity of the `certain_map` slots.
+///
+/// `ParamSet`, [`ParamRemove`] and [`ParamTake`] are declared by the `param` crate, so their
+/// methods can not be `#[must_use]`; the handlers of the maps and the wrappers of this crate are.
 pub use param::ParamSet;
 /// Item of type T has been set in certain_map slot and can be removed
 /// from the slot, leaving it vacant.
+///
+/// The returned handler is not `#[must_use]`, see [`ParamSet`].
 pub use param::ParamTake;
 
+/// Append an item to a collection field, generated for fields with `#[push]`.
+///
+/// The first push creates the collection and occupies the field, later pushes extend it.
+///
+/// ```rust
+/// # use certain_map::{certain_map, ParamPush, ParamRef};
+/// pub struct Warning(&'static str);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         #[push]
+///         warnings: Vec<Warning>,
+///     }
+/// }
+///
+/// let mut store = Cx::new();
+/// let cx = store.handler().param_push(Warning("slow")).param_push(Warning("retried"));
+/// assert_eq!(ParamRef::<Vec<Warning>>::param_ref(&cx).len(), 2);
+/// ```
+pub trait ParamPush<T> {
+    type Transformed;
+    #[must_use = "dropping the returned handler drops the context"]
+    fn param_push(self, item: T) -> Self::Transformed;
+}
+
+/// Item of type T can be removed like [`ParamRemove`], reporting whether a value was dropped.
+///
+/// The state of the field is erased in code generic over it, so cleanup layers which account
+/// for removed data can not tell it from the types.
+///
+/// ```rust
+/// # use certain_map::{certain_map, ParamDiscard, ParamSet};
+/// pub struct Session(u64);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         session: Session,
+///     }
+/// }
+///
+/// let cx = Cx::owned_handler().param_set(Session(1));
+/// let (cx, dropped) = ParamDiscard::<Session>::param_discard(cx);
+/// assert!(dropped);
+/// let (_cx, dropped) = ParamDiscard::<Session>::param_discard(cx);
+/// assert!(!dropped);
+/// ```
+pub trait ParamDiscard<T> {
+    type Transformed;
+    #[must_use = "dropping the returned handler drops the context"]
+    fn param_discard(self) -> (Self::Transformed, bool);
+}
+
+/// Item of type T can be marked vacant without running its destructor, for values owned
+/// elsewhere like in an arena.
+pub trait ParamForget<T> {
+    type Transformed;
+    #[must_use = "dropping the returned handler drops the context"]
+    fn param_forget(self) -> Self::Transformed;
+}
+
+/// Item of type T is not set in the certain_map slot.
+///
+/// As a trait bound it guarantees that nothing has populated the field yet, for example a layer
+/// which must run before routing decided the backend.
+///
+/// ```rust
+/// # use certain_map::{certain_map, ParamSet, ParamVacant};
+/// pub struct Backend(String);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         backend: Backend,
+///     }
+/// }
+///
+/// fn before_routing<CX: ParamVacant<Backend>>(_cx: &CX) {}
+///
+/// let mut store = Cx::new();
+/// let cx = store.handler();
+/// before_routing(&cx);
+/// let _cx = cx.param_set(Backend("upstream".to_string()));
+/// // The following line fails to compile since the backend is set.
+/// // before_routing(&_cx);
+/// ```
+pub trait ParamVacant<T> {}
+
+/// The occupancy of the field of type T, [`OccupiedM`] or [`Vacancy`], implemented by prefilled
+/// handlers for every field.
+///
+/// Combinators like [`Chained`] use it to tell at compile time which of their contexts has the
+/// field.
+pub trait ParamState<T> {
+    type State: MaybeAvailable;
+}
+
+/// A `#[flag]` field of type T is set.
+///
+/// Flags are unit structs, so they cost no space and are set with `set_flag::<T>()` of the
+/// handler without a value.
+///
+/// ```rust
+/// # use certain_map::{certain_map, Flag};
+/// pub struct TlsTerminated;
+///
+/// certain_map! {
+///     pub struct Cx {
+///         #[flag]
+///         tls: TlsTerminated,
+///     }
+/// }
+///
+/// fn after_tls<CX: Flag<TlsTerminated>>(_cx: &CX) {}
+///
+/// let cx = Cx::owned_handler();
+/// assert!(!cx.has_flag::<TlsTerminated>());
+/// let cx = cx.set_flag::<TlsTerminated>();
+/// assert!(cx.has_flag::<TlsTerminated>());
+/// after_tls(&cx);
+/// ```
+pub trait Flag<T>: ParamRef<T> {}
+
+/// The value of a `#[flag]` field of the map with the store `Store`, implemented by the macro.
+pub trait FlagOf<Store>: Sized {
+    const FLAG: Self;
+}
+
+/// A field of type T with `#[ensure(PartialEq)]` is set, so it can be compared with
+/// [`assert_param_eq!`] and [`assert_param_ne!`].
+///
+/// ```rust
+/// # use certain_map::{assert_param_eq, assert_param_ne, certain_map, ParamSet};
+/// #[derive(Debug, PartialEq)]
+/// pub struct UserAge(u8);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         #[ensure(PartialEq)]
+///         age: UserAge,
+///     }
+/// }
+///
+/// let cx = Cx::owned_handler().param_set(UserAge(24));
+/// assert_param_eq!(cx, UserAge(24));
+/// assert_param_ne!(cx, UserAge(25), "age of {}", "ihciah");
+/// ```
+pub trait ParamEq<T: PartialEq>: ParamRef<T> {}
+
+#[doc(hidden)]
+#[inline]
+pub fn __param_eq_ref<'a, T: PartialEq + std::fmt::Debug, CX: ParamEq<T>>(
+    cx: &'a CX,
+    _: &T,
+) -> &'a T {
+    cx.param_ref()
+}
+
+/// Assert that the field of a context equals the given value, with the field type inferred from
+/// the value. The field needs `#[ensure(PartialEq)]`, see [`ParamEq`].
+#[macro_export]
+macro_rules! assert_param_eq {
+    ($cx:expr, $expected:expr $(,)?) => {
+        match &$expected {
+            expected => ::std::assert_eq!($crate::__param_eq_ref(&$cx, expected), expected),
+        }
+    };
+    ($cx:expr, $expected:expr, $($arg:tt)+) => {
+        match &$expected {
+            expected => ::std::assert_eq!($crate::__param_eq_ref(&$cx, expected), expected, $($arg)+),
+        }
+    };
+}
+
+/// Assert that the field of a context does not equal the given value, see
+/// [`assert_param_eq!`].
+#[macro_export]
+macro_rules! assert_param_ne {
+    ($cx:expr, $expected:expr $(,)?) => {
+        match &$expected {
+            expected => ::std::assert_ne!($crate::__param_eq_ref(&$cx, expected), expected),
+        }
+    };
+    ($cx:expr, $expected:expr, $($arg:tt)+) => {
+        match &$expected {
+            expected => ::std::assert_ne!($crate::__param_eq_ref(&$cx, expected), expected, $($arg)+),
+        }
+    };
+}
+
+/// Set a field from any value convertible into it.
+///
+/// It is implemented for every context, and the transform is the same as
+/// [`ParamSet<T>`](ParamSet), so call sites can pass raw values for newtype fields.
+///
+/// ```rust
+/// # use certain_map::{certain_map, ParamRef, ParamSetFrom};
+/// pub struct UserName(String);
+///
+/// impl From<&str> for UserName {
+///     fn from(name: &str) -> Self {
+///         Self(name.to_string())
+///     }
+/// }
+///
+/// certain_map! {
+///     pub struct Cx {
+///         name: UserName,
+///     }
+/// }
+///
+/// let mut store = Cx::new();
+/// let cx = store.handler().param_set_from::<UserName>("ihciah");
+/// assert_eq!(cx.param_ref().0, "ihciah");
+/// ```
+pub trait ParamSetFrom: Sized {
+    #[inline]
+    #[must_use = "dropping the returned handler drops the context"]
+    fn param_set_from<T>(self, item: impl Into<T>) -> <Self as ParamSet<T>>::Transformed
+    where
+        Self: ParamSet<T>,
+    {
+        self.param_set(item.into())
+    }
+}
+
+impl<CX> ParamSetFrom for CX {}
+
+/// Borrow several fields at once as a tuple of shared references.
+///
+/// It is implemented for every context with `ParamRef` of all the elements, up to 4 of them, so
+/// read-mostly code needs one bound and one call.
+///
+/// ```rust
+/// # use certain_map::{certain_map, ParamRefs, ParamSet};
+/// pub struct UserName(String);
+/// pub struct UserAge(u8);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         name: UserName,
+///         age: UserAge,
+///     }
+/// }
+///
+/// fn greet<CX: ParamRefs<(UserName, UserAge)>>(cx: &CX) -> String {
+///     let (name, age) = cx.param_refs();
+///     format!("{} is {}", name.0, age.0)
+/// }
+///
+/// let mut store = Cx::new();
+/// let cx = store
+///     .handler()
+///     .param_set(UserName("ihciah".to_string()))
+///     .param_set(UserAge(24));
+/// assert_eq!(greet(&cx), "ihciah is 24");
+/// ```
+#[cfg(feature = "gat")]
+pub trait ParamRefs<T: ParamTuple> {
+    fn param_refs(&self) -> T::Refs<'_>;
+}
+
+/// A tuple of field types accepted by [`ParamRefs`].
+#[cfg(feature = "gat")]
+pub trait ParamTuple {
+    type Refs<'a>
+    where
+        Self: 'a;
+}
+
+#[cfg(feature = "gat")]
+macro_rules! impl_param_refs {
+    ($($ty:ident),+) => {
+        impl<$($ty),+> ParamTuple for ($($ty,)+) {
+            type Refs<'a> = ($(&'a $ty,)+) where Self: 'a;
+        }
+
+        impl<CX: ?Sized, $($ty),+> ParamRefs<($($ty,)+)> for CX
+        where
+            $(CX: ParamRef<$ty>,)+
+        {
+            #[inline]
+            fn param_refs(&self) -> ($(&$ty,)+) {
+                ($(ParamRef::<$ty>::param_ref(self),)+)
+            }
+        }
+    };
+}
+
+#[cfg(feature = "gat")]
+impl_param_refs!(A, B);
+#[cfg(feature = "gat")]
+impl_param_refs!(A, B, C);
+#[cfg(feature = "gat")]
+impl_param_refs!(A, B, C, D);
+
 /// Represents an occupied slot in a certain_map slot.
 #[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Occupied<T>(pub T);
@@ -137,9 +594,22 @@ mod sealed {
     pub trait Sealed {}
     impl Sealed for super::OccupiedM {}
     impl Sealed for super::Vacancy {}
+
+    pub trait SealedStorage {}
+    impl<S> SealedStorage for &mut S {}
+    impl<S> SealedStorage for Box<S> {}
+    impl<S> SealedStorage for std::cell::RefMut<'_, S> {}
+    impl<S> SealedStorage for std::sync::MutexGuard<'_, S> {}
+    impl<S> SealedStorage for std::sync::RwLockWriteGuard<'_, S> {}
+
+    pub trait SealedPoolCell {}
+    impl<S> SealedPoolCell for std::sync::Mutex<Vec<S>> {}
+    impl<S> SealedPoolCell for std::cell::RefCell<Vec<S>> {}
 }
 
 pub trait MaybeAvailable: sealed::Sealed {
+    /// Whether the slot is occupied in this state.
+    const OCCUPIED: bool;
     /// # Safety
     /// Must called with correspond data reference.
     unsafe fn do_maybe_ref<T>(data: &MaybeUninit<T>) -> Option<&T>;
@@ -163,6 +633,11 @@ pub trait MaybeAvailable: sealed::Sealed {
     ) -> std::fmt::Result;
 }
 
+#[diagnostic::on_unimplemented(
+    message = "the certain_map slot is `{Self}` in this state",
+    label = "this field has not been set",
+    note = "set the field with `ParamSet` before the layer which reads it"
+)]
 pub trait Available: MaybeAvailable {
     /// # Safety
     /// Must called with correspond data reference.
@@ -198,6 +673,8 @@ impl Available for OccupiedM {
 }
 
 impl MaybeAvailable for OccupiedM {
+    const OCCUPIED: bool = true;
+
     #[inline]
     unsafe fn do_maybe_ref<T>(data: &MaybeUninit<T>) -> Option<&T> {
         Some(data.assume_init_ref())
@@ -210,8 +687,10 @@ impl MaybeAvailable for OccupiedM {
 
     #[inline]
     unsafe fn do_set<T>(data: &mut MaybeUninit<T>, value: T) {
-        data.assume_init_drop();
-        *data = MaybeUninit::new(value)
+        // Replace before dropping, so the slot is never left with a dropped value if the
+        // destructor panics.
+        let old = std::mem::replace(data, MaybeUninit::new(value));
+        drop(old.assume_init())
     }
 
     #[inline]
@@ -234,6 +713,8 @@ impl MaybeAvailable for OccupiedM {
 }
 
 impl MaybeAvailable for Vacancy {
+    const OCCUPIED: bool = false;
+
     #[inline]
     unsafe fn do_maybe_ref<T>(_data: &MaybeUninit<T>) -> Option<&T> {
         None
@@ -261,6 +742,342 @@ impl MaybeAvailable for Vacancy {
     }
 }
 
+/// The slot of a value of type T can be cloned, which needs `T: Clone` only when it is
+/// occupied. It bounds the generated `clone_owned()`.
+pub trait SlotClone<T>: MaybeAvailable {
+    /// # Safety
+    /// Must called with correspond data reference.
+    unsafe fn clone_slot(data: &MaybeUninit<T>) -> MaybeUninit<T>;
+}
+
+impl<T: Clone> SlotClone<T> for OccupiedM {
+    #[inline]
+    unsafe fn clone_slot(data: &MaybeUninit<T>) -> MaybeUninit<T> {
+        MaybeUninit::new(data.assume_init_ref().clone())
+    }
+}
+
+impl<T> SlotClone<T> for Vacancy {
+    #[inline]
+    unsafe fn clone_slot(_data: &MaybeUninit<T>) -> MaybeUninit<T> {
+        MaybeUninit::uninit()
+    }
+}
+
+/// The state of a slot after merging the slot of another handler into it, used by the
+/// generated `absorb()`.
+pub trait SlotUnion<R: MaybeAvailable>: MaybeAvailable {
+    type Output: MaybeAvailable;
+}
+
+impl SlotUnion<Vacancy> for Vacancy {
+    type Output = Vacancy;
+}
+
+impl SlotUnion<OccupiedM> for Vacancy {
+    type Output = OccupiedM;
+}
+
+impl<R: MaybeAvailable> SlotUnion<R> for OccupiedM {
+    type Output = OccupiedM;
+}
+
+/// The state of a slot after joining the handler with another one, occupied only if it is
+/// occupied in both.
+pub trait SlotIntersect<R: MaybeAvailable>: MaybeAvailable {
+    type Output: MaybeAvailable;
+}
+
+impl SlotIntersect<OccupiedM> for OccupiedM {
+    type Output = OccupiedM;
+}
+
+impl SlotIntersect<Vacancy> for OccupiedM {
+    type Output = Vacancy;
+}
+
+impl<R: MaybeAvailable> SlotIntersect<R> for Vacancy {
+    type Output = Vacancy;
+}
+
+/// Convert a handler into the common state it shares with the handler type `Other`, where
+/// a field is occupied only if it is occupied in both. The fields occupied in this handler only
+/// are dropped.
+///
+/// It is implemented by prefilled handlers for the handlers of every other state on the same
+/// storage. When the arms of a branch set different fields, joining each arm with the other one
+/// gives both the type [`Joined<A, B>`].
+///
+/// ```rust
+/// # use certain_map::{certain_map, Join, Joined, OccupiedM, ParamRef, ParamSet, Vacancy};
+/// pub struct Region(&'static str);
+/// pub struct Backend(&'static str);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         region: Region,
+///         backend: Backend,
+///     }
+/// }
+///
+/// type Cached = CxOwnedHandler<OccupiedM, Vacancy>;
+/// type Routed = CxOwnedHandler<OccupiedM, OccupiedM>;
+///
+/// fn route(cx: CxOwnedHandler<Vacancy, Vacancy>, cached: bool) -> Joined<Cached, Routed> {
+///     let cx = cx.param_set(Region("eu"));
+///     if cached {
+///         Join::<Routed>::join(cx)
+///     } else {
+///         Join::<Cached>::join(cx.param_set(Backend("origin")))
+///     }
+/// }
+///
+/// let cx = route(Cx::owned_handler(), false);
+/// assert_eq!(ParamRef::<Region>::param_ref(&cx).0, "eu");
+/// ```
+pub trait Join<Other>: Sized {
+    type Output;
+    fn join(self) -> Self::Output;
+}
+
+/// The common state of the handler types `A` and `B`, see [`Join`].
+pub type Joined<A, B> = <A as Join<B>>::Output;
+
+/// The slot can be weakened into the state `To`, vacant slots stay vacant.
+#[diagnostic::on_unimplemented(
+    message = "the certain_map slot `{Self}` can not be downgraded to `{To}`",
+    label = "the target state has a field which is not set"
+)]
+pub trait SlotDowngrade<To: MaybeAvailable>: MaybeAvailable {}
+
+impl SlotDowngrade<OccupiedM> for OccupiedM {}
+
+impl SlotDowngrade<Vacancy> for OccupiedM {}
+
+impl SlotDowngrade<Vacancy> for Vacancy {}
+
+/// Convert a handler into the state `Target`, whose occupied fields are a subset of the
+/// current ones, used by the generated `downgrade_to::<Target>()` and `forget_to::<Target>()`.
+///
+/// Loops which set fields in every iteration can re-enter with the loop-invariant state.
+///
+/// ```rust
+/// # use certain_map::{certain_map, OccupiedM, ParamRef, ParamSet, Vacancy};
+/// pub struct Conn(u32);
+/// pub struct Attempt(u8);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         conn: Conn,
+///         attempt: Attempt,
+///     }
+/// }
+///
+/// let mut cx = Cx::owned_handler().param_set(Conn(1));
+/// for attempt in 0..3 {
+///     let tried = cx.param_set(Attempt(attempt));
+///     assert_eq!(ParamRef::<Conn>::param_ref(&tried).0, 1);
+///     cx = tried.downgrade_to::<CxState<OccupiedM, Vacancy>>();
+/// }
+/// ```
+pub trait DowngradeTo<Target>: Sized {
+    type Output;
+    fn downgrade(self) -> Self::Output;
+    fn forget_extra(self) -> Self::Output;
+}
+
+/// The fields absent from a context, returned by the `try_from_cx()` generated by
+/// [`TryFromContext`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MissingParam {
+    pub missing: Vec<&'static str>,
+}
+
+impl std::fmt::Display for MissingParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "missing context fields: {}", self.missing.join(", "))
+    }
+}
+
+impl std::error::Error for MissingParam {}
+
+/// The error of the generated `try_promote()`, which gives back the handler.
+pub struct PromoteError<H> {
+    pub handler: H,
+    pub missing: MissingParam,
+}
+
+impl<H> std::fmt::Debug for PromoteError<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PromoteError")
+            .field("missing", &self.missing)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H> std::fmt::Display for PromoteError<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.missing, f)
+    }
+}
+
+impl<H> std::error::Error for PromoteError<H> {}
+
+/// Static information about a field of a map, listed in the generated `FIELDS` constant of the
+/// store in declaration order.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FieldInfo {
+    pub name: &'static str,
+    /// The type as written in the definition.
+    pub type_name: &'static str,
+    pub size: usize,
+    pub index: usize,
+}
+
+/// Probes of a vacant field, returned by the generated `vacancy_stats()` of the store with the
+/// `vacancy-stats` feature.
+///
+/// Fields with many misses are read by layers running before the field is set, which usually
+/// means the layers are in the wrong order or the field is never populated.
+#[cfg(feature = "vacancy-stats")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VacancyStat {
+    pub name: &'static str,
+    /// The number of `ParamMaybeRef` / `ParamMaybeMut` calls which returned `None`.
+    pub misses: usize,
+}
+
+#[cfg(feature = "vacancy-stats")]
+#[doc(hidden)]
+#[inline]
+pub fn __count_vacancy<T>(value: Option<T>, counter: &std::sync::atomic::AtomicUsize) -> Option<T> {
+    if value.is_none() {
+        counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+    value
+}
+
+/// Runtime information about a field of a handler, returned by the generated `audit()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FieldAudit {
+    pub name: &'static str,
+    pub type_name: &'static str,
+    pub occupied: bool,
+    /// Debug output of the value, if the field is occupied and ensures Debug.
+    pub debug: Option<String>,
+    /// Where the field was last set, taken or removed, with the `provenance` feature. Always
+    /// `None` without it.
+    pub location: Option<&'static std::panic::Location<'static>>,
+}
+
+/// Overwrite a slot with zeros for `#[secure_clear]` maps. The writes are volatile so they are
+/// not optimized out even if the slot is never read again.
+#[doc(hidden)]
+#[inline]
+pub fn __secure_clear<T>(slot: &mut MaybeUninit<T>) {
+    let ptr = slot.as_mut_ptr().cast::<u8>();
+    for offset in 0..std::mem::size_of::<T>() {
+        // Safety: the pointer is in bounds and MaybeUninit can hold any bytes.
+        unsafe { ptr.add(offset).write_volatile(0) };
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Implemented by the field types which are `Send`, checked for every field of maps with
+/// `#[assert_send]`. `Field` is a marker named after the field, so the error names it.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "the field `{Field}` of type `{Self}` is not `Send`",
+    label = "this field makes the context `!Send`",
+    note = "remove `#[assert_send]` to keep a context which stays on one thread"
+)]
+pub trait SendField<Field> {}
+
+impl<T: Send + ?Sized, Field> SendField<Field> for T {}
+
+#[doc(hidden)]
+#[inline]
+pub fn assert_send_field<Field, T: SendField<Field> + ?Sized>() {}
+
+/// Storage aligned to a cache line, used by fields with `#[padded]` or maps with
+/// `#[align_fields(cacheline)]`.
+///
+/// Modern x86_64 and aarch64 CPUs prefetch cache lines in pairs, so 128 bytes are used there.
+/// The value starts at the beginning of the storage.
+#[cfg_attr(
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    repr(C, align(128))
+)]
+#[cfg_attr(
+    not(any(target_arch = "x86_64", target_arch = "aarch64")),
+    repr(C, align(64))
+)]
+pub struct CachePadded<T>(pub T);
+
+/// The storage of a `#[reorder_by_align]` store at position `I` of its alignment order.
+#[doc(hidden)]
+pub struct SortedSlot<const I: usize>;
+
+/// Implemented by `SortedSlot<I>` for the `I`-th slot of a `#[reorder_by_align]` store.
+#[doc(hidden)]
+pub trait SlotOf<Store> {
+    type Slot;
+    const UNINIT: Self::Slot;
+}
+
+/// Debug a slot according to its state.
+#[doc(hidden)]
+pub struct DebugSlot<'a, S, T> {
+    data: &'a MaybeUninit<T>,
+    state: std::marker::PhantomData<S>,
+}
+
+impl<'a, S, T> DebugSlot<'a, S, T> {
+    /// # Safety
+    /// Must called with correspond data reference.
+    #[inline]
+    pub unsafe fn new(data: &'a MaybeUninit<T>) -> Self {
+        Self {
+            data,
+            state: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<S: MaybeAvailable, T: std::fmt::Debug> std::fmt::Debug for DebugSlot<'_, S, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        unsafe { S::do_debug(self.data, f) }
+    }
+}
+
+/// A handle through which a prefilled handler accesses its store.
+///
+/// The generated `{Map}HandlerIn<S, ..>` is generic over it: `{Map}Handler<'a, ..>` holds a
+/// `&'a mut` store and `{Map}OwnedHandler<..>` a `Box`. A store shared with `Rc<RefCell<_>>` or
+/// `Arc<Mutex<_>>` is used through its `RefMut` or `MutexGuard` with `{Map}::handler_in`.
+///
+/// The trait is sealed since the handler relies on every dereference reaching the same store.
+///
+/// ```rust
+/// # use std::{cell::RefCell, rc::Rc};
+/// # use certain_map::{certain_map, ParamRef, ParamSet};
+/// pub struct Region(&'static str);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         region: Region,
+///     }
+/// }
+///
+/// let shared = Rc::new(RefCell::new(Cx::new()));
+/// let cx = Cx::handler_in(shared.borrow_mut()).param_set(Region("eu"));
+/// assert_eq!(cx.param_ref().0, "eu");
+/// ```
+pub trait Storage<Store: ?Sized>: DerefMut<Target = Store> + sealed::SealedStorage {}
+
+impl<T: DerefMut + sealed::SealedStorage> Storage<T::Target> for T {}
+
+#[cfg(feature = "gat")]
 pub trait Handler {
     type Hdr<'a>
     where
@@ -268,12 +1085,19 @@ pub trait Handler {
     fn handler(&mut self) -> Self::Hdr<'_>;
 }
 
+/// Drop the `#[persistent]` fields left in a store by its last handler, implemented for every
+/// prefilled store. [`StorePool`] calls it before keeping a store for the next request.
+pub trait ReleasePersisted {
+    fn release_persisted(&mut self);
+}
+
 pub trait Fork {
     type Store;
     type State;
     fn fork(&self) -> (Self::Store, Self::State);
 }
 
+#[cfg(feature = "gat")]
 pub trait Attach<Store> {
     type Hdr<'a>
     where
@@ -282,3 +1106,50 @@ pub trait Attach<Store> {
     /// The caller must make sure the attached map has the data of current state.
     unsafe fn attach(self, store: &mut Store) -> Self::Hdr<'_>;
 }
+
+/// Attach a state to a boxed store, returning the owned handler of the state.
+///
+/// Unlike [`Attach`], the handler owns the store, so it is `'static` and can be stored or moved
+/// into a task.
+///
+/// ```rust
+/// # use certain_map::{certain_map, Fork, ParamRef, ParamSet};
+/// # #[derive(Clone)]
+/// # pub struct Region(&'static str);
+/// # certain_map! {
+/// #     #[derive(Clone)]
+/// #     pub struct Cx {
+/// #         region: Region,
+/// #     }
+/// # }
+/// let mut store = Cx::new();
+/// let cx = store.handler().param_set(Region("eu"));
+/// let (forked, state) = cx.fork();
+/// // Safety: the state is forked together with the store.
+/// let owned = unsafe { state.attach_owned(Box::new(forked)) };
+/// assert_eq!(ParamRef::<Region>::param_ref(&owned).0, "eu");
+/// ```
+pub trait AttachOwned<Store> {
+    type Owned;
+    /// # Safety
+    /// The caller must make sure the attached map has the data of current state.
+    unsafe fn attach_owned(self, store: Box<Store>) -> Self::Owned;
+}
+
+/// Fork a handler into an owned handler holding a boxed copy of the store.
+///
+/// Unlike [`Fork`], the forked state can not be attached to a wrong store, so no `unsafe` is
+/// needed to use the result.
+pub trait ForkOwned {
+    type Owned;
+    fn fork_owned(&self) -> Self::Owned;
+}
+
+/// Drop all occupied fields and return the empty map.
+///
+/// For prefilled handlers, the returned handler borrows the same store, so the store can be
+/// reused for the next iteration of a loop without re-creating the store/handler pair.
+pub trait Reset {
+    type Empty;
+    fn reset(self) -> Self::Empty;
+}
@@ -0,0 +1,139 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Pieces for thread-per-core runtimes like [monoio](https://docs.rs/monoio), where contexts
+//! never leave the thread which created them.
+//!
+//! The module does not depend on monoio: it only relies on the contexts staying on one thread,
+//! and [`spawn_with_ctx`] takes the spawner of the runtime. It has no context types either,
+//! stacks like monolake define their own maps.
+//!
+//! - [`LocalStorePool`] reuses stores without locking.
+//! - [`SharedCx`] shares a context by `Rc` instead of forking it, for the requests of a
+//!   connection which only read it.
+//! - [`spawn_with_ctx`] forks a context into a task spawned by any spawner, like
+//!   `monoio::spawn`, which accepts `!Send` futures.
+//! - Fields with `#[clone_strategy(rc)]`, with the `rc` feature, are shared between forks by
+//!   `Rc` instead of being cloned.
+//!
+//! ```rust
+//! use std::net::SocketAddr;
+//!
+//! use certain_map::{certain_map, local::SharedCx, ForkOwned, OccupiedM, ParamRef, ParamSet, Vacancy};
+//!
+//! #[derive(Clone, Copy, PartialEq, Debug)]
+//! pub struct PeerAddr(SocketAddr);
+//! #[derive(Clone, Copy, PartialEq, Debug)]
+//! pub struct RequestId(u64);
+//!
+//! certain_map! {
+//!     #[derive(Clone)]
+//!     pub struct Cx {
+//!         peer_addr: PeerAddr,
+//!         request_id: RequestId,
+//!     }
+//! }
+//!
+//! // Once per connection.
+//! let conn: CxOwnedHandler<OccupiedM, Vacancy> =
+//!     Cx::owned_handler().param_set(PeerAddr("10.0.0.1:5000".parse().unwrap()));
+//! let conn = SharedCx::new(conn);
+//!
+//! // For every request on the connection.
+//! for id in 0..2 {
+//!     let req = conn.fork_owned().param_set(RequestId(id));
+//!     assert_eq!(ParamRef::<PeerAddr>::param_ref(&req), conn.param_ref());
+//! }
+//! ```
+
+use std::{cell::RefCell, future::Future, ops::Deref, rc::Rc};
+
+use crate::{ForkOwned, ParamMaybeRef, ParamRef, StorePool};
+
+/// A pool of reusable stores for a single thread.
+///
+/// It is a [`StorePool`] keeping the stores in a `RefCell` instead of a `Mutex`, so it takes no
+/// lock and is `!Sync`.
+///
+/// ```rust
+/// # use certain_map::{certain_map, local::LocalStorePool};
+/// certain_map! {
+///     pub struct Cx {
+///         id: u64,
+///     }
+/// }
+///
+/// let pool = LocalStorePool::<Cx>::new(1);
+/// pool.put(pool.get());
+/// pool.put(Cx::new());
+/// assert_eq!(pool.idle(), 1);
+/// ```
+pub type LocalStorePool<Store> = StorePool<Store, RefCell<Vec<Store>>>;
+
+/// A context shared by `Rc`.
+///
+/// Cloning it is cheap, and it forwards `ParamRef` and `ParamMaybeRef`, so it satisfies the same
+/// bounds as the handler for reading. `Param` is reached through `Deref` since `SharedCx` is
+/// itself `Clone`. Use [`ForkOwned`] to derive a context which can be changed.
+pub struct SharedCx<H>(Rc<H>);
+
+impl<H> SharedCx<H> {
+    #[inline]
+    pub fn new(cx: H) -> Self {
+        Self(Rc::new(cx))
+    }
+}
+
+impl<H> Clone for SharedCx<H> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<H> Deref for SharedCx<H> {
+    type Target = H;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<H: ParamRef<T>, T> ParamRef<T> for SharedCx<H> {
+    #[inline]
+    fn param_ref(&self) -> &T {
+        self.0.param_ref()
+    }
+}
+
+impl<H: ParamMaybeRef<T>, T> ParamMaybeRef<T> for SharedCx<H> {
+    #[inline]
+    fn param_maybe_ref(&self) -> Option<&T> {
+        self.0.param_maybe_ref()
+    }
+}
+
+impl<H: ForkOwned> ForkOwned for SharedCx<H> {
+    type Owned = H::Owned;
+
+    #[inline]
+    fn fork_owned(&self) -> Self::Owned {
+        self.0.fork_owned()
+    }
+}
+
+/// Fork the context into an owned handler and spawn a task running `f` with it by `spawn`.
+///
+/// Unlike `task::spawn_with_ctx` for tokio, neither the context nor the future has to be
+/// `Send`, so it works with the spawners of thread-per-core runtimes:
+/// `spawn_with_ctx(&cx, monoio::spawn, |cx| async move { .. })`.
+#[inline]
+pub fn spawn_with_ctx<H, S, J, F, Fut>(cx: &H, spawn: S, f: F) -> J
+where
+    H: ForkOwned,
+    S: FnOnce(Fut) -> J,
+    F: FnOnce(H::Owned) -> Fut,
+    Fut: Future + 'static,
+{
+    spawn(f(cx.fork_owned()))
+}
@@ -0,0 +1,94 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Propagate context fields through [W3C baggage](https://www.w3.org/TR/baggage/) with
+//! [opentelemetry](https://docs.rs/opentelemetry).
+//!
+//! Fields marked `#[baggage("key")]` are exported by the generated `to_baggage()` and imported
+//! by `from_baggage()`, which sets all of them or fails with the handler untouched.
+//!
+//! ```rust
+//! use certain_map::{certain_map, ParamRef, ParamSet};
+//!
+//! certain_map! {
+//!     pub struct Cx {
+//!         #[baggage("tenant")]
+//!         tenant: String,
+//!         local: u8,
+//!     }
+//! }
+//!
+//! let mut store = Cx::new();
+//! let cx = store.handler().param_set("acme".to_string());
+//! let baggage = cx.to_baggage();
+//!
+//! // On the other side of the process boundary.
+//! let mut remote_store = Cx::new();
+//! let cx = remote_store.handler().from_baggage(&baggage).unwrap();
+//! assert_eq!(ParamRef::<String>::param_ref(&cx), "acme");
+//!
+//! // A missing key gives the handler back.
+//! let mut store = Cx::new();
+//! let Err(err) = store.handler().param_set(7u8).from_baggage(&Default::default()) else {
+//!     unreachable!()
+//! };
+//! assert_eq!(err.error, certain_map::otel::BaggageError::Missing("tenant"));
+//! assert_eq!(*ParamRef::<u8>::param_ref(&err.handler), 7);
+//! ```
+
+use std::{fmt, str::FromStr};
+
+pub use opentelemetry::baggage::Baggage;
+
+/// Error of the generated `from_baggage()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BaggageError {
+    /// The key is not in the baggage.
+    Missing(&'static str),
+    /// The value of the key can not be parsed into the field type.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for BaggageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BaggageError::Missing(key) => write!(f, "baggage key `{key}` is missing"),
+            BaggageError::Invalid(key) => write!(f, "baggage key `{key}` has an invalid value"),
+        }
+    }
+}
+
+impl std::error::Error for BaggageError {}
+
+/// Error of the generated `from_baggage()`, which gives the handler back since nothing is set
+/// when any field fails to parse.
+pub struct FromBaggageError<H> {
+    pub handler: H,
+    pub error: BaggageError,
+}
+
+impl<H> fmt::Debug for FromBaggageError<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FromBaggageError")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H> fmt::Display for FromBaggageError<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl<H> std::error::Error for FromBaggageError<H> {}
+
+#[doc(hidden)]
+#[inline]
+pub fn parse_baggage<T: FromStr>(baggage: &Baggage, key: &'static str) -> Result<T, BaggageError> {
+    baggage
+        .get(key)
+        .ok_or(BaggageError::Missing(key))?
+        .as_str()
+        .parse()
+        .map_err(|_| BaggageError::Invalid(key))
+}
@@ -0,0 +1,102 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+use std::{cell::RefCell, marker::PhantomData, sync::Mutex};
+
+use crate::ReleasePersisted;
+
+/// A pool of reusable stores.
+///
+/// A prefilled store holds no values once its handler is dropped, except the `#[persistent]`
+/// fields it keeps for `resume()`, which are dropped when it is returned to the pool. So it can
+/// be handed out to the next request instead of being created from scratch. At most `capacity`
+/// idle stores are kept; stores returned to a full pool are dropped.
+///
+/// The idle stores are kept in `Cell`, a `Mutex` by default. `LocalStorePool` of the `local`
+/// feature keeps them in a `RefCell` for a single thread.
+#[derive(Debug)]
+pub struct StorePool<Store, Cell = Mutex<Vec<Store>>> {
+    stores: Cell,
+    capacity: usize,
+    _store: PhantomData<fn() -> Store>,
+}
+
+impl<Store, Cell: PoolCell<Store>> StorePool<Store, Cell> {
+    #[inline]
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            stores: Cell::EMPTY,
+            capacity,
+            _store: PhantomData,
+        }
+    }
+
+    /// Take an idle store from the pool, or create a new one if the pool is empty.
+    #[inline]
+    pub fn get(&self) -> Store
+    where
+        Store: Default,
+    {
+        self.stores.with(|stores| stores.pop()).unwrap_or_default()
+    }
+
+    /// Return a store to the pool, dropping the `#[persistent]` fields left in it.
+    ///
+    /// The handler borrowing the store must have been dropped, which leaves only the
+    /// `#[persistent]` fields in the store.
+    #[inline]
+    pub fn put(&self, mut store: Store)
+    where
+        Store: ReleasePersisted,
+    {
+        store.release_persisted();
+        let overflow = self.stores.with(|stores| {
+            if stores.len() < self.capacity {
+                stores.push(store);
+                None
+            } else {
+                Some(store)
+            }
+        });
+        // Dropped after the lock is released.
+        drop(overflow);
+    }
+
+    /// Number of idle stores in the pool.
+    #[inline]
+    pub fn idle(&self) -> usize {
+        self.stores.with(|stores| stores.len())
+    }
+
+    #[inline]
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// The cell keeping the idle stores of a [`StorePool`], implemented for `Mutex<Vec<Store>>` and
+/// `RefCell<Vec<Store>>`.
+pub trait PoolCell<Store>: crate::sealed::SealedPoolCell {
+    #[doc(hidden)]
+    const EMPTY: Self;
+    #[doc(hidden)]
+    fn with<R>(&self, f: impl FnOnce(&mut Vec<Store>) -> R) -> R;
+}
+
+impl<Store> PoolCell<Store> for Mutex<Vec<Store>> {
+    const EMPTY: Self = Mutex::new(Vec::new());
+
+    #[inline]
+    fn with<R>(&self, f: impl FnOnce(&mut Vec<Store>) -> R) -> R {
+        // A panic while holding the lock cannot leave the Vec in an invalid state.
+        f(&mut self.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl<Store> PoolCell<Store> for RefCell<Vec<Store>> {
+    const EMPTY: Self = RefCell::new(Vec::new());
+
+    #[inline]
+    fn with<R>(&self, f: impl FnOnce(&mut Vec<Store>) -> R) -> R {
+        f(&mut self.borrow_mut())
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Fill fields with providers computing them from other fields of the context.
+//!
+//! A provider declares the fields it reads and the field it computes with [`Provider`], and
+//! implements [`Provide<CX>`] for the contexts it can work with, usually by requiring `ParamRef`
+//! bounds on `CX`. [`Providers::resolve`] runs the registered providers in the order of their
+//! inputs, whatever the order they are registered in: a provider runs once all its inputs are
+//! occupied, and only if its output is still vacant. Inputs no provider can fill leave the
+//! providers depending on them unrun, so using their outputs fails to compile. A single
+//! provider can be run with [`ProvideExt::resolve`], or an async one with
+//! [`ProvideExt::fill_with`].
+//!
+//! The occupancy of the fields is read from [`ParamState`], which prefilled handlers implement.
+//!
+//! ```rust
+//! use certain_map::{
+//!     certain_map,
+//!     provide::{Provide, Provider, Providers},
+//!     ParamRef, ParamSet,
+//! };
+//!
+//! #[derive(Clone)]
+//! pub struct Host(&'static str);
+//! #[derive(Clone)]
+//! pub struct Upstream(String);
+//! #[derive(Clone)]
+//! pub struct Timeout(u64);
+//!
+//! certain_map! {
+//!     pub struct Cx {
+//!         host: Host,
+//!         upstream: Upstream,
+//!         timeout: Timeout,
+//!     }
+//! }
+//!
+//! struct UpstreamProvider;
+//! impl Provider for UpstreamProvider {
+//!     type Inputs = (Host,);
+//!     type Output = Upstream;
+//! }
+//! impl<CX: ParamRef<Host>> Provide<CX> for UpstreamProvider {
+//!     fn provide(&self, cx: &CX) -> Upstream {
+//!         Upstream(format!("{}.internal", cx.param_ref().0))
+//!     }
+//! }
+//!
+//! struct TimeoutProvider;
+//! impl Provider for TimeoutProvider {
+//!     type Inputs = (Upstream,);
+//!     type Output = Timeout;
+//! }
+//! impl<CX: ParamRef<Upstream>> Provide<CX> for TimeoutProvider {
+//!     fn provide(&self, cx: &CX) -> Timeout {
+//!         Timeout(if cx.param_ref().0.starts_with("db") { 100 } else { 10 })
+//!     }
+//! }
+//!
+//! // The timeout provider is registered first, but runs after the upstream it reads.
+//! let providers = Providers::new().with(TimeoutProvider).with(UpstreamProvider);
+//! let mut store = Cx::new();
+//! let cx = providers.resolve(store.handler().param_set(Host("db")));
+//! assert_eq!(ParamRef::<Timeout>::param_ref(&cx).0, 100);
+//!
+//! // Occupied fields are kept, and the providers reading them use them.
+//! let mut store = Cx::new();
+//! let cx = store.handler().param_set(Host("db")).param_set(Upstream("web".to_string()));
+//! let cx = providers.resolve(cx);
+//! assert_eq!(ParamRef::<Upstream>::param_ref(&cx).0, "web");
+//! assert_eq!(ParamRef::<Timeout>::param_ref(&cx).0, 10);
+//! ```
+
+use std::future::Future;
+
+use crate::{OccupiedM, ParamSet, ParamState, ParamVacant, Vacancy};
+
+/// The fields a provider reads and the field it computes, whatever the context.
+pub trait Provider {
+    /// The types of the fields read by the provider, as a tuple like `(Host, Upstream)`.
+    type Inputs;
+    type Output;
+}
+
+/// Compute a field from the context.
+pub trait Provide<CX>: Provider {
+    fn provide(&self, cx: &CX) -> Self::Output;
+}
+
+/// Compute a field from the context asynchronously, like fetching a config or resolving a name.
+///
+/// Implementations may use `async fn provide`.
+pub trait ProvideAsync<CX>: Provider {
+    fn provide(&self, cx: &CX) -> impl Future<Output = Self::Output>;
+}
+
+/// Run a single provider and set its output into the context.
+pub trait ProvideExt: Sized {
+    /// Compute the field with the provider and set it. The field must be vacant.
+    ///
+    /// ```rust,compile_fail
+    /// # use certain_map::{certain_map, provide::{Provide, ProvideExt, Provider}, ParamRef, ParamSet};
+    /// # pub struct Host(&'static str);
+    /// # pub struct Upstream(String);
+    /// # certain_map! {
+    /// #     pub struct Cx {
+    /// #         host: Host,
+    /// #         upstream: Upstream,
+    /// #     }
+    /// # }
+    /// struct UpstreamProvider;
+    /// impl Provider for UpstreamProvider {
+    ///     type Inputs = (Host,);
+    ///     type Output = Upstream;
+    /// }
+    /// impl<CX: ParamRef<Host>> Provide<CX> for UpstreamProvider {
+    ///     fn provide(&self, cx: &CX) -> Upstream {
+    ///         Upstream(format!("{}.internal", cx.param_ref().0))
+    ///     }
+    /// }
+    ///
+    /// let mut store = Cx::new();
+    /// let cx = store.handler().param_set(Host("db")).param_set(Upstream("web".to_string()));
+    /// // The upstream is occupied, so it can not be resolved again.
+    /// let _cx = cx.resolve(&UpstreamProvider);
+    /// ```
+    #[inline]
+    fn resolve<P>(self, provider: &P) -> Self::Transformed
+    where
+        P: Provide<Self>,
+        Self: ParamVacant<P::Output> + ParamSet<P::Output>,
+    {
+        let value = provider.provide(&self);
+        self.param_set(value)
+    }
+
+    /// Compute the field with the async provider and set it. The field must be vacant.
+    ///
+    /// ```rust
+    /// # use certain_map::{certain_map, provide::{ProvideAsync, ProvideExt, Provider}, ParamRef, ParamSet};
+    /// # #[derive(Clone)]
+    /// # pub struct Host(&'static str);
+    /// # #[derive(Clone)]
+    /// # pub struct Addr(String);
+    /// # certain_map! {
+    /// #     pub struct Cx {
+    /// #         host: Host,
+    /// #         addr: Addr,
+    /// #     }
+    /// # }
+    /// struct Lookup;
+    /// impl Provider for Lookup {
+    ///     type Inputs = (Host,);
+    ///     type Output = Addr;
+    /// }
+    /// impl<CX: ParamRef<Host>> ProvideAsync<CX> for Lookup {
+    ///     async fn provide(&self, cx: &CX) -> Addr {
+    ///         // A real lookup would await here.
+    ///         Addr(format!("{}:443", cx.param_ref().0))
+    ///     }
+    /// }
+    ///
+    /// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+    /// let mut store = Cx::new();
+    /// let cx = store.handler().param_set(Host("example.com")).fill_with(&Lookup).await;
+    /// assert_eq!(ParamRef::<Addr>::param_ref(&cx).0, "example.com:443");
+    /// # });
+    /// ```
+    #[inline]
+    fn fill_with<P>(self, provider: &P) -> impl Future<Output = Self::Transformed>
+    where
+        P: ProvideAsync<Self>,
+        Self: ParamVacant<P::Output> + ParamSet<P::Output>,
+    {
+        async move {
+            let value = provider.provide(&self).await;
+            self.param_set(value)
+        }
+    }
+}
+
+impl<CX> ProvideExt for CX {}
+
+/// The inputs of a provider, a tuple of field types. `Occupied` is [`OccupiedM`] when all of
+/// them are occupied in `CX`, and [`Vacancy`] otherwise.
+pub trait Inputs<CX> {
+    type Occupied;
+}
+
+/// `Output` is `B` for [`OccupiedM`] and [`Vacancy`] for [`Vacancy`].
+pub trait Both<B> {
+    type Output;
+}
+
+impl<B> Both<B> for OccupiedM {
+    type Output = B;
+}
+
+impl<B> Both<B> for Vacancy {
+    type Output = Vacancy;
+}
+
+impl<CX> Inputs<CX> for () {
+    type Occupied = OccupiedM;
+}
+
+macro_rules! impl_inputs {
+    ($head:ident $(, $tail:ident)*) => {
+        impl<CX, $head, $($tail),*> Inputs<CX> for ($head, $($tail,)*)
+        where
+            CX: ParamState<$head>,
+            ($($tail,)*): Inputs<CX>,
+            <CX as ParamState<$head>>::State: Both<<($($tail,)*) as Inputs<CX>>::Occupied>,
+        {
+            type Occupied = <<CX as ParamState<$head>>::State as Both<
+                <($($tail,)*) as Inputs<CX>>::Occupied,
+            >>::Output;
+        }
+        impl_inputs!($($tail),*);
+    };
+    () => {};
+}
+
+impl_inputs!(A, B, C, D, E, F, G, H);
+
+/// Run the provider `P` on `CX` or skip it, implemented for the occupancy of its inputs and of
+/// its output: it only runs when the inputs are occupied and the output is vacant.
+pub trait Step<CX, P> {
+    type Output;
+    fn step(cx: CX, provider: &P) -> Self::Output;
+}
+
+impl<CX, P> Step<CX, P> for (OccupiedM, Vacancy)
+where
+    P: Provide<CX>,
+    CX: ParamSet<P::Output>,
+{
+    type Output = CX::Transformed;
+    #[inline]
+    fn step(cx: CX, provider: &P) -> Self::Output {
+        let value = provider.provide(&cx);
+        cx.param_set(value)
+    }
+}
+
+impl<CX, P> Step<CX, P> for (OccupiedM, OccupiedM) {
+    type Output = CX;
+    #[inline]
+    fn step(cx: CX, _provider: &P) -> Self::Output {
+        cx
+    }
+}
+
+impl<CX, P, S> Step<CX, P> for (Vacancy, S) {
+    type Output = CX;
+    #[inline]
+    fn step(cx: CX, _provider: &P) -> Self::Output {
+        cx
+    }
+}
+
+/// One pass over the providers, running the ones which are ready.
+///
+/// Implemented for `()` and `(P, Rest)`, where `P` is stepped before `Rest`.
+pub trait ProvidePass<CX> {
+    type Output;
+    fn provide_pass(&self, cx: CX) -> Self::Output;
+}
+
+impl<CX> ProvidePass<CX> for () {
+    type Output = CX;
+    #[inline]
+    fn provide_pass(&self, cx: CX) -> Self::Output {
+        cx
+    }
+}
+
+// The occupancy of the inputs and of the output of the provider `P` in `CX`, which selects its
+// step.
+type StepOf<CX, P> = (
+    <<P as Provider>::Inputs as Inputs<CX>>::Occupied,
+    <CX as ParamState<<P as Provider>::Output>>::State,
+);
+
+impl<CX, P, Rest> ProvidePass<CX> for (P, Rest)
+where
+    P: Provider,
+    P::Inputs: Inputs<CX>,
+    CX: ParamState<P::Output>,
+    StepOf<CX, P>: Step<CX, P>,
+    Rest: ProvidePass<<StepOf<CX, P> as Step<CX, P>>::Output>,
+{
+    type Output = Rest::Output;
+    #[inline]
+    fn provide_pass(&self, cx: CX) -> Self::Output {
+        let cx = <StepOf<CX, P> as Step<CX, P>>::step(cx, &self.0);
+        self.1.provide_pass(cx)
+    }
+}
+
+/// Passes over the providers `L`, one for every element of the counter implementing it, so a
+/// pass per provider runs even the last one of a chain registered in reverse.
+pub trait ProvidePasses<L, CX> {
+    type Output;
+    fn provide_passes(list: &L, cx: CX) -> Self::Output;
+}
+
+impl<L, CX> ProvidePasses<L, CX> for () {
+    type Output = CX;
+    #[inline]
+    fn provide_passes(_list: &L, cx: CX) -> Self::Output {
+        cx
+    }
+}
+
+impl<L, CX, P, Rest> ProvidePasses<L, CX> for (P, Rest)
+where
+    L: ProvidePass<CX>,
+    Rest: ProvidePasses<L, L::Output>,
+{
+    type Output = Rest::Output;
+    #[inline]
+    fn provide_passes(list: &L, cx: CX) -> Self::Output {
+        Rest::provide_passes(list, list.provide_pass(cx))
+    }
+}
+
+/// A registry of providers, run in the order of their inputs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Providers<L = ()> {
+    list: L,
+}
+
+impl Providers {
+    #[inline]
+    pub const fn new() -> Self {
+        Self { list: () }
+    }
+}
+
+impl<L> Providers<L> {
+    /// Register a provider.
+    #[inline]
+    pub fn with<P>(self, provider: P) -> Providers<(P, L)> {
+        Providers {
+            list: (provider, self.list),
+        }
+    }
+
+    /// Run the providers whose inputs are occupied and whose output is vacant, until none is
+    /// left, and return the context with their outputs set.
+    #[inline]
+    pub fn resolve<CX>(&self, cx: CX) -> <L as ProvidePasses<L, CX>>::Output
+    where
+        L: ProvidePasses<L, CX>,
+    {
+        L::provide_passes(&self.list, cx)
+    }
+}
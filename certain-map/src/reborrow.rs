@@ -0,0 +1,109 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+use std::ops::Deref;
+
+use crate::{Fork, ForkOwned, Param, ParamMaybeMut, ParamMaybeRef, ParamMut, ParamRef};
+
+/// A context borrowed from a handler for a sub-call, returned by `reborrow()` of the generated
+/// handlers.
+///
+/// Sub-calls usually take the context by value, which moves the handler away. `Reborrow`
+/// forwards the `Param*` traits which do not change the state instead, so the fields can be
+/// read and mutated by the sub-call and the original handler is usable again afterwards.
+/// Setting or removing fields still needs the handler by value. Handlers are covariant in the
+/// lifetime of their store, so one passed by value shortens its lifetime without any method.
+///
+/// ```rust
+/// # use certain_map::{certain_map, ParamMut, ParamRef, ParamSet};
+/// #[derive(Debug, PartialEq)]
+/// pub struct Retries(u8);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         retries: Retries,
+///     }
+/// }
+///
+/// fn retry<CX: ParamMut<Retries>>(mut cx: CX) {
+///     cx.param_mut().0 += 1;
+/// }
+///
+/// let mut store = Cx::new();
+/// let mut cx = store.handler().param_set(Retries(0));
+/// retry(cx.reborrow());
+/// retry(cx.reborrow());
+/// assert_eq!(cx.param_ref(), &Retries(2));
+/// ```
+pub struct Reborrow<'a, H> {
+    hdr: &'a mut H,
+}
+
+impl<'a, H> Reborrow<'a, H> {
+    #[inline]
+    pub fn new(hdr: &'a mut H) -> Self {
+        Self { hdr }
+    }
+}
+
+impl<H> Deref for Reborrow<'_, H> {
+    type Target = H;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.hdr
+    }
+}
+
+impl<H: ParamRef<T>, T> ParamRef<T> for Reborrow<'_, H> {
+    #[inline]
+    fn param_ref(&self) -> &T {
+        self.hdr.param_ref()
+    }
+}
+
+impl<H: ParamMut<T>, T> ParamMut<T> for Reborrow<'_, H> {
+    #[inline]
+    fn param_mut(&mut self) -> &mut T {
+        self.hdr.param_mut()
+    }
+}
+
+impl<H: ParamMaybeRef<T>, T> ParamMaybeRef<T> for Reborrow<'_, H> {
+    #[inline]
+    fn param_maybe_ref(&self) -> Option<&T> {
+        self.hdr.param_maybe_ref()
+    }
+}
+
+impl<H: ParamMaybeMut<T>, T> ParamMaybeMut<T> for Reborrow<'_, H> {
+    #[inline]
+    fn param_maybe_mut(&mut self) -> Option<&mut T> {
+        self.hdr.param_maybe_mut()
+    }
+}
+
+impl<H: Param<T>, T> Param<T> for Reborrow<'_, H> {
+    #[inline]
+    fn param(&self) -> T {
+        self.hdr.param()
+    }
+}
+
+impl<H: Fork> Fork for Reborrow<'_, H> {
+    type Store = H::Store;
+    type State = H::State;
+
+    #[inline]
+    fn fork(&self) -> (Self::Store, Self::State) {
+        self.hdr.fork()
+    }
+}
+
+impl<H: ForkOwned> ForkOwned for Reborrow<'_, H> {
+    type Owned = H::Owned;
+
+    #[inline]
+    fn fork_owned(&self) -> Self::Owned {
+        self.hdr.fork_owned()
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! [JSON Schema](https://json-schema.org) of full maps with [schemars](https://docs.rs/schemars).
+//!
+//! With `#[derive(JsonSchema)]` on a prefilled map, the full handlers implement `JsonSchema`,
+//! describing an object with every field of the map. Fields shown in Debug output (with
+//! `#[derive(Debug)]` or `#[ensure(Debug)]`) use the schema of their type, which must implement
+//! `JsonSchema`, and the others are redacted: their schema only marks them `writeOnly`.
+//!
+//! ```rust
+//! use certain_map::certain_map;
+//!
+//! certain_map! {
+//!     #[full(CxFull)]
+//!     #[derive(JsonSchema)]
+//!     pub struct Cx {
+//!         #[ensure(Debug)]
+//!         user: String,
+//!         token: Vec<u8>,
+//!     }
+//! }
+//!
+//! let schema = schemars::schema_for!(CxFull<'static>);
+//! assert_eq!(schema.pointer("/properties/user/type").unwrap(), "string");
+//! assert_eq!(schema.pointer("/properties/token/writeOnly").unwrap(), true);
+//! ```
+
+use std::collections::BTreeMap;
+
+pub use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+
+/// Build the schema of a full map from the schemas of its fields, `None` for redacted ones.
+#[doc(hidden)]
+pub fn map_schema(title: &str, fields: Vec<(&'static str, Option<Schema>)>) -> Schema {
+    let required: Vec<_> = fields.iter().map(|(name, _)| *name).collect();
+    let properties: BTreeMap<_, _> = fields
+        .into_iter()
+        .map(|(name, schema)| {
+            let schema = schema.unwrap_or_else(|| {
+                json_schema!({
+                    "description": "Redacted.",
+                    "writeOnly": true,
+                })
+            });
+            (name, schema)
+        })
+        .collect();
+    json_schema!({
+        "title": title,
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
@@ -0,0 +1,69 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Helpers for keeping [secrecy](https://docs.rs/secrecy) secrets in a certain_map.
+//!
+//! Store credentials as `SecretBox<T>` (or `SecretString`) fields. The generated
+//! `ParamRef<SecretBox<T>>` keeps the secret wrapped, and [`ParamExpose`] reads the inner value
+//! explicitly where it is really needed. Secrets are redacted in the generated Debug output and
+//! `audit()`, since `SecretBox` only prints its type, and they are zeroized when dropped, which
+//! happens when the field is removed or the handler is reset or dropped.
+//!
+//! ```rust
+//! use certain_map::{certain_map, secret::{ParamExpose, SecretString}, ParamSet};
+//!
+//! certain_map! {
+//!     #[derive(Debug)]
+//!     pub struct Cx {
+//!         token: SecretString,
+//!     }
+//! }
+//!
+//! fn authorize<CX: ParamExpose<str>>(cx: &CX) -> bool {
+//!     cx.param_expose() == "hunter2"
+//! }
+//!
+//! let mut store = Cx::new();
+//! let cx = store.handler().param_set(SecretString::from("hunter2"));
+//! assert!(authorize(&cx));
+//! assert!(!format!("{cx:?}").contains("hunter2"));
+//! ```
+
+use secrecy::zeroize::Zeroize;
+pub use secrecy::{ExposeSecret, ExposeSecretMut, SecretBox, SecretString};
+
+use crate::{ParamMut, ParamRef};
+
+/// Expose the secret stored as `SecretBox<T>` in the map.
+///
+/// Implemented for every `ParamRef<SecretBox<T>>`, so it can be used as a bound just like
+/// `ParamRef`, while making the place where the secret is read explicit.
+pub trait ParamExpose<T: Zeroize + ?Sized> {
+    fn param_expose(&self) -> &T;
+}
+
+impl<H, T> ParamExpose<T> for H
+where
+    H: ParamRef<SecretBox<T>>,
+    T: Zeroize + ?Sized,
+{
+    #[inline]
+    fn param_expose(&self) -> &T {
+        self.param_ref().expose_secret()
+    }
+}
+
+/// Expose the secret stored as `SecretBox<T>` in the map mutably.
+pub trait ParamExposeMut<T: Zeroize + ?Sized> {
+    fn param_expose_mut(&mut self) -> &mut T;
+}
+
+impl<H, T> ParamExposeMut<T> for H
+where
+    H: ParamMut<SecretBox<T>>,
+    T: Zeroize + ?Sized,
+{
+    #[inline]
+    fn param_expose_mut(&mut self) -> &mut T {
+        self.param_mut().expose_secret_mut()
+    }
+}
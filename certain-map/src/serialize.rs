@@ -0,0 +1,30 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Serialization of prefilled maps with [serde](https://serde.rs).
+//!
+//! With `#[derive(Serialize)]` on a prefilled map, the handlers of every state implement
+//! `Serialize` as a struct of their occupied fields. With `#[derive(Deserialize)]`, the owned
+//! handler with every field occupied implements `Deserialize`. Fields take `#[serde(rename =
+//! "..")]`, `#[serde(with = "..")]` and `#[serde(skip)]`; skipped fields are never serialized and
+//! stay vacant after deserialization.
+//!
+//! ```rust
+//! use certain_map::certain_map;
+//!
+//! certain_map! {
+//!     #[derive(Serialize, Deserialize)]
+//!     pub struct Cx {
+//!         #[serde(rename = "user_id")]
+//!         user: u64,
+//!         #[serde(skip)]
+//!         token: Vec<u8>,
+//!     }
+//! }
+//!
+//! let cx: CxOwnedHandler<_, _> = serde_json::from_str(r#"{"user_id":7}"#).unwrap();
+//! assert_eq!(*certain_map::ParamRef::<u64>::param_ref(&cx), 7);
+//! assert_eq!(serde_json::to_string(&cx).unwrap(), r#"{"user_id":7}"#);
+//! ```
+
+pub use serde;
+pub use serde::{Deserialize, Deserializer, Serialize, Serializer};
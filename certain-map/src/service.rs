@@ -0,0 +1,881 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Helpers for passing certain_map contexts through
+//! [service-async](https://docs.rs/service-async) service stacks.
+
+use std::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+};
+
+pub use service_async::Service;
+use service_async::{layer::FactoryLayer, MakeService};
+
+use crate::{Attach, Fork, Handler, ParamSet, ReleasePersisted, StorePool};
+
+/// A service stack that can be called with the empty context of `Store`.
+///
+/// It is implemented for every `S` that accepts `(Req, Store::Hdr<'_>)`. Each layer of a
+/// stack usually requires some `ParamRef<T>` bounds and sets fields with `ParamSet<T>`, so
+/// whether the whole stack accepts the empty handler is only known after all the layers are
+/// composed. Use [`assert_stack_ok!`](crate::assert_stack_ok) to check that at the place
+/// where the stack is defined instead of where it is first called.
+pub trait StackCheck<Req, Store> {}
+
+impl<S, Req, Store> StackCheck<Req, Store> for S
+where
+    Store: Handler,
+    for<'a> S: Service<(Req, Store::Hdr<'a>)>,
+{
+}
+
+/// Assert at compile time that a service stack can run from the empty state of a map.
+///
+/// The first argument is the map (store) type, the second one is the service stack, and the
+/// optional third one is the request type passed along with the handler (defaults to `()`).
+///
+/// ```rust
+/// use std::{convert::Infallible, future::Future};
+///
+/// use certain_map::{assert_stack_ok, certain_map, service::Service, ParamRef, ParamSet};
+///
+/// pub struct RawBeforeAdd(u8);
+/// pub struct RawBeforeMul(u8);
+///
+/// certain_map! {
+///     pub struct MyCertainMap {
+///         raw_before_add: RawBeforeAdd,
+///         raw_before_mul: RawBeforeMul,
+///     }
+/// }
+///
+/// // Sets `RawBeforeAdd`.
+/// pub struct Add1<T>(T);
+///
+/// impl<T, CX> Service<(u8, CX)> for Add1<T>
+/// where
+///     CX: ParamSet<RawBeforeAdd>,
+///     T: Service<(u8, CX::Transformed)>,
+/// {
+///     type Response = T::Response;
+///     type Error = T::Error;
+///
+///     fn call(&self, (num, cx): (u8, CX)) -> impl Future<Output = Result<T::Response, T::Error>> {
+///         self.0.call((num + 1, cx.param_set(RawBeforeAdd(num))))
+///     }
+/// }
+///
+/// // Sets `RawBeforeMul`.
+/// pub struct Mul2<T>(T);
+///
+/// impl<T, CX> Service<(u8, CX)> for Mul2<T>
+/// where
+///     CX: ParamSet<RawBeforeMul>,
+///     T: Service<(u8, CX::Transformed)>,
+/// {
+///     type Response = T::Response;
+///     type Error = T::Error;
+///
+///     fn call(&self, (num, cx): (u8, CX)) -> impl Future<Output = Result<T::Response, T::Error>> {
+///         self.0.call((num * 2, cx.param_set(RawBeforeMul(num))))
+///     }
+/// }
+///
+/// // Reads both.
+/// pub struct Identical;
+///
+/// impl<CX> Service<(u8, CX)> for Identical
+/// where
+///     CX: ParamRef<RawBeforeAdd> + ParamRef<RawBeforeMul>,
+/// {
+///     type Response = u8;
+///     type Error = Infallible;
+///
+///     async fn call(&self, (num, cx): (u8, CX)) -> Result<u8, Infallible> {
+///         let add: &RawBeforeAdd = cx.param_ref();
+///         let mul: &RawBeforeMul = cx.param_ref();
+///         Ok(num + add.0 + mul.0)
+///     }
+/// }
+///
+/// assert_stack_ok!(MyCertainMap, Add1<Mul2<Identical>>, u8);
+/// ```
+///
+/// When some layer reads a field that no outer layer has set, compilation fails and the
+/// `required for` notes walk through the layers down to the one whose bound is unmet. Without
+/// `Mul2`, the error says that `RawBeforeMul` has not been set, with the note
+/// ``required for `Identical` to implement `for<'a> Service<(u8, MyCertainMapHandlerIn<&'a mut
+/// MyCertainMap, OccupiedM, Vacancy>)>` ``, naming `Identical` as the first layer which fails:
+///
+/// ```rust,compile_fail,E0277
+/// # use std::{convert::Infallible, future::Future};
+/// # use certain_map::{assert_stack_ok, certain_map, service::Service, ParamRef, ParamSet};
+/// # pub struct RawBeforeAdd(u8);
+/// # pub struct RawBeforeMul(u8);
+/// # certain_map! {
+/// #     pub struct MyCertainMap {
+/// #         raw_before_add: RawBeforeAdd,
+/// #         raw_before_mul: RawBeforeMul,
+/// #     }
+/// # }
+/// # pub struct Add1<T>(T);
+/// # impl<T, CX> Service<(u8, CX)> for Add1<T>
+/// # where
+/// #     CX: ParamSet<RawBeforeAdd>,
+/// #     T: Service<(u8, CX::Transformed)>,
+/// # {
+/// #     type Response = T::Response;
+/// #     type Error = T::Error;
+/// #     fn call(&self, (num, cx): (u8, CX)) -> impl Future<Output = Result<T::Response, T::Error>> {
+/// #         self.0.call((num + 1, cx.param_set(RawBeforeAdd(num))))
+/// #     }
+/// # }
+/// # pub struct Mul2<T>(T);
+/// # impl<T, CX> Service<(u8, CX)> for Mul2<T>
+/// # where
+/// #     CX: ParamSet<RawBeforeMul>,
+/// #     T: Service<(u8, CX::Transformed)>,
+/// # {
+/// #     type Response = T::Response;
+/// #     type Error = T::Error;
+/// #     fn call(&self, (num, cx): (u8, CX)) -> impl Future<Output = Result<T::Response, T::Error>> {
+/// #         self.0.call((num * 2, cx.param_set(RawBeforeMul(num))))
+/// #     }
+/// # }
+/// # pub struct Identical;
+/// # impl<CX> Service<(u8, CX)> for Identical
+/// # where
+/// #     CX: ParamRef<RawBeforeAdd> + ParamRef<RawBeforeMul>,
+/// # {
+/// #     type Response = u8;
+/// #     type Error = Infallible;
+/// #     async fn call(&self, (num, cx): (u8, CX)) -> Result<u8, Infallible> {
+/// #         let add: &RawBeforeAdd = cx.param_ref();
+/// #         let mul: &RawBeforeMul = cx.param_ref();
+/// #         Ok(num + add.0 + mul.0)
+/// #     }
+/// # }
+/// assert_stack_ok!(MyCertainMap, Add1<Identical>, u8);
+/// ```
+#[macro_export]
+macro_rules! assert_stack_ok {
+    ($store:ty, $stack:ty $(,)?) => {
+        $crate::assert_stack_ok!($store, $stack, ());
+    };
+    ($store:ty, $stack:ty, $req:ty $(,)?) => {
+        const _: () = {
+            fn __assert_stack_ok<S: $crate::service::StackCheck<$req, $store>>() {}
+            #[allow(dead_code)]
+            fn __check() {
+                __assert_stack_ok::<$stack>();
+            }
+        };
+    };
+}
+
+/// A service which sets a clone of `value` into the context before calling the inner service.
+///
+/// ```rust
+/// # use certain_map::{certain_map, service::SetParam};
+/// #[derive(Clone)]
+/// pub struct Region(&'static str);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         region: Region,
+///     }
+/// }
+///
+/// # struct Inner;
+/// // Every request passed through `svc` has `Region("eu")` set in its context.
+/// let svc = SetParam::new(Region("eu"), Inner);
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SetParam<T, S> {
+    pub value: T,
+    pub inner: S,
+}
+
+impl<T, S> SetParam<T, S> {
+    #[inline]
+    pub const fn new(value: T, inner: S) -> Self {
+        Self { value, inner }
+    }
+}
+
+impl<T, S, R, CX> Service<(R, CX)> for SetParam<T, S>
+where
+    T: Clone,
+    CX: ParamSet<T>,
+    S: Service<(R, CX::Transformed)>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    #[inline]
+    fn call(
+        &self,
+        (req, cx): (R, CX),
+    ) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        self.inner.call((req, cx.param_set(self.value.clone())))
+    }
+}
+
+/// A service which transforms the context with `f` before calling the inner service.
+///
+/// The function receives the context by value, so it may set, take or remove any fields, as
+/// long as its output type is accepted by the inner service.
+///
+/// ```rust
+/// use std::convert::Infallible;
+///
+/// use certain_map::{
+///     certain_map,
+///     service::{MapContext, Service},
+///     ParamMaybeRef, ParamRemove, ParamSet,
+/// };
+///
+/// pub struct UserName(String);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         name: UserName,
+///     }
+/// }
+///
+/// struct Inner;
+///
+/// impl<CX: ParamMaybeRef<UserName>> Service<((), CX)> for Inner {
+///     type Response = bool;
+///     type Error = Infallible;
+///
+///     async fn call(&self, (_, cx): ((), CX)) -> Result<bool, Infallible> {
+///         Ok(cx.param_maybe_ref().is_some())
+///     }
+/// }
+///
+/// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+/// // Drop the user name before calling the inner service.
+/// let svc = MapContext::new(ParamRemove::<UserName>::param_remove, Inner);
+/// let mut store = Cx::new();
+/// let cx = store.handler().param_set(UserName("ihciah".to_string()));
+/// assert_eq!(svc.call(((), cx)).await, Ok(false));
+/// # });
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MapContext<F, S> {
+    pub f: F,
+    pub inner: S,
+}
+
+impl<F, S> MapContext<F, S> {
+    #[inline]
+    pub const fn new(f: F, inner: S) -> Self {
+        Self { f, inner }
+    }
+}
+
+impl<F, S, R, CX, CXOut> Service<(R, CX)> for MapContext<F, S>
+where
+    F: Fn(CX) -> CXOut,
+    S: Service<(R, CXOut)>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    #[inline]
+    fn call(
+        &self,
+        (req, cx): (R, CX),
+    ) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        self.inner.call((req, (self.f)(cx)))
+    }
+}
+
+/// Reads the request and the context for [`Inspect`].
+///
+/// It is implemented for closures taking `(&R, &CX)`, which suit a concrete context type like
+/// in tests. Implement it on a struct to bound `CX` with the param traits the inspector needs
+/// instead, so it fits any stack setting those fields.
+pub trait Inspector<R, CX> {
+    fn inspect(&self, req: &R, cx: &CX);
+}
+
+impl<F, R, CX> Inspector<R, CX> for F
+where
+    F: Fn(&R, &CX),
+{
+    #[inline]
+    fn inspect(&self, req: &R, cx: &CX) {
+        self(req, cx)
+    }
+}
+
+/// A service which runs `f` with the request and a read-only view of the context before
+/// calling the inner service.
+///
+/// The context is moved into the inner service afterwards, so to see the fields set by a
+/// layer, place `Inspect` inside that layer.
+///
+/// ```rust
+/// # use certain_map::{certain_map, service::{Inspect, Inspector}, ParamRef};
+/// pub struct UserName(String);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         name: UserName,
+///     }
+/// }
+///
+/// struct LogUser;
+///
+/// impl<R, CX: ParamRef<UserName>> Inspector<R, CX> for LogUser {
+///     fn inspect(&self, _req: &R, cx: &CX) {
+///         println!("user: {}", cx.param_ref().0);
+///     }
+/// }
+///
+/// # struct Inner;
+/// let svc = Inspect::new(LogUser, Inner);
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Inspect<F, S> {
+    pub f: F,
+    pub inner: S,
+}
+
+impl<F, S> Inspect<F, S> {
+    #[inline]
+    pub const fn new(f: F, inner: S) -> Self {
+        Self { f, inner }
+    }
+}
+
+impl<F, S, R, CX> Service<(R, CX)> for Inspect<F, S>
+where
+    F: Inspector<R, CX>,
+    S: Service<(R, CX)>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    #[inline]
+    fn call(
+        &self,
+        (req, cx): (R, CX),
+    ) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        self.f.inspect(&req, &cx);
+        self.inner.call((req, cx))
+    }
+}
+
+/// A service which forks the context `count` times and calls the inner service with every
+/// fork concurrently, returning all the responses in order.
+///
+/// The request is cloned for each call. The first error is returned after all the calls are
+/// finished. Forking requires the handler to implement [`Fork`], which is generated when the
+/// map derives `Clone`.
+#[derive(Debug, Clone, Copy)]
+pub struct ForkJoin<S> {
+    pub count: usize,
+    pub inner: S,
+}
+
+impl<S> ForkJoin<S> {
+    #[inline]
+    pub const fn new(count: usize, inner: S) -> Self {
+        Self { count, inner }
+    }
+}
+
+impl<S, R, CX, Store, State, Resp, Err> Service<(R, CX)> for ForkJoin<S>
+where
+    R: Clone,
+    CX: Fork<Store = Store, State = State>,
+    State: Attach<Store>,
+    for<'a> S: Service<(R, State::Hdr<'a>), Response = Resp, Error = Err>,
+{
+    type Response = Vec<Resp>;
+    type Error = Err;
+
+    async fn call(&self, (req, cx): (R, CX)) -> Result<Self::Response, Self::Error> {
+        let (mut stores, states): (Vec<Store>, Vec<State>) =
+            (0..self.count).map(|_| cx.fork()).unzip();
+        let calls = stores.iter_mut().zip(states).map(|(store, state)| {
+            // Safety: the state is forked together with the store.
+            let hdr = unsafe { state.attach(store) };
+            self.inner.call((req.clone(), hdr))
+        });
+        join_all(calls).await.into_iter().collect()
+    }
+}
+
+/// A service which takes an iterator as request and calls the inner service concurrently with
+/// every item and its own fork of the context, returning all the responses in order.
+///
+/// The first error is returned after all the calls are finished.
+///
+/// ```rust
+/// use std::convert::Infallible;
+///
+/// use certain_map::{
+///     certain_map,
+///     service::{ForkEach, Service},
+///     ParamRef, ParamSet,
+/// };
+///
+/// #[derive(Clone)]
+/// pub struct Base(u32);
+/// #[derive(Clone)]
+/// pub struct Item(u32);
+///
+/// certain_map! {
+///     #[derive(Clone)]
+///     pub struct Cx {
+///         base: Base,
+///         item: Item,
+///     }
+/// }
+///
+/// struct Sum;
+///
+/// impl<CX: ParamSet<Item>> Service<(u32, CX)> for Sum
+/// where
+///     CX::Transformed: ParamRef<Base> + ParamRef<Item>,
+/// {
+///     type Response = u32;
+///     type Error = Infallible;
+///
+///     async fn call(&self, (item, cx): (u32, CX)) -> Result<u32, Infallible> {
+///         // Every call sets the item on its own fork.
+///         let cx = cx.param_set(Item(item));
+///         Ok(ParamRef::<Base>::param_ref(&cx).0 + ParamRef::<Item>::param_ref(&cx).0)
+///     }
+/// }
+///
+/// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+/// let mut store = Cx::new();
+/// let cx = store.handler().param_set(Base(10));
+/// assert_eq!(ForkEach::new(Sum).call((vec![1, 2, 3], cx)).await, Ok(vec![11, 12, 13]));
+/// # });
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ForkEach<S> {
+    pub inner: S,
+}
+
+impl<S> ForkEach<S> {
+    #[inline]
+    pub const fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S, I, CX, Store, State, Resp, Err> Service<(I, CX)> for ForkEach<S>
+where
+    I: IntoIterator,
+    CX: Fork<Store = Store, State = State>,
+    State: Attach<Store>,
+    for<'a> S: Service<(I::Item, State::Hdr<'a>), Response = Resp, Error = Err>,
+{
+    type Response = Vec<Resp>;
+    type Error = Err;
+
+    async fn call(&self, (items, cx): (I, CX)) -> Result<Self::Response, Self::Error> {
+        let items: Vec<I::Item> = items.into_iter().collect();
+        let (mut stores, states): (Vec<Store>, Vec<State>) =
+            items.iter().map(|_| cx.fork()).unzip();
+        let calls = stores
+            .iter_mut()
+            .zip(states)
+            .zip(items)
+            .map(|((store, state), item)| {
+                // Safety: the state is forked together with the store.
+                let hdr = unsafe { state.attach(store) };
+                self.inner.call((item, hdr))
+            });
+        join_all(calls).await.into_iter().collect()
+    }
+}
+
+enum MaybeDone<F: Future> {
+    Pending(F),
+    Done(F::Output),
+    Gone,
+}
+
+/// Poll all the futures concurrently and collect their outputs in order.
+fn join_all<F: Future>(
+    futures: impl IntoIterator<Item = F>,
+) -> impl Future<Output = Vec<F::Output>> {
+    let mut elems: Pin<Box<[MaybeDone<F>]>> = futures
+        .into_iter()
+        .map(MaybeDone::Pending)
+        .collect::<Box<[_]>>()
+        .into();
+    poll_fn(move |cx| {
+        // Safety: the elements are never moved out of the pinned slice while pending.
+        let elems = unsafe { elems.as_mut().get_unchecked_mut() };
+        let mut all_done = true;
+        for elem in elems.iter_mut() {
+            if let MaybeDone::Pending(fut) = elem {
+                match unsafe { Pin::new_unchecked(fut) }.poll(cx) {
+                    Poll::Ready(output) => *elem = MaybeDone::Done(output),
+                    Poll::Pending => all_done = false,
+                }
+            }
+        }
+        if !all_done {
+            return Poll::Pending;
+        }
+        Poll::Ready(
+            elems
+                .iter_mut()
+                .map(|elem| match std::mem::replace(elem, MaybeDone::Gone) {
+                    MaybeDone::Done(output) => output,
+                    _ => unreachable!("join_all polled after completion"),
+                })
+                .collect(),
+        )
+    })
+}
+
+/// Maps the error of the inner service of [`ContextService`].
+pub trait MapError<E> {
+    type Error;
+    fn map_error(&self, error: E) -> Self::Error;
+}
+
+/// The default [`MapError`] which returns the error as is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Identity;
+
+impl<E> MapError<E> for Identity {
+    type Error = E;
+    #[inline]
+    fn map_error(&self, error: E) -> Self::Error {
+        error
+    }
+}
+
+impl<F, E, O> MapError<E> for F
+where
+    F: Fn(E) -> O,
+{
+    type Error = O;
+    #[inline]
+    fn map_error(&self, error: E) -> Self::Error {
+        self(error)
+    }
+}
+
+/// A service which creates a store for every request and calls the inner service with
+/// `(request, handler)`.
+///
+/// The store is created with `Default` unless a pool is configured with
+/// [`with_pool`](Self::with_pool). Fields which should be set for every request can be added
+/// with [`with_default`](Self::with_default), and the inner error can be converted with
+/// [`map_err`](Self::map_err).
+///
+/// ```rust
+/// # use certain_map::{certain_map, service::ContextService};
+/// # #[derive(Clone)]
+/// # pub struct Region(&'static str);
+/// # certain_map! {
+/// #     pub struct Cx {
+/// #         region: Region,
+/// #     }
+/// # }
+/// # struct Inner;
+/// let svc = ContextService::<Cx, _>::new(Inner)
+///     .with_pool(1024)
+///     .with_default(Region("eu"));
+/// ```
+pub struct ContextService<Store, S, M = Identity> {
+    pub inner: S,
+    pub map_err: M,
+    pool: Option<StorePool<Store>>,
+}
+
+impl<Store, S> ContextService<Store, S> {
+    #[inline]
+    pub const fn new(inner: S) -> Self {
+        Self {
+            inner,
+            map_err: Identity,
+            pool: None,
+        }
+    }
+}
+
+impl<Store, S, M> ContextService<Store, S, M> {
+    /// Reuse up to `capacity` stores across requests instead of creating one per request.
+    #[inline]
+    pub fn with_pool(mut self, capacity: usize) -> Self {
+        self.pool = Some(StorePool::new(capacity));
+        self
+    }
+
+    /// Set a clone of `value` into every new context before calling the inner service.
+    #[inline]
+    pub fn with_default<T>(self, value: T) -> ContextService<Store, SetParam<T, S>, M> {
+        ContextService {
+            inner: SetParam::new(value, self.inner),
+            map_err: self.map_err,
+            pool: self.pool,
+        }
+    }
+
+    /// Convert the error returned by the inner service with `f`.
+    #[inline]
+    pub fn map_err<F>(self, f: F) -> ContextService<Store, S, F> {
+        ContextService {
+            inner: self.inner,
+            map_err: f,
+            pool: self.pool,
+        }
+    }
+
+    #[inline]
+    pub fn pool(&self) -> Option<&StorePool<Store>> {
+        self.pool.as_ref()
+    }
+}
+
+impl<Store, S, M, R, Resp, Err> Service<R> for ContextService<Store, S, M>
+where
+    Store: Handler + ReleasePersisted + Default,
+    M: MapError<Err>,
+    for<'a> S: Service<(R, Store::Hdr<'a>), Response = Resp, Error = Err>,
+{
+    type Response = Resp;
+    type Error = M::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let mut store = Checkout::new(self.pool.as_ref());
+        let result = self.inner.call((req, store.handler())).await;
+        result.map_err(|e| self.map_err.map_error(e))
+    }
+}
+
+/// A store for one call, which is returned to the pool when dropped.
+///
+/// The handler borrowing the store is dropped before it, also when the call is cancelled or
+/// panics, which leaves only the `#[persistent]` fields in the store. The pool drops them when
+/// the store is returned.
+struct Checkout<'a, Store: ReleasePersisted> {
+    pool: Option<&'a StorePool<Store>>,
+    store: Option<Store>,
+}
+
+impl<'a, Store: Handler + ReleasePersisted + Default> Checkout<'a, Store> {
+    #[inline]
+    fn new(pool: Option<&'a StorePool<Store>>) -> Self {
+        let store = match pool {
+            Some(pool) => pool.get(),
+            None => Store::default(),
+        };
+        Self {
+            pool,
+            store: Some(store),
+        }
+    }
+
+    #[inline]
+    fn handler(&mut self) -> Store::Hdr<'_> {
+        self.store
+            .as_mut()
+            .expect("the store is only taken on drop")
+            .handler()
+    }
+}
+
+impl<Store: ReleasePersisted> Drop for Checkout<'_, Store> {
+    #[inline]
+    fn drop(&mut self) {
+        if let (Some(pool), Some(store)) = (self.pool, self.store.take()) {
+            pool.put(store);
+        }
+    }
+}
+
+/// A service which checks a store out of a shared pool for every request, calls the inner
+/// service with its empty handler, and returns the store to the pool when the call is done,
+/// including on error, panic or cancellation.
+///
+/// Unlike [`ContextService::with_pool`], the pool is shared by all the services made from the
+/// same [`RecycleLayer`] or cloned from one, so it survives service reloads.
+///
+/// ```rust
+/// # use certain_map::{certain_map, service::{Recycle, RecycleLayer}};
+/// # pub struct Region(&'static str);
+/// # certain_map! {
+/// #     pub struct Cx {
+/// #         region: Region,
+/// #     }
+/// # }
+/// # struct Inner;
+/// let svc = Recycle::<Cx, _>::new(1024, Inner);
+/// assert_eq!(svc.pool().capacity(), 1024);
+///
+/// // Or as a layer of a service-async factory stack, sharing the pool of `svc`.
+/// let layer = RecycleLayer::with_pool(svc.pool().clone());
+/// ```
+pub struct Recycle<Store, S> {
+    pub inner: S,
+    pool: Arc<StorePool<Store>>,
+}
+
+impl<Store, S> Recycle<Store, S> {
+    /// Keep up to `capacity` idle stores in a new pool.
+    #[inline]
+    pub fn new(capacity: usize, inner: S) -> Self {
+        Self::with_pool(Arc::new(StorePool::new(capacity)), inner)
+    }
+
+    #[inline]
+    pub const fn with_pool(pool: Arc<StorePool<Store>>, inner: S) -> Self {
+        Self { inner, pool }
+    }
+
+    #[inline]
+    pub fn pool(&self) -> &Arc<StorePool<Store>> {
+        &self.pool
+    }
+}
+
+impl<Store, S: Clone> Clone for Recycle<Store, S> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<Store, S, R, Resp, Err> Service<R> for Recycle<Store, S>
+where
+    Store: Handler + ReleasePersisted + Default,
+    for<'a> S: Service<(R, Store::Hdr<'a>), Response = Resp, Error = Err>,
+{
+    type Response = Resp;
+    type Error = Err;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let mut store = Checkout::new(Some(&*self.pool));
+        self.inner.call((req, store.handler())).await
+    }
+}
+
+// As a factory, the services it makes share its pool.
+impl<Store, F: MakeService> MakeService for Recycle<Store, F> {
+    type Service = Recycle<Store, F::Service>;
+    type Error = F::Error;
+
+    #[inline]
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Recycle {
+            inner: self.inner.make_via_ref(old.map(|old| &old.inner))?,
+            pool: self.pool.clone(),
+        })
+    }
+}
+
+/// A service-async [`FactoryLayer`] wrapping the inner factory with [`Recycle`], so every
+/// service it makes shares one pool of stores.
+pub struct RecycleLayer<Store> {
+    pool: Arc<StorePool<Store>>,
+}
+
+impl<Store> RecycleLayer<Store> {
+    /// Keep up to `capacity` idle stores in a new pool.
+    #[inline]
+    pub fn new(capacity: usize) -> Self {
+        Self::with_pool(Arc::new(StorePool::new(capacity)))
+    }
+
+    #[inline]
+    pub const fn with_pool(pool: Arc<StorePool<Store>>) -> Self {
+        Self { pool }
+    }
+
+    #[inline]
+    pub fn pool(&self) -> &Arc<StorePool<Store>> {
+        &self.pool
+    }
+}
+
+impl<Store> Clone for RecycleLayer<Store> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<C, F, Store> FactoryLayer<C, F> for RecycleLayer<Store> {
+    type Factory = Recycle<Store, F>;
+
+    #[inline]
+    fn layer(&self, _config: &C, inner: F) -> Self::Factory {
+        Recycle::with_pool(self.pool.clone(), inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::Arc,
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    use super::join_all;
+
+    struct NoopWake;
+
+    impl Wake for NoopWake {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    // Ready after being polled `pending` times.
+    async fn after(pending: usize, output: usize) -> usize {
+        let mut left = pending;
+        std::future::poll_fn(|_| {
+            if left == 0 {
+                return Poll::Ready(());
+            }
+            left -= 1;
+            Poll::Pending
+        })
+        .await;
+        output
+    }
+
+    fn poll<F: Future>(fut: std::pin::Pin<&mut F>) -> Poll<F::Output> {
+        let waker = Waker::from(Arc::new(NoopWake));
+        fut.poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn join_all_keeps_the_order() {
+        let mut joined = pin!(join_all([after(2, 0), after(0, 1), after(1, 2)]));
+        assert!(poll(joined.as_mut()).is_pending());
+        assert!(poll(joined.as_mut()).is_pending());
+        assert_eq!(poll(joined.as_mut()), Poll::Ready(vec![0, 1, 2]));
+    }
+
+    #[test]
+    #[should_panic(expected = "join_all polled after completion")]
+    fn join_all_polled_after_completion() {
+        let mut joined = pin!(join_all([after(0, 0)]));
+        assert_eq!(poll(joined.as_mut()), Poll::Ready(vec![0]));
+        // The outputs have been moved out by the first ready poll.
+        let _ = poll(joined.as_mut());
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+use std::{mem::ManuallyDrop, ops::Deref};
+
+use crate::{Attach, Fork, ForkOwned, Param, ParamMaybeMut, ParamMaybeRef, ParamMut, ParamRef};
+
+/// A boxed store together with its state, which can be kept in a long-lived struct.
+///
+/// Keeping a handler inside a connection struct is not possible since the handler borrows its
+/// store. `StoredCtx` owns both the store and the state instead, and re-derives a handler with
+/// [`handler`](Self::handler) whenever one is needed. The re-derived handler can read and
+/// mutate fields but can not change the state; to set or remove fields, convert the
+/// `StoredCtx` into the generated owned handler with `From`, transform it, and convert it back.
+///
+/// Occupied fields are dropped when the `StoredCtx` is dropped.
+pub struct StoredCtx<Store, State: Attach<Store>> {
+    store: Box<Store>,
+    state: ManuallyDrop<State>,
+}
+
+impl<Store, State: Attach<Store>> StoredCtx<Store, State> {
+    /// # Safety
+    /// The store must have the data of the state.
+    #[inline]
+    pub unsafe fn from_parts(store: Box<Store>, state: State) -> Self {
+        Self {
+            store,
+            state: ManuallyDrop::new(state),
+        }
+    }
+
+    /// Split into the store and the state without dropping any field.
+    #[inline]
+    pub fn into_parts(self) -> (Box<Store>, State) {
+        let this = ManuallyDrop::new(self);
+        // Safety: the fields are read exactly once and self is never dropped.
+        unsafe { (std::ptr::read(&this.store), std::ptr::read(&*this.state)) }
+    }
+
+    /// Re-derive a handler borrowing the stored store.
+    #[inline]
+    pub fn handler(&mut self) -> StoredHandler<State::Hdr<'_>>
+    where
+        State: Clone,
+    {
+        // Safety: the store always has the data of the state since the state can not be
+        // changed while the store is kept here.
+        let hdr = unsafe { State::clone(&self.state).attach(&mut self.store) };
+        StoredHandler {
+            hdr: ManuallyDrop::new(hdr),
+        }
+    }
+}
+
+impl<Store, State: Attach<Store>> Drop for StoredCtx<Store, State> {
+    fn drop(&mut self) {
+        // Safety: the state is taken once here and the store has its data. Dropping the
+        // attached handler drops the occupied fields.
+        unsafe { ManuallyDrop::take(&mut self.state).attach(&mut self.store) };
+    }
+}
+
+/// A handler re-derived from a [`StoredCtx`].
+///
+/// It forwards the `Param*` traits which do not change the state, and never drops the fields
+/// since they are still owned by the `StoredCtx`.
+pub struct StoredHandler<H> {
+    hdr: ManuallyDrop<H>,
+}
+
+impl<H> Deref for StoredHandler<H> {
+    type Target = H;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.hdr
+    }
+}
+
+impl<H: ParamRef<T>, T> ParamRef<T> for StoredHandler<H> {
+    #[inline]
+    fn param_ref(&self) -> &T {
+        self.hdr.param_ref()
+    }
+}
+
+impl<H: ParamMut<T>, T> ParamMut<T> for StoredHandler<H> {
+    #[inline]
+    fn param_mut(&mut self) -> &mut T {
+        self.hdr.param_mut()
+    }
+}
+
+impl<H: ParamMaybeRef<T>, T> ParamMaybeRef<T> for StoredHandler<H> {
+    #[inline]
+    fn param_maybe_ref(&self) -> Option<&T> {
+        self.hdr.param_maybe_ref()
+    }
+}
+
+impl<H: ParamMaybeMut<T>, T> ParamMaybeMut<T> for StoredHandler<H> {
+    #[inline]
+    fn param_maybe_mut(&mut self) -> Option<&mut T> {
+        self.hdr.param_maybe_mut()
+    }
+}
+
+impl<H: Param<T>, T> Param<T> for StoredHandler<H> {
+    #[inline]
+    fn param(&self) -> T {
+        self.hdr.param()
+    }
+}
+
+impl<H: Fork> Fork for StoredHandler<H> {
+    type Store = H::Store;
+    type State = H::State;
+
+    #[inline]
+    fn fork(&self) -> (Self::Store, Self::State) {
+        self.hdr.fork()
+    }
+}
+
+impl<H: ForkOwned> ForkOwned for StoredHandler<H> {
+    type Owned = H::Owned;
+
+    #[inline]
+    fn fork_owned(&self) -> Self::Owned {
+        self.hdr.fork_owned()
+    }
+}
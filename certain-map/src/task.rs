@@ -0,0 +1,48 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Spawn tokio tasks carrying a context.
+
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+
+use crate::ForkOwned;
+
+/// Fork the context into an owned handler and spawn a task running `f` with it.
+///
+/// This replaces forking the store and state, moving them into the task and attaching them
+/// there with `unsafe`. The owned handler is `'static`, so `f` may hold it across awaits. To
+/// move a context into a task instead of forking it, spawn with an owned handler directly.
+///
+/// ```rust
+/// use certain_map::{certain_map, task::spawn_with_ctx, ParamRef, ParamSet};
+///
+/// #[derive(Clone)]
+/// pub struct TraceId(u64);
+///
+/// certain_map! {
+///     #[derive(Clone)]
+///     pub struct Cx {
+///         trace: TraceId,
+///     }
+/// }
+///
+/// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+/// let mut store = Cx::new();
+/// let cx = store.handler().param_set(TraceId(7));
+/// let trace = spawn_with_ctx(&cx, |cx| async move { cx.param_ref().0 }).await.unwrap();
+/// assert_eq!(trace, 7);
+/// # });
+/// ```
+#[inline]
+pub fn spawn_with_ctx<H, F, Fut>(cx: &H, f: F) -> JoinHandle<Fut::Output>
+where
+    H: ForkOwned,
+    H::Owned: Send + 'static,
+    F: FnOnce(H::Owned) -> Fut + Send + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    let owned = cx.fork_owned();
+    tokio::spawn(async move { f(owned).await })
+}
@@ -0,0 +1,114 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Keep a context in a tokio task-local, for code which can not pass the handler through every
+//! call yet.
+//!
+//! Declare the task-local with [`task_local_ctx!`](crate::task_local_ctx), install a value with
+//! [`scope`](TaskLocalCtx::scope) and read it anywhere inside the scope with
+//! [`with`](TaskLocalCtx::with). The value is usually an owned handler of a fixed state (like
+//! the `#[full(..)]` state) or a [`StoredCtx`](crate::StoredCtx), both of which are `'static`.
+//!
+//! ```rust
+//! use certain_map::{certain_map, task_local_ctx, ParamRef, ParamSet};
+//!
+//! #[derive(Clone)]
+//! pub struct UserId(u64);
+//!
+//! certain_map! {
+//!     pub struct Cx {
+//!         user: UserId,
+//!     }
+//! }
+//!
+//! type CxOwnedFull = CxOwnedHandler<certain_map::OccupiedM>;
+//!
+//! task_local_ctx! {
+//!     static CX: CxOwnedFull;
+//! }
+//!
+//! fn current_user() -> u64 {
+//!     CX.with(|cx| cx.param_ref().0)
+//! }
+//!
+//! # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+//! let cx = Cx::owned_handler().param_set(UserId(42));
+//! CX.scope(cx, async { assert_eq!(current_user(), 42) }).await;
+//! # });
+//! ```
+
+use std::{cell::RefCell, future::Future};
+
+pub use tokio::task::{futures::TaskLocalFuture, LocalKey};
+#[doc(hidden)]
+pub use tokio::task_local as __task_local;
+
+/// A context kept in a tokio task-local, declared with
+/// [`task_local_ctx!`](crate::task_local_ctx).
+pub struct TaskLocalCtx<T: 'static> {
+    key: &'static LocalKey<RefCell<T>>,
+}
+
+impl<T: 'static> TaskLocalCtx<T> {
+    #[doc(hidden)]
+    #[inline]
+    pub const fn new(key: &'static LocalKey<RefCell<T>>) -> Self {
+        Self { key }
+    }
+
+    /// Run the future with the context installed.
+    #[inline]
+    pub fn scope<F: Future>(&'static self, value: T, f: F) -> TaskLocalFuture<RefCell<T>, F> {
+        self.key.scope(RefCell::new(value), f)
+    }
+
+    /// Run the closure with the context installed.
+    #[inline]
+    pub fn sync_scope<R>(&'static self, value: T, f: impl FnOnce() -> R) -> R {
+        self.key.sync_scope(RefCell::new(value), f)
+    }
+
+    /// Access the context.
+    ///
+    /// # Panics
+    /// Panics if called outside of a scope, or while the context is borrowed mutably.
+    #[inline]
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        self.key.with(|cx| f(&cx.borrow()))
+    }
+
+    /// Access the context mutably.
+    ///
+    /// # Panics
+    /// Panics if called outside of a scope, or while the context is borrowed.
+    #[inline]
+    pub fn with_mut<R>(&'static self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.key.with(|cx| f(&mut cx.borrow_mut()))
+    }
+
+    /// Access the context, returns `None` if called outside of a scope.
+    #[inline]
+    pub fn try_with<R>(&'static self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.key.try_with(|cx| f(&cx.borrow())).ok()
+    }
+}
+
+/// Declare a [`TaskLocalCtx`].
+///
+/// ```rust,ignore
+/// certain_map::task_local_ctx! {
+///     pub static CX: CxOwnedFull;
+/// }
+/// ```
+#[macro_export]
+macro_rules! task_local_ctx {
+    ($($vis:vis static $name:ident: $ty:ty;)+) => {
+        $(
+            $vis static $name: $crate::task_local::TaskLocalCtx<$ty> = {
+                $crate::task_local::__task_local! {
+                    static KEY: ::std::cell::RefCell<$ty>;
+                }
+                $crate::task_local::TaskLocalCtx::new(&KEY)
+            };
+        )+
+    };
+}
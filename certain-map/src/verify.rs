@@ -0,0 +1,481 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Kani proofs for the unsafe slot primitives and the transitions of a generated map.
+//!
+//! Run them with `cargo kani -p certain-map`. Without Kani they are unit tests with fixed values
+//! in place of the symbolic ones, which `cargo miri test -p certain-map --lib` can run too.
+
+use std::{cell::Cell, mem::MaybeUninit, rc::Rc};
+
+use crate::{
+    __secure_clear, certain_map, Available, MaybeAvailable, OccupiedM, Param, ParamMaybeRef,
+    ParamRef, ParamRemove, ParamSet, ParamTake, Vacancy,
+};
+
+// A symbolic value under Kani, and a fixed one in the unit tests.
+#[cfg(kani)]
+fn any<T: kani::Arbitrary>() -> T {
+    kani::any()
+}
+
+#[cfg(not(kani))]
+fn any<T: From<u8>>() -> T {
+    T::from(7)
+}
+
+// A value counting its drops in a counter shared by its clones, to check that every value a map
+// holds is dropped exactly once.
+#[derive(Clone, Debug)]
+struct Counted<const K: u8>(Rc<Cell<usize>>);
+
+impl<const K: u8> Counted<K> {
+    fn new(drops: &Rc<Cell<usize>>) -> Self {
+        Self(drops.clone())
+    }
+}
+
+impl<const K: u8> Drop for Counted<K> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn occupied_set_then_read() {
+    let value: u32 = any();
+    let mut slot = MaybeUninit::uninit();
+    unsafe {
+        <Vacancy as MaybeAvailable>::do_set(&mut slot, value);
+        assert_eq!(*OccupiedM::do_ref(&slot), value);
+        assert_eq!(OccupiedM::do_maybe_ref(&slot), Some(&value));
+        *OccupiedM::do_mut(&mut slot) = value.wrapping_add(1);
+        assert_eq!(OccupiedM::do_read(&slot), value.wrapping_add(1));
+        assert_eq!(OccupiedM::do_take(&slot), value.wrapping_add(1));
+    }
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn occupied_set_replaces_value() {
+    let (first, second): (u32, u32) = (any(), any());
+    let mut slot = MaybeUninit::new(first);
+    unsafe {
+        <OccupiedM as MaybeAvailable>::do_set(&mut slot, second);
+        assert_eq!(*OccupiedM::do_ref(&slot), second);
+    }
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn vacancy_never_reads() {
+    // Reading an uninitialized slot is undefined behavior, which Kani reports.
+    let mut slot = MaybeUninit::<u32>::uninit();
+    unsafe {
+        assert!(Vacancy::do_maybe_ref(&slot).is_none());
+        assert!(Vacancy::do_maybe_mut(&mut slot).is_none());
+        let _ = Vacancy::do_clone(&slot);
+        Vacancy::do_drop(&mut slot);
+    }
+    assert_eq!(
+        [
+            <Vacancy as MaybeAvailable>::OCCUPIED,
+            <OccupiedM as MaybeAvailable>::OCCUPIED
+        ],
+        [false, true]
+    );
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn slot_drops_each_value_once() {
+    let drops = Rc::new(Cell::new(0));
+    let mut slot = MaybeUninit::uninit();
+    unsafe {
+        <Vacancy as MaybeAvailable>::do_set(&mut slot, Counted::<0>::new(&drops));
+        // Replacing drops the old value.
+        <OccupiedM as MaybeAvailable>::do_set(&mut slot, Counted::<0>::new(&drops));
+        assert_eq!(drops.get(), 1);
+        let mut clone = OccupiedM::do_clone(&slot);
+        OccupiedM::do_drop(&mut clone);
+        assert_eq!(drops.get(), 2);
+        drop(OccupiedM::do_take(&slot));
+        assert_eq!(drops.get(), 3);
+        // The slot is logically vacant after the take, so dropping it as vacant drops nothing.
+        Vacancy::do_drop(&mut slot);
+    }
+    assert_eq!(drops.get(), 3);
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn secure_clear_zeroes() {
+    let mut slot = MaybeUninit::new(any::<u64>());
+    __secure_clear(&mut slot);
+    assert_eq!(unsafe { slot.assume_init() }, 0);
+}
+
+struct Key(u64);
+struct Pin(u64);
+
+certain_map! {
+    #[secure_clear]
+    struct Vault {
+        key: Key,
+        pin: Pin,
+    }
+}
+
+// The bytes left in the slots, read after the handler is forgotten so the store is reachable
+// while the handler still considers the fields vacant.
+fn vault_slots(store: &Vault) -> (u64, u64) {
+    unsafe {
+        (
+            store.key.as_ptr().cast::<u64>().read(),
+            store.pin.as_ptr().cast::<u64>().read(),
+        )
+    }
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn secure_clear_zeroes_the_map_slots() {
+    let (key, pin): (u64, u64) = (any(), any());
+    let mut store = Vault::new();
+    let cx = store.handler().param_set(Key(key)).param_set(Pin(pin));
+    let (cx, taken) = ParamTake::<Key>::param_take(cx);
+    assert_eq!(taken.0, key);
+    std::mem::forget(cx);
+    assert_eq!(vault_slots(&store).0, 0);
+
+    let cx = store.handler().param_set(Key(key)).param_set(Pin(pin));
+    assert_eq!(ParamRef::<Pin>::param_ref(&cx).0, pin);
+    let cx = ParamRemove::<Pin>::param_remove(cx);
+    std::mem::forget(cx);
+    assert_eq!(vault_slots(&store).1, 0);
+
+    let cx = store.handler().param_set(Key(key)).param_set(Pin(pin));
+    std::mem::forget(cx.reset());
+    assert_eq!(vault_slots(&store), (0, 0));
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Left(u32);
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Right(u32);
+
+certain_map! {
+    #[derive(Clone)]
+    struct Pair {
+        left: Left,
+        right: Right,
+    }
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn map_transitions() {
+    let (left, right) = (Left(any()), Right(any()));
+    let mut store = Pair::new();
+    let cx = store.handler().param_set(left);
+    assert_eq!(ParamMaybeRef::<Right>::param_maybe_ref(&cx), None);
+    let cx = cx.param_set(right);
+    assert_eq!(*ParamRef::<Left>::param_ref(&cx), left);
+    assert_eq!(Param::<Right>::param(&cx), right);
+
+    let (forked, state) = cx.fork();
+    let (cx, taken) = ParamTake::<Left>::param_take(cx);
+    assert_eq!(taken, left);
+    let cx = ParamRemove::<Right>::param_remove(cx);
+    assert!(cx.occupied_names().next().is_none());
+
+    let mut forked = forked;
+    let forked = unsafe { state.attach(&mut forked) };
+    assert_eq!(*ParamRef::<Left>::param_ref(&forked), left);
+    let _ = forked.reset();
+}
+
+certain_map! {
+    #[derive(Clone)]
+    struct Tracked {
+        first: Counted<0>,
+        second: Counted<1>,
+    }
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn transition_moves_the_handle() {
+    let drops = Rc::new(Cell::new(0));
+    let mut store = Tracked::new();
+    let cx = store.handler().param_set(Counted::<0>::new(&drops));
+    // The target state has the data of the store, so nothing is dropped or duplicated.
+    let cx = unsafe { cx.transition::<OccupiedM, Vacancy>() };
+    assert_eq!(drops.get(), 0);
+    drop(cx);
+    assert_eq!(drops.get(), 1);
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn tracked_fork_and_reset_drop_once() {
+    let drops = Rc::new(Cell::new(0));
+    let mut store = Tracked::new();
+    let cx = store
+        .handler()
+        .param_set(Counted::<0>::new(&drops))
+        .param_set(Counted::<1>::new(&drops));
+    let (mut forked, state) = cx.fork();
+    let forked_cx = unsafe { state.attach(&mut forked) };
+    let cx = ParamRemove::<Counted<0>>::param_remove(cx);
+    assert_eq!(drops.get(), 1);
+    let _ = cx.reset();
+    assert_eq!(drops.get(), 2);
+    drop(forked_cx);
+    assert_eq!(drops.get(), 4);
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn absorb_drops_the_replaced_values() {
+    let drops = Rc::new(Cell::new(0));
+    let mut store = Tracked::new();
+    let cx = store.handler().param_set(Counted::<0>::new(&drops));
+    let other = Tracked::owned_handler()
+        .param_set(Counted::<0>::new(&drops))
+        .param_set(Counted::<1>::new(&drops));
+    // The first value of cx is replaced, the values of other move into cx.
+    let cx = cx.absorb(other);
+    assert_eq!(drops.get(), 1);
+    drop(cx);
+    assert_eq!(drops.get(), 3);
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn migrate_moves_the_ownership() {
+    let drops = Rc::new(Cell::new(0));
+    let mut store = Tracked::new();
+    let cx = store
+        .handler()
+        .param_set(Counted::<0>::new(&drops))
+        .param_set(Counted::<1>::new(&drops));
+    let mut dest = Tracked::new();
+    let (state, empty) = cx.migrate(&mut dest);
+    drop(empty);
+    assert_eq!(drops.get(), 0);
+    let cx = unsafe { state.attach(&mut dest) };
+    drop(cx);
+    assert_eq!(drops.get(), 2);
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn try_promote_drops_the_fields_left_behind() {
+    let drops = Rc::new(Cell::new(0));
+    let mut store = Tracked::new();
+    let cx = store
+        .handler()
+        .param_set(Counted::<0>::new(&drops))
+        .param_set(Counted::<1>::new(&drops));
+    // The target has no second field, which is dropped.
+    let cx = cx.try_promote::<OccupiedM, Vacancy>().unwrap();
+    assert_eq!(drops.get(), 1);
+    assert!(Rc::ptr_eq(
+        &ParamRef::<Counted<0>>::param_ref(&cx).0,
+        &drops
+    ));
+    drop(cx);
+    assert_eq!(drops.get(), 2);
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn try_promote_gives_back_the_handler() {
+    let drops = Rc::new(Cell::new(0));
+    let mut store = Tracked::new();
+    let cx = store.handler().param_set(Counted::<0>::new(&drops));
+    let Err(err) = cx.try_promote::<OccupiedM, OccupiedM>() else {
+        unreachable!("the second field is vacant");
+    };
+    assert_eq!(err.missing.missing, ["second"]);
+    // Nothing is dropped until the returned handler is.
+    assert_eq!(drops.get(), 0);
+    let cx = err.handler.param_set(Counted::<1>::new(&drops));
+    let cx = cx.try_promote::<OccupiedM, OccupiedM>().unwrap();
+    assert_eq!(drops.get(), 0);
+    drop(cx);
+    assert_eq!(drops.get(), 2);
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn forget_all_leaves_the_values_to_their_owner() {
+    let drops = Rc::new(Cell::new(0));
+    let mut store = Tracked::new();
+    let cx = store
+        .handler()
+        .param_set(Counted::<0>::new(&drops))
+        .param_set(Counted::<1>::new(&drops));
+    // Stand-ins for the owner of the values, like an arena.
+    let (first, second) = unsafe {
+        (
+            std::ptr::read(ParamRef::<Counted<0>>::param_ref(&cx)),
+            std::ptr::read(ParamRef::<Counted<1>>::param_ref(&cx)),
+        )
+    };
+    let cx = cx.forget_all();
+    assert_eq!(drops.get(), 0);
+    drop(cx);
+    assert_eq!(drops.get(), 0);
+
+    // The store is empty and can be used again.
+    let cx = store.handler().param_set(Counted::<0>::new(&drops));
+    drop(cx);
+    assert_eq!(drops.get(), 1);
+    drop((first, second));
+    assert_eq!(drops.get(), 3);
+}
+
+certain_map! {
+    #[partition(Front: first; Back: second)]
+    struct Split {
+        first: Counted<0>,
+        second: Counted<1>,
+        rest: Counted<2>,
+    }
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn partition_drops_the_fields_in_no_partition() {
+    let drops = Rc::new(Cell::new(0));
+    let mut store = Split::new();
+    let cx = store
+        .handler()
+        .param_set(Counted::<0>::new(&drops))
+        .param_set(Counted::<1>::new(&drops))
+        .param_set(Counted::<2>::new(&drops));
+    // Only rest is dropped, the other values move into the partitions.
+    let (front, back) = cx.partition();
+    assert_eq!(drops.get(), 1);
+    assert!(Rc::ptr_eq(
+        &ParamRef::<Counted<0>>::param_ref(&front).0,
+        &drops
+    ));
+    drop(front);
+    assert_eq!(drops.get(), 2);
+    drop(back);
+    assert_eq!(drops.get(), 3);
+    // The store is left empty.
+    drop(store.handler());
+    assert_eq!(drops.get(), 3);
+}
+
+certain_map! {
+    struct Peer {
+        #[one_of(peer)]
+        name: Counted<0>,
+        #[one_of(peer)]
+        code: Counted<1>,
+        port: Counted<2>,
+    }
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn union_slots_drop_the_occupied_member() {
+    let drops = Rc::new(Cell::new(0));
+    let mut store = Peer::new();
+    let cx = store
+        .handler()
+        .param_set(Counted::<0>::new(&drops))
+        .param_set(Counted::<2>::new(&drops));
+    assert!(matches!(cx.peer(), Some(PeerPeer::name(name)) if Rc::ptr_eq(&name.0, &drops)));
+    let cx = ParamRemove::<Counted<0>>::param_remove(cx);
+    assert_eq!(drops.get(), 1);
+    assert!(cx.peer().is_none());
+    // The shared slot now holds the other member.
+    let cx = cx.param_set(Counted::<1>::new(&drops));
+    assert!(matches!(cx.peer(), Some(PeerPeer::code(code)) if Rc::ptr_eq(&code.0, &drops)));
+    let (cx, code) = ParamTake::<Counted<1>>::param_take(cx);
+    assert_eq!(drops.get(), 1);
+    drop(code);
+    assert_eq!(drops.get(), 2);
+    let cx = cx.param_set(Counted::<1>::new(&drops));
+    drop(cx);
+    assert_eq!(drops.get(), 4);
+}
+
+certain_map! {
+    struct Login {
+        #[conflicts_with(token)]
+        password: Counted<0>,
+        token: Counted<1>,
+        user: Counted<2>,
+    }
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn conflicting_slots_drop_the_occupied_member() {
+    let drops = Rc::new(Cell::new(0));
+    let mut store = Login::new();
+    let cx = store
+        .handler()
+        .param_set(Counted::<0>::new(&drops))
+        .param_set(Counted::<2>::new(&drops));
+    let cx = ParamRemove::<Counted<0>>::param_remove(cx);
+    assert_eq!(drops.get(), 1);
+    // The shared slot now holds the other member.
+    let cx = cx.param_set(Counted::<1>::new(&drops));
+    let (cx, token) = ParamTake::<Counted<1>>::param_take(cx);
+    assert_eq!(drops.get(), 1);
+    drop(token);
+    assert_eq!(drops.get(), 2);
+    let cx = cx.param_set(Counted::<1>::new(&drops));
+    assert!(Rc::ptr_eq(
+        &ParamRef::<Counted<1>>::param_ref(&cx).0,
+        &drops
+    ));
+    drop(cx);
+    assert_eq!(drops.get(), 4);
+}
+
+certain_map! {
+    struct Conn {
+        #[persistent]
+        session: Counted<0>,
+        request: Counted<1>,
+    }
+}
+
+#[cfg_attr(kani, kani::proof)]
+#[cfg_attr(not(kani), test)]
+fn persistent_slots_survive_the_handler() {
+    let drops = Rc::new(Cell::new(0));
+    let mut store = Conn::new();
+    let cx = store
+        .handler()
+        .param_set(Counted::<0>::new(&drops))
+        .param_set(Counted::<1>::new(&drops));
+    // Only the request is dropped, the session is left in the store.
+    drop(cx);
+    assert_eq!(drops.get(), 1);
+    let cx = store.resume().expect("the session is persisted");
+    let cx = cx.param_set(Counted::<1>::new(&drops));
+    drop(cx);
+    assert_eq!(drops.get(), 2);
+    // A new handler drops the session left by the last one.
+    let cx = store.handler();
+    assert_eq!(drops.get(), 3);
+    drop(cx);
+    assert!(store.resume().is_none());
+
+    // The store drops a persisted session.
+    let cx = store.handler().param_set(Counted::<0>::new(&drops));
+    drop(cx);
+    assert_eq!(drops.get(), 3);
+    drop(store);
+    assert_eq!(drops.get(), 4);
+}
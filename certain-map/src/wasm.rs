@@ -0,0 +1,35 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! Exchange contexts with JavaScript through
+//! [wasm-bindgen](https://docs.rs/wasm-bindgen), for edge filters running as wasm.
+//!
+//! With `#[wasm]` on a prefilled map, `to_js()` of the full handler converts it into a JS
+//! object keyed by the field names, and `Store::from_js(value)` builds the full owned handler
+//! back. Values are converted with [serde-wasm-bindgen](https://docs.rs/serde-wasm-bindgen).
+//!
+//! ```rust,no_run
+//! use certain_map::{certain_map, wasm::JsValue, ParamSet};
+//!
+//! certain_map! {
+//!     #[wasm]
+//!     pub struct Cx {
+//!         tenant: String,
+//!         score: u32,
+//!     }
+//! }
+//!
+//! // Called from JavaScript with the context of the request.
+//! fn filter(cx: JsValue) -> Result<JsValue, JsValue> {
+//!     let cx = Cx::from_js(cx)?;
+//!     Ok(cx.to_js()?)
+//! }
+//!
+//! let cx = Cx::owned_handler()
+//!     .param_set("acme".to_string())
+//!     .param_set(7u32);
+//! filter(cx.to_js().unwrap()).unwrap();
+//! ```
+
+pub use serde;
+pub use serde_wasm_bindgen::{from_value, to_value, Error};
+pub use wasm_bindgen::JsValue;
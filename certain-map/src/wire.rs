@@ -0,0 +1,279 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+//! A compact binary encoding of contexts, for handing a context from one process to another,
+//! like from an L4 front proxy to an L7 backend.
+//!
+//! Fields marked `#[wire]` are encoded by the generated `to_wire()` as records of the field
+//! index, the length and the bytes of the value, all lengths and indexes as LEB128 varints.
+//! Only the occupied fields are written, so partial contexts can be encoded as well.
+//! `from_wire()` sets all of them or fails, and skips the records of unknown indexes.
+//!
+//! Values are encoded with [`Wire`], or with `#[wire(with = module)]` by the functions
+//! `module::encode(&T, &mut Vec<u8>)` and `module::decode(&[u8]) -> Option<T>`. The indexes are
+//! the declaration order of the fields in the map, so both sides must agree on it.
+//!
+//! ```rust
+//! use std::net::SocketAddr;
+//!
+//! use certain_map::{certain_map, wire::Wire, ParamRef, ParamSet};
+//!
+//! pub struct RequestId(u64);
+//!
+//! impl Wire for RequestId {
+//!     fn encode(&self, buf: &mut Vec<u8>) {
+//!         self.0.encode(buf)
+//!     }
+//!
+//!     fn decode(bytes: &[u8]) -> Option<Self> {
+//!         u64::decode(bytes).map(RequestId)
+//!     }
+//! }
+//!
+//! certain_map! {
+//!     pub struct Cx {
+//!         #[wire]
+//!         peer: SocketAddr,
+//!         #[wire]
+//!         id: RequestId,
+//!         local: u8,
+//!     }
+//! }
+//!
+//! let cx = Cx::owned_handler()
+//!     .param_set("10.0.0.1:5000".parse::<SocketAddr>().unwrap())
+//!     .param_set(RequestId(7));
+//! let bytes = cx.to_wire();
+//!
+//! // In the backend process.
+//! let cx = Cx::owned_handler().from_wire(&bytes).unwrap();
+//! assert_eq!(ParamRef::<RequestId>::param_ref(&cx).0, 7);
+//! ```
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+/// Error of the generated `from_wire()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    /// The input ends in the middle of a record.
+    Truncated,
+    /// The field is not in the input.
+    Missing(&'static str),
+    /// The bytes of the field can not be decoded.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::Truncated => write!(f, "wire input is truncated"),
+            WireError::Missing(name) => write!(f, "wire field `{name}` is missing"),
+            WireError::Invalid(name) => write!(f, "wire field `{name}` has an invalid value"),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// The wire encoding of a field value.
+pub trait Wire: Sized {
+    /// Append the encoding of the value to `buf`.
+    fn encode(&self, buf: &mut Vec<u8>);
+    /// Decode a value from all the bytes of its record.
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! wire_int {
+    ($($ty:ty),*) => {
+        $(
+            impl Wire for $ty {
+                #[inline]
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                }
+
+                #[inline]
+                fn decode(bytes: &[u8]) -> Option<Self> {
+                    bytes.try_into().ok().map(<$ty>::from_le_bytes)
+                }
+            }
+        )*
+    };
+}
+
+wire_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl Wire for bool {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for String {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        std::str::from_utf8(bytes).ok().map(str::to_owned)
+    }
+}
+
+impl Wire for Vec<u8> {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl Wire for Ipv4Addr {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.octets());
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        <[u8; 4]>::try_from(bytes).ok().map(Ipv4Addr::from)
+    }
+}
+
+impl Wire for Ipv6Addr {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.octets());
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        <[u8; 16]>::try_from(bytes).ok().map(Ipv6Addr::from)
+    }
+}
+
+// The length tells the versions apart.
+impl Wire for IpAddr {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            IpAddr::V4(ip) => ip.encode(buf),
+            IpAddr::V6(ip) => ip.encode(buf),
+        }
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes.len() {
+            4 => Ipv4Addr::decode(bytes).map(IpAddr::V4),
+            16 => Ipv6Addr::decode(bytes).map(IpAddr::V6),
+            _ => None,
+        }
+    }
+}
+
+// The ip followed by the port.
+impl Wire for SocketAddr {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.ip().encode(buf);
+        self.port().encode(buf);
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (ip, port) = bytes.split_at(bytes.len().checked_sub(2)?);
+        Some(SocketAddr::new(IpAddr::decode(ip)?, u16::decode(port)?))
+    }
+}
+
+// The seconds followed by the nanoseconds.
+impl Wire for Duration {
+    #[inline]
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_secs().encode(buf);
+        self.subsec_nanos().encode(buf);
+    }
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 12 {
+            return None;
+        }
+        let (secs, nanos) = bytes.split_at(8);
+        let nanos = u32::decode(nanos)?;
+        if nanos >= 1_000_000_000 {
+            return None;
+        }
+        Some(Duration::new(u64::decode(secs)?, nanos))
+    }
+}
+
+#[inline]
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[inline]
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, WireError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or(WireError::Truncated)?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(WireError::Truncated)
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn write_record<T: ?Sized>(
+    buf: &mut Vec<u8>,
+    index: u64,
+    value: &T,
+    encode: impl FnOnce(&T, &mut Vec<u8>),
+) {
+    let mut bytes = Vec::new();
+    encode(value, &mut bytes);
+    write_varint(buf, index);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(&bytes);
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn read_record<'a>(bytes: &mut &'a [u8]) -> Result<(u64, &'a [u8]), WireError> {
+    let index = read_varint(bytes)?;
+    let len = usize::try_from(read_varint(bytes)?).map_err(|_| WireError::Truncated)?;
+    if bytes.len() < len {
+        return Err(WireError::Truncated);
+    }
+    let (value, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok((index, value))
+}
@@ -0,0 +1,215 @@
+// Copyright 2024 ihciah. All Rights Reserved.
+
+use std::ops::{Deref, DerefMut};
+
+use crate::{
+    Flag, Fork, ForkOwned, Param, ParamDiscard, ParamEq, ParamForget, ParamMaybeMut, ParamMaybeRef,
+    ParamMut, ParamPush, ParamRef, ParamRemove, ParamSet, ParamState, ParamTake, ParamVacant,
+};
+
+/// A request paired with its context handler, which moves through service layers as a single
+/// value.
+///
+/// Combinators of tower or hyper stacks expect one request type, which `(req, cx)` tuples do
+/// not fit well. `WithCx` forwards all the `Param*` traits to the handler, and the traits which
+/// transform the handler keep the request, so `WithCx<R, H>` becomes `WithCx<R, H::Transformed>`.
+/// The request is reachable through `Deref` or the `req` field.
+///
+/// ```rust
+/// # use certain_map::{certain_map, ParamRef, ParamSet, WithCx};
+/// pub struct UserId(u64);
+///
+/// certain_map! {
+///     pub struct Cx {
+///         user: UserId,
+///     }
+/// }
+///
+/// fn authenticate<CX: ParamSet<UserId>>(cx: CX) -> CX::Transformed {
+///     cx.param_set(UserId(7))
+/// }
+///
+/// let mut store = Cx::new();
+/// let req = authenticate(WithCx::new("GET /", store.handler()));
+/// assert_eq!(*req, "GET /");
+/// assert_eq!(ParamRef::<UserId>::param_ref(&req).0, 7);
+/// ```
+// Wrappers forwarding `Param<T>` to a context, like this one and `Chained`, must not be Clone:
+// the param crate implements `Param<T> for T: Clone`, which would overlap with the forwarding
+// impl once the wrapper itself is a `T: Clone`.
+#[must_use = "dropping the returned handler drops the context"]
+pub struct WithCx<R, H> {
+    pub req: R,
+    pub cx: H,
+}
+
+impl<R, H> WithCx<R, H> {
+    #[inline]
+    pub const fn new(req: R, cx: H) -> Self {
+        Self { req, cx }
+    }
+
+    /// Split into the request and the handler.
+    #[inline]
+    pub fn into_parts(self) -> (R, H) {
+        (self.req, self.cx)
+    }
+
+    /// Replace the request with the result of `f`, keeping the handler.
+    #[inline]
+    pub fn map_req<R2>(self, f: impl FnOnce(R) -> R2) -> WithCx<R2, H> {
+        WithCx {
+            req: f(self.req),
+            cx: self.cx,
+        }
+    }
+}
+
+impl<R, H> Deref for WithCx<R, H> {
+    type Target = R;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.req
+    }
+}
+
+impl<R, H> DerefMut for WithCx<R, H> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.req
+    }
+}
+
+impl<R, H: ParamRef<T>, T> ParamRef<T> for WithCx<R, H> {
+    #[inline]
+    fn param_ref(&self) -> &T {
+        self.cx.param_ref()
+    }
+}
+
+impl<R, H: ParamMut<T>, T> ParamMut<T> for WithCx<R, H> {
+    #[inline]
+    fn param_mut(&mut self) -> &mut T {
+        self.cx.param_mut()
+    }
+}
+
+impl<R, H: ParamMaybeRef<T>, T> ParamMaybeRef<T> for WithCx<R, H> {
+    #[inline]
+    fn param_maybe_ref(&self) -> Option<&T> {
+        self.cx.param_maybe_ref()
+    }
+}
+
+impl<R, H: ParamMaybeMut<T>, T> ParamMaybeMut<T> for WithCx<R, H> {
+    #[inline]
+    fn param_maybe_mut(&mut self) -> Option<&mut T> {
+        self.cx.param_maybe_mut()
+    }
+}
+
+// Does not overlap with `Param<T> for T: Clone`, see the note on `WithCx`.
+impl<R, H: Param<T>, T> Param<T> for WithCx<R, H> {
+    #[inline]
+    fn param(&self) -> T {
+        self.cx.param()
+    }
+}
+
+impl<R, H: ParamSet<T>, T> ParamSet<T> for WithCx<R, H> {
+    type Transformed = WithCx<R, H::Transformed>;
+
+    #[inline]
+    fn param_set(self, item: T) -> Self::Transformed {
+        WithCx {
+            req: self.req,
+            cx: self.cx.param_set(item),
+        }
+    }
+}
+
+impl<R, H: ParamRemove<T>, T> ParamRemove<T> for WithCx<R, H> {
+    type Transformed = WithCx<R, H::Transformed>;
+
+    #[inline]
+    fn param_remove(self) -> Self::Transformed {
+        WithCx {
+            req: self.req,
+            cx: self.cx.param_remove(),
+        }
+    }
+}
+
+impl<R, H: ParamTake<T>, T> ParamTake<T> for WithCx<R, H> {
+    type Transformed = WithCx<R, H::Transformed>;
+
+    #[inline]
+    fn param_take(self) -> (Self::Transformed, T) {
+        let (cx, item) = self.cx.param_take();
+        (WithCx { req: self.req, cx }, item)
+    }
+}
+
+impl<R, H: ParamPush<T>, T> ParamPush<T> for WithCx<R, H> {
+    type Transformed = WithCx<R, H::Transformed>;
+
+    #[inline]
+    fn param_push(self, item: T) -> Self::Transformed {
+        WithCx {
+            req: self.req,
+            cx: self.cx.param_push(item),
+        }
+    }
+}
+
+impl<R, H: ParamDiscard<T>, T> ParamDiscard<T> for WithCx<R, H> {
+    type Transformed = WithCx<R, H::Transformed>;
+
+    #[inline]
+    fn param_discard(self) -> (Self::Transformed, bool) {
+        let (cx, dropped) = self.cx.param_discard();
+        (WithCx { req: self.req, cx }, dropped)
+    }
+}
+
+impl<R, H: ParamForget<T>, T> ParamForget<T> for WithCx<R, H> {
+    type Transformed = WithCx<R, H::Transformed>;
+
+    #[inline]
+    fn param_forget(self) -> Self::Transformed {
+        WithCx {
+            req: self.req,
+            cx: self.cx.param_forget(),
+        }
+    }
+}
+
+impl<R, H: ParamVacant<T>, T> ParamVacant<T> for WithCx<R, H> {}
+
+impl<R, H: ParamState<T>, T> ParamState<T> for WithCx<R, H> {
+    type State = H::State;
+}
+
+impl<R, H: Flag<T>, T> Flag<T> for WithCx<R, H> {}
+
+impl<R, H: ParamEq<T>, T: PartialEq> ParamEq<T> for WithCx<R, H> {}
+
+impl<R, H: Fork> Fork for WithCx<R, H> {
+    type Store = H::Store;
+    type State = H::State;
+
+    #[inline]
+    fn fork(&self) -> (Self::Store, Self::State) {
+        self.cx.fork()
+    }
+}
+
+impl<R, H: ForkOwned> ForkOwned for WithCx<R, H> {
+    type Owned = H::Owned;
+
+    #[inline]
+    fn fork_owned(&self) -> Self::Owned {
+        self.cx.fork_owned()
+    }
+}
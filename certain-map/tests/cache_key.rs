@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use certain_map::{certain_map, ParamSet};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Tenant(&'static str);
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Route(&'static str);
+#[derive(Debug, PartialEq)]
+pub struct RequestId(u64);
+
+certain_map! {
+    #[cache_key(tenant, route)]
+    pub struct Cx {
+        tenant: Tenant,
+        route: Route,
+        request_id: RequestId,
+    }
+}
+
+#[test]
+fn cache_key_ignores_other_fields() {
+    let mut responses = HashMap::new();
+    let mut store = Cx::new();
+    let cx = store
+        .handler()
+        .param_set(Tenant("a"))
+        .param_set(Route("/"))
+        .param_set(RequestId(1));
+    let key = cx.cache_key();
+    assert_eq!((&key.tenant, &key.route), (&Tenant("a"), &Route("/")));
+    responses.insert(key, "cached");
+
+    // Another request with the same tenant and route hits the entry.
+    let mut store = Cx::new();
+    let cx = store
+        .handler()
+        .param_set(Route("/"))
+        .param_set(Tenant("a"))
+        .param_set(RequestId(2));
+    assert_eq!(responses.get(&cx.cache_key()), Some(&"cached"));
+
+    let cx = cx.param_set(Tenant("b"));
+    assert!(!responses.contains_key(&cx.cache_key()));
+}
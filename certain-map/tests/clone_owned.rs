@@ -0,0 +1,34 @@
+use certain_map::{certain_map, ParamMaybeRef, ParamRef, ParamSet};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceId(u64);
+// Not Clone, but vacant when cloning.
+#[derive(Debug, PartialEq)]
+pub struct Body(Vec<u8>);
+
+certain_map! {
+    pub struct Cx {
+        trace_id: TraceId,
+        body: Body,
+    }
+}
+
+#[test]
+fn clone_owned_needs_only_occupied_fields_to_be_clone() {
+    let mut store = Cx::new();
+    let cx = store.handler().param_set(TraceId(7));
+    let owned = cx.clone_owned();
+    // The owned handler is 'static, so it can outlive the store, like in a background task.
+    let trace_id = std::thread::spawn(move || {
+        assert_eq!(ParamMaybeRef::<Body>::param_maybe_ref(&owned), None);
+        ParamRef::<TraceId>::param_ref(&owned).clone()
+    })
+    .join()
+    .unwrap();
+    assert_eq!(trace_id, TraceId(7));
+
+    // The original handler keeps its value and goes on independently.
+    let cx = cx.param_set(Body(vec![1]));
+    assert_eq!(ParamRef::<TraceId>::param_ref(&cx), &TraceId(7));
+    assert_eq!(ParamRef::<Body>::param_ref(&cx), &Body(vec![1]));
+}
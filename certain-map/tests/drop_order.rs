@@ -0,0 +1,50 @@
+use std::{cell::RefCell, rc::Rc};
+
+use certain_map::{certain_map, ParamSet};
+
+// Records its name in a shared log when dropped.
+struct Logged<const NAME: char>(Rc<RefCell<Vec<char>>>);
+
+impl<const NAME: char> Drop for Logged<NAME> {
+    fn drop(&mut self) {
+        self.0.borrow_mut().push(NAME);
+    }
+}
+
+certain_map! {
+    #[drop_order(c)]
+    struct Cx {
+        a: Logged<'a'>,
+        b: Logged<'b'>,
+        c: Logged<'c'>,
+    }
+}
+
+#[test]
+fn handler_drop_follows_drop_order() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut store = Cx::new();
+    let handler = store
+        .handler()
+        .param_set(Logged::<'a'>(log.clone()))
+        .param_set(Logged::<'b'>(log.clone()))
+        .param_set(Logged::<'c'>(log.clone()));
+    drop(handler);
+    // The listed field first, then the others in declaration order.
+    assert_eq!(*log.borrow(), ['c', 'a', 'b']);
+}
+
+#[test]
+fn reset_follows_drop_order() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut store = Cx::new();
+    let handler = store
+        .handler()
+        .param_set(Logged::<'b'>(log.clone()))
+        .param_set(Logged::<'c'>(log.clone()))
+        .param_set(Logged::<'a'>(log.clone()));
+    let handler = handler.reset();
+    assert_eq!(*log.borrow(), ['c', 'a', 'b']);
+    drop(handler);
+    assert_eq!(log.borrow().len(), 3);
+}
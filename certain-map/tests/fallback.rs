@@ -0,0 +1,45 @@
+use certain_map::{certain_map, Param, ParamRef, ParamRemove, ParamSet};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region(&'static str);
+#[derive(Debug, PartialEq)]
+pub struct Timeout(u64);
+
+certain_map! {
+    pub struct Cx {
+        #[ensure(Clone)]
+        #[fallback(Region("global"))]
+        region: Region,
+        #[fallback(Timeout(30))]
+        timeout: Timeout,
+    }
+}
+
+fn region_of<CX: Param<Region>>(cx: &CX) -> Region {
+    cx.param()
+}
+
+#[test]
+fn fallback_is_read_when_vacant() {
+    let mut store = Cx::new();
+    let cx = store.handler();
+    assert_eq!(region_of(&cx), Region("global"));
+    assert_eq!(ParamRef::<Timeout>::param_ref(&cx), &Timeout(30));
+
+    let cx = cx.param_set(Region("eu")).param_set(Timeout(5));
+    assert_eq!(region_of(&cx), Region("eu"));
+    assert_eq!(ParamRef::<Timeout>::param_ref(&cx), &Timeout(5));
+
+    // The fallback is back once the field is removed.
+    let cx = ParamRemove::<Timeout>::param_remove(cx);
+    assert_eq!(ParamRef::<Timeout>::param_ref(&cx), &Timeout(30));
+}
+
+#[test]
+fn fallback_is_created_once() {
+    let mut store = Cx::new();
+    let first: *const Timeout = ParamRef::<Timeout>::param_ref(&store.handler());
+    let mut other = Cx::new();
+    let second: *const Timeout = ParamRef::<Timeout>::param_ref(&other.handler());
+    assert_eq!(first, second);
+}
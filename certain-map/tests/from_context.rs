@@ -0,0 +1,64 @@
+use certain_map::{certain_map, FromContext, MissingParam, ParamSet, TryFromContext};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Peer(String);
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retries(u8);
+
+certain_map! {
+    pub struct Cx {
+        peer: Peer,
+        retries: Retries,
+        // A field which is itself optional.
+        deadline: Option<u64>,
+    }
+}
+
+#[derive(FromContext, TryFromContext, Debug, PartialEq)]
+pub struct View {
+    peer: Peer,
+    #[cx(maybe)]
+    retries: Option<Retries>,
+    deadline: Option<u64>,
+}
+
+#[test]
+fn maybe_fields_are_opt_in() {
+    let mut store = Cx::new();
+    let cx = store
+        .handler()
+        .param_set(Peer("a".to_string()))
+        .param_set(None::<u64>);
+    assert_eq!(
+        View::from_cx(&cx),
+        View {
+            peer: Peer("a".to_string()),
+            retries: None,
+            deadline: None,
+        }
+    );
+
+    let cx = cx.param_set(Retries(3)).param_set(Some(5u64));
+    assert_eq!(
+        View::from_cx(&cx),
+        View {
+            peer: Peer("a".to_string()),
+            retries: Some(Retries(3)),
+            deadline: Some(5),
+        }
+    );
+}
+
+#[test]
+fn unmarked_option_fields_can_be_missing() {
+    let mut store = Cx::new();
+    let cx = store.handler().param_set(Peer("a".to_string()));
+    assert_eq!(
+        View::try_from_cx(&cx),
+        Err(MissingParam {
+            missing: vec!["deadline"],
+        })
+    );
+    let cx = cx.param_set(Some(5u64));
+    assert_eq!(View::try_from_cx(&cx).unwrap().deadline, Some(5));
+}
@@ -0,0 +1,51 @@
+use certain_map::{certain_map, ParamRef, ParamSet};
+
+#[derive(Debug, PartialEq)]
+pub struct Region(&'static str);
+#[derive(Debug, PartialEq)]
+pub struct Limit(u32);
+
+certain_map! {
+    #[global]
+    pub struct Config {
+        region: Region,
+        limit: Limit,
+    }
+
+    // Never initialized.
+    #[global]
+    pub struct Unset {
+        region: Region,
+    }
+}
+
+#[test]
+fn global_is_set_once() {
+    assert!(Config::try_global_handler().is_none());
+    let handler = Config::owned_handler()
+        .param_set(Region("eu"))
+        .param_set(Limit(100));
+    assert!(Config::init_global(handler).is_ok());
+
+    // The second handler is given back.
+    let handler = Config::owned_handler()
+        .param_set(Region("us"))
+        .param_set(Limit(1));
+    let handler = Config::init_global(handler).unwrap_err();
+    assert_eq!(ParamRef::<Region>::param_ref(&handler), &Region("us"));
+
+    let global = Config::global_handler();
+    assert_eq!(ParamRef::<Region>::param_ref(global), &Region("eu"));
+    assert_eq!(ParamRef::<Limit>::param_ref(global), &Limit(100));
+    // Readable from other threads too.
+    let limit = std::thread::spawn(|| ParamRef::<Limit>::param_ref(Config::global_handler()))
+        .join()
+        .unwrap();
+    assert_eq!(limit, &Limit(100));
+}
+
+#[test]
+#[should_panic(expected = "Unset::init_global() must be called before global_handler()")]
+fn global_handler_panics_before_init() {
+    Unset::global_handler();
+}
@@ -0,0 +1,49 @@
+use certain_map::{certain_map, ParamMaybeRef, ParamRef, ParamSet};
+
+#[derive(Debug, PartialEq)]
+pub struct User(&'static str);
+#[derive(Debug, PartialEq)]
+pub struct Lang(&'static str);
+
+certain_map! {
+    pub struct Cx {
+        user: User,
+        lang: Lang,
+    }
+
+    impl Cx {
+        // Attached to the handlers of every state.
+        pub fn lang_or_default(&self) -> &'static str {
+            ParamMaybeRef::<Lang>::param_maybe_ref(self).map_or("en", |lang| lang.0)
+        }
+
+        #[requires(user)]
+        pub fn greeting(&self) -> String {
+            format!("hello, {}", ParamRef::<User>::param_ref(self).0)
+        }
+
+        #[produces(lang)]
+        pub fn with_lang(self, lang: &'static str) -> _ {
+            self.param_set(Lang(lang))
+        }
+    }
+}
+
+#[test]
+fn methods_are_attached_to_every_state() {
+    let mut store = Cx::new();
+    let cx = store.handler();
+    assert_eq!(cx.lang_or_default(), "en");
+    let cx = cx.param_set(User("ihciah"));
+    assert_eq!(cx.lang_or_default(), "en");
+    assert_eq!(cx.greeting(), "hello, ihciah");
+}
+
+#[test]
+fn produces_names_the_returned_state() {
+    let mut store = Cx::new();
+    let cx = store.handler().with_lang("zh");
+    // The field is known to be occupied after the call.
+    assert_eq!(ParamRef::<Lang>::param_ref(&cx), &Lang("zh"));
+    assert_eq!(cx.lang_or_default(), "zh");
+}
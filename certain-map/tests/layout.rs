@@ -0,0 +1,62 @@
+use std::mem::MaybeUninit;
+
+use certain_map::{certain_map, ParamRef, ParamSet};
+
+#[derive(Debug, PartialEq)]
+pub struct Kind(u8);
+#[derive(Debug)]
+pub struct Len(u64);
+#[derive(Debug)]
+pub struct Text(String);
+#[derive(Debug)]
+pub struct Bytes(Vec<u8>);
+
+certain_map! {
+    #[derive(Debug)]
+    pub struct Cx {
+        r#type: Kind,
+        #[padded]
+        len: Len,
+        #[one_of(body)]
+        text: Text,
+        #[one_of(body)]
+        bytes: Bytes,
+    }
+}
+
+// Read a value through the layout constants, like an external reader of the store.
+unsafe fn read<T>(store: &Cx, offset: usize) -> &T {
+    &*(store as *const Cx).cast::<u8>().add(offset).cast::<T>()
+}
+
+#[test]
+fn raw_identifier_fields() {
+    let mut store = Cx::new();
+    let cx = store
+        .handler()
+        .param_set(Kind(1))
+        .param_set(Text("text".to_string()));
+    assert_eq!(ParamRef::<Kind>::param_ref(&cx), &Kind(1));
+    assert_eq!(ParamRef::<Text>::param_ref(&cx).0, "text");
+    assert!(format!("{cx:?}").contains("Kind(1)"));
+}
+
+#[test]
+fn layout_constants() {
+    let mut store = Cx::new();
+    let cx = store
+        .handler()
+        .param_set(Kind(7))
+        .param_set(Len(42))
+        .param_set(Bytes(vec![1, 2]));
+    drop(cx.forget_all());
+    unsafe {
+        assert_eq!(read::<Kind>(&store, Cx::OFFSET_TYPE), &Kind(7));
+        assert_eq!(read::<Len>(&store, Cx::OFFSET_LEN).0, 42);
+        assert_eq!(read::<Bytes>(&store, Cx::OFFSET_BYTES).0, [1, 2]);
+        assert_eq!(Cx::OFFSET_TEXT, Cx::OFFSET_BYTES);
+        drop(std::ptr::read(read::<Bytes>(&store, Cx::OFFSET_BYTES)));
+    }
+    assert_eq!(Cx::ALIGN_TYPE, std::mem::align_of::<MaybeUninit<Kind>>());
+    assert_eq!(Cx::OFFSET_LEN % Cx::ALIGN_LEN, 0);
+}
@@ -0,0 +1,50 @@
+// None of the param traits are imported, the prefixed methods work without them.
+use certain_map::certain_map;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region(&'static str);
+#[derive(Debug, PartialEq)]
+pub struct Hits(u32);
+
+certain_map! {
+    #[method_prefix(cx)]
+    pub struct Cx {
+        #[ensure(Clone)]
+        region: Region,
+        hits: Hits,
+    }
+}
+
+certain_map! {
+    #[style = "unfilled"]
+    #[method_prefix(cx)]
+    pub struct Unfilled {
+        region: Region,
+    }
+}
+
+#[test]
+fn prefixed_methods_forward_to_the_param_traits() {
+    let mut store = Cx::new();
+    let cx = store.handler();
+    assert_eq!(cx.cx_maybe_ref::<Region>(), None);
+
+    let mut cx = cx.cx_set(Region("eu")).cx_set(Hits(0));
+    assert_eq!(cx.cx_get::<Region>(), Region("eu"));
+    cx.cx_mut::<Hits>().0 += 1;
+    if let Some(hits) = cx.cx_maybe_mut::<Hits>() {
+        hits.0 += 1;
+    }
+    assert_eq!(cx.cx_ref::<Hits>(), &Hits(2));
+
+    let (cx, hits) = cx.cx_take::<Hits>();
+    assert_eq!(hits, Hits(2));
+    let cx = cx.cx_remove::<Region>();
+    assert_eq!(cx.cx_maybe_ref::<Region>(), None);
+}
+
+#[test]
+fn unfilled_maps_have_prefixed_methods() {
+    let cx = Unfilled::new().cx_set(Region("eu"));
+    assert_eq!(cx.cx_ref::<Region>(), &Region("eu"));
+}
@@ -0,0 +1,128 @@
+use std::{cell::Cell, rc::Rc};
+
+use certain_map::{certain_map, ParamRef, ParamSet, StorePool};
+
+// Counts the drops of its values.
+struct Conn(Rc<Cell<usize>>);
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+struct Req(Rc<Cell<usize>>);
+
+impl Drop for Req {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+certain_map! {
+    struct Cx {
+        #[persistent]
+        conn: Conn,
+        req: Req,
+    }
+}
+
+#[test]
+fn resume_keeps_persistent_fields() {
+    let conns = Rc::new(Cell::new(0));
+    let reqs = Rc::new(Cell::new(0));
+    let mut store = Cx::new();
+    assert!(store.resume().is_none());
+
+    let handler = store
+        .handler()
+        .param_set(Conn(conns.clone()))
+        .param_set(Req(reqs.clone()));
+    drop(handler);
+    assert_eq!((conns.get(), reqs.get()), (0, 1));
+
+    let handler = store.resume().unwrap().param_set(Req(reqs.clone()));
+    assert!(Rc::ptr_eq(&ParamRef::<Conn>::param_ref(&handler).0, &conns));
+    drop(handler);
+    assert_eq!((conns.get(), reqs.get()), (0, 2));
+
+    // resuming consumes the kept fields, a handler without them does not keep them again
+    let handler = store.resume().unwrap();
+    let _ = handler.reset();
+    assert_eq!(conns.get(), 1);
+    assert!(store.resume().is_none());
+    drop(store);
+    assert_eq!((conns.get(), reqs.get()), (1, 2));
+}
+
+#[test]
+fn handler_drops_persistent_fields() {
+    let conns = Rc::new(Cell::new(0));
+    let mut store = Cx::new();
+    drop(store.handler().param_set(Conn(conns.clone())));
+    assert_eq!(conns.get(), 0);
+
+    drop(store.handler());
+    assert_eq!(conns.get(), 1);
+    assert!(store.resume().is_none());
+    drop(store);
+    assert_eq!(conns.get(), 1);
+}
+
+#[test]
+fn store_drops_persistent_fields() {
+    let conns = Rc::new(Cell::new(0));
+    let mut store = Cx::new();
+    drop(store.handler().param_set(Conn(conns.clone())));
+    assert_eq!(conns.get(), 0);
+    drop(store);
+    assert_eq!(conns.get(), 1);
+
+    // the boxed store of an owned handler is dropped with it
+    drop(Cx::owned_handler().param_set(Conn(conns.clone())));
+    assert_eq!(conns.get(), 2);
+}
+
+#[test]
+fn migrate_drops_persistent_fields_of_dest() {
+    let conns = Rc::new(Cell::new(0));
+    let moved = Rc::new(Cell::new(0));
+    let mut dest = Cx::new();
+    drop(dest.handler().param_set(Conn(conns.clone())));
+
+    let mut source = Cx::new();
+    let handler = source.handler().param_set(Conn(moved.clone()));
+    let (state, empty) = handler.migrate(&mut dest);
+    drop(empty);
+    assert_eq!((conns.get(), moved.get()), (1, 0));
+
+    let handler = unsafe { state.attach(&mut dest) };
+    assert!(Rc::ptr_eq(&ParamRef::<Conn>::param_ref(&handler).0, &moved));
+    drop(handler);
+    drop(source);
+    assert_eq!(moved.get(), 0);
+    drop(dest);
+    assert_eq!((conns.get(), moved.get()), (1, 1));
+}
+
+#[test]
+fn pool_drops_persistent_fields() {
+    let conns = Rc::new(Cell::new(0));
+    let pool = StorePool::<Cx>::new(1);
+
+    let mut store = pool.get();
+    drop(store.handler().param_set(Conn(conns.clone())));
+    pool.put(store);
+    assert_eq!(conns.get(), 1);
+    assert_eq!(pool.idle(), 1);
+
+    // the pool is full, the store is dropped along with its persistent fields
+    let mut store = Cx::new();
+    drop(store.handler().param_set(Conn(conns.clone())));
+    pool.put(store);
+    assert_eq!(conns.get(), 2);
+    assert_eq!(pool.idle(), 1);
+
+    let mut store = pool.get();
+    assert!(store.resume().is_none());
+}
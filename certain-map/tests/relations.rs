@@ -0,0 +1,58 @@
+use certain_map::{certain_map, Param, ParamRef, ParamSet};
+
+#[derive(Debug, PartialEq)]
+pub struct Peer(&'static str);
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region(&'static str);
+#[derive(Debug, PartialEq)]
+pub struct Route(&'static str);
+
+certain_map! {
+    pub struct Base {
+        peer: Peer,
+        #[ensure(Clone)]
+        region: Region,
+    }
+
+    #[extends(Base)]
+    pub struct Req {
+        route: Route,
+    }
+
+    #[subset_of(Req)]
+    pub struct Small {
+        region: _,
+        route: Route,
+    }
+}
+
+fn names(fields: &[certain_map::FieldInfo]) -> Vec<&'static str> {
+    fields.iter().map(|field| field.name).collect()
+}
+
+#[test]
+fn extends_prepends_the_base_fields() {
+    assert_eq!(names(Req::FIELDS), ["peer", "region", "route"]);
+    let mut store = Req::new();
+    let cx = store
+        .handler()
+        .param_set(Peer("10.0.0.1"))
+        .param_set(Region("eu"))
+        .param_set(Route("/"));
+    assert_eq!(ParamRef::<Peer>::param_ref(&cx), &Peer("10.0.0.1"));
+    // The attributes of the base fields come along.
+    assert_eq!(Param::<Region>::param(&cx), Region("eu"));
+}
+
+#[test]
+fn subset_of_takes_the_inferred_fields_from_the_base() {
+    assert_eq!(names(Small::FIELDS), ["region", "route"]);
+    let mut store = Small::new();
+    let cx = store
+        .handler()
+        .param_set(Region("eu"))
+        .param_set(Route("/"));
+    // `region: _` has the type and the `#[ensure(Clone)]` of the base field.
+    assert_eq!(Param::<Region>::param(&cx), Region("eu"));
+    assert_eq!(ParamRef::<Route>::param_ref(&cx), &Route("/"));
+}
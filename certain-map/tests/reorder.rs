@@ -0,0 +1,62 @@
+use std::mem::{align_of, size_of};
+
+use certain_map::{certain_map, ParamRef, ParamSet, ParamTake};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Flag(u8);
+#[derive(Clone, Debug, PartialEq)]
+pub struct Id(u64);
+#[derive(Clone, Debug, PartialEq)]
+pub struct Port(u16);
+#[derive(Clone, Debug, PartialEq)]
+pub struct Name(String);
+#[derive(Clone, Debug, PartialEq)]
+pub struct Code(u32);
+
+certain_map! {
+    #[derive(Clone, Debug)]
+    #[reorder_by_align]
+    pub struct Cx {
+        flag: Flag,
+        port: Port,
+        id: Id,
+        #[one_of(peer)]
+        name: Name,
+        #[one_of(peer)]
+        code: Code,
+    }
+}
+
+#[test]
+fn slots_are_sorted_by_alignment() {
+    // The declaration order would pad the flag and the port up to the alignment of the id.
+    // Sorted, the slots follow each other, the union of the peer after the id declared before
+    // it.
+    assert_eq!(Cx::OFFSET_ID, 0);
+    assert_eq!(Cx::OFFSET_NAME, size_of::<Id>());
+    assert_eq!(Cx::OFFSET_CODE, Cx::OFFSET_NAME);
+    assert_eq!(Cx::OFFSET_PORT, Cx::OFFSET_NAME + size_of::<Name>());
+    assert_eq!(Cx::OFFSET_FLAG, Cx::OFFSET_PORT + size_of::<Port>());
+    assert_eq!(Cx::ALIGN, align_of::<Id>());
+}
+
+#[test]
+fn sorted_store_keeps_the_values() {
+    let mut store = Cx::new();
+    let cx = store
+        .handler()
+        .param_set(Flag(1))
+        .param_set(Port(80))
+        .param_set(Id(7))
+        .param_set(Name("peer".to_string()));
+    assert_eq!(ParamRef::<Flag>::param_ref(&cx), &Flag(1));
+    assert_eq!(ParamRef::<Port>::param_ref(&cx), &Port(80));
+    assert_eq!(ParamRef::<Id>::param_ref(&cx), &Id(7));
+    assert_eq!(ParamRef::<Name>::param_ref(&cx).0, "peer");
+
+    let forked = cx.fork_owned();
+    let (forked, name) = ParamTake::<Name>::param_take(forked);
+    assert_eq!(name, Name("peer".to_string()));
+    assert_eq!(ParamRef::<Id>::param_ref(&forked), &Id(7));
+    assert!(format!("{cx:?}").contains("Port(80)"));
+}
@@ -0,0 +1,39 @@
+use certain_map::{certain_map, OccupiedM, ParamRef, ParamSet, Vacancy};
+
+#[derive(Debug, PartialEq)]
+pub struct User(&'static str);
+#[derive(Debug, PartialEq)]
+pub struct Role(&'static str);
+
+certain_map! {
+    #[state_enum(CxPhase)]
+    #[derive(Debug)]
+    pub struct Cx {
+        user: User,
+        role: Role,
+    }
+}
+
+#[test]
+fn state_enum_round_trip() {
+    let mut store = Cx::new();
+    let phase = CxPhase::from(store.handler().param_set(User("ihciah")));
+    assert_eq!(phase.state_name(), "user");
+    assert!(matches!(phase, CxPhase::user(_)));
+
+    // Converting back to another state gives the enum back.
+    let Err(phase) = CxHandler::<'_, OccupiedM, OccupiedM>::try_from(phase) else {
+        unreachable!("the role is vacant");
+    };
+    let handler = CxHandler::<'_, OccupiedM, Vacancy>::try_from(phase).unwrap();
+    assert_eq!(handler.param_ref(), &User("ihciah"));
+
+    let phase = CxPhase::from(handler.param_set(Role("admin")));
+    assert_eq!(phase.state_name(), "user__role");
+    let phase = CxPhase::from(
+        CxHandler::<'_, OccupiedM, OccupiedM>::try_from(phase)
+            .unwrap()
+            .reset(),
+    );
+    assert_eq!(phase.state_name(), "Empty");
+}
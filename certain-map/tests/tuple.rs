@@ -0,0 +1,27 @@
+use certain_map::{certain_map, ParamRef, ParamSet, ParamTake};
+
+#[derive(Debug, PartialEq)]
+pub struct Peer(&'static str);
+#[derive(Debug, PartialEq)]
+pub struct Route(&'static str);
+
+certain_map! {
+    #[derive(Debug)]
+    pub struct Cx(Peer, Route);
+}
+
+#[test]
+fn tuple_fields_are_named_by_position() {
+    let names: Vec<_> = Cx::FIELDS.iter().map(|field| field.name).collect();
+    assert_eq!(names, ["_0", "_1"]);
+
+    let mut store = Cx::new();
+    let cx = store
+        .handler()
+        .param_set(Route("/"))
+        .param_set(Peer("10.0.0.1"));
+    assert_eq!(ParamRef::<Peer>::param_ref(&cx), &Peer("10.0.0.1"));
+    let (cx, route) = ParamTake::<Route>::param_take(cx);
+    assert_eq!(route, Route("/"));
+    assert_eq!(cx.occupied_names().collect::<Vec<_>>(), ["_0"]);
+}
@@ -0,0 +1,42 @@
+use certain_map::{certain_map, ParamMaybeMut, ParamMaybeRef, ParamSet, VacancyStat};
+
+pub struct User;
+pub struct Route;
+
+certain_map! {
+    pub struct Cx {
+        user: User,
+        route: Route,
+    }
+}
+
+#[test]
+fn vacancy_stats_count_the_misses() {
+    let mut store = Cx::new();
+    let mut cx = store.handler().param_set(User);
+    // Occupied fields are no misses.
+    assert!(ParamMaybeRef::<User>::param_maybe_ref(&cx).is_some());
+    assert!(ParamMaybeRef::<Route>::param_maybe_ref(&cx).is_none());
+    assert!(ParamMaybeMut::<Route>::param_maybe_mut(&mut cx).is_none());
+    drop(cx);
+
+    // The counts are shared by all instances of the map.
+    let mut other = Cx::new();
+    assert!(ParamMaybeRef::<User>::param_maybe_ref(&other.handler()).is_none());
+    assert_eq!(
+        Cx::vacancy_stats(),
+        [
+            VacancyStat {
+                name: "user",
+                misses: 1,
+            },
+            VacancyStat {
+                name: "route",
+                misses: 2,
+            },
+        ]
+    );
+
+    Cx::reset_vacancy_stats();
+    assert!(Cx::vacancy_stats().iter().all(|stat| stat.misses == 0));
+}
@@ -0,0 +1,40 @@
+use certain_map::{certain_map, ParamSet};
+
+#[derive(Debug, PartialEq)]
+pub struct Peer(&'static str);
+#[derive(Debug, PartialEq)]
+pub struct Route(&'static str);
+
+certain_map! {
+    #[ref_view(CxRefs)]
+    #[maybe_view(CxMaybeRefs)]
+    pub struct Cx {
+        peer: Peer,
+        route: Route,
+    }
+}
+
+#[test]
+fn ref_view_borrows_every_field() {
+    let mut store = Cx::new();
+    let cx = store
+        .handler()
+        .param_set(Peer("10.0.0.1"))
+        .param_set(Route("/"));
+    let CxRefs { peer, route } = cx.ref_view();
+    assert_eq!(peer, &Peer("10.0.0.1"));
+    assert_eq!(route, &Route("/"));
+}
+
+#[test]
+fn maybe_view_borrows_the_occupied_fields() {
+    let mut store = Cx::new();
+    let cx = store.handler();
+    let view = cx.maybe_view();
+    assert!(view.peer.is_none() && view.route.is_none());
+
+    let cx = cx.param_set(Route("/"));
+    let CxMaybeRefs { peer, route } = cx.maybe_view();
+    assert_eq!(peer, None);
+    assert_eq!(route, Some(&Route("/")));
+}
@@ -0,0 +1,30 @@
+// The map is private to `inner`, the overrides make all but its state visible here.
+mod inner {
+    use certain_map::certain_map;
+
+    pub struct Peer(pub &'static str);
+
+    certain_map! {
+        #[vis(store = pub, handler = pub, owned = pub)]
+        struct Cx {
+            peer: Peer,
+        }
+    }
+}
+
+use certain_map::{ParamRef, ParamSet};
+use inner::{Cx, CxHandler, CxOwnedHandler, Peer};
+
+fn peer_of(cx: &CxHandler<'_, certain_map::OccupiedM>) -> &'static str {
+    ParamRef::<Peer>::param_ref(cx).0
+}
+
+#[test]
+fn vis_overrides_apply() {
+    let mut store = Cx::new();
+    let cx = store.handler().param_set(Peer("10.0.0.1"));
+    assert_eq!(peer_of(&cx), "10.0.0.1");
+
+    let owned: CxOwnedHandler<certain_map::OccupiedM> = Cx::owned_handler().param_set(Peer("::1"));
+    assert_eq!(ParamRef::<Peer>::param_ref(&owned).0, "::1");
+}
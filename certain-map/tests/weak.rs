@@ -0,0 +1,35 @@
+use std::{rc, sync};
+
+use certain_map::{certain_map, Param, ParamSet};
+
+pub struct Pool(u8);
+
+certain_map! {
+    pub struct Cx {
+        #[weak]
+        pool: sync::Weak<Pool>,
+        #[weak]
+        local_pool: std::rc::Weak<Pool>,
+    }
+}
+
+#[test]
+fn weak_fields_upgrade_while_the_resource_lives() {
+    let mut store = Cx::new();
+    let cx = store.handler();
+    assert!(Param::<Option<sync::Arc<Pool>>>::param(&cx).is_none());
+
+    let pool = sync::Arc::new(Pool(1));
+    let local_pool = rc::Rc::new(Pool(2));
+    let cx = cx.param_set(pool.clone()).param_set(local_pool.clone());
+    // The context does not keep the resources alive.
+    assert_eq!(sync::Arc::strong_count(&pool), 1);
+    assert_eq!(rc::Rc::strong_count(&local_pool), 1);
+    assert_eq!(Param::<Option<sync::Arc<Pool>>>::param(&cx).unwrap().0, 1);
+    assert_eq!(Param::<Option<rc::Rc<Pool>>>::param(&cx).unwrap().0, 2);
+
+    drop(pool);
+    drop(local_pool);
+    assert!(Param::<Option<sync::Arc<Pool>>>::param(&cx).is_none());
+    assert!(Param::<Option<rc::Rc<Pool>>>::param(&cx).is_none());
+}